@@ -1,16 +1,18 @@
 // src/admin_handlers.rs
 
-use crate::auth::{self, AppState, User};
+use crate::auth::{AppState, AuthUser, User};
 use crate::users;
 use crate::escala::Genero;
+use crate::escala_dividas::DestinoDividas;
+use crate::store::Recusa;
+use crate::virada_ano::{self, OpcoesVirada};
 use axum::{
     debug_handler,
-    extract::{Form, State},
-    http::StatusCode,
-    response::{IntoResponse, Redirect},
+    extract::{Form, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
 };
 use serde::Deserialize;
-use tower_cookies::Cookies;
 // ADICIONADO: Importar o novo módulo de views
 use crate::views;
 
@@ -31,17 +33,12 @@ pub struct CreateUserForm {
     turma: String,
 }
 
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
 /// Apresenta a página de administração.
 #[debug_handler]
 pub async fn admin_page_handler(
-    State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    // --- CORRIGIDO: Adicionado .await ---
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado. Apenas para administradores.").into_response();
-    }
-
     // MODIFICADO: Chama a função da view em vez de ter o HTML aqui
     views::admin::admin_page().into_response()
 }
@@ -50,15 +47,9 @@ pub async fn admin_page_handler(
 #[debug_handler]
 pub async fn change_password_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
     Form(form): Form<ChangePasswordForm>,
 ) -> impl IntoResponse {
-    // --- CORRIGIDO: Adicionado .await ---
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-
-    let users_to_save;
+    let user_to_save;
     {
         let mut users_map = state.users.lock().unwrap();
         if let Some(user) = users_map.get_mut(&form.username) {
@@ -66,14 +57,20 @@ pub async fn change_password_handler(
                 Ok(h) => h,
                 Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar hash da senha.").into_response(),
             };
+            // A senha reposta é conhecida pelo admin: o utilizador escolhe outra no próximo login
+            user.trocar_senha = true;
+            user_to_save = user.clone();
         } else {
             return (StatusCode::NOT_FOUND, format!("Utilizador '{}' não encontrado.", form.username)).into_response();
         }
-        users_to_save = users_map.clone();
     }
 
-    if let Err(e) = users::save_users(&users_to_save).await {
-        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Erro ao guardar ficheiro: {}", e)).into_response();
+    if let Err(e) = users::save_user(state.user_store.as_ref(), &user_to_save).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Erro ao guardar o utilizador: {}", e)).into_response();
+    }
+    // A senha antiga deixa de valer: termina todas as sessões abertas do utilizador
+    if let Err(e) = state.sessions.revoke_user(&form.username).await {
+        eprintln!("🔥 Falha ao terminar as sessões de {}: {}", form.username, e);
     }
     println!("✅ Senha do utilizador '{}' alterada com sucesso.", form.username);
     Redirect::to("/admin").into_response()
@@ -83,15 +80,9 @@ pub async fn change_password_handler(
 #[debug_handler]
 pub async fn create_user_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
     Form(form): Form<CreateUserForm>,
 ) -> impl IntoResponse {
-    // --- CORRIGIDO: Adicionado .await ---
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-
-    let users_to_save;
+    let user_to_save;
     {
         let mut users_map = state.users.lock().unwrap();
         if users_map.contains_key(&form.username) {
@@ -119,14 +110,362 @@ pub async fn create_user_handler(
             curso: form.curso,
             genero: form.genero,
             roles,
+            ativo: true,
+            trocar_senha: true,
+            token_calendario: None,
         };
-        users_map.insert(form.username.clone(), new_user);
-        users_to_save = users_map.clone();
+        users_map.insert(form.username.clone(), new_user.clone());
+        user_to_save = new_user;
     }
 
-    if let Err(e) = users::save_users(&users_to_save).await {
-        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Erro ao guardar o ficheiro de utilizadores: {}", e)).into_response();
+    if let Err(e) = users::save_user(state.user_store.as_ref(), &user_to_save).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Erro ao guardar o utilizador: {}", e)).into_response();
     }
     println!("✅ Utilizador '{}' criado com sucesso.", form.username);
     Redirect::to("/admin").into_response()
-}
\ No newline at end of file
+}
+// --- GESTÃO DE UTILIZADORES ---
+
+#[derive(Debug, Deserialize)]
+pub struct UtilizadoresQuery {
+    #[serde(default)]
+    q: String,
+    ano: Option<String>,
+    estado: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditarQuery {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UtilizadorIdForm {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportarCsvForm {
+    csv: String,
+}
+
+/// Lista e pesquisa de utilizadores.
+#[debug_handler]
+pub async fn utilizadores_page(
+    State(state): State<AppState>,
+    Query(query): Query<UtilizadoresQuery>,
+) -> impl IntoResponse {
+    let users_map = state.users.lock().unwrap().clone();
+    let estado = query.estado.as_deref().unwrap_or("ativos");
+    let ano = query.ano.as_deref().and_then(|a| a.parse::<u8>().ok());
+    let pesquisa = query.q.trim().to_lowercase();
+
+    let mut users: Vec<&User> = users_map
+        .values()
+        .filter(|u| match estado {
+            "inativos" => !u.ativo,
+            "todos" => true,
+            _ => u.ativo,
+        })
+        .filter(|u| ano.is_none_or(|a| u.ano == a))
+        .filter(|u| {
+            pesquisa.is_empty()
+                || u.id.to_lowercase().contains(&pesquisa)
+                || u.name.to_lowercase().contains(&pesquisa)
+                || u.turma.to_lowercase().contains(&pesquisa)
+        })
+        .collect();
+    users.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let filtro = views::admin::FiltroUtilizadores { pesquisa: query.q.trim(), ano, estado };
+    views::admin::utilizadores_page(&users, &filtro, users_map.len()).into_response()
+}
+
+/// Formulário de edição de um utilizador.
+#[debug_handler]
+pub async fn editar_utilizador_page(
+    State(state): State<AppState>,
+    Query(query): Query<EditarQuery>,
+) -> impl IntoResponse {
+    let user = state.users.lock().unwrap().get(&query.id).cloned();
+    match user {
+        Some(user) => views::admin::editar_utilizador_page(&user).into_response(),
+        None => (StatusCode::NOT_FOUND, Html(format!("Utilizador '{}' não encontrado.", query.id))).into_response(),
+    }
+}
+
+/// Grava a edição de um utilizador. O formulário é lido como pares porque as funções
+/// chegam como várias caixas `funcoes` com o mesmo nome.
+#[debug_handler]
+pub async fn editar_utilizador_handler(
+    State(state): State<AppState>,
+    operador: AuthUser,
+    Form(campos): Form<Vec<(String, String)>>,
+) -> impl IntoResponse {
+    let campo = |nome: &str| campos.iter().find(|(k, _)| k == nome).map(|(_, v)| v.trim().to_string());
+    let Some(id) = campo("id") else {
+        return (StatusCode::BAD_REQUEST, Html("Falta o número do utilizador.".to_string())).into_response();
+    };
+    let Some(mut user) = state.users.lock().unwrap().get(&id).cloned() else {
+        return (StatusCode::NOT_FOUND, Html(format!("Utilizador '{}' não encontrado.", id))).into_response();
+    };
+
+    let nome = campo("name").unwrap_or_default();
+    if nome.is_empty() {
+        return (StatusCode::BAD_REQUEST, Html("O nome é obrigatório.".to_string())).into_response();
+    }
+    let Some(ano) = campo("ano").and_then(|a| a.parse::<u8>().ok()) else {
+        return (StatusCode::BAD_REQUEST, Html("Ano inválido.".to_string())).into_response();
+    };
+    let Some(curso) = campo("curso").and_then(|c| c.chars().next()) else {
+        return (StatusCode::BAD_REQUEST, Html("Curso inválido.".to_string())).into_response();
+    };
+    let genero = match campo("genero").as_deref() {
+        Some("M") => Genero::Masculino,
+        Some("F") => Genero::Feminino,
+        Some("X") => Genero::Misto,
+        _ => return (StatusCode::BAD_REQUEST, Html("Género inválido.".to_string())).into_response(),
+    };
+    let outras = campo("outras_funcoes").unwrap_or_default();
+    let roles = users::normalizar_funcoes(
+        campos
+            .iter()
+            .filter(|(k, _)| k == "funcoes")
+            .map(|(_, v)| v.as_str())
+            .chain(outras.split(',')),
+    );
+    let ativo = campo("ativo").as_deref() != Some("false");
+
+    // Um administrador não pode tirar a si próprio o acesso à administração
+    if id == operador.user_id && (!ativo || !roles.iter().any(|r| r == "admin")) {
+        return (StatusCode::CONFLICT, Html("Não pode desativar nem retirar a função de admin a si próprio.".to_string())).into_response();
+    }
+
+    let mudou_acesso = user.roles != roles || user.ativo != ativo;
+    user.name = nome;
+    user.turma = campo("turma").unwrap_or_default();
+    user.ano = ano;
+    user.curso = curso;
+    user.genero = genero;
+    user.roles = roles;
+    user.ativo = ativo;
+
+    if let Err(e) = guardar_e_atualizar(&state, &user, mudou_acesso).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao guardar o utilizador: {}", e))).into_response();
+    }
+    println!("✅ Utilizador '{}' atualizado por {}.", user.id, operador.user_id);
+    Redirect::to("/admin/utilizadores?estado=todos").into_response()
+}
+
+/// Desativa um utilizador: deixa de entrar na escala, nas refeições e na presença.
+#[debug_handler]
+pub async fn desativar_utilizador_handler(
+    State(state): State<AppState>,
+    operador: AuthUser,
+    Form(form): Form<UtilizadorIdForm>,
+) -> impl IntoResponse {
+    if form.id == operador.user_id {
+        return (StatusCode::CONFLICT, Html("Não pode desativar a sua própria conta.".to_string())).into_response();
+    }
+    alterar_estado(&state, &form.id, false).await
+}
+
+/// Reativa um utilizador desativado.
+#[debug_handler]
+pub async fn reativar_utilizador_handler(
+    State(state): State<AppState>,
+    Form(form): Form<UtilizadorIdForm>,
+) -> impl IntoResponse {
+    alterar_estado(&state, &form.id, true).await
+}
+
+async fn alterar_estado(state: &AppState, id: &str, ativo: bool) -> Response {
+    let Some(mut user) = state.users.lock().unwrap().get(id).cloned() else {
+        return (StatusCode::NOT_FOUND, Html(format!("Utilizador '{}' não encontrado.", id))).into_response();
+    };
+    user.ativo = ativo;
+    if let Err(e) = guardar_e_atualizar(state, &user, true).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao guardar o utilizador: {}", e))).into_response();
+    }
+    println!("✅ Utilizador '{}' {}.", id, if ativo { "reativado" } else { "desativado" });
+    Redirect::to("/admin/utilizadores?estado=todos").into_response()
+}
+
+/// Apaga um utilizador de vez, se não tiver empréstimos nem dívidas de serviço pendentes.
+#[debug_handler]
+pub async fn apagar_utilizador_handler(
+    State(state): State<AppState>,
+    operador: AuthUser,
+    Form(form): Form<UtilizadorIdForm>,
+) -> impl IntoResponse {
+    if form.id == operador.user_id {
+        return (StatusCode::CONFLICT, Html("Não pode apagar a sua própria conta.".to_string())).into_response();
+    }
+    if !state.users.lock().unwrap().contains_key(&form.id) {
+        return (StatusCode::NOT_FOUND, Html(format!("Utilizador '{}' não encontrado.", form.id))).into_response();
+    }
+    match users::impedimentos_remocao(state.escala_store.as_ref(), &state.config, &form.id).await {
+        Ok(motivos) if !motivos.is_empty() => {
+            let mensagem = format!(
+                "Não é possível apagar '{}': {}. Resolva primeiro ou desative o utilizador.",
+                form.id,
+                motivos.join("; ")
+            );
+            return (StatusCode::CONFLICT, Html(mensagem)).into_response();
+        }
+        Ok(_) => {}
+        Err(e) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao verificar o utilizador: {}", e))).into_response();
+        }
+    }
+
+    if let Err(e) = state.user_store.apagar(&form.id).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao apagar o utilizador: {}", e))).into_response();
+    }
+    state.users.lock().unwrap().remove(&form.id);
+    if let Err(e) = state.sessions.revoke_user(&form.id).await {
+        eprintln!("🔥 Falha ao terminar as sessões de {}: {}", form.id, e);
+    }
+    println!("🗑️ Utilizador '{}' apagado por {}.", form.id, operador.user_id);
+    Redirect::to("/admin/utilizadores?estado=todos").into_response()
+}
+
+/// Exporta todos os utilizadores em CSV (sem senhas).
+#[debug_handler]
+pub async fn exportar_utilizadores_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let users_map = state.users.lock().unwrap().clone();
+    match users::exportar_csv(users_map.values()) {
+        Ok(csv) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, "text/csv; charset=utf-8".parse().unwrap());
+            headers.insert(header::CONTENT_DISPOSITION, "attachment; filename=\"utilizadores.csv\"".parse().unwrap());
+            (headers, csv).into_response()
+        }
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao exportar: {}", e))).into_response(),
+    }
+}
+
+/// Importa utilizadores de um CSV: cria os novos e atualiza os existentes.
+#[debug_handler]
+pub async fn importar_utilizadores_handler(
+    State(state): State<AppState>,
+    operador: AuthUser,
+    Form(form): Form<ImportarCsvForm>,
+) -> impl IntoResponse {
+    let existentes = state.users.lock().unwrap().clone();
+    // O hash das senhas é lento; não pode bloquear as outras tarefas
+    let resultado = tokio::task::spawn_blocking(move || {
+        users::importar_csv(&form.csv, &existentes).map_err(|e| e.to_string())
+    })
+    .await;
+    let importados = match resultado {
+        Ok(Ok(importados)) => importados,
+        Ok(Err(e)) => {
+            return (StatusCode::BAD_REQUEST, Html(format!("<h1>CSV inválido</h1><pre>{}</pre><a href='/admin/utilizadores'>Voltar</a>", e))).into_response();
+        }
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro na importação: {}", e))).into_response(),
+    };
+    if let Some(proprio) = importados.iter().find(|u| u.id == operador.user_id) {
+        if !proprio.ativo || !proprio.roles.iter().any(|r| r == "admin") {
+            return (StatusCode::CONFLICT, Html("O CSV desativaria ou retiraria a função de admin à sua própria conta.".to_string())).into_response();
+        }
+    }
+
+    for user in &importados {
+        let anterior = state.users.lock().unwrap().get(&user.id).cloned();
+        let mudou_acesso = anterior.is_some_and(|a| a.roles != user.roles || a.ativo != user.ativo || a.password != user.password);
+        if let Err(e) = guardar_e_atualizar(&state, user, mudou_acesso).await {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao guardar '{}': {}", user.id, e))).into_response();
+        }
+    }
+    println!("📥 {} utilizadores importados por {}.", importados.len(), operador.user_id);
+    Redirect::to("/admin/utilizadores?estado=todos").into_response()
+}
+
+/// Grava o utilizador, atualiza a cópia em memória e, se as funções, o estado ou a senha
+/// mudaram, termina as sessões abertas (guardam as funções do momento do login).
+async fn guardar_e_atualizar(state: &AppState, user: &User, mudou_acesso: bool) -> AppResult<()> {
+    users::save_user(state.user_store.as_ref(), user).await?;
+    state.users.lock().unwrap().insert(user.id.clone(), user.clone());
+    if mudou_acesso {
+        state.sessions.revoke_user(&user.id).await?;
+    }
+    Ok(())
+}
+
+// --- VIRADA DE ANO ---
+
+/// Pré-visualiza a virada de ano com as opções escolhidas. Nada é gravado.
+#[debug_handler]
+pub async fn virada_ano_page(
+    State(state): State<AppState>,
+    Query(opcoes): Query<OpcoesVirada>,
+) -> impl IntoResponse {
+    let users_map = match users::load_users(state.user_store.as_ref()).await {
+        Ok(u) => u,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler os utilizadores: {}", e))).into_response(),
+    };
+    match virada_ano::carregar_plano(state.escala_store.as_ref(), &users_map, opcoes).await {
+        Ok(plano) => views::admin::virada_ano_page(&plano, &opcoes).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao preparar a virada de ano: {}", e))).into_response(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AplicarViradaForm {
+    zerar_contagem: bool,
+    dividas: DestinoDividas,
+    manter_punicoes: bool,
+    assinatura: String,
+}
+
+/// Aplica a virada de ano, se os dados não mudaram desde a pré-visualização.
+#[debug_handler]
+pub async fn aplicar_virada_ano_handler(
+    State(state): State<AppState>,
+    operador: AuthUser,
+    Form(form): Form<AplicarViradaForm>,
+) -> impl IntoResponse {
+    let users_map = match users::load_users(state.user_store.as_ref()).await {
+        Ok(u) => u,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler os utilizadores: {}", e))).into_response(),
+    };
+    let opcoes = OpcoesVirada {
+        zerar_contagem: form.zerar_contagem,
+        dividas: Some(form.dividas),
+        manter_punicoes: form.manter_punicoes,
+    };
+    let plano = match virada_ano::carregar_plano(state.escala_store.as_ref(), &users_map, opcoes).await {
+        Ok(p) => p,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao preparar a virada de ano: {}", e))).into_response(),
+    };
+    if plano.assinatura() != form.assinatura {
+        return (
+            StatusCode::CONFLICT,
+            Html("<h1>Os dados mudaram</h1><p>Os utilizadores ou a escala foram alterados desde a pré-visualização (ou a virada já foi aplicada). Nada foi gravado.</p><a href='/admin/virada-ano'>Ver de novo</a>"),
+        )
+            .into_response();
+    }
+
+    if let Err(e) = virada_ano::aplicar(state.escala_store.as_ref(), &plano, &operador.user_id).await {
+        if let Some(recusa) = e.downcast_ref::<Recusa>() {
+            return (
+                StatusCode::CONFLICT,
+                Html(format!("<h1>Os dados mudaram</h1><p>{} Nada foi gravado.</p><a href='/admin/virada-ano'>Ver de novo</a>", recusa)),
+            )
+                .into_response();
+        }
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao aplicar a virada de ano (nada foi gravado): {}", e))).into_response();
+    }
+    if let Ok(fresh_users) = users::load_users(state.user_store.as_ref()).await {
+        *state.users.lock().unwrap() = fresh_users;
+    }
+    // Quem saiu deixa de poder entrar
+    for formado in plano.formados() {
+        if let Err(e) = state.sessions.revoke_user(&formado.id).await {
+            eprintln!("🔥 Falha ao terminar as sessões de {}: {}", formado.id, e);
+        }
+    }
+    println!("🎓 Virada de ano aplicada por {}.", operador.user_id);
+    Redirect::to("/admin/utilizadores?estado=todos").into_response()
+}
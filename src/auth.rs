@@ -1,23 +1,42 @@
 // src/auth.rs
 
+use crate::config::Config;
 use crate::checkin::CheckinState;
-use crate::escala::{self, EscalaDiaria, EstadoEscala};
+use crate::limite_login::LimiteLogin;
 use crate::presence_state::PresenceSocketState;
+use crate::sessions::{self, Sessao, SessionStore};
+use crate::store::{DashboardStore, EscalaStore, MealStore, PresenceStore, UserStore};
+use axum::{
+    async_trait,
+    extract::FromRequestParts,
+    http::{header, request::Parts, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
 use chrono::{Local, Timelike, Duration, NaiveDate};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::sync::{Arc, Mutex};
-use tokio::fs;
 use tower_cookies::Cookies;
-use crate::escala::Genero;
+use crate::escala::{Genero, PropostaPendente};
 
 /// Representa o estado partilhado da aplicação.
+/// Os dados são acedidos apenas pelos stores, nunca diretamente em ficheiros ou na base de dados.
 #[derive(Clone)]
 pub struct AppState {
-    pub sessions: Arc<Mutex<HashSet<String>>>,
+    pub config: Arc<Config>,
+    pub user_store: Arc<dyn UserStore>,
+    pub presence_store: Arc<dyn PresenceStore>,
+    pub meal_store: Arc<dyn MealStore>,
+    pub escala_store: Arc<dyn EscalaStore>,
+    pub dashboard_store: Arc<dyn DashboardStore>,
+    pub sessions: SessionStore,
+    pub limite_login: LimiteLogin,
     pub users: Arc<Mutex<HashMap<String, User>>>,
     pub checkin_state: CheckinState,
     pub presence_state: PresenceSocketState,
+    /// Escala gerada à espera de ser revista e gravada pelo admin.
+    pub proposta_escala: Arc<Mutex<Option<PropostaPendente>>>,
 }
 
 /// Representa um utilizador do sistema.
@@ -31,6 +50,22 @@ pub struct User {
     pub curso: char,
     pub genero: Genero,
     pub roles: Vec<String>,
+    /// Utilizadores desativados (p. ex. quem saiu) não entram na escala, nas refeições
+    /// nem na presença, e não podem entrar no sistema. O registo é mantido.
+    #[serde(default = "ativo_padrao")]
+    pub ativo: bool,
+    /// A senha foi definida por outra pessoa (utilizador novo ou senha reposta pelo admin):
+    /// até escolher uma nova em `/conta/senha`, o utilizador não acede a mais nada.
+    #[serde(default)]
+    pub trocar_senha: bool,
+    /// Segredo do endereço `/calendario/<token>`, que dá os serviços da pessoa em
+    /// iCalendar sem sessão. Gerado a pedido do próprio, que o pode trocar a qualquer momento.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_calendario: Option<String>,
+}
+
+fn ativo_padrao() -> bool {
+    true
 }
 
 /// Estrutura para deserializar os dados do formulário de login.
@@ -41,64 +76,204 @@ pub struct LoginForm {
 }
 
 
-/// Verifica se um utilizador tem uma função, seja ela permanente ou temporária (do posto de serviço).
-pub async fn has_role(state: &AppState, cookies: &Cookies, required_role: &str) -> bool {
-    let user_id = match cookies.get("user_id") {
-        Some(cookie) => cookie.value().to_string(),
-        None => return false,
+/// Obtém a sessão válida associada ao cookie do pedido, se existir.
+/// É a única fonte de verdade sobre quem está a fazer o pedido.
+pub async fn current_session(state: &AppState, cookies: &Cookies) -> Option<Sessao> {
+    let session_id = cookies.get(sessions::SESSION_COOKIE)?.value().to_string();
+    state.sessions.validate(&session_id).await
+}
+
+/// Postos que o utilizador ocupa no dia de serviço atual.
+/// O dia de serviço vai das 08:00 às 08:00 do dia seguinte e só conta dentro do período da escala em vigor.
+async fn postos_de_servico(store: &dyn EscalaStore, user_id: &str) -> Vec<String> {
+    let now = Local::now();
+    let service_date: NaiveDate = if now.hour() < 8 {
+        now.date_naive() - Duration::days(1)
+    } else {
+        now.date_naive()
     };
 
-    // --- CORRIGIDO: Usa to_lowercase() para uma comparação mais robusta ---
-    let required_role_lower = required_role.to_lowercase();
+    let Ok(estado) = store.estado().await else { return Vec::new() };
+    if service_date < estado.periodo_atual.start_date || service_date > estado.periodo_atual.end_date {
+        return Vec::new();
+    }
+
+    let Ok(Some(escala_diaria)) = store.dia(service_date).await else { return Vec::new() };
+    escala_diaria
+        .escala
+        .into_iter()
+        .filter(|(_, horarios)| horarios.values().any(|alocacao| alocacao.user_id == user_id))
+        .map(|(posto_nome, _)| posto_nome)
+        .collect()
+}
+
+/// Indica se o utilizador ainda está a usar uma senha que não foi ele a escolher.
+pub fn precisa_trocar_senha(state: &AppState, user_id: &str) -> bool {
+    state.users.lock().unwrap().get(user_id).is_some_and(|u| u.trocar_senha)
+}
+
+// --- EXTRATORES DE AUTORIZAÇÃO ---
+// Usados como `route_layer` no `main.rs` para proteger grupos de rotas num só sítio,
+// e nos handlers que precisam de saber quem é o utilizador autenticado.
+
+/// O utilizador dono da sessão do pedido.
+#[derive(Debug, Clone)]
+pub struct AuthUser {
+    pub user_id: String,
+    /// Funções permanentes, guardadas na sessão no momento do login.
+    pub roles: Vec<String>,
+    /// Postos que ocupa no dia de serviço atual, que também contam como funções.
+    pub postos: Vec<String>,
+}
+
+impl AuthUser {
+    /// Verifica se o utilizador tem uma função, permanente ou do posto de serviço.
+    pub fn has_role(&self, required_role: &str) -> bool {
+        self.roles
+            .iter()
+            .chain(&self.postos)
+            .any(|role| role.to_lowercase() == required_role.to_lowercase())
+    }
+}
+
+/// Uma função (permanente ou de posto) exigida por `RequireRole`.
+pub trait Role {
+    const NAME: &'static str;
+}
+
+/// Um conjunto de funções aceites por `RequireAnyRole`.
+pub trait RoleSet {
+    const ROLES: &'static [&'static str];
+}
+
+pub struct Admin;
+impl Role for Admin { const NAME: &'static str = "admin"; }
+
+pub struct Rancheiro;
+impl Role for Rancheiro { const NAME: &'static str = "rancheiro"; }
+
+pub struct Conferencia;
+impl Role for Conferencia { const NAME: &'static str = "conferência"; }
+
+pub struct Policia;
+impl Role for Policia { const NAME: &'static str = "polícia"; }
+
+pub struct ChefeDeDia;
+impl Role for ChefeDeDia { const NAME: &'static str = "chefe de dia"; }
+
+pub struct Cautela;
+impl Role for Cautela { const NAME: &'static str = "cautela"; }
 
-    // 1. Verifica as funções permanentes
-    {
-        let users = state.users.lock().unwrap();
-        if let Some(user) = users.get(&user_id) {
-            if user.roles.iter().any(|role| role.to_lowercase() == required_role_lower) {
-                return true;
+/// Funções permanentes que podem ser atribuídas na gestão de utilizadores.
+pub const FUNCOES: &[&str] = &[Admin::NAME, Rancheiro::NAME, Conferencia::NAME, Policia::NAME, ChefeDeDia::NAME, Cautela::NAME];
+
+impl<A: Role, B: Role> RoleSet for (A, B) {
+    const ROLES: &'static [&'static str] = &[A::NAME, B::NAME];
+}
+
+impl<A: Role, B: Role, C: Role> RoleSet for (A, B, C) {
+    const ROLES: &'static [&'static str] = &[A::NAME, B::NAME, C::NAME];
+}
+
+/// Exige que o utilizador autenticado tenha a função `R`.
+/// Para saber quem é o utilizador, o handler usa `AuthUser` (já validado pela camada).
+pub struct RequireRole<R: Role>(PhantomData<R>);
+
+/// Exige que o utilizador autenticado tenha pelo menos uma das funções de `R`.
+pub struct RequireAnyRole<R: RoleSet>(PhantomData<R>);
+
+/// Motivo pelo qual um pedido foi recusado pelos extratores.
+pub enum AuthRejection {
+    /// Sem sessão válida: 401, ou redirecionamento para o login nas páginas HTML.
+    NaoAutenticado { html: bool },
+    /// Sessão válida, mas sem a função necessária: 403.
+    Proibido { html: bool },
+    /// O utilizador tem de escolher uma senha nova antes de continuar.
+    TrocarSenha { html: bool },
+}
+
+impl IntoResponse for AuthRejection {
+    fn into_response(self) -> Response {
+        match self {
+            AuthRejection::NaoAutenticado { html: true } => Redirect::to("/").into_response(),
+            AuthRejection::NaoAutenticado { html: false } => {
+                (StatusCode::UNAUTHORIZED, "Sessão inválida ou expirada.").into_response()
+            }
+            AuthRejection::Proibido { html: true } => (
+                StatusCode::FORBIDDEN,
+                Html("<h1>Acesso Negado</h1><p>Esta funcionalidade é restrita.</p><a href='/dashboard'>Voltar</a>"),
+            )
+                .into_response(),
+            AuthRejection::Proibido { html: false } => (StatusCode::FORBIDDEN, "Acesso negado.").into_response(),
+            AuthRejection::TrocarSenha { html: true } => Redirect::to("/conta/senha").into_response(),
+            AuthRejection::TrocarSenha { html: false } => {
+                (StatusCode::FORBIDDEN, "É necessário alterar a senha antes de continuar.").into_response()
             }
         }
     }
+}
 
-    // 2. Se não encontrou, verifica as funções temporárias da escala
-    let now = Local::now();
-    let today = now.date_naive();
+/// Indica se o pedido foi feito por um navegador à procura de uma página.
+fn wants_html(parts: &Parts) -> bool {
+    parts
+        .headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"))
+}
 
-    if let Ok(estado_content) = fs::read_to_string("data/escala/estado.json").await {
-        if let Ok(estado) = serde_json::from_str::<EstadoEscala>(&estado_content) {
-            
-            if today < estado.periodo_atual.start_date || today > estado.periodo_atual.end_date {
-                return false;
-            }
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AuthRejection;
 
-            let service_date: NaiveDate = if now.hour() < 8 {
-                today - Duration::days(1)
-            } else {
-                today
-            };
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        // Se a camada da rota já validou a sessão, reaproveita o resultado
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
 
-            if service_date < estado.periodo_atual.start_date || service_date > estado.periodo_atual.end_date {
-                 return false;
-            }
+        let html = wants_html(parts);
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|_| AuthRejection::NaoAutenticado { html })?;
+        let sessao = current_session(state, &cookies)
+            .await
+            .ok_or(AuthRejection::NaoAutenticado { html })?;
+        if precisa_trocar_senha(state, &sessao.user_id) {
+            return Err(AuthRejection::TrocarSenha { html });
+        }
 
-            let filename = format!("{}/{}.json", escala::ESCALA_DATA_DIR, service_date.format("%Y-%m-%d"));
-            
-            if let Ok(content) = fs::read_to_string(filename).await {
-                if let Ok(escala_diaria) = serde_json::from_str::<EscalaDiaria>(&content) {
-                    for (posto_nome, horarios) in escala_diaria.escala {
-                        if posto_nome.to_lowercase() == required_role_lower {
-                            for alocacao in horarios.values() {
-                                if alocacao.user_id == user_id {
-                                    return true;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        let postos = postos_de_servico(state.escala_store.as_ref(), &sessao.user_id).await;
+        let user = AuthUser { user_id: sessao.user_id, roles: sessao.roles, postos };
+        parts.extensions.insert(user.clone());
+        Ok(user)
+    }
+}
+
+#[async_trait]
+impl<R: Role + Send + Sync> FromRequestParts<AppState> for RequireRole<R> {
+    type Rejection = AuthRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        if !user.has_role(R::NAME) {
+            return Err(AuthRejection::Proibido { html: wants_html(parts) });
         }
+        Ok(RequireRole(PhantomData))
     }
+}
+
+#[async_trait]
+impl<R: RoleSet + Send + Sync> FromRequestParts<AppState> for RequireAnyRole<R> {
+    type Rejection = AuthRejection;
 
-    false
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        for role in R::ROLES {
+            if user.has_role(role) {
+                return Ok(RequireAnyRole(PhantomData));
+            }
+        }
+        Err(AuthRejection::Proibido { html: wants_html(parts) })
+    }
 }
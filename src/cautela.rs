@@ -5,14 +5,31 @@
 //! Este módulo define todas as estruturas de dados e funções de acesso
 //! ao banco de dados para o sistema de empréstimo e devolução de itens.
 
+use crate::auth::User;
+use crate::config::Config;
+use crate::escala::Genero;
+use crate::store::UserStore;
+use crate::users;
 use chrono::{DateTime, Local, NaiveDate};
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use tokio::fs;
 use tokio_rusqlite::Connection;
 
-// --- CONSTANTES DE DIRETÓRIO E BANCO DE DADOS ---
-pub const PASTA_PAIOL: &str = "data/paioldelivros";
-pub const DB_FILE: &str = "data/paioldelivros/paioldelivros.db";
+// --- CONSTANTES DE DIRETÓRIO E BANCO DE DADOS (relativas à pasta dos dados) ---
+pub const PASTA_PAIOL: &str = "paioldelivros";
+pub const DB_FILE: &str = "paioldelivros/paioldelivros.db";
+/// Função que dá acesso ao módulo da cautela.
+pub const CAUTELA_ROLE: &str = "cautela";
+/// Conta de testes que vinha pré-criada na antiga tabela `responsavel`.
+const RESPONSAVEL_PADRAO: &str = "teste";
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// O banco de dados da cautela dentro da pasta dos dados configurada.
+pub fn caminho_db(config: &Config) -> PathBuf {
+    config.caminho(DB_FILE)
+}
 
 // --- ESTRUTURAS DE DADOS (structs) ---
 // Estas structs representam os dados que movemos de e para o banco de dados.
@@ -80,26 +97,24 @@ pub struct Emprestimo {
 // --- FUNÇÃO DE INICIALIZAÇÃO DO BANCO DE DADOS ---
 
 /// Garante que a estrutura de diretórios e o banco de dados da cautela existam e estejam configurados.
-pub async fn ensure_paioldelivros_structure() {
-    if let Err(e) = fs::create_dir_all(PASTA_PAIOL).await {
-        eprintln!("🔥 Falha crítica ao criar o diretório '{}': {}", PASTA_PAIOL, e);
+pub async fn ensure_paioldelivros_structure(config: &Config) {
+    let pasta = config.caminho(PASTA_PAIOL);
+    if let Err(e) = fs::create_dir_all(&pasta).await {
+        eprintln!("🔥 Falha crítica ao criar o diretório '{}': {}", pasta.display(), e);
         return;
     }
 
-    if fs::try_exists(DB_FILE).await.unwrap_or(false) {
+    let db_file = caminho_db(config);
+    if fs::try_exists(&db_file).await.unwrap_or(false) {
         return; // O banco de dados já existe, não faz nada.
     }
     
-    println!("📝 A criar e inicializar o banco de dados em {}...", DB_FILE);
-    match Connection::open(DB_FILE).await {
+    println!("📝 A criar e inicializar o banco de dados em {}...", db_file.display());
+    match Connection::open(&db_file).await {
         Ok(conn) => {
             let _ = conn.call(|conn| {
                 conn.execute_batch(
                     "BEGIN;
-                    CREATE TABLE responsavel (
-                        username TEXT PRIMARY KEY,
-                        password_hash TEXT NOT NULL
-                    );
                     CREATE TABLE itens (
                         id TEXT PRIMARY KEY,
                         nome TEXT NOT NULL,
@@ -130,16 +145,92 @@ pub async fn ensure_paioldelivros_structure() {
                     );
                     COMMIT;"
                 )?;
-
-                let default_pass = bcrypt::hash("12345", bcrypt::DEFAULT_COST).unwrap();
-                conn.execute(
-                    "INSERT INTO responsavel (username, password_hash) VALUES (?1, ?2)",
-                    ("teste", default_pass),
-                )?;
                 Ok(())
             }).await;
             println!("✅ Banco de dados inicializado com sucesso.");
         }
         Err(e) => eprintln!("🔥 Falha crítica ao abrir/criar o banco de dados: {}", e),
     }
-}
\ No newline at end of file
+}
+
+/// Número de exemplares que o utilizador tem emprestados neste momento.
+pub async fn emprestimos_ativos(config: &Config, user_id: &str) -> AppResult<usize> {
+    let db_file = caminho_db(config);
+    if !fs::try_exists(&db_file).await.unwrap_or(false) {
+        return Ok(0);
+    }
+    let conn = Connection::open(&db_file).await?;
+    let user_id = user_id.to_string();
+    let total = conn.call(move |conn| {
+        Ok(conn.query_row(
+            "SELECT COUNT(*) FROM emprestimos WHERE aluno_id = ?1 AND status = 'Emprestado'",
+            [&user_id],
+            |row| row.get::<_, i64>(0),
+        )?)
+    }).await?;
+    Ok(total as usize)
+}
+
+// --- MIGRAÇÃO DOS RESPONSÁVEIS ---
+
+/// Importa as contas da antiga tabela `responsavel` para os utilizadores principais.
+/// Quem já existe em `users.json` recebe a função `cautela`; os restantes são criados
+/// com a mesma senha e sem turma (`ano` 0), para não entrarem na escala nem na presença.
+/// A conta padrão `teste` é descartada e a tabela é removida no fim.
+pub async fn migrar_responsaveis(store: &dyn UserStore, users_map: &mut HashMap<String, User>, config: &Config) -> AppResult<()> {
+    let db_file = caminho_db(config);
+    if !fs::try_exists(&db_file).await.unwrap_or(false) {
+        return Ok(());
+    }
+    let conn = Connection::open(&db_file).await?;
+    let responsaveis: Vec<Responsavel> = conn.call(|conn| {
+        let existe: bool = conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'responsavel')",
+            [],
+            |row| row.get(0),
+        )?;
+        if !existe {
+            return Ok(Vec::new());
+        }
+        let mut stmt = conn.prepare("SELECT username, password_hash FROM responsavel")?;
+        let rows = stmt.query_map([], |row| Ok(Responsavel { username: row.get(0)?, password_hash: row.get(1)? }))?;
+        Ok(rows.collect::<Result<Vec<_>, _>>()?)
+    }).await?;
+
+    let mut alterados = Vec::new();
+    for responsavel in responsaveis.iter().filter(|r| r.username != RESPONSAVEL_PADRAO) {
+        match users_map.get_mut(&responsavel.username) {
+            Some(user) => {
+                if !user.roles.iter().any(|r| r.eq_ignore_ascii_case(CAUTELA_ROLE)) {
+                    user.roles.push(CAUTELA_ROLE.to_string());
+                    alterados.push(user.id.clone());
+                }
+            }
+            None => {
+                users_map.insert(responsavel.username.clone(), User {
+                    id: responsavel.username.clone(),
+                    password: responsavel.password_hash.clone(),
+                    name: responsavel.username.clone(),
+                    turma: String::new(),
+                    ano: 0,
+                    curso: '-',
+                    genero: Genero::Misto,
+                    roles: vec![CAUTELA_ROLE.to_string()],
+                    ativo: true,
+                    trocar_senha: false,
+                    token_calendario: None,
+                });
+                alterados.push(responsavel.username.clone());
+            }
+        }
+        println!("🔑 Responsável '{}' migrado para a função '{}'.", responsavel.username, CAUTELA_ROLE);
+    }
+
+    // Os utilizadores são gravados antes de a tabela antiga ser removida
+    users::save_users(store, alterados.iter().filter_map(|id| users_map.get(id))).await?;
+    conn.call(|conn| {
+        conn.execute_batch("DROP TABLE IF EXISTS responsavel;")?;
+        Ok(())
+    }).await?;
+    Ok(())
+}
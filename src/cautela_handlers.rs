@@ -5,7 +5,7 @@
 //! Este módulo contém os handlers HTTP para a interface do responsável,
 //! utilizando um banco de dados SQLite e operando como uma Single Page Application (SPA).
 
-use crate::auth::{AppState};
+use crate::auth::{AppState, AuthUser};
 use crate::cautela::{self, Emprestimo, EventoEmprestimo, ItemCatalogo, Exemplar, StatusExemplar, TipoEvento, AtrasoInfo};
 // ADICIONADO: Importar o novo módulo de views
 use crate::views::cautela as view;
@@ -20,16 +20,12 @@ use rusqlite::params;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use tokio_rusqlite::Connection;
-use tower_cookies::{Cookie, Cookies};
 use uuid::Uuid;
 use unidecode::unidecode;
 
 
 // --- ESTRUTURAS PARA FORMULÁRIOS E QUERIES ---
 
-#[derive(Debug, Deserialize)]
-pub struct CautelaLoginForm { pub username: String, pub password: String }
-
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AddItemForm { nome: String, setor: String, numero_identificacao: String }
 
@@ -72,64 +68,23 @@ fn normalize_for_search(text: &str) -> String {
     unidecode(text).to_lowercase()
 }
 
-/// Verifica a autenticação do responsável através dos cookies da sessão.
-async fn check_cautela_auth(state: &AppState, cookies: &Cookies) -> Result<String, impl IntoResponse> {
-    if let Some(session_id) = cookies.get("cautela_session_id").map(|c| c.value().to_string()) {
-        if state.sessions.lock().unwrap().contains(&session_id) {
-            let username = cookies.get("cautela_user").map_or("".to_string(), |c| c.value().to_string());
-            return Ok(username);
-        }
-    }
-    Err(Redirect::to("/cautela"))
-}
-
-
 // --- O MÓDULO 'VIEW' FOI REMOVIDO DAQUI ---
 
 
 // --- HANDLERS ---
 
+/// Entrada do módulo. O acesso é feito pelo login principal, com a função `cautela`.
 #[debug_handler]
-pub async fn cautela_login_page() -> impl IntoResponse { view::login_page(None) }
-
-#[debug_handler]
-pub async fn cautela_login_handler(State(state): State<AppState>, cookies: Cookies, Form(login): Form<CautelaLoginForm>) -> impl IntoResponse {
-    let username_for_db = login.username.clone();
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
-    let res: Result<Option<String>, _> = conn.call(move |conn| {
-        let mut stmt = conn.prepare("SELECT password_hash FROM responsavel WHERE username = ?1")?;
-        let mut rows = stmt.query_map([&username_for_db], |row| row.get(0))?;
-        Ok(rows.next().transpose()?)
-    }).await;
-    if let Ok(Some(password_hash)) = res {
-        if bcrypt::verify(&login.password, &password_hash).unwrap_or(false) {
-            let session_id = Uuid::new_v4().to_string();
-            state.sessions.lock().unwrap().insert(session_id.clone());
-            cookies.add(Cookie::new("cautela_session_id", session_id));
-            cookies.add(Cookie::new("cautela_user", login.username));
-            return Redirect::to("/cautela/dashboard").into_response();
-        }
-    }
-    view::login_page(Some("Utilizador ou palavra-passe incorretos.")).into_response()
-}
-
-#[debug_handler]
-pub async fn cautela_logout_handler(State(state): State<AppState>, cookies: Cookies) -> impl IntoResponse {
-    if let Some(cookie) = cookies.get("cautela_session_id") { state.sessions.lock().unwrap().remove(cookie.value()); }
-    cookies.remove(Cookie::from("cautela_session_id"));
-    cookies.remove(Cookie::from("cautela_user"));
-    Redirect::to("/cautela").into_response()
-}
+pub async fn cautela_home_handler() -> impl IntoResponse { Redirect::to("/cautela/dashboard") }
 
 #[debug_handler]
 pub async fn cautela_dashboard_handler(
-    State(state): State<AppState>, cookies: Cookies, Query(query): Query<SearchQuery>,
+    State(state): State<AppState>, operador: AuthUser, Query(query): Query<SearchQuery>,
 ) -> impl IntoResponse {
-    let username = match check_cautela_auth(&state, &cookies).await { Ok(u) => u, Err(r) => return r.into_response() };
     let search_query = query.q.clone().unwrap_or_default();
     let users = state.users.lock().unwrap().clone();
     
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let (search_results, active_loans) = conn.call({
         let search_query = search_query.clone();
         let users = users.clone();
@@ -169,11 +124,11 @@ pub async fn cautela_dashboard_handler(
                     if let Some(item) = items_map.get_mut(&item_id) { item.exemplares.push(exemplar); }
                 }
             }
-            let mut stmt_loans = conn.prepare("SELECT e.id, ex.item_id, e.exemplar_id, e.aluno_id, h.data_devolucao_prevista FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao JOIN historico_emprestimos h ON e.id = h.emprestimo_id WHERE e.status = 'Emprestado' AND h.id = (SELECT MAX(id) FROM historico_emprestimos WHERE emprestimo_id = e.id)")?;
+            let mut stmt_loans = conn.prepare("SELECT e.id, ex.item_id, e.exemplar_id, e.aluno_id, h.data_devolucao_prevista, h.responsavel_id FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao JOIN historico_emprestimos h ON e.id = h.emprestimo_id WHERE e.status = 'Emprestado' AND h.id = (SELECT MAX(id) FROM historico_emprestimos WHERE emprestimo_id = e.id)")?;
             let mut loans = HashMap::new();
             for loan_res in stmt_loans.query_map([], |row| {
                 let dev_date_str: String = row.get(4)?;
-                let event = EventoEmprestimo { tipo: TipoEvento::Emprestimo, data_evento: Local::now(), data_devolucao_prevista: NaiveDate::parse_from_str(&dev_date_str, "%Y-%m-%d").unwrap(), responsavel_id: "".to_string() };
+                let event = EventoEmprestimo { tipo: TipoEvento::Emprestimo, data_evento: Local::now(), data_devolucao_prevista: NaiveDate::parse_from_str(&dev_date_str, "%Y-%m-%d").unwrap(), responsavel_id: row.get(5)? };
                 Ok(Emprestimo { id: row.get(0)?, item_id: row.get(1)?, exemplar_id: row.get(2)?, aluno_id: row.get(3)?, status: StatusExemplar::Emprestado, historico: vec![event] })
             })? {
                 let loan = loan_res?;
@@ -182,18 +137,18 @@ pub async fn cautela_dashboard_handler(
             Ok((items_map.into_values().collect::<Vec<_>>(), loans))
         }
     }).await.unwrap();
-    view::dashboard_page(&username, &search_query, &search_results, &active_loans, &users).into_response()
+    let operador_nome = users.get(&operador.user_id).map_or(operador.user_id.clone(), |u| u.name.clone());
+    view::dashboard_page(&operador_nome, &search_query, &search_results, &active_loans, &users).into_response()
 }
 
 #[debug_handler]
 pub async fn cautela_catalogo_page(
-    State(state): State<AppState>, cookies: Cookies, Query(query): Query<CatalogoQuery>,
+    State(state): State<AppState>, Query(query): Query<CatalogoQuery>,
 ) -> impl IntoResponse {
-    if let Err(r) = check_cautela_auth(&state, &cookies).await { return r.into_response(); }
     let selected_setor = query.setor.clone();
     let users = state.users.lock().unwrap().clone();
 
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let (items, setores, active_loans) = conn.call(move |conn| {
         let mut stmt_setores = conn.prepare("SELECT DISTINCT setor FROM itens ORDER BY setor")?;
         let setores_list: Vec<String> = stmt_setores.query_map([], |row| row.get(0))?.collect::<Result<Vec<_>, _>>()?;
@@ -227,11 +182,11 @@ pub async fn cautela_catalogo_page(
             if let Some(exemplares) = exemplares_map.remove(&item.id) { item.exemplares = exemplares; }
         }
 
-        let mut stmt_loans = conn.prepare("SELECT e.id, ex.item_id, e.exemplar_id, e.aluno_id, h.data_devolucao_prevista FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao JOIN historico_emprestimos h ON e.id = h.emprestimo_id WHERE e.status = 'Emprestado' AND h.id = (SELECT MAX(id) FROM historico_emprestimos WHERE emprestimo_id = e.id)")?;
+        let mut stmt_loans = conn.prepare("SELECT e.id, ex.item_id, e.exemplar_id, e.aluno_id, h.data_devolucao_prevista, h.responsavel_id FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao JOIN historico_emprestimos h ON e.id = h.emprestimo_id WHERE e.status = 'Emprestado' AND h.id = (SELECT MAX(id) FROM historico_emprestimos WHERE emprestimo_id = e.id)")?;
         let mut loans = HashMap::new();
         for loan_res in stmt_loans.query_map([], |row| {
             let dev_date_str: String = row.get(4)?;
-            let event = EventoEmprestimo { tipo: TipoEvento::Emprestimo, data_evento: Local::now(), data_devolucao_prevista: NaiveDate::parse_from_str(&dev_date_str, "%Y-%m-%d").unwrap(), responsavel_id: "".to_string() };
+            let event = EventoEmprestimo { tipo: TipoEvento::Emprestimo, data_evento: Local::now(), data_devolucao_prevista: NaiveDate::parse_from_str(&dev_date_str, "%Y-%m-%d").unwrap(), responsavel_id: row.get(5)? };
             Ok(Emprestimo { id: row.get(0)?, item_id: row.get(1)?, exemplar_id: row.get(2)?, aluno_id: row.get(3)?, status: StatusExemplar::Emprestado, historico: vec![event] })
         })? {
             let loan = loan_res?;
@@ -245,10 +200,9 @@ pub async fn cautela_catalogo_page(
 }
 
 #[debug_handler]
-pub async fn cautela_add_item_handler(State(state): State<AppState>, cookies: Cookies, Form(form): Form<AddItemForm>) -> impl IntoResponse {
-    if let Err(r) = check_cautela_auth(&state, &cookies).await { return r.into_response(); }
+pub async fn cautela_add_item_handler(State(state): State<AppState>, Form(form): Form<AddItemForm>) -> impl IntoResponse {
 
-    let conn = match Connection::open(cautela::DB_FILE).await {
+    let conn = match Connection::open(cautela::caminho_db(&state.config)).await {
         Ok(c) => c,
         Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Falha ao conectar à base de dados: {}", e)).into_response(),
     };
@@ -279,10 +233,9 @@ pub async fn cautela_add_item_handler(State(state): State<AppState>, cookies: Co
 
 #[debug_handler]
 pub async fn cautela_add_exemplar_handler(
-    State(state): State<AppState>, cookies: Cookies, Json(form): Json<AddExemplarForm>
+    State(state): State<AppState>, Json(form): Json<AddExemplarForm>
 ) -> impl IntoResponse {
-    if let Err(r) = check_cautela_auth(&state, &cookies).await { return r.into_response(); }
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let res = conn.call(move |conn| {
         conn.execute("INSERT OR IGNORE INTO exemplares (numero_identificacao, item_id, status) VALUES (?1, ?2, 'Disponivel')", params![form.numero_identificacao, form.item_id])?;
         Ok(form)
@@ -295,10 +248,9 @@ pub async fn cautela_add_exemplar_handler(
 
 #[debug_handler]
 pub async fn cautela_delete_exemplar_handler(
-    State(state): State<AppState>, cookies: Cookies, Json(form): Json<DeleteExemplarForm>
+    State(state): State<AppState>, Json(form): Json<DeleteExemplarForm>
 ) -> impl IntoResponse {
-    if let Err(r) = check_cautela_auth(&state, &cookies).await { return r.into_response(); }
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let res = conn.call(move |conn| {
         let changed = conn.execute("DELETE FROM exemplares WHERE item_id = ?1 AND numero_identificacao = ?2 AND status = 'Disponivel'", params![form.item_id, form.numero_identificacao])?;
         if changed == 0 { 
@@ -314,13 +266,13 @@ pub async fn cautela_delete_exemplar_handler(
 }
 
 pub async fn cautela_emprestar_handler(
-    State(state): State<AppState>, cookies: Cookies, Json(form): Json<EmprestarForm>
+    State(state): State<AppState>, AuthUser { user_id: responsavel_id, .. }: AuthUser, Json(form): Json<EmprestarForm>
 ) -> impl IntoResponse {
-    let responsavel_id = match check_cautela_auth(&state, &cookies).await { Ok(u) => u, Err(r) => return r.into_response() };
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let emprestimo_id = Uuid::new_v4().to_string();
     let form_data = form.clone();
     let emprestimo_id_clone = emprestimo_id.clone();
+    let operador_id = responsavel_id.clone();
     let res: Result<(), tokio_rusqlite::Error> = conn.call(move |conn| {
         let tx = conn.transaction()?;
         let updated_rows = tx.execute("UPDATE exemplares SET status = 'Emprestado' WHERE numero_identificacao = ?1 AND status = 'Disponivel'", [&form_data.exemplar_id])?;
@@ -336,7 +288,7 @@ pub async fn cautela_emprestar_handler(
     }).await;
 
     if res.is_ok() {
-        let evento = EventoEmprestimo { tipo: TipoEvento::Emprestimo, data_evento: Local::now(), data_devolucao_prevista: form.data_devolucao, responsavel_id: "".into() };
+        let evento = EventoEmprestimo { tipo: TipoEvento::Emprestimo, data_evento: Local::now(), data_devolucao_prevista: form.data_devolucao, responsavel_id: operador_id };
         let emprestimo_res = Emprestimo { id: emprestimo_id, item_id: form.item_id, exemplar_id: form.exemplar_id, aluno_id: form.aluno_id, status: StatusExemplar::Emprestado, historico: vec![evento] };
         
         (StatusCode::CREATED, Json(emprestimo_res)).into_response()
@@ -346,12 +298,11 @@ pub async fn cautela_emprestar_handler(
 }
 
 
-#[debug_handler]
+#[debug_handler(state = AppState)]
 pub async fn cautela_devolver_handler(
-    State(state): State<AppState>, cookies: Cookies, Json(form): Json<DevolverForm>
+    State(state): State<AppState>, AuthUser { user_id: responsavel_id, .. }: AuthUser, Json(form): Json<DevolverForm>
 ) -> impl IntoResponse {
-    let responsavel_id = match check_cautela_auth(&state, &cookies).await { Ok(u) => u, Err(r) => return r.into_response() };
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let res: Result<(String, String), _> = conn.call(move |conn| {
         let tx = conn.transaction()?;
         let (exemplar_id, item_id): (String, String) = tx.query_row("SELECT e.exemplar_id, ex.item_id FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao WHERE e.id = ?1", [&form.emprestimo_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
@@ -371,16 +322,15 @@ pub async fn cautela_devolver_handler(
     }
 }
 
-#[debug_handler]
+#[debug_handler(state = AppState)]
 pub async fn cautela_renovar_handler(
-    State(state): State<AppState>, cookies: Cookies, Json(form): Json<RenovarForm>
+    State(state): State<AppState>, AuthUser { user_id: responsavel_id, .. }: AuthUser, Json(form): Json<RenovarForm>
 ) -> impl IntoResponse {
-    let responsavel_id = match check_cautela_auth(&state, &cookies).await { Ok(u) => u, Err(r) => return r.into_response() };
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let res: Result<Emprestimo, _> = conn.call(move |conn| {
         conn.execute("INSERT INTO historico_emprestimos (emprestimo_id, tipo_evento, data_evento, data_devolucao_prevista, responsavel_id) VALUES (?1, 'Renovacao', ?2, ?3, ?4)", params![&form.emprestimo_id, Utc::now().to_rfc3339(), form.nova_data_devolucao.to_string(), responsavel_id])?;
         let (item_id, exemplar_id, aluno_id): (String, String, String) = conn.query_row("SELECT ex.item_id, e.exemplar_id, e.aluno_id FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao WHERE e.id = ?1", [&form.emprestimo_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
-        let evento = EventoEmprestimo { tipo: TipoEvento::Renovacao, data_evento: Local::now(), data_devolucao_prevista: form.nova_data_devolucao, responsavel_id: "".into() };
+        let evento = EventoEmprestimo { tipo: TipoEvento::Renovacao, data_evento: Local::now(), data_devolucao_prevista: form.nova_data_devolucao, responsavel_id: responsavel_id.clone() };
         Ok(Emprestimo { id: form.emprestimo_id, item_id, exemplar_id, aluno_id, status: StatusExemplar::Emprestado, historico: vec![evento] })
     }).await;
     match res {
@@ -390,9 +340,8 @@ pub async fn cautela_renovar_handler(
 }
 
 #[debug_handler]
-pub async fn cautela_atrasos_page(State(state): State<AppState>, cookies: Cookies) -> impl IntoResponse {
-    if let Err(r) = check_cautela_auth(&state, &cookies).await { return r.into_response(); }
-    let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+pub async fn cautela_atrasos_page(State(state): State<AppState>) -> impl IntoResponse {
+    let conn = Connection::open(cautela::caminho_db(&state.config)).await.unwrap();
     let mut atrasos = conn.call(move |conn| {
         let mut stmt = conn.prepare("SELECT e.aluno_id, i.nome, e.exemplar_id, h.data_devolucao_prevista FROM emprestimos e JOIN exemplares ex ON e.exemplar_id = ex.numero_identificacao JOIN itens i ON ex.item_id = i.id JOIN historico_emprestimos h ON e.id = h.emprestimo_id WHERE e.status = 'Emprestado' AND h.id = (SELECT MAX(id) FROM historico_emprestimos WHERE emprestimo_id = e.id) AND h.data_devolucao_prevista < date('now')")?;
         let today = Local::now().date_naive();
@@ -1,7 +1,8 @@
 // src/checkin_handlers.rs
 
-use crate::auth::{self, AppState, User};
+use crate::auth::{AppState, AuthUser, User};
 use crate::checkin::{CheckinAction, CheckinState, CheckinUpdate};
+use crate::store::MealStore;
 use crate::meals::{self};
 // ADICIONADO: Importar o novo módulo de views
 use crate::views::checkin as view;
@@ -18,31 +19,21 @@ use chrono::Local;
 use futures_util::{stream::StreamExt, SinkExt};
 use serde::Deserialize;
 use std::collections::BTreeMap;
+use std::sync::Arc;
 use tokio::sync::mpsc;
-use tower_cookies::Cookies;
 use uuid::Uuid;
 
-fn get_current_user_name(state: &AppState, cookies: &Cookies) -> String {
-    let user_id = cookies
-        .get("user_id")
-        .map_or("Desconhecido".to_string(), |c| c.value().to_string());
+fn get_current_user_name(state: &AppState, user: &AuthUser) -> String {
     let users = state.users.lock().unwrap();
-    users.get(&user_id).map_or(user_id, |u| u.name.clone())
+    users.get(&user.user_id).map_or(user.user_id.clone(), |u| u.name.clone())
 }
 
 #[debug_handler]
 pub async fn checkin_page(
     State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "rancheiro").await
-        && !auth::has_role(&state, &cookies, "conferência").await
-    {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-
     let today = Local::now().date_naive();
-    let daily_data = match meals::load_daily_meals(today).await {
+    let daily_data = match meals::load_daily_meals(state.meal_store.as_ref(), today).await {
         Ok(data) => data,
         Err(_) => return view::checkin_page(today, String::new(), String::new()).into_response(),
     };
@@ -148,10 +139,11 @@ pub struct ReportParams {
 
 #[debug_handler]
 pub async fn generate_absent_report_handler(
+    State(state): State<AppState>,
     Query(params): Query<ReportParams>,
 ) -> impl IntoResponse {
     let today = Local::now().date_naive();
-    let daily_data = match meals::load_daily_meals(today).await {
+    let daily_data = match meals::load_daily_meals(state.meal_store.as_ref(), today).await {
         Ok(data) => data,
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Não foi possível carregar os dados das refeições.").into_response(),
     };
@@ -211,14 +203,14 @@ pub async fn generate_absent_report_handler(
 #[debug_handler]
 pub async fn checkin_websocket_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    let operator_name = get_current_user_name(&state, &cookies);
-    ws.on_upgrade(move |socket| handle_socket(socket, state.checkin_state, operator_name))
+    let operator_name = get_current_user_name(&state, &user);
+    ws.on_upgrade(move |socket| handle_socket(socket, state.meal_store, state.checkin_state, operator_name))
 }
 
-async fn handle_socket(socket: WebSocket, state: CheckinState, operator_name: String) {
+async fn handle_socket(socket: WebSocket, store: Arc<dyn MealStore>, state: CheckinState, operator_name: String) {
     let (mut sender, mut receiver) = socket.split();
     
     let (tx, mut rx) = mpsc::channel(32);
@@ -239,50 +231,55 @@ async fn handle_socket(socket: WebSocket, state: CheckinState, operator_name: St
         while let Some(Ok(Message::Text(text))) = receiver.next().await {
             if let Ok(action) = serde_json::from_str::<CheckinAction>(&text) {
                 let today = Local::now().date_naive();
-                if let Ok(mut daily_data) = meals::load_daily_meals(today).await {
-                    if let Some(selection) = daily_data.get_mut(&action.user_id) {
-                        
-                        let (status_updated, marker_field, time_field) = match action.meal.as_str() {
-                            "cafe" if !selection.cafe_realizado => (true, Some(&mut selection.cafe_marcado_por), Some(&mut selection.cafe_marcado_em)),
-                            "almoco" if !selection.almoco_realizado => (true, Some(&mut selection.almoco_marcado_por), Some(&mut selection.almoco_marcado_em)),
-                            "janta" if !selection.janta_realizado => (true, Some(&mut selection.janta_marcado_por), Some(&mut selection.janta_marcado_em)),
-                            "ceia" if !selection.ceia_realizado => (true, Some(&mut selection.ceia_marcado_por), Some(&mut selection.ceia_marcado_em)),
-                            _ => (false, None, None),
-                        };
+                let operador = operator_name.clone();
+                let meal = action.meal.clone();
+                let now_str = Local::now().format("%H:%M").to_string();
+                let marked_at = now_str.clone();
+                // Lê e grava a refeição numa só transação, para não perder marcações simultâneas
+                let resultado = meals::update_meal_selection(store.as_ref(), today, &action.user_id, move |selection| {
+                    let (status_updated, marker_field, time_field) = match meal.as_str() {
+                        "cafe" if !selection.cafe_realizado => (true, Some(&mut selection.cafe_marcado_por), Some(&mut selection.cafe_marcado_em)),
+                        "almoco" if !selection.almoco_realizado => (true, Some(&mut selection.almoco_marcado_por), Some(&mut selection.almoco_marcado_em)),
+                        "janta" if !selection.janta_realizado => (true, Some(&mut selection.janta_marcado_por), Some(&mut selection.janta_marcado_em)),
+                        "ceia" if !selection.ceia_realizado => (true, Some(&mut selection.ceia_marcado_por), Some(&mut selection.ceia_marcado_em)),
+                        _ => (false, None, None),
+                    };
 
-                        if status_updated {
-                            let now_str = Local::now().format("%H:%M").to_string();
-                            if let Some(field) = marker_field {
-                                *field = Some(operator_name.clone());
-                            }
-                            if let Some(field) = time_field {
-                                *field = Some(now_str.clone());
-                            }
-                            
-                            match action.meal.as_str() {
-                                "cafe" => selection.cafe_realizado = true,
-                                "almoco" => selection.almoco_realizado = true,
-                                "janta" => selection.janta_realizado = true,
-                                "ceia" => selection.ceia_realizado = true,
-                                _ => (),
-                            }
+                    if !status_updated {
+                        return false;
+                    }
+                    if let Some(field) = marker_field {
+                        *field = Some(operador);
+                    }
+                    if let Some(field) = time_field {
+                        *field = Some(now_str);
+                    }
 
-                            if let Err(e) = meals::save_daily_meals(today, &daily_data).await {
-                                eprintln!("Erro ao guardar check-in: {}", e);
-                                continue;
-                            }
-                            
-                            let update_msg = CheckinUpdate {
-                                user_id: action.user_id.clone(),
-                                meal: action.meal.clone(),
-                                new_status: true,
-                                marked_by: operator_name.clone(),
-                                marked_at: now_str,
-                            };
-                            let broadcast_text = serde_json::to_string(&update_msg).unwrap();
-                            state_clone.broadcast(broadcast_text).await;
-                        }
+                    match meal.as_str() {
+                        "cafe" => selection.cafe_realizado = true,
+                        "almoco" => selection.almoco_realizado = true,
+                        "janta" => selection.janta_realizado = true,
+                        "ceia" => selection.ceia_realizado = true,
+                        _ => (),
+                    }
+                    true
+                })
+                .await;
+
+                match resultado {
+                    Ok(true) => {
+                        let update_msg = CheckinUpdate {
+                            user_id: action.user_id.clone(),
+                            meal: action.meal.clone(),
+                            new_status: true,
+                            marked_by: operator_name.clone(),
+                            marked_at,
+                        };
+                        let broadcast_text = serde_json::to_string(&update_msg).unwrap();
+                        state_clone.broadcast(broadcast_text).await;
                     }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Erro ao guardar check-in: {}", e),
                 }
             }
         }
@@ -0,0 +1,334 @@
+// src/config.rs
+
+//! # Configuração do Servidor
+//!
+//! Tudo o que muda de uma instalação para outra (endereço, TLS, pasta dos dados, prazos das
+//! sessões, regras das senhas e do login, cabeçalho e assinatura dos documentos, administrador inicial) vem do ficheiro `mercal.toml`.
+//! Cada valor pode ainda ser substituído na linha de comandos ou por uma variável de
+//! ambiente `MERCAL_*`, o que permite correr várias instâncias lado a lado.
+//!
+//! Ordem de prioridade: linha de comandos / ambiente > `mercal.toml` > valores padrão.
+
+use clap::Parser;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Ficheiro lido quando não é indicado outro com `--config`.
+pub const CONFIG_FILE: &str = "mercal.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub servidor: Servidor,
+    pub tls: Tls,
+    pub dados: Dados,
+    pub sessoes: Sessoes,
+    pub senhas: Senhas,
+    pub login: Login,
+    pub instituicao: Instituicao,
+    /// Administrador criado no primeiro arranque, quando ainda não há utilizadores.
+    pub admin: Option<AdminInicial>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Servidor {
+    pub endereco: IpAddr,
+    pub porta: u16,
+}
+
+/// HTTPS servido pelo próprio MercAl. Só fica ativo com certificado e chave.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    /// Certificado em PEM (com a cadeia, se houver).
+    pub certificado: Option<PathBuf>,
+    /// Chave privada do certificado em PEM.
+    pub chave: Option<PathBuf>,
+    /// Porta HTTP simples que apenas redireciona para o HTTPS. Sem ela, não há HTTP.
+    pub porta_redirecionamento: Option<u16>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Dados {
+    /// Pasta com a base de dados, a cautela e os ficheiros JSON.
+    pub pasta: PathBuf,
+    /// Ficheiro de utilizadores da organização antiga (importação e armazenamento em ficheiros).
+    pub utilizadores: PathBuf,
+    /// `sqlite`, `ficheiros` ou `memoria`.
+    pub armazenamento: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sessoes {
+    /// Minutos sem atividade até a sessão expirar.
+    pub inatividade_minutos: i64,
+    /// Horas de vida de uma sessão, independentemente da atividade.
+    pub duracao_maxima_horas: i64,
+}
+
+/// Regras mínimas para as senhas escolhidas pelos próprios utilizadores.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Senhas {
+    pub tamanho_minimo: usize,
+    /// Exige pelo menos uma letra e um algarismo.
+    pub exigir_letras_e_numeros: bool,
+}
+
+/// Bloqueio temporário do login depois de várias senhas erradas.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Login {
+    /// Falhas seguidas permitidas para o mesmo utilizador.
+    pub tentativas: u32,
+    /// Falhas permitidas vindas do mesmo endereço IP, para qualquer utilizador.
+    pub tentativas_por_ip: u32,
+    /// Duração do bloqueio e da janela em que as falhas são contadas.
+    pub bloqueio_minutos: i64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Instituicao {
+    /// Linhas do cabeçalho impresso no topo de cada página do PDF da escala.
+    pub cabecalho: Vec<String>,
+    /// Quem assina o PDF da escala à esquerda; à direita assina quem o gera.
+    pub assinatura_nome: String,
+    pub assinatura_cargo: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminInicial {
+    pub id: String,
+    pub senha: String,
+    #[serde(default = "nome_admin_padrao")]
+    pub nome: String,
+}
+
+impl Default for Servidor {
+    fn default() -> Self {
+        Self { endereco: IpAddr::V4(Ipv4Addr::LOCALHOST), porta: 3000 }
+    }
+}
+
+impl Default for Dados {
+    fn default() -> Self {
+        Self {
+            pasta: PathBuf::from("data"),
+            utilizadores: PathBuf::from("users.json"),
+            armazenamento: "sqlite".to_string(),
+        }
+    }
+}
+
+impl Default for Sessoes {
+    fn default() -> Self {
+        Self { inatividade_minutos: 2 * 60, duracao_maxima_horas: 12 }
+    }
+}
+
+impl Default for Senhas {
+    fn default() -> Self {
+        Self { tamanho_minimo: 8, exigir_letras_e_numeros: true }
+    }
+}
+
+impl Default for Login {
+    fn default() -> Self {
+        Self { tentativas: 5, tentativas_por_ip: 20, bloqueio_minutos: 15 }
+    }
+}
+
+impl Default for Instituicao {
+    fn default() -> Self {
+        Self {
+            cabecalho: vec![
+                "CENTRO DE INSTRUÇÃO ALMIRANTE GRAÇA ARANHA".to_string(),
+                "ESCOLA DE FORMAÇÃO DE OFICIAIS DA MARINHA MERCANTE".to_string(),
+                "DETALHE DE SERVIÇO DO CORPO DE ALUNOS DA EFOMM".to_string(),
+            ],
+            assinatura_nome: String::new(),
+            assinatura_cargo: String::new(),
+        }
+    }
+}
+
+fn nome_admin_padrao() -> String {
+    "Administrador".to_string()
+}
+
+/// Opções da linha de comandos. Todas são opcionais e substituem o `mercal.toml`.
+#[derive(Debug, Parser)]
+#[command(name = "mercal", version, about = "Servidor MercAl")]
+struct Args {
+    /// Ficheiro de configuração
+    #[arg(short, long, env = "MERCAL_CONFIG")]
+    config: Option<PathBuf>,
+    /// Endereço IP onde o servidor escuta
+    #[arg(long, env = "MERCAL_ENDERECO")]
+    endereco: Option<IpAddr>,
+    /// Porta onde o servidor escuta
+    #[arg(short, long, env = "MERCAL_PORTA")]
+    porta: Option<u16>,
+    /// Certificado TLS (PEM); ativa o HTTPS junto com --tls-chave
+    #[arg(long, env = "MERCAL_TLS_CERTIFICADO")]
+    tls_certificado: Option<PathBuf>,
+    /// Chave privada do certificado TLS (PEM)
+    #[arg(long, env = "MERCAL_TLS_CHAVE")]
+    tls_chave: Option<PathBuf>,
+    /// Porta HTTP que redireciona para o HTTPS
+    #[arg(long, env = "MERCAL_PORTA_REDIRECIONAMENTO")]
+    porta_redirecionamento: Option<u16>,
+    /// Pasta dos dados
+    #[arg(long, env = "MERCAL_DADOS")]
+    dados: Option<PathBuf>,
+    /// Ficheiro users.json da organização antiga
+    #[arg(long, env = "MERCAL_UTILIZADORES")]
+    utilizadores: Option<PathBuf>,
+    /// Armazenamento: sqlite, ficheiros ou memoria
+    #[arg(long, env = "MERCAL_ARMAZENAMENTO")]
+    armazenamento: Option<String>,
+    /// Minutos sem atividade até a sessão expirar
+    #[arg(long, env = "MERCAL_SESSAO_INATIVIDADE")]
+    sessao_inatividade: Option<i64>,
+    /// Horas de vida máxima de uma sessão
+    #[arg(long, env = "MERCAL_SESSAO_MAXIMA")]
+    sessao_maxima: Option<i64>,
+    /// Linhas do cabeçalho da instituição, separadas por '|'
+    #[arg(long, env = "MERCAL_CABECALHO", value_delimiter = '|')]
+    cabecalho: Option<Vec<String>>,
+    /// Nome de quem assina o PDF da escala
+    #[arg(long, env = "MERCAL_ASSINATURA_NOME")]
+    assinatura_nome: Option<String>,
+    /// Cargo de quem assina o PDF da escala
+    #[arg(long, env = "MERCAL_ASSINATURA_CARGO")]
+    assinatura_cargo: Option<String>,
+    /// Número do administrador criado no primeiro arranque
+    #[arg(long, env = "MERCAL_ADMIN_ID", requires = "admin_senha")]
+    admin_id: Option<String>,
+    /// Senha do administrador criado no primeiro arranque
+    #[arg(long, env = "MERCAL_ADMIN_SENHA", requires = "admin_id", hide_env_values = true)]
+    admin_senha: Option<String>,
+    /// Nome do administrador criado no primeiro arranque
+    #[arg(long, env = "MERCAL_ADMIN_NOME")]
+    admin_nome: Option<String>,
+}
+
+impl Config {
+    /// Lê a linha de comandos, o ambiente e o ficheiro de configuração.
+    pub fn carregar() -> AppResult<Self> {
+        let args = Args::parse();
+        let mut config = match &args.config {
+            // Um ficheiro pedido explicitamente tem de existir
+            Some(caminho) => Self::ler_ficheiro(caminho)?,
+            None if Path::new(CONFIG_FILE).exists() => Self::ler_ficheiro(Path::new(CONFIG_FILE))?,
+            None => Self::default(),
+        };
+        config.aplicar(args);
+        config.validar()?;
+        Ok(config)
+    }
+
+    fn ler_ficheiro(caminho: &Path) -> AppResult<Self> {
+        let conteudo = std::fs::read_to_string(caminho)
+            .map_err(|e| format!("Falha ao ler {}: {}", caminho.display(), e))?;
+        let config = toml::from_str(&conteudo).map_err(|e| format!("Erro em {}: {}", caminho.display(), e))?;
+        println!("⚙️ Configuração lida de {}", caminho.display());
+        Ok(config)
+    }
+
+    fn aplicar(&mut self, args: Args) {
+        if let Some(endereco) = args.endereco {
+            self.servidor.endereco = endereco;
+        }
+        if let Some(porta) = args.porta {
+            self.servidor.porta = porta;
+        }
+        if let Some(certificado) = args.tls_certificado {
+            self.tls.certificado = Some(certificado);
+        }
+        if let Some(chave) = args.tls_chave {
+            self.tls.chave = Some(chave);
+        }
+        if let Some(porta) = args.porta_redirecionamento {
+            self.tls.porta_redirecionamento = Some(porta);
+        }
+        if let Some(pasta) = args.dados {
+            self.dados.pasta = pasta;
+        }
+        if let Some(utilizadores) = args.utilizadores {
+            self.dados.utilizadores = utilizadores;
+        }
+        if let Some(armazenamento) = args.armazenamento {
+            self.dados.armazenamento = armazenamento;
+        }
+        if let Some(minutos) = args.sessao_inatividade {
+            self.sessoes.inatividade_minutos = minutos;
+        }
+        if let Some(horas) = args.sessao_maxima {
+            self.sessoes.duracao_maxima_horas = horas;
+        }
+        if let Some(cabecalho) = args.cabecalho {
+            self.instituicao.cabecalho = cabecalho;
+        }
+        if let Some(nome) = args.assinatura_nome {
+            self.instituicao.assinatura_nome = nome;
+        }
+        if let Some(cargo) = args.assinatura_cargo {
+            self.instituicao.assinatura_cargo = cargo;
+        }
+        if let (Some(id), Some(senha)) = (args.admin_id, args.admin_senha) {
+            self.admin = Some(AdminInicial { id, senha, nome: nome_admin_padrao() });
+        }
+        if let (Some(admin), Some(nome)) = (self.admin.as_mut(), args.admin_nome) {
+            admin.nome = nome;
+        }
+    }
+
+    fn validar(&self) -> AppResult<()> {
+        if self.tls.certificado.is_some() != self.tls.chave.is_some() {
+            return Err("O TLS precisa do certificado e da chave".into());
+        }
+        if let Some(porta) = self.tls.porta_redirecionamento {
+            if !self.tls_ativo() {
+                return Err("A porta de redirecionamento só faz sentido com TLS".into());
+            }
+            if porta == self.servidor.porta {
+                return Err("A porta de redirecionamento tem de ser diferente da porta do servidor".into());
+            }
+        }
+        if self.sessoes.inatividade_minutos <= 0 || self.sessoes.duracao_maxima_horas <= 0 {
+            return Err("Os prazos das sessões têm de ser positivos".into());
+        }
+        if self.login.tentativas == 0 || self.login.tentativas_por_ip == 0 || self.login.bloqueio_minutos <= 0 {
+            return Err("Os limites do login têm de ser positivos".into());
+        }
+        if let Some(admin) = &self.admin {
+            if admin.id.trim().is_empty() || admin.senha.is_empty() {
+                return Err("O administrador inicial precisa de número e senha".into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn endereco(&self) -> SocketAddr {
+        SocketAddr::new(self.servidor.endereco, self.servidor.porta)
+    }
+
+    /// O servidor fala HTTPS (certificado e chave configurados).
+    pub fn tls_ativo(&self) -> bool {
+        self.tls.certificado.is_some() && self.tls.chave.is_some()
+    }
+
+    /// Caminho de um ficheiro dentro da pasta dos dados.
+    pub fn caminho(&self, relativo: &str) -> PathBuf {
+        self.dados.pasta.join(relativo)
+    }
+}
@@ -0,0 +1,362 @@
+// src/db.rs
+
+//! # Módulo da Base de Dados Principal
+//!
+//! Toda a informação da aplicação (utilizadores, presenças, refeições, escalas e sessões)
+//! vive numa única base de dados SQLite. Este módulo abre a ligação, aplica as migrações
+//! versionadas do esquema e oferece as operações genéricas usadas pelos módulos de domínio.
+//! Também contém o importador único dos antigos ficheiros JSON.
+
+use crate::store::ficheiros::Caminhos;
+use chrono::Local;
+use rusqlite::{params, OptionalExtension};
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::Path;
+use tokio::fs;
+use tokio_rusqlite::Connection;
+
+/// Ficheiro da base de dados, dentro da pasta dos dados.
+pub const DB_FILE: &str = "mercal.db";
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Migrações do esquema, aplicadas por ordem. Nunca alterar uma migração já publicada:
+/// qualquer mudança ao esquema entra como uma nova entrada no fim da lista.
+const MIGRACOES: &[(i64, &str, &str)] = &[
+    (1, "sessões", "
+        CREATE TABLE IF NOT EXISTS sessoes (
+            id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            roles TEXT NOT NULL,
+            criada_em INTEGER NOT NULL,
+            ultimo_acesso INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS idx_sessoes_user ON sessoes (user_id);
+    "),
+    (2, "utilizadores, presenças, refeições e escalas", "
+        CREATE TABLE utilizadores (
+            id TEXT PRIMARY KEY,
+            dados TEXT NOT NULL
+        );
+        CREATE TABLE presencas (
+            user_id TEXT PRIMARY KEY,
+            dados TEXT NOT NULL
+        );
+        CREATE TABLE refeicoes (
+            data TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            dados TEXT NOT NULL,
+            PRIMARY KEY (data, user_id)
+        );
+        CREATE TABLE escalas (
+            data TEXT PRIMARY KEY,
+            dados TEXT NOT NULL
+        );
+        CREATE TABLE trocas (
+            id TEXT PRIMARY KEY,
+            criada_em INTEGER NOT NULL,
+            dados TEXT NOT NULL
+        );
+        CREATE TABLE documentos (
+            chave TEXT PRIMARY KEY,
+            dados TEXT NOT NULL,
+            atualizado_em TEXT NOT NULL
+        );
+    "),
+    (3, "histórico das gerações da escala", "
+        CREATE TABLE execucoes_escala (
+            versao INTEGER PRIMARY KEY,
+            gravada_em TEXT NOT NULL,
+            dados TEXT NOT NULL
+        );
+    "),
+];
+
+/// Ligação partilhada à base de dados principal.
+/// Todas as chamadas passam pela mesma thread do `tokio_rusqlite`, por isso cada
+/// `call` corre isolada das restantes: uma leitura-alteração-escrita feita dentro de
+/// um único `call` nunca perde atualizações concorrentes.
+#[derive(Clone)]
+pub struct Db {
+    conn: Connection,
+}
+
+impl Db {
+    /// Abre a base de dados e aplica as migrações que ainda faltam.
+    pub async fn open(path: impl AsRef<Path>) -> AppResult<Self> {
+        let conn = Connection::open(path).await?;
+        conn.call(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+            aplicar_migracoes(conn)?;
+            Ok(())
+        })
+        .await?;
+        Ok(Self { conn })
+    }
+
+    /// Ligação crua, para os módulos que precisam de SQL próprio.
+    pub fn conn(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Lê um documento. Devolve `None` se ainda não existir.
+    pub async fn documento<T>(&self, chave: &'static str) -> AppResult<Option<T>>
+    where
+        T: DeserializeOwned + Send + 'static,
+    {
+        let dados: Option<String> = self
+            .conn
+            .call(move |conn| {
+                Ok(conn
+                    .query_row("SELECT dados FROM documentos WHERE chave = ?1", [chave], |row| row.get(0))
+                    .optional()?)
+            })
+            .await?;
+        match dados {
+            Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lê um documento, usando o valor por omissão se ainda não existir.
+    pub async fn documento_ou_padrao<T>(&self, chave: &'static str) -> AppResult<T>
+    where
+        T: DeserializeOwned + Default + Send + 'static,
+    {
+        Ok(self.documento(chave).await?.unwrap_or_default())
+    }
+
+    /// Grava um documento por inteiro.
+    pub async fn guardar_documento<T: Serialize + ?Sized>(&self, chave: &'static str, valor: &T) -> AppResult<()> {
+        let json = serde_json::to_string(valor)?;
+        self.conn
+            .call(move |conn| {
+                guardar_documento_sync(conn, chave, &json)?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Corre várias operações numa só transação: ou ficam todas gravadas, ou nenhuma.
+    pub async fn transacao<R, F>(&self, f: F) -> AppResult<R>
+    where
+        F: FnOnce(&rusqlite::Transaction) -> rusqlite::Result<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let res = self
+            .conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let resultado = f(&tx)?;
+                tx.commit()?;
+                Ok(resultado)
+            })
+            .await?;
+        Ok(res)
+    }
+
+    /// Importa, uma única vez, os ficheiros JSON usados antes da base de dados.
+    /// Só corre se a base de dados ainda não tiver utilizadores e existir um `users.json`.
+    pub async fn importar_json_legado(&self, caminhos: &Caminhos) -> AppResult<()> {
+        let vazia: bool = self
+            .conn
+            .call(|conn| Ok(conn.query_row("SELECT COUNT(*) FROM utilizadores", [], |row| row.get::<_, i64>(0))? == 0))
+            .await?;
+        if !vazia || !fs::try_exists(&caminhos.utilizadores).await.unwrap_or(false) {
+            return Ok(());
+        }
+
+        println!("📦 A importar os ficheiros JSON antigos de {}...", caminhos.dados.display());
+        let dados = legado::ler_tudo(caminhos).await?;
+        let resumo = format!(
+            "{} utilizadores, {} presenças, {} dias de refeições, {} dias de escala, {} trocas",
+            dados.utilizadores.len(),
+            dados.presencas.len(),
+            dados.refeicoes.len(),
+            dados.escalas.len(),
+            dados.trocas.len()
+        );
+        self.conn
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for (id, json) in &dados.utilizadores {
+                    tx.execute("INSERT INTO utilizadores (id, dados) VALUES (?1, ?2)", params![id, json])?;
+                }
+                for (user_id, json) in &dados.presencas {
+                    tx.execute("INSERT INTO presencas (user_id, dados) VALUES (?1, ?2)", params![user_id, json])?;
+                }
+                for (data, linhas) in &dados.refeicoes {
+                    for (user_id, json) in linhas {
+                        tx.execute(
+                            "INSERT INTO refeicoes (data, user_id, dados) VALUES (?1, ?2, ?3)",
+                            params![data, user_id, json],
+                        )?;
+                    }
+                }
+                for (data, json) in &dados.escalas {
+                    tx.execute("INSERT INTO escalas (data, dados) VALUES (?1, ?2)", params![data, json])?;
+                }
+                for (ordem, (id, json)) in dados.trocas.iter().enumerate() {
+                    tx.execute(
+                        "INSERT INTO trocas (id, criada_em, dados) VALUES (?1, ?2, ?3)",
+                        params![id, ordem as i64, json],
+                    )?;
+                }
+                for (chave, json) in &dados.documentos {
+                    guardar_documento_sync(&tx, chave, json)?;
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await?;
+        println!("✅ Importação concluída: {}. Os ficheiros JSON já não são usados.", resumo);
+        Ok(())
+    }
+}
+
+fn guardar_documento_sync(conn: &rusqlite::Connection, chave: &str, json: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO documentos (chave, dados, atualizado_em) VALUES (?1, ?2, ?3)
+         ON CONFLICT (chave) DO UPDATE SET dados = excluded.dados, atualizado_em = excluded.atualizado_em",
+        params![chave, json, Local::now().to_rfc3339()],
+    )?;
+    Ok(())
+}
+
+/// Lê um documento dentro de uma transação aberta com `Db::transacao`.
+pub fn ler_documento_tx<T: DeserializeOwned>(tx: &rusqlite::Transaction, chave: &str) -> rusqlite::Result<Option<T>> {
+    let dados: Option<String> = tx
+        .query_row("SELECT dados FROM documentos WHERE chave = ?1", [chave], |row| row.get(0))
+        .optional()?;
+    dados.map(|json| serde_json::from_str(&json).map_err(erro_json)).transpose()
+}
+
+/// Grava um documento dentro de uma transação aberta com `Db::transacao`.
+pub fn guardar_documento_tx<T: Serialize + ?Sized>(tx: &rusqlite::Transaction, chave: &str, valor: &T) -> rusqlite::Result<()> {
+    guardar_documento_sync(tx, chave, &serde_json::to_string(valor).map_err(erro_json)?)
+}
+
+/// Converte um erro de (de)serialização num erro do `rusqlite`, para poder sair de dentro de um `call`.
+pub fn erro_json(e: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(e))
+}
+
+fn aplicar_migracoes(conn: &mut rusqlite::Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            versao INTEGER PRIMARY KEY,
+            descricao TEXT NOT NULL,
+            aplicada_em TEXT NOT NULL
+        );",
+    )?;
+    let atual: i64 = conn.query_row("SELECT COALESCE(MAX(versao), 0) FROM schema_version", [], |row| row.get(0))?;
+
+    for (versao, descricao, sql) in MIGRACOES.iter().filter(|(v, _, _)| *v > atual) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.execute(
+            "INSERT INTO schema_version (versao, descricao, aplicada_em) VALUES (?1, ?2, ?3)",
+            params![versao, descricao, Local::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+        println!("🗄️ Migração {} aplicada: {}", versao, descricao);
+    }
+    Ok(())
+}
+
+/// Leitura dos ficheiros JSON usados antes da base de dados, apenas para a importação.
+mod legado {
+    use super::*;
+    use crate::store::Documento;
+    use serde_json::Value;
+
+    /// Tudo o que foi lido, já serializado para as linhas da base de dados.
+    pub struct DadosLegados {
+        pub utilizadores: Vec<(String, String)>,
+        pub presencas: Vec<(String, String)>,
+        pub refeicoes: Vec<(String, Vec<(String, String)>)>,
+        pub escalas: Vec<(String, String)>,
+        pub trocas: Vec<(String, String)>,
+        pub documentos: Vec<(&'static str, String)>,
+    }
+
+    async fn ler_json(caminho: &Path) -> Option<Value> {
+        let conteudo = fs::read_to_string(caminho).await.ok()?;
+        match serde_json::from_str(&conteudo) {
+            Ok(valor) => Some(valor),
+            Err(e) => {
+                eprintln!("⚠️ Ficheiro {} ignorado na importação: {}", caminho.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Lista os ficheiros `AAAA-MM-DD.json` de uma pasta, com a data como chave.
+    async fn ficheiros_diarios(pasta: &Path) -> AppResult<Vec<(String, Value)>> {
+        let mut resultado = Vec::new();
+        let Ok(mut entradas) = fs::read_dir(pasta).await else { return Ok(resultado) };
+        while let Some(entrada) = entradas.next_entry().await? {
+            let nome = entrada.file_name().to_string_lossy().to_string();
+            let Some(data) = nome.strip_suffix(".json") else { continue };
+            if chrono::NaiveDate::parse_from_str(data, "%Y-%m-%d").is_err() {
+                continue;
+            }
+            if let Some(valor) = ler_json(&entrada.path()).await {
+                resultado.push((data.to_string(), valor));
+            }
+        }
+        resultado.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(resultado)
+    }
+
+    fn objeto_para_linhas(valor: Value) -> Vec<(String, String)> {
+        match valor {
+            Value::Object(mapa) => mapa.into_iter().map(|(k, v)| (k, v.to_string())).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub async fn ler_tudo(caminhos: &Caminhos) -> AppResult<DadosLegados> {
+        let utilizadores = match ler_json(&caminhos.utilizadores).await {
+            Some(Value::Array(lista)) => lista
+                .into_iter()
+                .filter_map(|u| Some((u.get("id")?.as_str()?.to_string(), u.to_string())))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let presencas = ler_json(&caminhos.presencas()).await.map(objeto_para_linhas).unwrap_or_default();
+
+        let refeicoes = ficheiros_diarios(&caminhos.refeicoes())
+            .await?
+            .into_iter()
+            .map(|(data, valor)| (data, objeto_para_linhas(valor)))
+            .collect();
+
+        let escalas = ficheiros_diarios(&caminhos.escala())
+            .await?
+            .into_iter()
+            .map(|(data, valor)| (data, valor.to_string()))
+            .collect();
+
+        let trocas = match ler_json(&caminhos.trocas()).await {
+            Some(Value::Array(lista)) => lista
+                .into_iter()
+                .filter_map(|t| Some((t.get("id")?.as_str()?.to_string(), t.to_string())))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut documentos = Vec::new();
+        for doc in Documento::TODOS {
+            if let Some(valor) = ler_json(&caminhos.documento(doc)).await {
+                if !valor.is_null() {
+                    documentos.push((doc.chave(), valor.to_string()));
+                }
+            }
+        }
+
+        Ok(DadosLegados { utilizadores, presencas, refeicoes, escalas, trocas, documentos })
+    }
+}
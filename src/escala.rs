@@ -1,23 +1,39 @@
 // src/escala.rs
 
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use chrono::NaiveDate;
-use tokio::fs;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Weekday};
 use crate::auth::User;
+use crate::escala_calendario::Calendario;
+use crate::escala_dividas::{OrigemDivida, RegrasDividas};
+use crate::escala_punicoes::{RegistoPunicao, ServicoCumprido};
+use crate::store::{EscalaStore, EscalaTx, Recusa};
+use uuid::Uuid;
 
 // --- STRUCTS E ENUMS ---
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub enum Genero {
     #[serde(rename = "M")]
     Masculino,
     #[serde(rename = "F")]
     Feminino,
     #[serde(rename = "X")]
+    #[default]
     Misto,
 }
 
+impl Genero {
+    /// Se alguém deste género serve onde se pede `self` (misto aceita todos).
+    pub fn aceita(&self, genero: &Genero) -> bool {
+        match self {
+            Genero::Masculino => *genero == Genero::Masculino,
+            Genero::Feminino => *genero == Genero::Feminino,
+            Genero::Misto => true,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub enum TipoServico {
     RN,
@@ -27,7 +43,7 @@ pub enum TipoServico {
     Retem,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Posto {
     pub nome: String,
     pub turmas_permitidas: Vec<u8>,
@@ -40,9 +56,126 @@ pub struct Posto {
     pub horarios_udrd: Vec<String>,
     #[serde(default)]
     pub horarios_er: Vec<String>,
+    /// Postos retirados ficam na lista, mas deixam de entrar nas novas gerações.
+    #[serde(default = "ativo_padrao")]
+    pub ativo: bool,
+    /// Título da secção do PDF onde o posto aparece. Sem ele, a secção é a do ano mais
+    /// antigo que o pode ocupar ("3º ANO").
+    #[serde(default)]
+    pub seccao_pdf: Option<String>,
+}
+
+fn ativo_padrao() -> bool {
+    true
+}
+
+impl Posto {
+    /// Se a pessoa é da turma, do género e tem a função que o posto pede.
+    pub fn aceita(&self, user: &User) -> bool {
+        self.turmas_permitidas.contains(&user.ano)
+            && self.funcao_exclusiva.as_ref().is_none_or(|f| user.roles.contains(f))
+            && self.genero.aceita(&user.genero)
+    }
+
+    /// A secção do PDF onde o posto aparece.
+    pub fn seccao(&self) -> String {
+        match (&self.seccao_pdf, self.turmas_permitidas.iter().max()) {
+            (Some(seccao), _) => seccao.clone(),
+            (None, Some(ano)) => format!("{}º ANO", ano),
+            (None, None) => "OUTROS".to_string(),
+        }
+    }
+
+    /// Os horários do posto num dia deste tipo (nenhum no retém).
+    pub fn horarios(&self, tipo: &TipoServico) -> &[String] {
+        match tipo {
+            TipoServico::RN => &self.horarios_rn,
+            TipoServico::RD => &self.horarios_rd,
+            TipoServico::UDRD => &self.horarios_udrd,
+            TipoServico::ER => &self.horarios_er,
+            TipoServico::Retem => &[],
+        }
+    }
+
+    /// Avisos sobre um posto que não se consegue preencher com os utilizadores ativos:
+    /// ninguém o pode ocupar, ou há mais horários num dia do que pessoas para eles.
+    pub fn avisos(&self, utilizadores: &[&User]) -> Vec<String> {
+        let possiveis = utilizadores.iter().filter(|u| u.ativo && self.aceita(u)).count();
+        if possiveis == 0 {
+            return vec![format!("Nenhum utilizador ativo pode ocupar o posto {}.", self.nome)];
+        }
+        let mut avisos = Vec::new();
+        for tipo in [TipoServico::RN, TipoServico::RD, TipoServico::UDRD, TipoServico::ER] {
+            let horarios = self.horarios(&tipo).len();
+            if horarios > possiveis {
+                avisos.push(format!(
+                    "O posto {} tem {} horários nos dias {:?}, mas só {} pessoa(s) o podem ocupar.",
+                    self.nome, horarios, tipo, possiveis
+                ));
+            }
+        }
+        avisos
+    }
+}
+
+/// Lê os campos do formulário de um posto: `nome`, `turmas` (anos separados por vírgula),
+/// `genero`, `funcao_exclusiva`, `seccao_pdf` e `horarios_rn`, `horarios_rd`, `horarios_udrd`
+/// e `horarios_er`, com um horário por linha. O posto fica ativo.
+pub fn posto_do_formulario(campos: &HashMap<String, String>) -> Result<Posto, String> {
+    let campo = |nome: &str| campos.get(nome).map(|v| v.trim()).unwrap_or_default();
+    let nome = campo("nome").to_string();
+    if nome.is_empty() {
+        return Err("Indique o nome do posto.".to_string());
+    }
+    let mut turmas_permitidas = Vec::new();
+    for ano in campo("turmas").split(',').map(str::trim).filter(|a| !a.is_empty()) {
+        match ano.trim_end_matches('º').parse::<u8>() {
+            Ok(ano) if !turmas_permitidas.contains(&ano) => turmas_permitidas.push(ano),
+            Ok(_) => {}
+            Err(_) => return Err(format!("'{}' não é um ano válido.", ano)),
+        }
+    }
+    if turmas_permitidas.is_empty() {
+        return Err("Indique pelo menos um ano que pode ocupar o posto.".to_string());
+    }
+    turmas_permitidas.sort();
+    let genero = match campo("genero") {
+        "M" => Genero::Masculino,
+        "F" => Genero::Feminino,
+        _ => Genero::Misto,
+    };
+    let funcao_exclusiva = Some(campo("funcao_exclusiva").to_lowercase()).filter(|f| !f.is_empty());
+    let seccao_pdf = Some(campo("seccao_pdf").to_uppercase()).filter(|s| !s.is_empty());
+
+    let horarios = |nome: &str, tipo: &str| -> Result<Vec<String>, String> {
+        let horarios: Vec<String> = campo(nome).lines().map(str::trim).filter(|h| !h.is_empty()).map(String::from).collect();
+        for horario in &horarios {
+            crate::escala_pdf::validar_horario(horario).map_err(|e| format!("Horário {} inválido: {}", tipo, e))?;
+        }
+        if horarios.len() > 1 && horarios.iter().any(|h| h == "DIARIO") {
+            return Err(format!("Nos dias {}, um posto DIARIO não pode ter outros horários.", tipo));
+        }
+        if let Some(repetido) = horarios.iter().enumerate().find(|(i, h)| horarios[..*i].contains(h)).map(|(_, h)| h) {
+            return Err(format!("O horário {} aparece duas vezes nos dias {}.", repetido, tipo));
+        }
+        Ok(horarios)
+    };
+
+    Ok(Posto {
+        nome,
+        turmas_permitidas,
+        genero,
+        funcao_exclusiva,
+        horarios_rn: horarios("horarios_rn", "RN")?,
+        horarios_rd: horarios("horarios_rd", "RD")?,
+        horarios_udrd: horarios("horarios_udrd", "UDRD")?,
+        horarios_er: horarios("horarios_er", "ER")?,
+        ativo: true,
+        seccao_pdf,
+    })
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
 pub struct ContagemUtilizador {
     #[serde(default)]
     pub rn: u32,
@@ -54,11 +187,18 @@ pub struct ContagemUtilizador {
 
 pub type Contagem = HashMap<String, ContagemUtilizador>;
 
+/// Um serviço por pagar. Fica na lista do devedor, em `DividasAtivas`.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Divida {
+    /// Quem tem o serviço a receber.
     pub credor: String,
     pub tipo_divida: TipoServico,
+    #[serde(default)]
+    pub origem: OrigemDivida,
+    #[serde(default)]
+    pub criada_em: Option<DateTime<Local>>,
 }
+/// As dívidas por pagar: chave = devedor.
 pub type DividasAtivas = HashMap<String, Vec<Divida>>;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -78,7 +218,7 @@ pub struct EscalaDiaria {
     pub retem: Vec<Alocacao>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Periodo {
     pub start_date: NaiveDate,
     pub end_date: NaiveDate,
@@ -91,7 +231,8 @@ pub struct EstadoEscala {
     pub status_trocas: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Um serviço numa troca. O retém tem o posto "RETEM" e o horário "SOBREAVISO".
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DetalheServico {
     pub data: NaiveDate,
     pub posto: String,
@@ -103,6 +244,8 @@ pub struct DetalheServico {
 pub enum TipoTroca {
     Permuta,
     Cobertura,
+    /// Troca circular entre várias pessoas, montada a partir do quadro de ofertas.
+    Cadeia,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -111,6 +254,16 @@ pub enum StatusTroca {
     PendenteAdmin,
     Aprovada,
     Recusada,
+    /// Desistência de quem a pediu, antes de ser decidida.
+    Cancelada,
+    /// O prazo passou, ou a escala dos seus dias foi gerada de novo, antes de ser decidida.
+    Expirada,
+}
+
+impl StatusTroca {
+    pub fn pendente(&self) -> bool {
+        matches!(self, StatusTroca::PendenteAlvo | StatusTroca::PendenteAdmin)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -121,92 +274,1085 @@ pub struct Troca {
     pub alvo: DetalheServico,
     pub motivo: String,
     pub status: StatusTroca,
+    #[serde(default)]
+    pub pedida_em: Option<DateTime<Local>>,
+    /// Até quando pode ser decidida; nunca depois do início do primeiro dia envolvido.
+    #[serde(default)]
+    pub expira_em: Option<DateTime<Local>>,
+    /// Quando foi aprovada, recusada, cancelada ou expirou.
+    #[serde(default)]
+    pub decidida_em: Option<DateTime<Local>>,
+    /// Numa cadeia, os serviços por ordem: quem tem cada um fica com o seguinte, e quem
+    /// tem o último fica com o primeiro.
+    #[serde(default)]
+    pub cadeia: Vec<DetalheServico>,
+    /// As ofertas do quadro que esta troca reserva.
+    #[serde(default)]
+    pub ofertas: Vec<String>,
 }
 
+impl Troca {
+    /// Se ainda está por decidir e o prazo já passou.
+    pub fn expirou(&self, agora: DateTime<Local>) -> bool {
+        self.status.pendente() && self.expira_em.is_some_and(|prazo| prazo <= agora)
+    }
+
+    /// Os serviços que mudam de mãos: o alvo e, numa permuta, o do requerente.
+    pub fn servicos(&self) -> Vec<&DetalheServico> {
+        match self.tipo {
+            TipoTroca::Permuta => vec![&self.requerente, &self.alvo],
+            TipoTroca::Cobertura => vec![&self.alvo],
+            TipoTroca::Cadeia => self.cadeia.iter().collect(),
+        }
+    }
+
+    pub fn envolve(&self, user_id: &str) -> bool {
+        self.requerente.user_id == user_id || self.alvo.user_id == user_id || self.cadeia.iter().any(|s| s.user_id == user_id)
+    }
+
+    /// O serviço que a pessoa passa a fazer se a troca for aprovada.
+    pub fn recebe(&self, user_id: &str) -> Option<&DetalheServico> {
+        match self.tipo {
+            TipoTroca::Permuta if self.alvo.user_id == user_id => Some(&self.requerente),
+            TipoTroca::Permuta | TipoTroca::Cobertura => Some(&self.alvo).filter(|_| self.requerente.user_id == user_id),
+            TipoTroca::Cadeia => {
+                let posicao = self.cadeia.iter().position(|s| s.user_id == user_id)?;
+                self.cadeia.get((posicao + 1) % self.cadeia.len())
+            }
+        }
+    }
+
+    /// O fim do prazo: o pedido, se for antes, ou o início do primeiro dia envolvido.
+    pub fn prazo(&self, pedido: Option<NaiveDateTime>) -> Option<DateTime<Local>> {
+        let primeiro_dia = self.servicos().iter().map(|s| s.data).min()?;
+        let limite = primeiro_dia.and_time(NaiveTime::MIN);
+        let prazo = pedido.map_or(limite, |p| p.min(limite));
+        Local.from_local_datetime(&prazo).earliest()
+    }
+}
+
+/// Um serviço publicado no quadro de ofertas por quem o quer dar.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OfertaServico {
+    pub id: String,
+    pub servico: DetalheServico,
+    pub motivo: String,
+    /// Aceita um serviço em troca, e por isso pode entrar numa cadeia. Sem isto, a oferta
+    /// só pode ser assumida por alguém (uma cobertura, que fica como dívida).
+    pub aceita_permuta: bool,
+    pub criada_em: DateTime<Local>,
+    #[serde(default)]
+    pub retirada_em: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EstadoOferta {
+    Aberta,
+    /// Numa troca que aguarda o escalante.
+    Reservada,
+    Concluida,
+    Retirada,
+}
+
+impl OfertaServico {
+    /// O estado vem das trocas que a usam: uma oferta recusada ou expirada volta a abrir.
+    pub fn estado(&self, trocas: &[Troca]) -> EstadoOferta {
+        let usada = |status: StatusTroca| trocas.iter().any(|t| t.status == status && t.ofertas.contains(&self.id));
+        if self.retirada_em.is_some() {
+            EstadoOferta::Retirada
+        } else if usada(StatusTroca::Aprovada) {
+            EstadoOferta::Concluida
+        } else if usada(StatusTroca::PendenteAdmin) || usada(StatusTroca::PendenteAlvo) {
+            EstadoOferta::Reservada
+        } else {
+            EstadoOferta::Aberta
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum CategoriaIndisponibilidade {
+    BaixaMedica,
+    Viagem,
+    Competicao,
+    #[default]
+    Outra,
+}
+
+impl CategoriaIndisponibilidade {
+    pub const TODAS: [CategoriaIndisponibilidade; 4] = [Self::BaixaMedica, Self::Viagem, Self::Competicao, Self::Outra];
+
+    pub fn nome(&self) -> &'static str {
+        match self {
+            Self::BaixaMedica => "Baixa médica",
+            Self::Viagem => "Viagem",
+            Self::Competicao => "Competição",
+            Self::Outra => "Outra",
+        }
+    }
+}
+
+/// As indisponibilidades marcadas pelo admin ficam logo aprovadas; as pedidas pelos
+/// utilizadores esperam pela decisão do admin.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum EstadoIndisponibilidade {
+    Pendente,
+    #[default]
+    Aprovada,
+    Rejeitada,
+}
+
+/// Um período em que o utilizador não pode fazer serviço: um dia, um intervalo de dias ou
+/// alguns dias da semana (dentro do intervalo, ou sem fim se não houver `data_fim`).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Indisponibilidade {
+    /// As entradas antigas não têm id; são identificadas pelo utilizador e pela data.
+    #[serde(default)]
+    pub id: String,
     pub user_id: String,
     pub data: NaiveDate,
+    #[serde(default)]
+    pub data_fim: Option<NaiveDate>,
+    #[serde(default)]
+    pub dias_semana: Vec<Weekday>,
+    #[serde(default)]
+    pub categoria: CategoriaIndisponibilidade,
     pub motivo: String,
+    #[serde(default)]
+    pub estado: EstadoIndisponibilidade,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Indisponibilidade {
+    pub fn chave(&self) -> String {
+        if self.id.is_empty() {
+            format!("{}:{}", self.user_id, self.data)
+        } else {
+            self.id.clone()
+        }
+    }
+
+    /// Se o utilizador está indisponível neste dia (sem olhar ao estado do pedido).
+    pub fn abrange(&self, dia: NaiveDate) -> bool {
+        if dia < self.data {
+            return false;
+        }
+        match self.data_fim {
+            Some(fim) if dia > fim => return false,
+            None if self.dias_semana.is_empty() => return dia == self.data,
+            _ => {}
+        }
+        self.dias_semana.is_empty() || self.dias_semana.contains(&dia.weekday())
+    }
+
+    /// As datas abrangidas, por extenso: "03/02/2025", "03/02/2025 a 07/02/2025",
+    /// "às terças, desde 03/02/2025".
+    pub fn descrever_datas(&self) -> String {
+        let inicio = self.data.format("%d/%m/%Y");
+        let dias = if self.dias_semana.is_empty() {
+            String::new()
+        } else {
+            let nomes: Vec<&str> = self.dias_semana.iter().map(|d| nome_dia_semana_plural(*d)).collect();
+            format!("às {}, ", nomes.join(", "))
+        };
+        match (self.data_fim, dias.is_empty()) {
+            (None, true) => inicio.to_string(),
+            (None, false) => format!("{}desde {}", dias, inicio),
+            (Some(fim), _) => format!("{}de {} a {}", dias, inicio, fim.format("%d/%m/%Y")),
+        }
+    }
+}
+
+pub fn nome_dia_semana_plural(dia: Weekday) -> &'static str {
+    match dia {
+        Weekday::Mon => "segundas",
+        Weekday::Tue => "terças",
+        Weekday::Wed => "quartas",
+        Weekday::Thu => "quintas",
+        Weekday::Fri => "sextas",
+        Weekday::Sat => "sábados",
+        Weekday::Sun => "domingos",
+    }
+}
+
+/// Lê os campos de um formulário de indisponibilidade: `data`, `data_fim` (opcional),
+/// `dia_semana_0` a `dia_semana_6` (segunda a domingo), `categoria` e `motivo`.
+pub fn indisponibilidade_do_formulario(
+    user_id: String,
+    campos: &HashMap<String, String>,
+    estado: EstadoIndisponibilidade,
+) -> Result<Indisponibilidade, Recusa> {
+    let data_do_campo = |nome: &str| campos.get(nome).filter(|v| !v.is_empty()).map(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d"));
+    let data = match data_do_campo("data") {
+        Some(Ok(data)) => data,
+        _ => return Err(Recusa("Indique uma data de início válida.")),
+    };
+    let data_fim = match data_do_campo("data_fim") {
+        None => None,
+        Some(Ok(fim)) if fim >= data => Some(fim),
+        Some(Ok(_)) => return Err(Recusa("A data de fim não pode ser anterior à data de início.")),
+        Some(Err(_)) => return Err(Recusa("A data de fim não é válida.")),
+    };
+    let dias_semana = (0..7u8)
+        .filter(|n| campos.contains_key(&format!("dia_semana_{}", n)))
+        .filter_map(|n| Weekday::try_from(n).ok())
+        .collect();
+    let categoria = CategoriaIndisponibilidade::TODAS
+        .into_iter()
+        .find(|c| campos.get("categoria").is_some_and(|v| *v == format!("{:?}", c)))
+        .unwrap_or_default();
+    let motivo = campos.get("motivo").map(|m| m.trim().to_string()).unwrap_or_default();
+    if motivo.is_empty() {
+        return Err(Recusa("Indique o motivo."));
+    }
+    Ok(Indisponibilidade {
+        id: Uuid::new_v4().to_string(),
+        user_id,
+        data,
+        data_fim,
+        dias_semana,
+        categoria,
+        motivo,
+        estado,
+    })
+}
+
+/// Serviços extra que uma pessoa tem de fazer nos postos de punição. As punições
+/// cumpridas ou anuladas ficam na lista, como histórico; só as ativas entram na geração.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct Punicao {
     pub user_id: String,
     pub total_a_cumprir: u32,
     pub ja_cumpridos: u32,
+    #[serde(default)]
+    pub motivo: String,
+    /// Quem deu a punição (por exemplo, "Comandante de Companhia").
+    #[serde(default)]
+    pub autoridade: String,
+    #[serde(default)]
+    pub aplicada_em: Option<NaiveDate>,
+    /// Data até à qual os serviços devem estar cumpridos.
+    #[serde(default)]
+    pub prazo: Option<NaiveDate>,
+    /// Os serviços da escala em que a punição foi cumprida.
+    #[serde(default)]
+    pub cumpridos: Vec<ServicoCumprido>,
+    #[serde(default)]
+    pub anulada_em: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub historico: Vec<RegistoPunicao>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+impl Punicao {
+    pub fn ativa(&self) -> bool {
+        self.anulada_em.is_none() && self.ja_cumpridos < self.total_a_cumprir
+    }
+
+    pub fn restantes(&self) -> u32 {
+        self.total_a_cumprir.saturating_sub(self.ja_cumpridos)
+    }
+
+    /// Ativa e com o prazo já passado.
+    pub fn em_atraso(&self, hoje: NaiveDate) -> bool {
+        self.ativa() && self.prazo.is_some_and(|p| p < hoje)
+    }
+
+    /// Conta um serviço cumprido num posto da escala e devolve o nome a mostrar na
+    /// alocação, com o progresso ("Nome (2/3)").
+    pub fn cumprir(&mut self, nome: &str, data: NaiveDate, posto: &str, horario: &str) -> String {
+        self.ja_cumpridos += 1;
+        self.cumpridos.push(ServicoCumprido { data, posto: posto.to_string(), horario: horario.to_string() });
+        format!("{} ({}/{})", nome, self.ja_cumpridos, self.total_a_cumprir)
+    }
+}
+
+/// Quantas pessoas de um ano fazem retém em cada dia. Sem `tipo_dia`, vale para todos os
+/// dias que não tenham quotas próprias para o seu tipo.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QuotaRetem {
+    pub ano: u8,
+    pub quantidade: usize,
+    #[serde(default)]
+    pub tipo_dia: Option<TipoServico>,
+    #[serde(default)]
+    pub genero: Genero,
+}
+
+impl QuotaRetem {
+    pub fn aceita(&self, user: &User) -> bool {
+        user.ano == self.ano && self.genero.aceita(&user.genero)
+    }
+
+    /// Por extenso: "4 do 1º ano", "2 do 3º ano (F) nos dias RD".
+    pub fn descrever(&self) -> String {
+        let genero = match self.genero {
+            Genero::Masculino => " (M)",
+            Genero::Feminino => " (F)",
+            Genero::Misto => "",
+        };
+        let tipo = self.tipo_dia.as_ref().map(|t| format!(" nos dias {:?}", t)).unwrap_or_default();
+        format!("{} do {}º ano{}{}", self.quantidade, self.ano, genero, tipo)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ConfiguracaoEscala {
     #[serde(default)]
     pub postos_punicao: Vec<String>,
+    #[serde(default = "quotas_retem_padrao")]
+    pub quotas_retem: Vec<QuotaRetem>,
+    /// Feriados e regras que pré-preenchem o tipo de cada dia na geração.
+    #[serde(default)]
+    pub calendario: Calendario,
+    /// O que acontece às dívidas por pagar na virada de ano.
+    #[serde(default)]
+    pub dividas: RegrasDividas,
+}
+
+impl Default for ConfiguracaoEscala {
+    fn default() -> Self {
+        Self {
+            postos_punicao: Vec::new(),
+            quotas_retem: quotas_retem_padrao(),
+            calendario: Calendario::default(),
+            dividas: RegrasDividas::default(),
+        }
+    }
 }
 
+/// As quotas usadas antes de serem configuráveis: 2 do 3º ano, 2 do 2º e 4 do 1º.
+pub fn quotas_retem_padrao() -> Vec<QuotaRetem> {
+    [(3, 2), (2, 2), (1, 4)]
+        .into_iter()
+        .map(|(ano, quantidade)| QuotaRetem { ano, quantidade, tipo_dia: None, genero: Genero::Misto })
+        .collect()
+}
+
+impl ConfiguracaoEscala {
+    /// As quotas do retém num dia deste tipo: as próprias do tipo, se houver, ou as gerais.
+    /// As de um só género vêm primeiro, para que as mistas não lhes tirem as pessoas.
+    pub fn quotas_do_dia(&self, tipo: &TipoServico) -> Vec<&QuotaRetem> {
+        let proprias: Vec<&QuotaRetem> = self.quotas_retem.iter().filter(|q| q.tipo_dia.as_ref() == Some(tipo)).collect();
+        let mut quotas = if proprias.is_empty() {
+            self.quotas_retem.iter().filter(|q| q.tipo_dia.is_none()).collect()
+        } else {
+            proprias
+        };
+        quotas.sort_by_key(|q| q.genero == Genero::Misto);
+        quotas
+    }
+}
+
+
+impl Default for EstadoEscala {
+    fn default() -> Self {
+        Self {
+            periodo_atual: Periodo {
+                start_date: NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(),
+                end_date: NaiveDate::from_ymd_opt(2025, 1, 7).unwrap(),
+            },
+            periodo_seguinte: None,
+            status_trocas: "Fechado".to_string(),
+        }
+    }
+}
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// --- ALTERAÇÕES AOS DADOS DA ESCALA ---
+// As leituras são feitas diretamente no `EscalaStore`; as alterações abaixo são atómicas.
+
+/// Altera o estado da escala numa só transação.
+pub async fn atualizar_estado<F>(store: &dyn EscalaStore, f: F) -> AppResult<()>
+where
+    F: FnOnce(&mut EstadoEscala) + Send + 'static,
+{
+    store
+        .transacao(Box::new(move |tx| {
+            let mut estado = tx.estado()?;
+            f(&mut estado);
+            tx.guardar_estado(&estado)
+        }))
+        .await
+}
+
+pub async fn atualizar_indisponibilidades<F>(store: &dyn EscalaStore, f: F) -> AppResult<()>
+where
+    F: FnOnce(&mut Vec<Indisponibilidade>) + Send + 'static,
+{
+    store
+        .transacao(Box::new(move |tx| {
+            let mut indisponibilidades = tx.indisponibilidades()?;
+            f(&mut indisponibilidades);
+            tx.guardar_indisponibilidades(&indisponibilidades)
+        }))
+        .await
+}
+
+pub async fn atualizar_configuracao<F>(store: &dyn EscalaStore, f: F) -> AppResult<()>
+where
+    F: FnOnce(&mut ConfiguracaoEscala) + Send + 'static,
+{
+    store
+        .transacao(Box::new(move |tx| {
+            let mut configuracao = tx.configuracao()?;
+            f(&mut configuracao);
+            tx.guardar_configuracao(&configuracao)
+        }))
+        .await
+}
+
+pub async fn atualizar_postos<F>(store: &dyn EscalaStore, f: F) -> AppResult<()>
+where
+    F: FnOnce(&mut Vec<Posto>, &mut ConfiguracaoEscala) -> AppResult<()> + Send + 'static,
+{
+    store
+        .transacao(Box::new(move |tx| {
+            let mut postos = tx.postos()?;
+            let mut configuracao = tx.configuracao()?;
+            f(&mut postos, &mut configuracao)?;
+            tx.guardar_postos(&postos)?;
+            tx.guardar_configuracao(&configuracao)
+        }))
+        .await
+}
+
+/// Muda o nome de um posto nos dias gravados do período em vigor e do seguinte, e nas trocas
+/// por decidir e nas ofertas desses dias, para a escala continuar válida depois da mudança.
+pub fn renomear_posto(tx: &mut dyn EscalaTx, antigo: &str, novo: &str) -> AppResult<()> {
+    let estado = tx.estado()?;
+    let inicio = estado.periodo_atual.start_date;
+    let fim = estado.periodo_seguinte.as_ref().map_or(estado.periodo_atual.end_date, |p| p.end_date.max(estado.periodo_atual.end_date));
+    let no_periodo = |s: &DetalheServico| s.posto == antigo && s.data >= inicio && s.data <= fim;
+
+    for data in inicio.iter_days().take_while(|d| *d <= fim) {
+        let Some(mut dia) = tx.dia(data)? else { continue };
+        if let Some(horarios) = dia.escala.remove(antigo) {
+            dia.escala.insert(novo.to_string(), horarios);
+            tx.guardar_dia(data, &dia)?;
+        }
+    }
+    for mut troca in tx.trocas()? {
+        if !troca.status.pendente() {
+            continue;
+        }
+        let servicos = [&mut troca.requerente, &mut troca.alvo].into_iter().chain(troca.cadeia.iter_mut());
+        let mut mudou = false;
+        for servico in servicos.filter(|s| no_periodo(s)) {
+            servico.posto = novo.to_string();
+            mudou = true;
+        }
+        if mudou {
+            tx.guardar_troca(&troca)?;
+        }
+    }
+    let mut ofertas = tx.ofertas()?;
+    if ofertas.iter().any(|o| no_periodo(&o.servico)) {
+        for oferta in ofertas.iter_mut().filter(|o| no_periodo(&o.servico)) {
+            oferta.servico.posto = novo.to_string();
+        }
+        tx.guardar_ofertas(&ofertas)?;
+    }
+    Ok(())
+}
+
+/// Marca como expiradas as trocas por decidir cujo prazo já passou.
+pub async fn expirar_trocas(store: &dyn EscalaStore) -> AppResult<()> {
+    store
+        .transacao(Box::new(|tx| {
+            let agora = Local::now();
+            for mut troca in tx.trocas()? {
+                if troca.expirou(agora) {
+                    troca.status = StatusTroca::Expirada;
+                    troca.decidida_em = Some(agora);
+                    tx.guardar_troca(&troca)?;
+                }
+            }
+            Ok(())
+        }))
+        .await
+}
+
+/// Os serviços de uma pessoa num dia, como aparecem nas trocas.
+pub fn servicos_no_dia(data: NaiveDate, dia: &EscalaDiaria, user_id: &str) -> Vec<DetalheServico> {
+    let servico = |posto: &str, horario: &str| DetalheServico {
+        data,
+        posto: posto.to_string(),
+        horario: horario.to_string(),
+        user_id: user_id.to_string(),
+    };
+    let mut servicos: Vec<DetalheServico> = dia
+        .escala
+        .iter()
+        .flat_map(|(posto, horarios)| horarios.iter().map(move |(horario, a)| (posto, horario, a)))
+        .filter(|(_, _, a)| a.user_id == user_id)
+        .map(|(posto, horario, _)| servico(posto, horario))
+        .collect();
+    if dia.retem.iter().any(|a| a.user_id == user_id) {
+        servicos.push(servico("RETEM", "SOBREAVISO"));
+    }
+    servicos
+}
+
+/// A alocação de um serviço, se a pessoa indicada ainda lá estiver.
+pub fn alocacao_do_servico<'a>(dia: &'a EscalaDiaria, servico: &DetalheServico) -> Option<&'a Alocacao> {
+    if servico.posto == "RETEM" {
+        dia.retem.iter().find(|a| a.user_id == servico.user_id)
+    } else {
+        dia.escala
+            .get(&servico.posto)
+            .and_then(|h| h.get(&servico.horario))
+            .filter(|a| a.user_id == servico.user_id)
+    }
+}
+
+/// Se `quem` pode fazer o serviço de `substituido`: um posto que o aceite ou, no retém,
+/// uma quota do dia que aceite os dois.
+fn pode_ocupar(quem: &User, substituido: &User, servico: &DetalheServico, tipo_dia: &TipoServico, postos: &[Posto], config: &ConfiguracaoEscala) -> bool {
+    if servico.posto == "RETEM" {
+        config.quotas_do_dia(tipo_dia).iter().any(|q| q.aceita(quem) && q.aceita(substituido))
+    } else {
+        postos.iter().find(|p| p.nome == servico.posto).is_some_and(|p| p.aceita(quem))
+    }
+}
+
+/// O pedido de quem assume uma oferta do quadro: uma cobertura que já conta com o
+/// acordo de quem a publicou, e por isso segue logo para o escalante.
+pub fn cobertura_da_oferta(oferta: &OfertaServico, user_id: &str, agora: DateTime<Local>) -> Troca {
+    let mut troca = Troca {
+        id: Uuid::new_v4().to_string(),
+        tipo: TipoTroca::Cobertura,
+        requerente: DetalheServico {
+            data: NaiveDate::from_ymd_opt(1970, 1, 1).unwrap(),
+            posto: "FOLGA".to_string(),
+            horario: "".to_string(),
+            user_id: user_id.to_string(),
+        },
+        alvo: oferta.servico.clone(),
+        motivo: oferta.motivo.clone(),
+        status: StatusTroca::PendenteAdmin,
+        pedida_em: Some(agora),
+        expira_em: None,
+        decidida_em: None,
+        cadeia: Vec::new(),
+        ofertas: vec![oferta.id.clone()],
+    };
+    troca.expira_em = troca.prazo(None);
+    troca
+}
+
+/// A troca de uma cadeia de ofertas, pela ordem dada. Todas as pessoas aceitaram trocar
+/// ao publicar, por isso segue logo para o escalante. Uma cadeia precisa de pelo menos
+/// duas ofertas; com menos devolve `None`.
+pub fn cadeia_das_ofertas(ofertas: &[&OfertaServico], agora: DateTime<Local>) -> Option<Troca> {
+    if ofertas.len() < 2 {
+        return None;
+    }
+    let cadeia: Vec<DetalheServico> = ofertas.iter().map(|o| o.servico.clone()).collect();
+    let mut troca = Troca {
+        id: Uuid::new_v4().to_string(),
+        tipo: TipoTroca::Cadeia,
+        requerente: cadeia[0].clone(),
+        alvo: cadeia[1].clone(),
+        motivo: ofertas.iter().map(|o| o.motivo.as_str()).collect::<Vec<_>>().join(" / "),
+        status: StatusTroca::PendenteAdmin,
+        pedida_em: Some(agora),
+        expira_em: None,
+        decidida_em: None,
+        cadeia,
+        ofertas: ofertas.iter().map(|o| o.id.clone()).collect(),
+    };
+    troca.expira_em = troca.prazo(None);
+    Some(troca)
+}
+
+/// Lê a escala de um dia, de uma transação ou de um período já carregado.
+pub type LerDia<'a> = dyn FnMut(NaiveDate) -> AppResult<Option<EscalaDiaria>> + 'a;
+
+/// Se a pessoa fica de serviço no dia, na véspera ou no dia seguinte de `data`, sem contar
+/// o serviço que deixa (`saida`).
+fn fadiga(ler_dia: &mut LerDia, user_id: &str, data: NaiveDate, saida: Option<&DetalheServico>) -> AppResult<bool> {
+    for dia in [data - Duration::days(1), data, data + Duration::days(1)] {
+        if let Some(escala_diaria) = ler_dia(dia)? {
+            if servicos_no_dia(dia, &escala_diaria, user_id).iter().any(|s| Some(s) != saida) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Confirma, contra a escala gravada, que uma troca ainda se pode fazer: os serviços
+/// existem e são de quem diz, não são de punição nem passados, cada pessoa pode fazer o
+/// serviço que recebe e ninguém fica de serviço em dias seguidos.
+pub fn validar_troca(tx: &mut dyn EscalaTx, troca: &Troca, users: &HashMap<String, User>) -> AppResult<()> {
+    let postos = tx.postos()?;
+    let config = tx.configuracao()?;
+    verificar_troca(&mut |data| tx.dia(data), troca, users, &postos, &config)
+}
+
+/// `validar_troca` sobre dias lidos de qualquer lado, por exemplo de um período já carregado.
+pub fn verificar_troca(
+    ler_dia: &mut LerDia,
+    troca: &Troca,
+    users: &HashMap<String, User>,
+    postos: &[Posto],
+    config: &ConfiguracaoEscala,
+) -> AppResult<()> {
+    if troca.tipo == TipoTroca::Cadeia {
+        return verificar_cadeia(ler_dia, &troca.cadeia, users, postos, config);
+    }
+    let recusa = |motivo: &'static str| -> AppResult<()> { Err(Recusa(motivo).into()) };
+    let (Some(requerente), Some(alvo)) = (users.get(&troca.requerente.user_id), users.get(&troca.alvo.user_id)) else {
+        return recusa("Utilizador desconhecido.");
+    };
+    if !requerente.ativo || !alvo.ativo {
+        return recusa("Um dos utilizadores está desativado.");
+    }
+    if requerente.id == alvo.id {
+        return recusa("Não pode trocar um serviço consigo próprio.");
+    }
+    let hoje = Local::now().date_naive();
+    if troca.servicos().iter().any(|s| s.data < hoje) {
+        return recusa("Só se trocam serviços que ainda não passaram.");
+    }
+    if troca.tipo == TipoTroca::Permuta && (troca.requerente.posto == "RETEM") != (troca.alvo.posto == "RETEM") {
+        return recusa("Uma permuta troca dois serviços de posto ou dois de retém.");
+    }
+
+    let mut tipos = HashMap::new();
+    for servico in troca.servicos() {
+        let Some(dia) = ler_dia(servico.data)? else {
+            return recusa("O serviço já não está na escala.");
+        };
+        match alocacao_do_servico(&dia, servico) {
+            None => return recusa("O serviço já não está na escala."),
+            Some(a) if a.punicao => return recusa("Não é possível trocar um serviço de punição."),
+            Some(_) => {}
+        }
+        tipos.insert(servico.data, dia.tipo_dia);
+    }
+
+    if !pode_ocupar(requerente, alvo, &troca.alvo, &tipos[&troca.alvo.data], postos, config) {
+        return recusa("Não pode fazer o serviço pedido (ano, género ou função).");
+    }
+    let saida = match troca.tipo {
+        TipoTroca::Permuta => {
+            if !pode_ocupar(alvo, requerente, &troca.requerente, &tipos[&troca.requerente.data], postos, config) {
+                return recusa("A outra pessoa não pode fazer o seu serviço (ano, género ou função).");
+            }
+            if fadiga(ler_dia, &alvo.id, troca.requerente.data, Some(&troca.alvo))? {
+                return recusa("A outra pessoa ficaria de serviço no mesmo dia, na véspera ou no dia seguinte ao seu serviço.");
+            }
+            Some(&troca.requerente)
+        }
+        TipoTroca::Cobertura | TipoTroca::Cadeia => None,
+    };
+    if fadiga(ler_dia, &requerente.id, troca.alvo.data, saida)? {
+        return recusa("Ficaria de serviço no mesmo dia, na véspera ou no dia seguinte ao serviço pedido.");
+    }
+    Ok(())
+}
+
+/// Confirma uma cadeia: os serviços existem, são de pessoas diferentes e todos de posto ou
+/// todos de retém, e cada pessoa pode fazer o serviço seguinte sem ficar em dias seguidos.
+pub fn verificar_cadeia(
+    ler_dia: &mut LerDia,
+    cadeia: &[DetalheServico],
+    users: &HashMap<String, User>,
+    postos: &[Posto],
+    config: &ConfiguracaoEscala,
+) -> AppResult<()> {
+    let recusa = |motivo: &'static str| -> AppResult<()> { Err(Recusa(motivo).into()) };
+    if cadeia.len() < 2 {
+        return recusa("Uma cadeia precisa de pelo menos dois serviços.");
+    }
+    let mut pessoas = Vec::new();
+    for servico in cadeia {
+        match users.get(&servico.user_id) {
+            None => return recusa("Utilizador desconhecido."),
+            Some(u) if !u.ativo => return recusa("Um dos utilizadores está desativado."),
+            Some(u) if pessoas.iter().any(|p: &&User| p.id == u.id) => return recusa("Cada pessoa só pode entrar uma vez numa cadeia."),
+            Some(u) => pessoas.push(u),
+        }
+    }
+    let hoje = Local::now().date_naive();
+    if cadeia.iter().any(|s| s.data < hoje) {
+        return recusa("Só se trocam serviços que ainda não passaram.");
+    }
+    if cadeia.iter().any(|s| (s.posto == "RETEM") != (cadeia[0].posto == "RETEM")) {
+        return recusa("Uma cadeia troca só serviços de posto ou só serviços de retém.");
+    }
+
+    let mut tipos = Vec::new();
+    for servico in cadeia {
+        let Some(dia) = ler_dia(servico.data)? else {
+            return recusa("Um serviço da cadeia já não está na escala.");
+        };
+        match alocacao_do_servico(&dia, servico) {
+            None => return recusa("Um serviço da cadeia já não está na escala."),
+            Some(a) if a.punicao => return recusa("Não é possível trocar um serviço de punição."),
+            Some(_) => {}
+        }
+        tipos.push(dia.tipo_dia);
+    }
+
+    for (i, servico) in cadeia.iter().enumerate() {
+        let seguinte = (i + 1) % cadeia.len();
+        let recebido = &cadeia[seguinte];
+        if !pode_ocupar(pessoas[i], pessoas[seguinte], recebido, &tipos[seguinte], postos, config) {
+            return recusa("Alguém na cadeia não pode fazer o serviço que recebe (ano, género ou função).");
+        }
+        if fadiga(ler_dia, &servico.user_id, recebido.data, Some(servico))? {
+            return recusa("Alguém na cadeia ficaria de serviço no mesmo dia, na véspera ou no dia seguinte ao serviço que recebe.");
+        }
+    }
+    Ok(())
+}
+
+/// Maior número de pessoas numa cadeia proposta pelo quadro de ofertas.
+pub const MAXIMO_CADEIA: usize = 4;
+
+/// Procura cadeias entre as ofertas abertas que aceitam permuta (A fica com o serviço de B,
+/// B com o de C, ..., e o último com o de A). Cada cadeia é a lista dos índices das
+/// ofertas, a começar pela de menor índice, e passa em `verificar_cadeia`.
+pub fn procurar_cadeias(
+    ofertas: &[&OfertaServico],
+    escalas: &BTreeMap<NaiveDate, EscalaDiaria>,
+    users: &HashMap<String, User>,
+    postos: &[Posto],
+    config: &ConfiguracaoEscala,
+    limite: usize,
+) -> Vec<Vec<usize>> {
+    let mut ler_dia = |data: NaiveDate| -> AppResult<Option<EscalaDiaria>> { Ok(escalas.get(&data).cloned()) };
+    let n = ofertas.len();
+    // seguintes[i]: as ofertas cujo serviço a pessoa da oferta i pode fazer, deixando o seu
+    let seguintes: Vec<Vec<usize>> = (0..n)
+        .map(|i| (0..n).filter(|&j| elo_possivel(&ofertas[i].servico, &ofertas[j].servico, escalas, users, postos, config)).collect())
+        .collect();
+
+    let mut cadeias = Vec::new();
+    for inicio in 0..n {
+        let mut caminho = vec![inicio];
+        estender_cadeia(&seguintes, ofertas, &mut caminho, &mut cadeias, limite);
+    }
+    cadeias.retain(|cadeia| {
+        let servicos: Vec<DetalheServico> = cadeia.iter().map(|&i| ofertas[i].servico.clone()).collect();
+        verificar_cadeia(&mut ler_dia, &servicos, users, postos, config).is_ok()
+    });
+    cadeias
+}
+
+/// Se quem tem `dado` pode ficar com `recebido` e deixar o seu.
+fn elo_possivel(
+    dado: &DetalheServico,
+    recebido: &DetalheServico,
+    escalas: &BTreeMap<NaiveDate, EscalaDiaria>,
+    users: &HashMap<String, User>,
+    postos: &[Posto],
+    config: &ConfiguracaoEscala,
+) -> bool {
+    let (Some(quem), Some(substituido), Some(dia)) = (users.get(&dado.user_id), users.get(&recebido.user_id), escalas.get(&recebido.data)) else {
+        return false;
+    };
+    let mut ler_dia = |data: NaiveDate| -> AppResult<Option<EscalaDiaria>> { Ok(escalas.get(&data).cloned()) };
+    quem.id != substituido.id
+        && (dado.posto == "RETEM") == (recebido.posto == "RETEM")
+        && pode_ocupar(quem, substituido, recebido, &dia.tipo_dia, postos, config)
+        && !fadiga(&mut ler_dia, &quem.id, recebido.data, Some(dado)).unwrap_or(true)
+}
+
+/// Procura em profundidade os ciclos que voltam ao início do caminho. Só se seguem ofertas
+/// de índice maior que o do início, para cada cadeia aparecer uma só vez.
+fn estender_cadeia(seguintes: &[Vec<usize>], ofertas: &[&OfertaServico], caminho: &mut Vec<usize>, cadeias: &mut Vec<Vec<usize>>, limite: usize) {
+    let (inicio, atual) = (caminho[0], caminho[caminho.len() - 1]);
+    for &proxima in &seguintes[atual] {
+        if cadeias.len() >= limite {
+            return;
+        }
+        if proxima == inicio && caminho.len() >= 2 {
+            cadeias.push(caminho.clone());
+        } else if proxima > inicio
+            && caminho.len() < MAXIMO_CADEIA
+            && !caminho.iter().any(|&i| ofertas[i].servico.user_id == ofertas[proxima].servico.user_id)
+        {
+            caminho.push(proxima);
+            estender_cadeia(seguintes, ofertas, caminho, cadeias, limite);
+            caminho.pop();
+        }
+    }
+}
 
-// --- CONSTANTES DE FICHEIROS ---
-pub const ESCALA_DATA_DIR: &str = "data/escala";
-const ESTADO_FILE: &str = "data/escala/estado.json";
-const POSTOS_FILE: &str = "data/escala/postos.json";
-const CONTAGEM_FILE: &str = "data/escala/contagem.json";
-const DIVIDAS_FILE: &str = "data/escala/dividas.json";
-const TROCAS_FILE: &str = "data/escala/trocas.json";
-const USERS_FILE: &str = "users.json";
-const INDISPONIBILIDADE_FILE: &str = "data/escala/indisponibilidade.json";
-const PUNIDOS_FILE: &str = "data/escala/punidos.json";
-const CONFIGURACAO_FILE: &str = "data/escala/configuracao.json";
 
 
 // --- LÓGICA PRINCIPAL DO ALGORITMO ---
+// A geração é feita em dois passos: `gerar_proposta` calcula tudo sem gravar nada, e
+// `gravar_proposta` grava o resultado de uma só vez, depois de o admin o ter revisto.
+
+/// Algoritmo usado para gerar a escala, escolhido em cada geração.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum MotorGeracao {
+    /// Preenche as vagas uma a uma, pela ordem das contagens, sem voltar atrás.
+    #[default]
+    Guloso,
+    /// Procura a distribuição mais justa de todo o período (`escala_solver`).
+    Otimizado,
+}
+
+pub fn e_dia_especial(tipo: &TipoServico) -> bool {
+    matches!(tipo, TipoServico::RD | TipoServico::UDRD | TipoServico::ER)
+}
+
+/// Primeiros dias de cada bloco de dias especiais seguidos: nesses dias não se cumpre o
+/// último serviço de uma punição, que fica para o dia seguinte do bloco.
+pub fn dias_para_adiar(dias_ordenados: &[(NaiveDate, TipoServico)]) -> HashSet<NaiveDate> {
+    let mut dias = HashSet::new();
+    for i in 0..dias_ordenados.len() {
+        if e_dia_especial(&dias_ordenados[i].1) {
+            let anterior_especial = i > 0 && e_dia_especial(&dias_ordenados[i - 1].1);
+            let seguinte_especial = i + 1 < dias_ordenados.len() && e_dia_especial(&dias_ordenados[i + 1].1);
+            if !anterior_especial && seguinte_especial {
+                dias.insert(dias_ordenados[i].0);
+            }
+        }
+    }
+    dias
+}
+
+/// Os dados guardados de que a geração precisa.
+pub struct EntradasGeracao {
+    pub postos: Vec<Posto>,
+    pub contagem: Contagem,
+    pub dividas: DividasAtivas,
+    pub punicoes: Vec<Punicao>,
+    pub configuracao: ConfiguracaoEscala,
+    pub indisponibilidades: Vec<Indisponibilidade>,
+    /// Quem está de serviço, nos postos ou no retém, na véspera do primeiro dia a gerar
+    /// (normalmente o último dia do período em vigor). Não pode ficar logo de serviço.
+    pub de_servico_na_vespera: Vec<String>,
+}
+
+impl EntradasGeracao {
+    /// Carrega as entradas para uma geração que começa em `inicio`.
+    pub async fn carregar(store: &dyn EscalaStore, inicio: NaiveDate) -> AppResult<Self> {
+        let vespera = store.dia(inicio - Duration::days(1)).await?;
+        Ok(Self {
+            postos: store.postos().await?.into_iter().filter(|p| p.ativo).collect(),
+            contagem: store.contagem().await?,
+            dividas: store.dividas().await?,
+            punicoes: store.punicoes().await?,
+            configuracao: store.configuracao().await?,
+            // Os pedidos por decidir e os rejeitados não contam
+            indisponibilidades: store
+                .indisponibilidades()
+                .await?
+                .into_iter()
+                .filter(|i| i.estado == EstadoIndisponibilidade::Aprovada)
+                .collect(),
+            de_servico_na_vespera: vespera
+                .map(|dia| dia.escala.values().flat_map(|h| h.values()).chain(&dia.retem).map(|a| a.user_id.clone()).collect())
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Confirma, antes de gerar, que há pessoas para as quotas do retém de cada dia: ativas,
+    /// disponíveis nesse dia e sem punições. Devolve um problema por quota em falta, com os
+    /// dias em que falta.
+    pub fn verificar_quotas_retem(&self, utilizadores: &[User], dias: &HashMap<NaiveDate, TipoServico>) -> Vec<String> {
+        let punidos: HashSet<&str> = self.punicoes.iter().filter(|p| p.ativa()).map(|p| p.user_id.as_str()).collect();
+        let mut faltas: BTreeMap<String, Vec<NaiveDate>> = BTreeMap::new();
+        for (data, tipo) in dias {
+            let disponiveis: Vec<&User> = utilizadores
+                .iter()
+                .filter(|u| u.ativo && !punidos.contains(u.id.as_str()))
+                .filter(|u| !self.indisponibilidades.iter().any(|i| i.user_id == u.id && i.abrange(*data)))
+                .collect();
+            let quotas = self.configuracao.quotas_do_dia(tipo);
+            for quota in &quotas {
+                let possiveis = disponiveis.iter().filter(|u| quota.aceita(u)).count();
+                if possiveis < quota.quantidade {
+                    let falta = format!("O retém pede {}, mas só há {} pessoa(s) que o podem fazer", quota.descrever(), possiveis);
+                    faltas.entry(falta).or_default().push(*data);
+                }
+            }
+            // Quotas do mesmo ano (por exemplo, por género) partilham as mesmas pessoas
+            let anos: BTreeSet<u8> = quotas.iter().map(|q| q.ano).collect();
+            for ano in anos {
+                let do_ano: Vec<&&QuotaRetem> = quotas.iter().filter(|q| q.ano == ano).collect();
+                let pedidas: usize = do_ano.iter().map(|q| q.quantidade).sum();
+                let possiveis = disponiveis.iter().filter(|u| do_ano.iter().any(|q| q.aceita(u))).count();
+                if do_ano.len() > 1 && possiveis < pedidas {
+                    let falta = format!("O retém pede {} pessoas do {}º ano, mas só há {} que o podem fazer", pedidas, ano, possiveis);
+                    faltas.entry(falta).or_default().push(*data);
+                }
+            }
+        }
+        faltas
+            .into_iter()
+            .map(|(falta, mut datas)| {
+                datas.sort();
+                let datas: Vec<String> = datas.iter().map(|d| d.format("%d/%m/%Y").to_string()).collect();
+                format!("{} ({}).", falta, datas.join(", "))
+            })
+            .collect()
+    }
+}
+
+/// Uma escala gerada e ainda por gravar: os dias e o estado final das contagens, dívidas
+/// e punições, com os valores de partida para comparação.
+/// Guarda também as entradas usadas, para o histórico das gerações.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PropostaEscala {
+    pub periodo: Periodo,
+    #[serde(default)]
+    pub motor: MotorGeracao,
+    /// O tipo de rotina pedido para cada dia.
+    pub tipos_dia: BTreeMap<NaiveDate, TipoServico>,
+    pub postos: Vec<Posto>,
+    pub configuracao: ConfiguracaoEscala,
+    pub indisponibilidades: Vec<Indisponibilidade>,
+    pub dias: BTreeMap<NaiveDate, EscalaDiaria>,
+    pub contagem: Contagem,
+    pub dividas: DividasAtivas,
+    pub punicoes: Vec<Punicao>,
+    pub contagem_antes: Contagem,
+    pub dividas_antes: DividasAtivas,
+    pub punicoes_antes: Vec<Punicao>,
+}
+
+/// A proposta que aguarda a revisão do admin. O `id` garante que é gravada a mesma
+/// proposta que foi pré-visualizada.
+#[derive(Debug, Clone)]
+pub struct PropostaPendente {
+    pub id: String,
+    pub gerada_por: String,
+    pub proposta: PropostaEscala,
+}
+
+/// Serviços de uma pessoa dentro de uma proposta.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServicosNoPeriodo {
+    pub normais: u32,
+    pub retem: u32,
+    pub punicao: u32,
+}
 
-pub async fn gerar_nova_escala(
+impl ServicosNoPeriodo {
+    pub fn total(&self) -> u32 {
+        self.normais + self.retem + self.punicao
+    }
+}
+
+/// Em que ponto está uma geração gravada.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EstadoExecucao {
+    /// Gravada como período seguinte; ainda pode ser revertida.
+    Gravada,
+    Lancada,
+    Revertida,
+}
+
+/// Uma versão do histórico das gerações: a proposta gravada (entradas, resultado e
+/// contagens antes e depois) e o que ela substituiu, para poder ser desfeita.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExecucaoEscala {
+    pub versao: u32,
+    pub gerada_por: String,
+    pub gravada_em: DateTime<Local>,
+    pub estado: EstadoExecucao,
+    pub lancada_em: Option<DateTime<Local>>,
+    pub revertida_em: Option<DateTime<Local>>,
+    pub proposta: PropostaEscala,
+    /// As escalas que já existiam nos dias gerados.
+    pub dias_substituidos: BTreeMap<NaiveDate, EscalaDiaria>,
+    /// As trocas por decidir que expiraram ao gravar, como estavam antes. As versões antigas
+    /// guardavam aqui todas as trocas, que eram apagadas.
+    #[serde(alias = "trocas_apagadas")]
+    pub trocas_expiradas: Vec<Troca>,
+    pub periodo_seguinte_antes: Option<Periodo>,
+}
+
+impl PropostaEscala {
+    /// Quantos serviços cada pessoa faz no período proposto.
+    pub fn servicos_por_utilizador(&self) -> HashMap<String, ServicosNoPeriodo> {
+        let mut servicos: HashMap<String, ServicosNoPeriodo> = HashMap::new();
+        for escala_diaria in self.dias.values() {
+            for alocacao in escala_diaria.escala.values().flat_map(|horarios| horarios.values()) {
+                let entrada = servicos.entry(alocacao.user_id.clone()).or_default();
+                if alocacao.punicao {
+                    entrada.punicao += 1;
+                } else {
+                    entrada.normais += 1;
+                }
+            }
+            for alocacao in &escala_diaria.retem {
+                servicos.entry(alocacao.user_id.clone()).or_default().retem += 1;
+            }
+        }
+        servicos
+    }
+
+    /// Número de dívidas de serviço pagas dentro do período.
+    pub fn dividas_pagas(&self) -> usize {
+        let contar = |d: &DividasAtivas| d.values().map(Vec::len).sum::<usize>();
+        contar(&self.dividas_antes).saturating_sub(contar(&self.dividas))
+    }
+}
+
+/// Gera a escala dos dias indicados sem gravar nada, com o motor escolhido.
+pub fn gerar_proposta(
+    entradas: EntradasGeracao,
+    todos_utilizadores: Vec<User>,
+    periodo: Periodo,
     dias_da_escala: HashMap<NaiveDate, TipoServico>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    
-    fs::write(TROCAS_FILE, "[]").await?;
-
-    // Carregamento de todos os dados necessários
-    let todos_utilizadores: Vec<User> = serde_json::from_str(&fs::read_to_string(USERS_FILE).await?)?;
-    let todos_postos: Vec<Posto> = serde_json::from_str(&fs::read_to_string(POSTOS_FILE).await?)?;
-    let mut contagens: Contagem = serde_json::from_str(&fs::read_to_string(CONTAGEM_FILE).await?)?;
-    let mut dividas: DividasAtivas = serde_json::from_str(&fs::read_to_string(DIVIDAS_FILE).await?)?;
-    let mut punicoes: Vec<Punicao> = serde_json::from_str(&fs::read_to_string(PUNIDOS_FILE).await?)?;
-    let config_escala: ConfiguracaoEscala = serde_json::from_str(&fs::read_to_string(CONFIGURACAO_FILE).await?)?;
-    let indisponibilidades_content = fs::read_to_string(INDISPONIBILIDADE_FILE).await?;
-    let todas_as_indisponibilidades: Vec<Indisponibilidade> = serde_json::from_str(&indisponibilidades_content)?;
-    
-    // Preparação das variáveis de estado do algoritmo
-    let utilizadores_indisponiveis: Vec<String> = todas_as_indisponibilidades
-        .into_iter()
-        .map(|i| i.user_id)
-        .collect();
+    motor: MotorGeracao,
+) -> AppResult<PropostaEscala> {
+    let faltas = entradas.verificar_quotas_retem(&todos_utilizadores, &dias_da_escala);
+    if !faltas.is_empty() {
+        return Err(crate::escala_solver::SemSolucao { conflitos: faltas }.into());
+    }
+    if motor == MotorGeracao::Otimizado {
+        return crate::escala_solver::gerar_proposta(entradas, todos_utilizadores, periodo, dias_da_escala);
+    }
+    // Quem foi desativado não entra na escala
+    let todos_utilizadores: Vec<User> = todos_utilizadores.into_iter().filter(|u| u.ativo).collect();
+
+    let EntradasGeracao {
+        postos: todos_postos,
+        contagem: mut contagens,
+        mut dividas,
+        mut punicoes,
+        configuracao: config_escala,
+        indisponibilidades: todas_as_indisponibilidades,
+        de_servico_na_vespera,
+    } = entradas;
+    let contagem_antes = contagens.clone();
+    let dividas_antes = dividas.clone();
+    let punicoes_antes = punicoes.clone();
+    let postos_usados = todos_postos.clone();
+    let configuracao_usada = config_escala.clone();
+    let indisponibilidades_usadas = todas_as_indisponibilidades.clone();
+    let tipos_dia: BTreeMap<NaiveDate, TipoServico> = dias_da_escala.iter().map(|(d, t)| (*d, t.clone())).collect();
     
-    let mut dividas_pagas: Vec<(String, Divida)> = Vec::new();
-    let mut utilizadores_fadigados: Vec<String> = Vec::new();
+    // Preparação das variáveis de estado do algoritmo; no primeiro dia, quem estava de
+    // serviço na véspera já está fadigado
+    let mut utilizadores_fadigados: Vec<String> = de_servico_na_vespera;
 
     let mut dias_ordenados: Vec<_> = dias_da_escala.into_iter().collect();
     dias_ordenados.sort_by_key(|k| k.0);
 
     // Lógica para adiar punições
-    let mut dias_para_adiar = HashMap::new();
-    for i in 0..dias_ordenados.len() {
-        let is_dia_atual_especial = matches!(dias_ordenados[i].1, TipoServico::RD | TipoServico::UDRD | TipoServico::ER);
-        if is_dia_atual_especial {
-            let is_dia_anterior_especial = if i > 0 { matches!(dias_ordenados[i-1].1, TipoServico::RD | TipoServico::UDRD | TipoServico::ER) } else { false };
-            let is_dia_seguinte_especial = if i + 1 < dias_ordenados.len() { matches!(dias_ordenados[i+1].1, TipoServico::RD | TipoServico::UDRD | TipoServico::ER) } else { false };
-            if !is_dia_anterior_especial && is_dia_seguinte_especial {
-                 dias_para_adiar.insert(dias_ordenados[i].0, true);
-            }
-        }
-    }
+    let dias_para_adiar = dias_para_adiar(&dias_ordenados);
 
     // Loop principal para gerar a escala de cada dia
+    let mut escalas_geradas: Vec<(NaiveDate, EscalaDiaria)> = Vec::new();
     for (data, tipo_dia) in &dias_ordenados {
         let mut escala_do_dia: HashMap<String, HashMap<String, Alocacao>> = HashMap::new();
         let mut utilizadores_ja_alocados_hoje: Vec<String> = Vec::new();
 
         let mut exclusao_hoje = utilizadores_fadigados.clone();
-        exclusao_hoje.extend(utilizadores_indisponiveis.clone());
+        exclusao_hoje.extend(
+            todas_as_indisponibilidades.iter().filter(|i| i.abrange(*data)).map(|i| i.user_id.clone()),
+        );
 
         let (tipo_contagem, ordem_decrescente) = match tipo_dia {
             TipoServico::RN => (TipoServico::RN, false),
@@ -254,12 +1400,11 @@ pub async fn gerar_nova_escala(
                 if matches!(tipo_dia, TipoServico::RD | TipoServico::UDRD | TipoServico::ER) 
                    && config_escala.postos_punicao.contains(&posto.nome) {
                     
-                    let adiar_neste_dia = *dias_para_adiar.get(data).unwrap_or(&false);
+                    let adiar_neste_dia = dias_para_adiar.contains(data);
 
-                    for punido in punicoes.iter_mut().filter(|p| p.ja_cumpridos < p.total_a_cumprir) {
+                    for punido in punicoes.iter_mut().filter(|p| p.ativa()) {
                         
-                        let servicos_restantes = punido.total_a_cumprir - punido.ja_cumpridos;
-                        if servicos_restantes == 1 && adiar_neste_dia {
+                        if punido.restantes() == 1 && adiar_neste_dia {
                             continue;
                         }
 
@@ -281,10 +1426,9 @@ pub async fn gerar_nova_escala(
                                 }
                                 alocacao_final = Some(Alocacao {
                                     user_id: user_punido.id.clone(),
-                                    nome: format!("{} ({}/{})", user_punido.name, punido.ja_cumpridos + 1, punido.total_a_cumprir),
+                                    nome: punido.cumprir(&user_punido.name, *data, &posto.nome, horario),
                                     punicao: true,
                                 });
-                                punido.ja_cumpridos += 1;
                                 break; 
                             }
                         }
@@ -301,11 +1445,15 @@ pub async fn gerar_nova_escala(
                         let mut pessoa_alocada: Option<User> = Some(candidato_justo.clone());
                         let mut divida_foi_paga = false;
 
-                        if let Some(lista_dividas) = dividas.get(candidato_justo.id.as_str()) {
-                            for divida in lista_dividas {
+                        // Quem deve um serviço ao candidato (a chave das dívidas) fá-lo por ele
+                        let mut devedores: Vec<&String> = dividas.keys().collect();
+                        devedores.sort();
+                        let mut paga: Option<(String, usize)> = None;
+                        'dividas: for devedor_id in devedores {
+                            for (posicao, divida) in dividas[devedor_id].iter().enumerate().filter(|(_, d)| d.credor == candidato_justo.id) {
                                 let divida_e_compativel = matches!((&divida.tipo_divida, &tipo_contagem), (TipoServico::RN, TipoServico::RN) | (_, TipoServico::RD));
                                 if divida_e_compativel {
-                                    if let Some(devedor) = todos_utilizadores.iter().find(|u| u.id == divida.credor) {
+                                    if let Some(devedor) = todos_utilizadores.iter().find(|u| &u.id == devedor_id) {
                                         let devedor_e_elegivel = !exclusao_hoje.contains(&devedor.id) && !utilizadores_ja_alocados_hoje.contains(&devedor.id) &&
                                                                  posto.turmas_permitidas.contains(&devedor.ano) &&
                                                                  (match &posto.funcao_exclusiva { Some(f) => devedor.roles.contains(f), None => true }) &&
@@ -316,14 +1464,23 @@ pub async fn gerar_nova_escala(
                                                                  });
                                         if devedor_e_elegivel {
                                             pessoa_alocada = Some(devedor.clone());
-                                            dividas_pagas.push((candidato_justo.id.clone(), divida.clone()));
-                                            divida_foi_paga = true;
-                                            break;
+                                            paga = Some((devedor.id.clone(), posicao));
+                                            break 'dividas;
                                         }
                                     }
                                 }
                             }
                         }
+                        // A dívida sai logo da lista, para não ser paga duas vezes no período
+                        if let Some((devedor_id, posicao)) = paga {
+                            if let Some(lista_dividas) = dividas.get_mut(&devedor_id) {
+                                lista_dividas.remove(posicao);
+                                if lista_dividas.is_empty() {
+                                    dividas.remove(&devedor_id);
+                                }
+                            }
+                            divida_foi_paga = true;
+                        }
                         
                         if let Some(alocado) = pessoa_alocada {
                              alocacao_final = Some(Alocacao {
@@ -356,16 +1513,14 @@ pub async fn gerar_nova_escala(
 
         // --- GERAÇÃO DA EQUIPA DE RETÉM ---
         let mut equipe_retem: Vec<Alocacao> = Vec::new();
-        let ids_punidos: Vec<String> = punicoes.iter().map(|p| p.user_id.clone()).collect();
+        let ids_punidos: Vec<String> = punicoes.iter().filter(|p| p.ativa()).map(|p| p.user_id.clone()).collect();
         let mut exclusao_retem = exclusao_hoje.clone();
         exclusao_retem.extend(ids_punidos);
 
-        let quotas_retem = [(3, 2), (2, 2), (1, 4)]; // (Ano, Quantidade)
-
-        for (ano, quantidade) in quotas_retem {
+        for quota in config_escala.quotas_do_dia(tipo_dia) {
             let mut candidatos_retem: Vec<&User> = todos_utilizadores
                 .iter()
-                .filter(|u| u.ano == ano)
+                .filter(|u| quota.aceita(u))
                 .filter(|u| !exclusao_retem.contains(&u.id))
                 .filter(|u| !utilizadores_ja_alocados_hoje.contains(&u.id))
                 .collect();
@@ -380,7 +1535,7 @@ pub async fn gerar_nova_escala(
                 })
             });
 
-            for candidato in candidatos_retem.iter().take(quantidade) {
+            for candidato in candidatos_retem.iter().take(quota.quantidade) {
                 let alocacao = Alocacao {
                     user_id: candidato.id.clone(),
                     nome: candidato.name.clone(),
@@ -394,75 +1549,376 @@ pub async fn gerar_nova_escala(
 
         utilizadores_fadigados = utilizadores_ja_alocados_hoje;
 
-        let json_diario = serde_json::to_string_pretty(&EscalaDiaria { 
-            tipo_dia: tipo_dia.clone(), 
+        escalas_geradas.push((*data, EscalaDiaria {
+            tipo_dia: tipo_dia.clone(),
             escala: escala_do_dia,
             retem: equipe_retem,
-        })?;
-        let filename = format!("{}/{}.json", ESCALA_DATA_DIR, data.format("%Y-%m-%d"));
-        fs::write(filename, json_diario).await?;
+        }));
     }
 
-    // Salvar o estado final dos ficheiros de contagem, dívidas e punições
-    for (credor_id, divida_paga) in dividas_pagas {
-        if let Some(lista_dividas) = dividas.get_mut(&credor_id) {
-            if let Some(pos) = lista_dividas.iter().position(|d| d == &divida_paga) {
-                lista_dividas.remove(pos);
+    Ok(PropostaEscala {
+        periodo,
+        motor: MotorGeracao::Guloso,
+        tipos_dia,
+        postos: postos_usados,
+        configuracao: configuracao_usada,
+        indisponibilidades: indisponibilidades_usadas,
+        dias: escalas_geradas.into_iter().collect(),
+        contagem: contagens,
+        dividas,
+        punicoes,
+        contagem_antes,
+        dividas_antes,
+        punicoes_antes,
+    })
+}
+
+/// Grava uma proposta numa só transação: as escalas do período, as contagens, as dívidas,
+/// as punições e o período seguinte. As trocas do período anterior deixam de fazer sentido
+/// e são apagadas. Se as contagens, dívidas ou punições mudaram desde a geração, nada é gravado.
+/// Na mesma transação fica registada uma nova versão no histórico das gerações, com o que
+/// é preciso para a desfazer enquanto não for lançada.
+pub async fn gravar_proposta(store: &dyn EscalaStore, proposta: PropostaEscala, gerada_por: String) -> AppResult<()> {
+    store
+        .transacao(Box::new(move |tx| {
+            if tx.contagem()? != proposta.contagem_antes
+                || tx.dividas()? != proposta.dividas_antes
+                || tx.punicoes()? != proposta.punicoes_antes
+            {
+                return Err(Recusa("As contagens, dívidas ou punições mudaram desde a geração. Gere a escala de novo.").into());
             }
-            if lista_dividas.is_empty() {
-                dividas.remove(&credor_id);
+            let mut dias_substituidos = BTreeMap::new();
+            for data in proposta.dias.keys() {
+                if let Some(existente) = tx.dia(*data)? {
+                    dias_substituidos.insert(*data, existente);
+                }
             }
-        }
-    }
-    fs::write(CONTAGEM_FILE, serde_json::to_string_pretty(&contagens)?).await?;
-    fs::write(DIVIDAS_FILE, serde_json::to_string_pretty(&dividas)?).await?;
-    fs::write(PUNIDOS_FILE, serde_json::to_string_pretty(&punicoes)?).await?;
-    
+            let mut estado = tx.estado()?;
+            let periodo_seguinte_antes = estado.periodo_seguinte.clone();
+
+            // As trocas ficam como histórico; só expiram as por decidir dos dias gerados de novo
+            let mut trocas_expiradas = Vec::new();
+            let agora = Local::now();
+            for troca in tx.trocas()? {
+                if troca.status.pendente() && troca.servicos().iter().any(|s| proposta.dias.contains_key(&s.data)) {
+                    let mut expirada = troca.clone();
+                    expirada.status = StatusTroca::Expirada;
+                    expirada.decidida_em = Some(agora);
+                    tx.guardar_troca(&expirada)?;
+                    trocas_expiradas.push(troca);
+                }
+            }
+            for (data, escala_diaria) in &proposta.dias {
+                tx.guardar_dia(*data, escala_diaria)?;
+            }
+            tx.guardar_contagem(&proposta.contagem)?;
+            tx.guardar_dividas(&proposta.dividas)?;
+            tx.guardar_punicoes(&proposta.punicoes)?;
+            estado.periodo_seguinte = Some(proposta.periodo.clone());
+            tx.guardar_estado(&estado)?;
+
+            let versao = tx.execucoes()?.iter().map(|e| e.versao).max().unwrap_or(0) + 1;
+            println!("🗂️ Geração gravada como versão {}.", versao);
+            tx.guardar_execucao(&ExecucaoEscala {
+                versao,
+                gerada_por,
+                gravada_em: Local::now(),
+                estado: EstadoExecucao::Gravada,
+                lancada_em: None,
+                revertida_em: None,
+                proposta,
+                dias_substituidos,
+                trocas_expiradas,
+                periodo_seguinte_antes,
+            })
+        }))
+        .await?;
+
     println!("Processo de geração de escala concluído com sucesso!");
     Ok(())
 }
 
+/// Torna o período seguinte no período atual e marca a geração correspondente como lançada.
+/// A partir daqui essa geração já não pode ser revertida.
+pub async fn lancar_periodo_seguinte(store: &dyn EscalaStore) -> AppResult<()> {
+    store
+        .transacao(Box::new(|tx| {
+            let mut estado = tx.estado()?;
+            let Some(periodo_seguinte) = estado.periodo_seguinte.take() else { return Ok(()) };
+            let execucao = tx
+                .execucoes()?
+                .into_iter()
+                .filter(|e| e.estado == EstadoExecucao::Gravada && e.proposta.periodo == periodo_seguinte)
+                .max_by_key(|e| e.versao);
+            if let Some(mut execucao) = execucao {
+                execucao.estado = EstadoExecucao::Lancada;
+                execucao.lancada_em = Some(Local::now());
+                tx.guardar_execucao(&execucao)?;
+            }
+            println!(
+                "✅ Nova escala de {} a {} foi lançada com sucesso.",
+                periodo_seguinte.start_date, periodo_seguinte.end_date
+            );
+            estado.periodo_atual = periodo_seguinte;
+            estado.status_trocas = "Fechado".to_string();
+            tx.guardar_estado(&estado)
+        }))
+        .await
+}
+
+/// Desfaz uma geração ainda não lançada, deixando tudo como estava antes de ser gravada:
+/// a contagem, as dívidas e as punições voltam exatamente aos valores de partida, os dias
+/// gerados voltam ao que eram (ou deixam de existir) e as trocas que expiraram ao gravar
+/// voltam a estar por decidir.
+/// Só a geração gravada mais recente pode ser revertida, e apenas se as contagens,
+/// dívidas e punições não mudaram entretanto.
+pub async fn reverter_execucao(store: &dyn EscalaStore, versao: u32) -> AppResult<()> {
+    store
+        .transacao(Box::new(move |tx| {
+            let execucoes = tx.execucoes()?;
+            let Some(mut execucao) = execucoes.iter().find(|e| e.versao == versao).cloned() else {
+                return Err(Recusa("Essa geração não existe.").into());
+            };
+            match execucao.estado {
+                EstadoExecucao::Lancada => return Err(Recusa("Essa geração já foi lançada e não pode ser revertida.").into()),
+                EstadoExecucao::Revertida => return Err(Recusa("Essa geração já foi revertida.").into()),
+                EstadoExecucao::Gravada => {}
+            }
+            if execucoes.iter().any(|e| e.versao > versao && e.estado != EstadoExecucao::Revertida) {
+                return Err(Recusa("Há gerações mais recentes; só a última pode ser revertida.").into());
+            }
+            let proposta = &execucao.proposta;
+            if tx.contagem()? != proposta.contagem
+                || tx.dividas()? != proposta.dividas
+                || tx.punicoes()? != proposta.punicoes
+            {
+                return Err(Recusa("As contagens, dívidas ou punições mudaram depois desta geração. Não é possível revertê-la sem perder essas alterações.").into());
+            }
+
+            tx.guardar_contagem(&proposta.contagem_antes)?;
+            tx.guardar_dividas(&proposta.dividas_antes)?;
+            tx.guardar_punicoes(&proposta.punicoes_antes)?;
+            for data in proposta.dias.keys() {
+                match execucao.dias_substituidos.get(data) {
+                    Some(anterior) => tx.guardar_dia(*data, anterior)?,
+                    None => tx.apagar_dia(*data)?,
+                }
+            }
+            for troca in &execucao.trocas_expiradas {
+                tx.guardar_troca(troca)?;
+            }
+            let mut estado = tx.estado()?;
+            estado.periodo_seguinte = execucao.periodo_seguinte_antes.clone();
+            tx.guardar_estado(&estado)?;
+
+            execucao.estado = EstadoExecucao::Revertida;
+            execucao.revertida_em = Some(Local::now());
+            tx.guardar_execucao(&execucao)
+        }))
+        .await
+}
+
 
-// --- LÓGICA DE CRIAÇÃO DE FICHEIROS ---
-pub async fn ensure_escala_structure() {
-    if let Err(e) = fs::create_dir_all(ESCALA_DATA_DIR).await {
-        eprintln!("🔥 Falha crítica ao criar o diretório '{}': {}", ESCALA_DATA_DIR, e);
-        return;
+// --- DADOS INICIAIS ---
+pub async fn ensure_escala_structure(store: &dyn EscalaStore) {
+    // O estado em falta é lido como o padrão; gravá-lo deixa-o visível para quem edita os dados à mão.
+    let resultado = store
+        .transacao(Box::new(|tx| {
+            let estado = tx.estado()?;
+            tx.guardar_estado(&estado)
+        }))
+        .await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha crítica ao criar o estado da escala: {}", e);
     }
-    
-    if fs::try_exists(ESTADO_FILE).await.unwrap_or(false) == false {
-        let default_estado = EstadoEscala {
-            periodo_atual: Periodo {
-                start_date: NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(),
-                end_date: NaiveDate::from_ymd_opt(2025, 1, 7).unwrap(),
-            },
-            periodo_seguinte: None,
-            status_trocas: "Fechado".to_string(),
-        };
-        if let Ok(json) = serde_json::to_string_pretty(&default_estado) {
-            let _ = fs::write(ESTADO_FILE, json).await;
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::testes::{data, dia, futuro, mapa, posto, utilizador};
+
+    struct Quadro {
+        escalas: BTreeMap<NaiveDate, EscalaDiaria>,
+        users: HashMap<String, User>,
+        postos: Vec<Posto>,
+        config: ConfiguracaoEscala,
+    }
+
+    /// 1001 e 1002 (1º ano) e 2001 (2º ano) de serviço no posto P em dias afastados;
+    /// o posto Q só aceita o 2º ano.
+    fn quadro() -> Quadro {
+        let escalas = [
+            (futuro(10), dia(TipoServico::RN, &[("P", "08-12", "1001")], &[])),
+            (futuro(20), dia(TipoServico::RN, &[("P", "08-12", "1002")], &[])),
+            (futuro(30), dia(TipoServico::RN, &[("P", "08-12", "1003")], &[])),
+            (futuro(40), dia(TipoServico::RN, &[("Q", "08-12", "2001")], &[])),
+        ]
+        .into_iter()
+        .collect();
+        Quadro {
+            escalas,
+            users: mapa(&[
+                utilizador("1001", 1, Genero::Masculino),
+                utilizador("1002", 1, Genero::Masculino),
+                utilizador("1003", 1, Genero::Masculino),
+                utilizador("2001", 2, Genero::Masculino),
+            ]),
+            postos: vec![posto("P", &[1], &["08-12"]), posto("Q", &[2], &["08-12"])],
+            config: ConfiguracaoEscala::default(),
         }
     }
 
-    if fs::try_exists(POSTOS_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(POSTOS_FILE, "[]").await;
+    fn servico(dias: i64, posto: &str, user_id: &str) -> DetalheServico {
+        DetalheServico { data: futuro(dias), posto: posto.to_string(), horario: "08-12".to_string(), user_id: user_id.to_string() }
     }
-    if fs::try_exists(CONTAGEM_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(CONTAGEM_FILE, "{}").await;
+
+    fn oferta(servico: DetalheServico) -> OfertaServico {
+        OfertaServico {
+            id: format!("oferta-{}", servico.user_id),
+            servico,
+            motivo: "Viagem".to_string(),
+            aceita_permuta: true,
+            criada_em: Local::now(),
+            retirada_em: None,
+        }
+    }
+
+    impl Quadro {
+        fn verificar(&self, cadeia: &[DetalheServico]) -> Result<(), String> {
+            let mut ler_dia = |data: NaiveDate| -> AppResult<Option<EscalaDiaria>> { Ok(self.escalas.get(&data).cloned()) };
+            verificar_cadeia(&mut ler_dia, cadeia, &self.users, &self.postos, &self.config).map_err(|e| e.to_string())
+        }
+
+        fn cadeias(&self, ofertas: &[OfertaServico]) -> Vec<Vec<usize>> {
+            let ofertas: Vec<&OfertaServico> = ofertas.iter().collect();
+            procurar_cadeias(&ofertas, &self.escalas, &self.users, &self.postos, &self.config, 10)
+        }
+    }
+
+    #[test]
+    fn cadeia_valida() {
+        let quadro = quadro();
+        let cadeia = [servico(10, "P", "1001"), servico(20, "P", "1002"), servico(30, "P", "1003")];
+        assert_eq!(quadro.verificar(&cadeia), Ok(()));
+    }
+
+    #[test]
+    fn cadeias_recusadas() {
+        let mut quadro = quadro();
+        let erro = |quadro: &Quadro, cadeia: &[DetalheServico]| quadro.verificar(cadeia).unwrap_err();
+
+        assert!(erro(&quadro, &[servico(10, "P", "1001")]).contains("pelo menos dois"));
+        assert!(erro(&quadro, &[servico(10, "P", "1001"), servico(10, "P", "1001")]).contains("uma vez"));
+        // O 1º ano não pode fazer o posto Q
+        assert!(erro(&quadro, &[servico(10, "P", "1001"), servico(40, "Q", "2001")]).contains("não pode fazer"));
+        // O serviço já não é de quem diz
+        assert!(erro(&quadro, &[servico(10, "P", "1002"), servico(20, "P", "1001")]).contains("já não está na escala"));
+
+        let punido = quadro.escalas.get_mut(&futuro(10)).unwrap();
+        punido.escala.get_mut("P").unwrap().get_mut("08-12").unwrap().punicao = true;
+        assert!(erro(&quadro, &[servico(10, "P", "1001"), servico(20, "P", "1002")]).contains("punição"));
     }
-    if fs::try_exists(DIVIDAS_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(DIVIDAS_FILE, "{}").await;
+
+    #[test]
+    fn cadeia_que_deixaria_alguem_em_dias_seguidos() {
+        let mut quadro = quadro();
+        // 1002 também está de serviço na véspera do serviço que receberia
+        quadro.escalas.insert(futuro(9), dia(TipoServico::RN, &[("P", "08-12", "1002")], &[]));
+        let erro = quadro.verificar(&[servico(10, "P", "1001"), servico(20, "P", "1002")]).unwrap_err();
+        assert!(erro.contains("dias seguidos") || erro.contains("véspera"), "{}", erro);
     }
-    if fs::try_exists(TROCAS_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(TROCAS_FILE, "[]").await;
+
+    #[test]
+    fn procura_as_cadeias_entre_as_ofertas() {
+        let quadro = quadro();
+        let ofertas = [
+            oferta(servico(10, "P", "1001")),
+            oferta(servico(40, "Q", "2001")),
+            oferta(servico(20, "P", "1002")),
+            oferta(servico(30, "P", "1003")),
+        ];
+        let cadeias = quadro.cadeias(&ofertas);
+        // 2001 não troca com ninguém; entre os outros três há dois pares e dois ciclos de três
+        assert!(cadeias.iter().all(|c| !c.contains(&1)));
+        assert!(cadeias.contains(&vec![0, 2]));
+        assert!(cadeias.contains(&vec![0, 3]));
+        assert!(cadeias.contains(&vec![2, 3]));
+        assert_eq!(cadeias.iter().filter(|c| c.len() == 3).count(), 2);
+        assert!(cadeias.iter().all(|c| c[0] == *c.iter().min().unwrap()));
     }
-    if fs::try_exists(INDISPONIBILIDADE_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(INDISPONIBILIDADE_FILE, "[]").await;
+
+    #[test]
+    fn troca_da_cadeia_precisa_de_duas_ofertas() {
+        let (a, b) = (oferta(servico(10, "P", "1001")), oferta(servico(20, "P", "1002")));
+        assert!(cadeia_das_ofertas(&[], Local::now()).is_none());
+        assert!(cadeia_das_ofertas(&[&a], Local::now()).is_none());
+
+        let troca = cadeia_das_ofertas(&[&a, &b], Local::now()).unwrap();
+        assert_eq!(troca.tipo, TipoTroca::Cadeia);
+        assert_eq!(troca.status, StatusTroca::PendenteAdmin);
+        assert_eq!((troca.requerente.user_id.as_str(), troca.alvo.user_id.as_str()), ("1001", "1002"));
+        assert_eq!(troca.ofertas, vec!["oferta-1001", "oferta-1002"]);
+        assert_eq!(troca.motivo, "Viagem / Viagem");
     }
-    if fs::try_exists(PUNIDOS_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(PUNIDOS_FILE, "[]").await;
+
+    #[test]
+    fn motor_guloso_poe_o_devedor_no_lugar_do_credor() {
+        let users: Vec<User> = ["1001", "1002", "1003"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect();
+        let mut entradas = EntradasGeracao {
+            postos: vec![posto("P", &[1], &["08-12"])],
+            contagem: Contagem::new(),
+            dividas: DividasAtivas::new(),
+            punicoes: Vec::new(),
+            configuracao: ConfiguracaoEscala { quotas_retem: Vec::new(), ..Default::default() },
+            indisponibilidades: Vec::new(),
+            de_servico_na_vespera: Vec::new(),
+        };
+        // 1001 é o próximo pela contagem, e 1002 deve-lhe um serviço
+        for id in ["1002", "1003"] {
+            entradas.contagem.insert(id.to_string(), ContagemUtilizador { rn: 5, rd: 0, retem: 0 });
+        }
+        let divida = Divida { credor: "1001".to_string(), tipo_divida: TipoServico::RN, origem: OrigemDivida::Manual, criada_em: None };
+        entradas.dividas.insert("1002".to_string(), vec![divida]);
+        let dias: Vec<NaiveDate> = (4..=7).map(|d| data(2030, 3, d)).collect();
+        let periodo = Periodo { start_date: dias[0], end_date: dias[3] };
+        let tipos = dias.iter().map(|d| (*d, TipoServico::RN)).collect();
+        let proposta = gerar_proposta(entradas, users, periodo, tipos, MotorGeracao::Guloso).unwrap();
+
+        let alocados: Vec<(&str, &str)> = dias
+            .iter()
+            .map(|d| &proposta.dias[d].escala["P"]["08-12"])
+            .map(|a| (a.user_id.as_str(), a.nome.as_str()))
+            .collect();
+        // No último dia 1001 volta a ser o próximo e 1002 está livre, mas a dívida já foi paga
+        assert_eq!(
+            alocados,
+            vec![("1002", "Aluno 1002 (PG)"), ("1001", "Aluno 1001"), ("1003", "Aluno 1003"), ("1001", "Aluno 1001")]
+        );
+        assert!(proposta.dividas.is_empty());
+        assert_eq!(proposta.dividas_pagas(), 1);
     }
-    if fs::try_exists(CONFIGURACAO_FILE).await.unwrap_or(false) == false {
-        let _ = fs::write(CONFIGURACAO_FILE, r#"{ "postos_punicao": [] }"#).await;
+
+    #[test]
+    fn motor_guloso_nao_comeca_com_quem_estava_de_servico_na_vespera() {
+        let users: Vec<User> = ["1001", "1002"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect();
+        let mut entradas = EntradasGeracao {
+            postos: vec![posto("P", &[1], &["08-12"])],
+            contagem: Contagem::new(),
+            dividas: DividasAtivas::new(),
+            punicoes: Vec::new(),
+            configuracao: ConfiguracaoEscala { quotas_retem: Vec::new(), ..Default::default() },
+            indisponibilidades: Vec::new(),
+            // 1001 fez o último dia do período em vigor
+            de_servico_na_vespera: vec!["1001".to_string()],
+        };
+        entradas.contagem.insert("1002".to_string(), ContagemUtilizador { rn: 5, rd: 0, retem: 0 });
+        let dias = [data(2030, 3, 4), data(2030, 3, 5)];
+        let periodo = Periodo { start_date: dias[0], end_date: dias[1] };
+        let tipos = dias.iter().map(|d| (*d, TipoServico::RN)).collect();
+        let proposta = gerar_proposta(entradas, users, periodo, tipos, MotorGeracao::Guloso).unwrap();
+
+        let alocados: Vec<&str> = dias.iter().map(|d| proposta.dias[d].escala["P"]["08-12"].user_id.as_str()).collect();
+        assert_eq!(alocados, vec!["1002", "1001"]);
     }
 }
@@ -1,56 +1,39 @@
 // src/escala_admin_handlers.rs
 
-use crate::auth::{self, AppState, User};
-use crate::escala::{self, EstadoEscala, EscalaDiaria, Posto, TipoServico, StatusTroca, Troca, Alocacao, Divida, DividasAtivas, Indisponibilidade, Punicao, ConfiguracaoEscala, DetalheServico, TipoTroca};
+use crate::auth::{AppState, AuthUser};
+use crate::escala::{self, StatusTroca, TipoServico, Alocacao, Divida, EstadoIndisponibilidade, Genero, QuotaRetem, DetalheServico, TipoTroca};
 use axum::http::{header, HeaderMap};
 use axum::{
     debug_handler,
-    extract::{State, Form},
+    extract::{Form, Query, State},
     http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    response::{Html, IntoResponse, Json, Redirect},
 };
-use chrono::{NaiveDate, Duration};
+use chrono::{Local, NaiveDate, Duration};
 use serde::{Deserialize};
-use std::collections::{BTreeMap, HashMap};
-use tokio::fs;
-use tower_cookies::Cookies;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use crate::escala_calendario::{self, tipo_dia_do_codigo, DiaCalendario};
+use crate::escala_dividas::{self, DestinoDividas, OrigemDivida, PosicaoDivida};
+use crate::escala_punicoes::{self, NovaPunicao, PosicaoPunicao};
 use crate::escala_pdf;
-
-// Constantes usadas pelos handlers de admin
-const ESTADO_ESCALA_FILE: &str = "data/escala/estado.json";
-const POSTOS_FILE: &str = "data/escala/postos.json";
-const TROCAS_FILE: &str = "data/escala/trocas.json";
-const ESCALA_DATA_DIR: &str = "data/escala";
-const USERS_FILE: &str = "users.json";
-const DIVIDAS_FILE: &str = "data/escala/dividas.json";
-const INDISPONIBILIDADE_FILE: &str = "data/escala/indisponibilidade.json";
-const PUNIDOS_FILE: &str = "data/escala/punidos.json";
-const CONFIGURACAO_FILE: &str = "data/escala/configuracao.json";
+use crate::escala_solver::SemSolucao;
+use crate::escala_validacao;
+use crate::store::{AppResult, EscalaStore, Recusa};
+use crate::views;
+use uuid::Uuid;
 
 
 // --- STRUCTS PARA FORMULÁRIOS ---
-#[derive(Deserialize)]
-pub struct AdicionarIndisponibilidadeForm {
-    user_id: String,
-    data: NaiveDate,
-    motivo: String,
-}
-
 #[derive(Deserialize)]
 pub struct RemoverIndisponibilidadeForm {
-    user_id: String,
-    data: NaiveDate,
-}
-
-#[derive(Deserialize)]
-pub struct AdicionarPunicaoForm {
-    user_id: String,
-    total_a_cumprir: u32,
+    id: String,
 }
 
 #[derive(Deserialize)]
-pub struct RemoverPunicaoForm {
-    user_id: String,
+pub struct DecidirIndisponibilidadeForm {
+    id: String,
+    acao: String,
 }
 
 // NOVO STRUCT PARA O FORMULÁRIO DE TROCA OBRIGATÓRIA
@@ -62,25 +45,11 @@ pub struct TrocaObrigatoriaForm {
 
 
 /// Verifica se um utilizador está escalado nos dias fornecidos. Retorna (true, "motivo") se encontrar um conflito.
-async fn verificar_conflito_escala(user_id: &str, datas_a_verificar: &[(NaiveDate, &'static str)]) -> (bool, &'static str) {
+async fn verificar_conflito_escala(store: &dyn EscalaStore, user_id: &str, datas_a_verificar: &[(NaiveDate, &'static str)]) -> (bool, &'static str) {
     for (data, motivo) in datas_a_verificar {
-        let filename = format!("{}/{}.json", ESCALA_DATA_DIR, data.format("%Y-%m-%d"));
-        if let Ok(content) = fs::read_to_string(filename).await {
-            if let Ok(escala_diaria) = serde_json::from_str::<EscalaDiaria>(&content) {
-                // Verifica na escala normal
-                for posto in escala_diaria.escala.values() {
-                    for alocacao in posto.values() {
-                        if alocacao.user_id == user_id {
-                            return (true, motivo);
-                        }
-                    }
-                }
-                // Verifica no retém
-                for alocacao in &escala_diaria.retem {
-                    if alocacao.user_id == user_id {
-                        return (true, motivo);
-                    }
-                }
+        if let Ok(Some(escala_diaria)) = store.dia(*data).await {
+            if !escala::servicos_no_dia(*data, &escala_diaria, user_id).is_empty() {
+                return (true, motivo);
             }
         }
     }
@@ -93,113 +62,109 @@ async fn verificar_conflito_escala(user_id: &str, datas_a_verificar: &[(NaiveDat
 #[debug_handler]
 pub async fn troca_obrigatoria_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
     Form(form): Form<TrocaObrigatoriaForm>,
 ) -> impl IntoResponse {
-    // 1. Validação de permissão
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, Html("Acesso negado.")).into_response();
-    }
-
-    // 2. Deserializar os detalhes do serviço original
+    // 1. Deserializar os detalhes do serviço original
     let original_service: DetalheServico = match serde_json::from_str(&form.original_service_json) {
         Ok(s) => s,
         Err(_) => return (StatusCode::BAD_REQUEST, Html("Dados do serviço original inválidos.")).into_response(),
     };
 
-    // 3. Carregar todos os utilizadores para encontrar o substituto
-    let users: HashMap<String, User> = match fs::read_to_string(USERS_FILE).await {
-        Ok(c) => serde_json::from_str::<Vec<User>>(&c).unwrap_or_default().into_iter().map(|u| (u.id.clone(), u)).collect(),
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao carregar utilizadores.")).into_response(),
-    };
-
-    let substitute_user = match users.get(&form.substitute_user_id) {
+    // 2. Encontrar o substituto entre os utilizadores
+    let substitute_user = match state.users.lock().unwrap().get(&form.substitute_user_id).cloned() {
         Some(u) => u,
         None => return (StatusCode::BAD_REQUEST, Html("ID do substituto não encontrado.")).into_response(),
     };
 
-    // 4. Verificar disponibilidade e risco de fadiga para o substituto
+    // 3. Verificar disponibilidade e risco de fadiga para o substituto
     let datas_verificacao = vec![
         (original_service.data, "já está de serviço no mesmo dia"),
         (original_service.data - Duration::days(1), "está de serviço no dia anterior (risco de fadiga)"),
         (original_service.data + Duration::days(1), "está de serviço no dia seguinte (risco de fadiga)"),
     ];
-    let (conflito, motivo) = verificar_conflito_escala(&substitute_user.id, &datas_verificacao).await;
+    let (conflito, motivo) = verificar_conflito_escala(state.escala_store.as_ref(), &substitute_user.id, &datas_verificacao).await;
     if conflito {
         let error_message = format!("<h1>Erro: Conflito de Escala!</h1><p>O substituto selecionado {}. A troca não foi efetuada.</p><a href='/escala'>Voltar</a>", motivo);
         return (StatusCode::CONFLICT, Html(error_message)).into_response();
     }
 
-    // 5. Carregar e modificar a escala diária
-    let filename = format!("{}/{}.json", ESCALA_DATA_DIR, original_service.data.format("%Y-%m-%d"));
-    let mut escala_diaria: EscalaDiaria = match fs::read_to_string(&filename).await {
-        Ok(c) => serde_json::from_str(&c).unwrap(),
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao carregar a escala do dia.")).into_response(),
-    };
-
-    let nova_alocacao = Alocacao {
-        user_id: substitute_user.id.clone(),
-        nome: format!("{} (TO)", substitute_user.name), // Adiciona a marcação (TO)
-        punicao: false,
-    };
-
-    let mut sucesso = false;
-    if original_service.posto == "RETEM" {
-        if let Some(aloc) = escala_diaria.retem.iter_mut().find(|a| a.user_id == original_service.user_id) {
-            *aloc = nova_alocacao;
-            sucesso = true;
-        }
-    } else {
-        if let Some(horarios) = escala_diaria.escala.get_mut(&original_service.posto) {
-            if let Some(aloc) = horarios.get_mut(&original_service.horario) {
-                if aloc.user_id == original_service.user_id {
-                    *aloc = nova_alocacao;
-                    sucesso = true;
+    // 4. Substituir o serviço e gerar a dívida do utilizador substituído, numa só transação
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        let Some(mut escala_diaria) = tx.dia(original_service.data)? else {
+            return Err(Recusa("Falha ao carregar a escala do dia.").into());
+        };
+
+        let nova_alocacao = Alocacao {
+            user_id: substitute_user.id.clone(),
+            nome: format!("{} (TO)", substitute_user.name), // Adiciona a marcação (TO)
+            punicao: false,
+        };
+
+        let mut sucesso = false;
+        if original_service.posto == "RETEM" {
+            if let Some(aloc) = escala_diaria.retem.iter_mut().find(|a| a.user_id == original_service.user_id) {
+                *aloc = nova_alocacao;
+                sucesso = true;
+            }
+        } else {
+            if let Some(horarios) = escala_diaria.escala.get_mut(&original_service.posto) {
+                if let Some(aloc) = horarios.get_mut(&original_service.horario) {
+                    if aloc.user_id == original_service.user_id {
+                        *aloc = nova_alocacao;
+                        sucesso = true;
+                    }
                 }
             }
         }
-    }
 
-    if !sucesso {
-        return (StatusCode::INTERNAL_SERVER_ERROR, Html("Não foi possível encontrar o serviço para substituir.")).into_response();
-    }
+        if !sucesso {
+            return Err(Recusa("Não foi possível encontrar o serviço para substituir.").into());
+        }
 
-    // 6. Salvar a escala diária modificada
-    if let Err(_) = fs::write(&filename, serde_json::to_string_pretty(&escala_diaria).unwrap()).await {
+        // 5. Salvar a escala diária modificada
+        tx.guardar_dia(original_service.data, &escala_diaria)?;
+
+        // 6. A dívida é do utilizador original; o substituto é o credor
+        let mut dividas = tx.dividas()?;
+        let divida = Divida {
+            credor: substitute_user.id.clone(),
+            tipo_divida: escala_diaria.tipo_dia,
+            origem: OrigemDivida::TrocaObrigatoria,
+            criada_em: Some(chrono::Local::now()),
+        };
+        dividas.entry(original_service.user_id).or_default().push(divida);
+        tx.guardar_dividas(&dividas)
+    })).await;
+
+    if let Err(e) = resultado {
+        if let Some(recusa) = e.downcast_ref::<Recusa>() {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html(recusa.0)).into_response();
+        }
+        eprintln!("🔥 Falha na troca obrigatória: {}", e);
         return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao salvar a escala modificada.")).into_response();
     }
 
-    // 7. Gerar a dívida para o utilizador substituído
-    let mut dividas: DividasAtivas = fs::read_to_string(DIVIDAS_FILE).await.ok().and_then(|c| serde_json::from_str(&c).ok()).unwrap_or_default();
-    let divida = Divida {
-        credor: substitute_user.id.clone(), // O substituto é o credor
-        tipo_divida: escala_diaria.tipo_dia,
-    };
-    dividas.entry(original_service.user_id).or_default().push(divida); // A dívida é do utilizador original
-
-    if let Err(_) = fs::write(DIVIDAS_FILE, serde_json::to_string_pretty(&dividas).unwrap()).await {
-        eprintln!("AVISO: Falha ao salvar a dívida da troca obrigatória.");
-    }
-
-    // 8. Redirecionar de volta para a página da escala
+    // 7. Redirecionar de volta para a página da escala
     Redirect::to("/escala").into_response()
 }
 
 #[debug_handler]
 pub async fn admin_escala_page(
     State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, Html("Acesso negado.")).into_response();
+    if let Err(e) = escala::expirar_trocas(state.escala_store.as_ref()).await {
+        eprintln!("🔥 Falha ao expirar as trocas: {}", e);
     }
-    
     // --- 1. Carregamento de todos os dados necessários ---
-    let estado_content = fs::read_to_string(ESTADO_ESCALA_FILE).await.unwrap_or_default();
-    let estado: EstadoEscala = serde_json::from_str(&estado_content).unwrap();
+    let estado = match state.escala_store.estado().await {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler o estado da escala: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao carregar o estado da escala.")).into_response();
+        }
+    };
 
-    let users_content = fs::read_to_string(USERS_FILE).await.unwrap_or_else(|_| "{}".to_string());
-    let users: HashMap<String, User> = serde_json::from_str::<Vec<User>>(&users_content).unwrap_or_default().into_iter().map(|u| (u.id.clone(), u)).collect();
+    let users = state.users.lock().unwrap().clone();
     
     // --- 2. Geração de todos os snippets HTML ---
 
@@ -219,6 +184,20 @@ pub async fn admin_escala_page(
         status_trocas_str
     );
 
+    // Uma escala gerada que ainda não foi gravada
+    let card_proposta_html = match state.proposta_escala.lock().unwrap().as_ref() {
+        Some(pendente) => format!(
+            r#"<div class="card" style="border-left: 4px solid #ffc107;">
+                   <h2>Proposta por Rever</h2>
+                   <p>Há uma escala gerada para <strong>{}</strong> a <strong>{}</strong> que ainda não foi gravada.</p>
+                   <a href="/admin/escala/proposta" class="btn btn-primary">Rever Proposta</a>
+               </div>"#,
+            pendente.proposta.periodo.start_date.format("%d/%m/%Y"),
+            pendente.proposta.periodo.end_date.format("%d/%m/%Y")
+        ),
+        None => String::new(),
+    };
+
     let card_geracao_html: String;
     let mut card_lancamento_html = String::new();
     if let Some(periodo_seguinte) = &estado.periodo_seguinte {
@@ -229,6 +208,8 @@ pub async fn admin_escala_page(
                    <form action="/admin/escala/lancar" method="post" onsubmit="return confirm('Tem a certeza que deseja tornar esta a escala atual? Esta ação não pode ser desfeita.');">
                        <button type="submit" class="btn btn-primary">Lançar e Tornar Atual</button>
                    </form>
+                   <p>Antes de lançar, a escala é <a href="/admin/escala/validar">validada</a>: com erros, não é lançada.</p>
+                   <p>Enquanto não for lançada, a geração pode ser revertida no <a href="/admin/escala/execucoes">histórico das gerações</a>.</p>
                </div>"#,
             periodo_seguinte.start_date.format("%d/%m/%Y"),
             periodo_seguinte.end_date.format("%d/%m/%Y")
@@ -242,11 +223,16 @@ pub async fn admin_escala_page(
                     <p><label for="start_date">Data de Início:</label><input type="date" id="start_date" name="start_date" required></p>
                     <p><label for="end_date">Data de Fim:</label><input type="date" id="end_date" name="end_date" required></p>
                     <h3>Definir Tipo de Rotina para cada Dia</h3>
+                    <p style="color: #6c757d;">Os dias vêm preenchidos a partir do <a href="/admin/escala/calendario">calendário</a>.</p>
                     <div id="dias-da-semana-container" style="display: grid; grid-template-columns: repeat(auto-fill, minmax(250px, 1fr)); gap: 15px;">
                         <p style="color: #666; grid-column: 1 / -1;"><em>Selecione as datas de início e fim para configurar os dias.</em></p>
                     </div>
-                    <br>
-                    <button type="submit" class="btn btn-primary">Gerar Escala</button>
+                    <p><label for="motor">Motor de geração:</label>
+                        <select id="motor" name="motor">
+                            <option value="guloso">Guloso (preenche vaga a vaga, pela ordem das contagens)</option>
+                            <option value="otimizado">Otimizado (procura a distribuição mais justa e explica os conflitos)</option>
+                        </select></p>
+                    <button type="submit" class="btn btn-primary">Gerar e Pré-visualizar</button>
                 </form>
             </div>"#.to_string();
     }
@@ -261,17 +247,32 @@ pub async fn admin_escala_page(
         status_text_trocas, trade_button_html
     );
 
-    let first_day_of_scale_file = format!("{}/{}.json", ESCALA_DATA_DIR, estado.periodo_atual.start_date.format("%Y-%m-%d"));
-    let scale_exists = fs::try_exists(&first_day_of_scale_file).await.unwrap_or(false);
+    let scale_exists = matches!(state.escala_store.dia(estado.periodo_atual.start_date).await, Ok(Some(_)));
 
     let card_pdf_html = if scale_exists {
-        r#"
+        let opcoes_postos: String = state
+            .escala_store
+            .postos()
+            .await
+            .unwrap_or_default()
+            .iter()
+            .map(|p| p.nome.clone())
+            .chain(std::iter::once(crate::escala_exportar::RETEM.to_string()))
+            .map(|nome| format!("<option value='{0}'>{0}</option>", nome))
+            .collect();
+        format!(r#"
         <div class="card">
             <h2>Exportar Escala</h2>
             <p>Gere um ficheiro PDF da escala atualmente em vigor para impressão ou arquivo.</p>
             <a href="/admin/escala/pdf" class="btn btn-primary" style="background-color: #6f42c1;">Gerar PDF da Escala Ativa</a>
+            <h3>Um só posto</h3>
+            <form action="/escala/posto/exportar" method="get">
+                <select name="posto">{opcoes_postos}</select>
+                <button type="submit" name="formato" value="pdf" class="btn btn-primary">PDF</button>
+                <button type="submit" name="formato" value="csv" class="btn btn-primary">CSV</button>
+            </form>
         </div>
-        "#.to_string()
+        "#)
     } else {
         r#"
         <div class="card">
@@ -282,8 +283,7 @@ pub async fn admin_escala_page(
         "#.to_string()
     };
 
-    let trocas_content = fs::read_to_string(TROCAS_FILE).await.unwrap_or_else(|_| "[]".to_string());
-    let todas_as_trocas: Vec<Troca> = serde_json::from_str(&trocas_content).unwrap_or_default();
+    let todas_as_trocas = state.escala_store.trocas().await.unwrap_or_default();
     let mut trocas_pendentes_html = String::new();
     if !todas_as_trocas.iter().any(|t| t.status == StatusTroca::PendenteAdmin) {
         trocas_pendentes_html = "<p>Não há pedidos de troca pendentes de aprovação.</p>".to_string();
@@ -291,21 +291,40 @@ pub async fn admin_escala_page(
         for troca in todas_as_trocas.iter().filter(|t| t.status == StatusTroca::PendenteAdmin) {
             
             let datas_verificacao_req = vec![(troca.alvo.data - Duration::days(1), ""), (troca.alvo.data + Duration::days(1), "")];
-            let (fadiga_req, _) = verificar_conflito_escala(&troca.requerente.user_id, &datas_verificacao_req).await;
+            let (fadiga_req, _) = verificar_conflito_escala(state.escala_store.as_ref(), &troca.requerente.user_id, &datas_verificacao_req).await;
             
             let mut fadiga_alvo = false;
             if troca.tipo == escala::TipoTroca::Permuta {
                 let datas_verificacao_alvo = vec![(troca.requerente.data - Duration::days(1), ""), (troca.requerente.data + Duration::days(1), "")];
-                (fadiga_alvo, _) = verificar_conflito_escala(&troca.alvo.user_id, &datas_verificacao_alvo).await;
+                (fadiga_alvo, _) = verificar_conflito_escala(state.escala_store.as_ref(), &troca.alvo.user_id, &datas_verificacao_alvo).await;
             }
 
             let warning_html = if fadiga_req || fadiga_alvo {
                 "<p style='color: red; font-weight: bold;'>⚠️ Aviso: A aprovação desta troca pode resultar em fadiga.</p>".to_string()
             } else { "".to_string() };
 
+            let descricao = if troca.tipo == escala::TipoTroca::Cadeia {
+                let elos: Vec<String> = troca
+                    .cadeia
+                    .iter()
+                    .enumerate()
+                    .map(|(i, servico)| {
+                        let recebido = &troca.cadeia[(i + 1) % troca.cadeia.len()];
+                        format!("<li>{} fica com {} ({} em {})</li>", servico.user_id, recebido.user_id, recebido.posto, recebido.data.format("%d/%m"))
+                    })
+                    .collect();
+                format!("<p><strong>Cadeia de {} trocas</strong> (do quadro de ofertas; cada elo fica como dívida):</p><ul>{}</ul>", troca.cadeia.len(), elos.concat())
+            } else {
+                format!(
+                    "<p><strong>Pedido de {:?}:</strong> {} ({} em {}) quer trocar com {} ({} em {}).</p>",
+                    troca.tipo, troca.requerente.user_id, troca.requerente.posto, troca.requerente.data.format("%d/%m"),
+                    troca.alvo.user_id, troca.alvo.posto, troca.alvo.data.format("%d/%m")
+                )
+            };
+
             trocas_pendentes_html.push_str(&format!(
                 r#"<div class="trade-request" style="border-top: 1px solid #eee; padding-top: 10px; margin-top: 10px;">
-                    <p><strong>Pedido de {tipo:?}:</strong> {req_id} ({req_posto} em {req_data}) quer trocar com {alvo_id} ({alvo_posto} em {alvo_data}).</p>
+                    {descricao}
                     <p><i>Motivo: {motivo}</i></p>
                     {warning_html}
                     <form action="/admin/escala/aprovar_troca" method="post" style="display: inline-block; margin-right: 5px;">
@@ -317,47 +336,69 @@ pub async fn admin_escala_page(
                         <button type="submit" class="btn btn-danger">Recusar</button>
                     </form>
                 </div>"#,
-                tipo = troca.tipo, req_id = troca.requerente.user_id, req_posto = troca.requerente.posto, req_data = troca.requerente.data.format("%d/%m"),
-                alvo_id = troca.alvo.user_id, alvo_posto = troca.alvo.posto, alvo_data = troca.alvo.data.format("%d/%m"),
-                motivo = troca.motivo, id = troca.id, warning_html = warning_html
+                descricao = descricao, motivo = troca.motivo, id = troca.id, warning_html = warning_html
             ));
         }
     }
 
-    let indisponibilidades_content = fs::read_to_string(INDISPONIBILIDADE_FILE).await.unwrap_or_else(|_| "[]".to_string());
-    let indisponibilidades: Vec<Indisponibilidade> = serde_json::from_str(&indisponibilidades_content).unwrap_or_default();
+    let indisponibilidades = state.escala_store.indisponibilidades().await.unwrap_or_default();
+    let (pedidos, aprovadas): (Vec<_>, Vec<_>) = indisponibilidades
+        .iter()
+        .filter(|i| i.estado != EstadoIndisponibilidade::Rejeitada)
+        .partition(|i| i.estado == EstadoIndisponibilidade::Pendente);
     let mut indisponibilidades_html = String::new();
-    if indisponibilidades.is_empty() {
+    if aprovadas.is_empty() {
         indisponibilidades_html.push_str("<p>Não há utilizadores marcados como indisponíveis.</p>");
     } else {
-        indisponibilidades_html.push_str("<table><thead><tr><th>ID</th><th>Nome</th><th>Data</th><th>Motivo</th><th>Ação</th></tr></thead><tbody>");
-        for ind in &indisponibilidades {
+        indisponibilidades_html.push_str("<table><thead><tr><th>ID</th><th>Nome</th><th>Datas</th><th>Categoria</th><th>Motivo</th><th>Ação</th></tr></thead><tbody>");
+        for ind in &aprovadas {
             let user_name = users.get(&ind.user_id).map_or("Desconhecido", |u| u.name.as_str());
             indisponibilidades_html.push_str(&format!(
-                r#"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>
+                r#"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>
                    <form action="/admin/escala/indisponibilidade/remover" method="post" style="margin:0;">
-                   <input type="hidden" name="user_id" value="{}"><input type="hidden" name="data" value="{}">
+                   <input type="hidden" name="id" value="{}">
                    <button type="submit" class="btn btn-danger btn-sm">Remover</button></form></td></tr>"#,
-                ind.user_id, user_name, ind.data.format("%d/%m/%Y"), ind.motivo, ind.user_id, ind.data
+                ind.user_id, user_name, ind.descrever_datas(), ind.categoria.nome(), ind.motivo, ind.chave()
             ));
         }
         indisponibilidades_html.push_str("</tbody></table>");
     }
+    let mut pedidos_indisponibilidade_html = String::new();
+    if pedidos.is_empty() {
+        pedidos_indisponibilidade_html.push_str("<p>Não há pedidos por decidir.</p>");
+    } else {
+        pedidos_indisponibilidade_html.push_str("<table><thead><tr><th>ID</th><th>Nome</th><th>Datas</th><th>Categoria</th><th>Motivo</th><th>Ação</th></tr></thead><tbody>");
+        for ind in &pedidos {
+            let user_name = users.get(&ind.user_id).map_or("Desconhecido", |u| u.name.as_str());
+            pedidos_indisponibilidade_html.push_str(&format!(
+                r#"<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>
+                   <form action="/admin/escala/indisponibilidade/decidir" method="post" style="margin:0;">
+                   <input type="hidden" name="id" value="{}">
+                   <button type="submit" name="acao" value="aprovar" class="btn btn-success btn-sm">Aprovar</button>
+                   <button type="submit" name="acao" value="rejeitar" class="btn btn-danger btn-sm">Rejeitar</button></form></td></tr>"#,
+                ind.user_id, user_name, ind.descrever_datas(), ind.categoria.nome(), ind.motivo, ind.chave()
+            ));
+        }
+        pedidos_indisponibilidade_html.push_str("</tbody></table>");
+    }
 
-    let punidos_content = fs::read_to_string(PUNIDOS_FILE).await.unwrap_or_else(|_| "[]".to_string());
-    let punicoes: Vec<Punicao> = serde_json::from_str(&punidos_content).unwrap_or_default();
+    let punicoes = state.escala_store.punicoes().await.unwrap_or_default();
+    let hoje = Local::now().date_naive();
     let mut punicoes_html = String::new();
-    if punicoes.is_empty() {
+    if !punicoes.iter().any(|p| p.ativa()) {
         punicoes_html.push_str("<p>Não há utilizadores com punições ativas.</p>");
     } else {
-        punicoes_html.push_str("<table><thead><tr><th>ID</th><th>Nome</th><th>Progresso</th><th>Ação</th></tr></thead><tbody>");
-        for punicao in &punicoes {
+        punicoes_html.push_str("<table><thead><tr><th>ID</th><th>Nome</th><th>Motivo</th><th>Prazo</th><th>Progresso</th></tr></thead><tbody>");
+        for punicao in punicoes.iter().filter(|p| p.ativa()) {
             let user_name = users.get(&punicao.user_id).map_or("Desconhecido", |u| u.name.as_str());
+            let prazo = match punicao.prazo {
+                Some(prazo) if punicao.em_atraso(hoje) => format!("<strong style='color:#c0392b'>{} (em atraso)</strong>", prazo.format("%d/%m/%Y")),
+                Some(prazo) => prazo.format("%d/%m/%Y").to_string(),
+                None => "—".to_string(),
+            };
             punicoes_html.push_str(&format!(
-                r#"<tr><td>{}</td><td>{}</td><td>{}/{}</td><td>
-                   <form action="/admin/escala/punicao/remover" method="post" style="margin:0;">
-                   <input type="hidden" name="user_id" value="{}"><button type="submit" class="btn btn-danger btn-sm">Remover</button></form></td></tr>"#,
-                punicao.user_id, user_name, punicao.ja_cumpridos, punicao.total_a_cumprir, punicao.user_id
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}/{}</td></tr>",
+                punicao.user_id, user_name, punicao.motivo, prazo, punicao.ja_cumpridos, punicao.total_a_cumprir
             ));
         }
         punicoes_html.push_str("</tbody></table>");
@@ -368,25 +409,51 @@ pub async fn admin_escala_page(
         user_options_html.push_str(&format!("<option value='{}'>{} - {}</option>", user.id, user.id, user.name));
     }
     
-    let config_content = fs::read_to_string(CONFIGURACAO_FILE).await.unwrap_or_else(|_| r#"{ "postos_punicao": [] }"#.to_string());
-    let config: ConfiguracaoEscala = serde_json::from_str(&config_content).unwrap_or_default();
-    let postos_content = fs::read_to_string(POSTOS_FILE).await.unwrap_or_else(|_| "[]".to_string());
-    let todos_postos: Vec<Posto> = serde_json::from_str(&postos_content).unwrap_or_default();
+    let config = state.escala_store.configuracao().await.unwrap_or_default();
+    let todos_postos = state.escala_store.postos().await.unwrap_or_default();
     
     let todos_postos_nomes: Vec<&str> = todos_postos.iter().map(|p| p.nome.as_str()).collect();
     let todos_postos_json = serde_json::to_string(&todos_postos_nomes).unwrap_or_else(|_| "[]".to_string());
     let postos_selecionados_json = serde_json::to_string(&config.postos_punicao).unwrap_or_else(|_| "[]".to_string());
 
+    // As quotas existentes e duas linhas vazias para acrescentar
+    let mut quotas_retem_html = String::new();
+    let vazias = std::iter::repeat_n(None, 2);
+    for quota in config.quotas_retem.iter().map(Some).chain(vazias) {
+        let opcoes = |valores: &[(&str, &str)], atual: &str| -> String {
+            valores
+                .iter()
+                .map(|(v, nome)| format!("<option value='{}'{}>{}</option>", v, if *v == atual { " selected" } else { "" }, nome))
+                .collect()
+        };
+        let tipo = quota.and_then(|q| q.tipo_dia.as_ref()).map(|t| format!("{:?}", t)).unwrap_or_default();
+        let genero = match quota.map(|q| &q.genero) {
+            Some(Genero::Masculino) => "M",
+            Some(Genero::Feminino) => "F",
+            _ => "X",
+        };
+        quotas_retem_html.push_str(&format!(
+            r#"<tr><td><input type="number" name="ano" min="1" value="{}"></td><td><input type="number" name="quantidade" min="0" value="{}"></td>
+               <td><select name="tipo_dia">{}</select></td><td><select name="genero">{}</select></td></tr>"#,
+            quota.map(|q| q.ano.to_string()).unwrap_or_default(),
+            quota.map(|q| q.quantidade.to_string()).unwrap_or_default(),
+            opcoes(&[("", "Todos"), ("RN", "RN"), ("RD", "RD"), ("UDRD", "UDRD"), ("ER", "ER")], &tipo),
+            opcoes(&[("X", "Qualquer"), ("M", "M"), ("F", "F")], genero),
+        ));
+    }
+
     let mut postos_html = String::new();
     if todos_postos.is_empty() {
         postos_html.push_str("<p>Nenhum posto configurado.</p>");
     } else {
         postos_html.push_str("<ul>");
         for posto in &todos_postos {
-            postos_html.push_str(&format!("<li>{}</li>", posto.nome));
+            let retirado = if posto.ativo { "" } else { " (retirado)" };
+            postos_html.push_str(&format!("<li>{}{}</li>", posto.nome, retirado));
         }
         postos_html.push_str("</ul>");
     }
+    postos_html.push_str(r#"<a href="/admin/escala/postos" class="btn btn-primary">Gerir Postos</a>"#);
 
     Html(format!(
         r#"
@@ -425,27 +492,27 @@ pub async fn admin_escala_page(
                 <button class="tablink" onclick="openTab(event, 'Punicao')">Punições</button>
                 <button class="tablink" onclick="openTab(event, 'Config')">Outras Configurações</button>
             </div>
-            <div id="Gestao" class="tabcontent">{card_lancamento_html}{card_geracao_html}{card_gestao_trocas_html}{card_pdf_html}</div>
+            <div id="Gestao" class="tabcontent">{card_proposta_html}{card_lancamento_html}{card_geracao_html}{card_gestao_trocas_html}{card_pdf_html}
+                <div class="card"><h2>Histórico das Gerações</h2><p>Cada escala gravada fica guardada como uma versão, que pode ser comparada com outras e revertida enquanto não for lançada.</p><a href="/admin/escala/execucoes" class="btn btn-primary">Ver Histórico</a></div>
+                <div class="card"><h2>Dívidas de Serviço</h2><p>Quem deve serviços a quem e porquê: lançar, perdoar e transferir dívidas, e o que lhes acontece na virada de ano.</p><a href="/admin/escala/dividas" class="btn btn-primary">Ver Dívidas</a></div>
+                <div class="card"><h2>Calendário</h2><p>Feriados, dias de rotina especial e as regras que preenchem o tipo de cada dia na geração.</p><a href="/admin/escala/calendario" class="btn btn-primary">Gerir Calendário</a></div>
+                <div class="card"><h2>Análise da Carga</h2><p>Serviços de cada pessoa, por posto, ano e género, em qualquer intervalo de datas, com o desvio em relação à média do ano.</p><a href="/admin/escala/analise" class="btn btn-primary">Ver Análise</a></div>
+                <div class="card"><h2>Validação</h2><p>Procura, num intervalo de datas, pessoas com dois serviços no mesmo dia ou em dias seguidos, postos que não aceitam quem lá está, indisponíveis e punidos no retém.</p><a href="/admin/escala/validar" class="btn btn-primary">Validar Escala</a></div></div>
             <div id="Aprovacao" class="tabcontent"><div class="card"><h2>Aprovação de Trocas</h2>{trocas_pendentes_html}</div></div>
             <div id="Indisponibilidade" class="tabcontent">
+                <div class="card"><h2>Pedidos por Decidir</h2>{pedidos_indisponibilidade_html}</div>
                 <div class="card"><h2>Utilizadores Indisponíveis</h2>{indisponibilidades_html}</div>
                 <div class="card"><h2>Adicionar Indisponibilidade</h2>
                     <form action="/admin/escala/indisponibilidade/adicionar" method="post">
                         <p><label>Utilizador:</label><input list="user-list" name="user_id" required><datalist id="user-list">{user_options_html}</datalist></p>
-                        <p><label>Data:</label><input type="date" name="data" required></p>
-                        <p><label>Motivo:</label><input type="text" name="motivo" required></p>
+                        {campos_indisponibilidade}
                         <button type="submit" class="btn btn-primary">Adicionar</button>
                     </form>
                 </div>
             </div>
             <div id="Punicao" class="tabcontent">
-                <div class="card"><h2>Punições Ativas</h2>{punicoes_html}</div>
-                <div class="card"><h2>Adicionar Punição</h2>
-                    <form action="/admin/escala/punicao/adicionar" method="post">
-                        <p><label>Utilizador:</label><input list="user-list" name="user_id" required><datalist id="user-list">{user_options_html}</datalist></p>
-                        <p><label>Nº de Serviços:</label><input type="number" name="total_a_cumprir" min="1" required></p>
-                        <button type="submit" class="btn btn-primary">Adicionar</button>
-                    </form>
+                <div class="card"><h2>Punições Ativas</h2>{punicoes_html}
+                    <p><a href="/admin/escala/punicoes" class="btn btn-primary">Gerir punições</a> Aplicar, agravar e anular punições, ver as que estão em atraso, imprimir a notificação e consultar o histórico.</p>
                 </div>
                 <div class="card"><h2>Configurar Postos de Punição</h2>
                     <p>Selecione os postos que podem ser preenchidos por utilizadores punidos.</p>
@@ -459,7 +526,15 @@ pub async fn admin_escala_page(
                     </form>
                 </div>
             </div>
-            <div id="Config" class="tabcontent"><div class="card"><h2>Postos Configurados no Sistema</h2>{postos_html}</div></div>
+            <div id="Config" class="tabcontent"><div class="card"><h2>Postos Configurados no Sistema</h2>{postos_html}</div>
+                <div class="card"><h2>Composição do Retém</h2>
+                    <p>Quantas pessoas de cada ano fazem retém por dia. As quotas de um tipo de dia substituem as gerais nesses dias. Para apagar uma quota, deixe a quantidade vazia.</p>
+                    <form action="/admin/escala/configuracao/retem" method="post">
+                        <table><thead><tr><th>Ano</th><th>Quantidade</th><th>Tipo de dia</th><th>Género</th></tr></thead><tbody>{quotas_retem_html}</tbody></table>
+                        <button type="submit" class="btn btn-primary">Salvar Quotas</button>
+                    </form>
+                </div>
+            </div>
             <a href="/dashboard">← Voltar ao Dashboard</a>
             
             <script>
@@ -506,7 +581,7 @@ pub async fn admin_escala_page(
                             const dayName = weekdays[currentDate.getUTCDay()];
                             const div = document.createElement('div');
                             div.innerHTML = `
-                                <p><strong>${{dayName}}</strong> (${{currentDate.toLocaleDateString('pt-BR', {{timeZone: 'UTC'}})}})</p>
+                                <p><strong>${{dayName}}</strong> (${{currentDate.toLocaleDateString('pt-BR', {{timeZone: 'UTC'}})}}) <small id="motivo-${{dateString}}" style="color: #6c757d;"></small></p>
                                 <div>
                                     <input type="radio" id="rn-${{dateString}}" name="tipo_dia_${{dateString}}" value="RN" checked><label for="rn-${{dateString}}">RN</label>
                                     <input type="radio" id="rd-${{dateString}}" name="tipo_dia_${{dateString}}" value="RD"><label for="rd-${{dateString}}">RD</label>
@@ -516,6 +591,15 @@ pub async fn admin_escala_page(
                             container.appendChild(div);
                             currentDate.setUTCDate(currentDate.getUTCDate() + 1);
                         }}
+                        // Pré-preenche com o calendário; o admin pode mudar qualquer dia
+                        fetch(`/admin/escala/calendario/tipos?inicio=${{start}}&fim=${{end}}`)
+                            .then(r => r.ok ? r.json() : [])
+                            .then(tipos => tipos.forEach(t => {{
+                                const radio = document.getElementById(`${{t.tipo.toLowerCase()}}-${{t.data}}`);
+                                if (radio) radio.checked = true;
+                                const motivo = document.getElementById(`motivo-${{t.data}}`);
+                                if (motivo) motivo.textContent = t.motivo;
+                            }}));
                     }}
                     startDateInput.addEventListener('change', updateDaySelectors);
                     endDateInput.addEventListener('change', updateDaySelectors);
@@ -579,14 +663,18 @@ pub async fn admin_escala_page(
         </html>
         "#,
         card_escala_atual_html = card_escala_atual_html,
+        card_proposta_html = card_proposta_html,
         card_lancamento_html = card_lancamento_html,
         card_geracao_html = card_geracao_html,
         card_gestao_trocas_html = card_gestao_trocas_html,
         trocas_pendentes_html = trocas_pendentes_html,
         indisponibilidades_html = indisponibilidades_html,
+        pedidos_indisponibilidade_html = pedidos_indisponibilidade_html,
+        campos_indisponibilidade = views::escala::campos_indisponibilidade(),
         punicoes_html = punicoes_html,
         user_options_html = user_options_html,
         postos_html = postos_html,
+        quotas_retem_html = quotas_retem_html,
         todos_postos_json = todos_postos_json,
         postos_selecionados_json = postos_selecionados_json,
         periodo_atual_start = estado.periodo_atual.start_date.format("%Y-%m-%d"),
@@ -597,16 +685,9 @@ pub async fn admin_escala_page(
 #[debug_handler]
 pub async fn fechar_trocas_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-    if let Ok(content) = fs::read_to_string(ESTADO_ESCALA_FILE).await {
-        if let Ok(mut estado) = serde_json::from_str::<EstadoEscala>(&content) {
-            estado.status_trocas = "Fechado".to_string();
-            if let Ok(json) = serde_json::to_string_pretty(&estado) {
-                let _ = fs::write(ESTADO_ESCALA_FILE, json).await;
-            }
-        }
+    if let Err(e) = escala::atualizar_estado(state.escala_store.as_ref(), |estado| estado.status_trocas = "Fechado".to_string()).await {
+        eprintln!("🔥 Falha ao fechar as trocas: {}", e);
     }
     Redirect::to("/admin/escala")
 }
@@ -614,143 +695,148 @@ pub async fn fechar_trocas_handler(
 #[debug_handler]
 pub async fn reabrir_trocas_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-    if let Ok(content) = fs::read_to_string(ESTADO_ESCALA_FILE).await {
-        if let Ok(mut estado) = serde_json::from_str::<EstadoEscala>(&content) {
-            estado.status_trocas = "Aberto".to_string();
-            if let Ok(json) = serde_json::to_string_pretty(&estado) {
-                let _ = fs::write(ESTADO_ESCALA_FILE, json).await;
-            }
-        }
+    if let Err(e) = escala::atualizar_estado(state.escala_store.as_ref(), |estado| estado.status_trocas = "Aberto".to_string()).await {
+        eprintln!("🔥 Falha ao reabrir as trocas: {}", e);
     }
     Redirect::to("/admin/escala")
 }
 
+/// Troca a alocação de `user_id` no posto/horário indicados pela nova alocação.
+fn substituir_alocacao(escala_diaria: &mut escala::EscalaDiaria, servico: &DetalheServico, nova: Alocacao) {
+    if servico.posto == "RETEM" {
+        if let Some(aloc) = escala_diaria.retem.iter_mut().find(|a| a.user_id == servico.user_id) { *aloc = nova; }
+    } else {
+        if let Some(aloc) = escala_diaria.escala.get_mut(&servico.posto).and_then(|h| h.get_mut(&servico.horario)) { *aloc = nova; }
+    }
+}
+
 #[debug_handler]
 pub async fn aprovar_troca_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
     Form(form_data): Form<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-
-    let troca_id = form_data.get("troca_id").unwrap().to_string();
-    let acao = form_data.get("acao").unwrap();
-
-    let mut trocas: Vec<Troca> = match fs::read_to_string(TROCAS_FILE).await {
-        Ok(content) => serde_json::from_str(&content).unwrap_or_default(),
-        Err(_) => return Redirect::to("/admin/escala"),
-    };
-    
-    let troca_index = match trocas.iter().position(|t| t.id == troca_id) {
-        Some(index) => index,
-        None => return Redirect::to("/admin/escala"),
-    };
-
-    if trocas[troca_index].status != StatusTroca::PendenteAdmin {
-        return Redirect::to("/admin/escala");
-    }
-
-    if acao == "recusar" {
-        trocas[troca_index].status = StatusTroca::Recusada;
-    } else {
-        trocas[troca_index].status = StatusTroca::Aprovada;
-        let troca = trocas[troca_index].clone();
+    let troca_id = form_data.get("troca_id").cloned().unwrap_or_default();
+    let aprovar = form_data.get("acao").map(String::as_str) != Some("recusar");
+    let users = state.users.lock().unwrap().clone();
+
+    // A troca, as escalas envolvidas e as dívidas são alteradas numa só transação
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        let Some(mut troca) = tx.troca(&troca_id)? else { return Ok(()) };
+        if troca.status != StatusTroca::PendenteAdmin {
+            return Ok(());
+        }
+        let agora = chrono::Local::now();
+        troca.decidida_em = Some(agora);
 
-        let users_content = fs::read_to_string(USERS_FILE).await.unwrap_or_else(|_| "[]".to_string());
-        let users: HashMap<String, User> = serde_json::from_str::<Vec<User>>(&users_content)
-            .unwrap_or_default().into_iter().map(|u| (u.id.clone(), u)).collect();
+        if troca.expirou(agora) {
+            troca.status = StatusTroca::Expirada;
+            return tx.guardar_troca(&troca);
+        }
+        if !aprovar {
+            troca.status = StatusTroca::Recusada;
+            return tx.guardar_troca(&troca);
+        }
+        // A escala pode ter mudado desde o pedido: a troca tem de continuar possível
+        escala::validar_troca(tx, &troca, &users)?;
+        troca.status = StatusTroca::Aprovada;
 
         if troca.tipo == TipoTroca::Permuta {
             let req_details = &troca.requerente;
             let alvo_details = &troca.alvo;
-            
-            let requester_user = users.get(&req_details.user_id).unwrap();
-            let target_user = users.get(&alvo_details.user_id).unwrap();
+
+            let (Some(requester_user), Some(target_user)) = (users.get(&req_details.user_id), users.get(&alvo_details.user_id)) else {
+                return Ok(());
+            };
 
             let new_alocacao_para_slot_requerente = Alocacao { user_id: target_user.id.clone(), nome: format!("{} (TR)", target_user.name), punicao: false };
             let new_alocacao_para_slot_alvo = Alocacao { user_id: requester_user.id.clone(), nome: format!("{} (TR)", requester_user.name), punicao: false };
 
-            let req_filename = format!("{}/{}.json", ESCALA_DATA_DIR, req_details.data.format("%Y-%m-%d"));
-            let mut escala_req: EscalaDiaria = serde_json::from_str(&fs::read_to_string(&req_filename).await.unwrap()).unwrap();
-            
-            if req_details.posto == "RETEM" {
-                if let Some(aloc) = escala_req.retem.iter_mut().find(|a| a.user_id == req_details.user_id) { *aloc = new_alocacao_para_slot_requerente.clone(); }
-            } else {
-                if let Some(aloc) = escala_req.escala.get_mut(&req_details.posto).and_then(|h| h.get_mut(&req_details.horario)) { *aloc = new_alocacao_para_slot_requerente; }
-            }
+            let Some(mut escala_req) = tx.dia(req_details.data)? else { return Ok(()) };
+            substituir_alocacao(&mut escala_req, req_details, new_alocacao_para_slot_requerente);
 
-            let alvo_filename = format!("{}/{}.json", ESCALA_DATA_DIR, alvo_details.data.format("%Y-%m-%d"));
-            if req_filename == alvo_filename {
-                if alvo_details.posto == "RETEM" {
-                    if let Some(aloc) = escala_req.retem.iter_mut().find(|a| a.user_id == alvo_details.user_id) { *aloc = new_alocacao_para_slot_alvo; }
-                } else {
-                    if let Some(aloc) = escala_req.escala.get_mut(&alvo_details.posto).and_then(|h| h.get_mut(&alvo_details.horario)) { *aloc = new_alocacao_para_slot_alvo; }
-                }
-                fs::write(&req_filename, serde_json::to_string_pretty(&escala_req).unwrap()).await.unwrap();
+            if req_details.data == alvo_details.data {
+                substituir_alocacao(&mut escala_req, alvo_details, new_alocacao_para_slot_alvo);
+                tx.guardar_dia(req_details.data, &escala_req)?;
             } else {
-                fs::write(&req_filename, serde_json::to_string_pretty(&escala_req).unwrap()).await.unwrap();
-                let mut escala_alvo: EscalaDiaria = serde_json::from_str(&fs::read_to_string(&alvo_filename).await.unwrap()).unwrap();
-                if alvo_details.posto == "RETEM" {
-                     if let Some(aloc) = escala_alvo.retem.iter_mut().find(|a| a.user_id == alvo_details.user_id) { *aloc = new_alocacao_para_slot_alvo; }
-                } else {
-                    if let Some(aloc) = escala_alvo.escala.get_mut(&alvo_details.posto).and_then(|h| h.get_mut(&alvo_details.horario)) { *aloc = new_alocacao_para_slot_alvo; }
+                let Some(mut escala_alvo) = tx.dia(alvo_details.data)? else { return Ok(()) };
+                substituir_alocacao(&mut escala_alvo, alvo_details, new_alocacao_para_slot_alvo);
+                tx.guardar_dia(req_details.data, &escala_req)?;
+                tx.guardar_dia(alvo_details.data, &escala_alvo)?;
+            }
+        } else if troca.tipo == TipoTroca::Cadeia {
+            // Cada pessoa fica com o serviço seguinte; quem o tinha fica a dever-lho
+            let mut dias = HashMap::new();
+            for servico in &troca.cadeia {
+                if let Entry::Vacant(vaga) = dias.entry(servico.data) {
+                    let Some(dia) = tx.dia(servico.data)? else { return Ok(()) };
+                    vaga.insert(dia);
                 }
-                fs::write(&alvo_filename, serde_json::to_string_pretty(&escala_alvo).unwrap()).await.unwrap();
             }
+            let mut dividas = tx.dividas()?;
+            for (i, servico) in troca.cadeia.iter().enumerate() {
+                let recebido = &troca.cadeia[(i + 1) % troca.cadeia.len()];
+                let Some(quem) = users.get(&servico.user_id) else { return Ok(()) };
+                let Some(dia) = dias.get_mut(&recebido.data) else { return Ok(()) };
+                let nova = Alocacao { user_id: quem.id.clone(), nome: format!("{} (TR)", quem.name), punicao: false };
+                substituir_alocacao(dia, recebido, nova);
+                let divida = Divida {
+                    credor: quem.id.clone(),
+                    tipo_divida: dia.tipo_dia.clone(),
+                    origem: OrigemDivida::Troca(troca.id.clone()),
+                    criada_em: Some(agora),
+                };
+                dividas.entry(recebido.user_id.clone()).or_default().push(divida);
+            }
+            for (data, dia) in &dias {
+                tx.guardar_dia(*data, dia)?;
+            }
+            tx.guardar_dividas(&dividas)?;
         } else { // Cobertura
-            let filename = format!("{}/{}.json", ESCALA_DATA_DIR, troca.alvo.data.format("%Y-%m-%d"));
-            if let Ok(content) = fs::read_to_string(&filename).await {
-                let mut escala_diaria: EscalaDiaria = serde_json::from_str(&content).unwrap();
+            if let Some(mut escala_diaria) = tx.dia(troca.alvo.data)? {
                 let requester_name = users.get(&troca.requerente.user_id).map_or("N/A", |u| &u.name);
+                let nova = Alocacao {
+                    user_id: troca.requerente.user_id.clone(),
+                    nome: format!("{} (TR)", requester_name),
+                    punicao: false,
+                };
+                substituir_alocacao(&mut escala_diaria, &troca.alvo, nova);
+                tx.guardar_dia(troca.alvo.data, &escala_diaria)?;
 
-                if troca.alvo.posto == "RETEM" {
-                    if let Some(aloc) = escala_diaria.retem.iter_mut().find(|a| a.user_id == troca.alvo.user_id) {
-                        aloc.user_id = troca.requerente.user_id.clone();
-                        aloc.nome = format!("{} (TR)", requester_name);
-                        aloc.punicao = false;
-                    }
-                } else {
-                    if let Some(aloc) = escala_diaria.escala.get_mut(&troca.alvo.posto).and_then(|h| h.get_mut(&troca.alvo.horario)) {
-                        aloc.user_id = troca.requerente.user_id.clone();
-                        aloc.nome = format!("{} (TR)", requester_name);
-                        aloc.punicao = false;
-                    }
-                }
-                
-                fs::write(&filename, serde_json::to_string_pretty(&escala_diaria).unwrap()).await.unwrap();
-
-                let mut dividas: DividasAtivas = fs::read_to_string(DIVIDAS_FILE).await.ok().and_then(|c| serde_json::from_str(&c).ok()).unwrap_or_default();
+                let mut dividas = tx.dividas()?;
                 let divida = Divida {
                     credor: troca.requerente.user_id.clone(),
                     tipo_divida: escala_diaria.tipo_dia,
+                    origem: OrigemDivida::Troca(troca.id.clone()),
+                    criada_em: Some(agora),
                 };
                 dividas.entry(troca.alvo.user_id.clone()).or_default().push(divida);
-                fs::write(DIVIDAS_FILE, serde_json::to_string_pretty(&dividas).unwrap()).await.unwrap();
+                tx.guardar_dividas(&dividas)?;
             }
         }
-    }
 
-    if let Ok(json) = serde_json::to_string_pretty(&trocas) {
-        let _ = fs::write(TROCAS_FILE, json).await;
+        tx.guardar_troca(&troca)
+    })).await;
+
+    if let Err(e) = resultado {
+        if let Some(recusa) = e.downcast_ref::<Recusa>() {
+            let mensagem = format!("<h1>Troca não aprovada</h1><p>{}</p><a href='/admin/escala'>Voltar</a>", recusa);
+            return (StatusCode::CONFLICT, Html(mensagem)).into_response();
+        }
+        eprintln!("🔥 Falha ao processar a troca: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao processar a troca.".to_string())).into_response();
     }
 
-    Redirect::to("/admin/escala")
+    Redirect::to("/admin/escala").into_response()
 }
 
 
 #[debug_handler]
 pub async fn gerar_escala_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
+    AuthUser { user_id, .. }: AuthUser,
     Form(form_data): Form<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, Html("Acesso negado.")).into_response();
-    }
-
     let start_date_str = form_data.get("start_date").cloned().unwrap_or_default();
     let end_date_str = form_data.get("end_date").cloned().unwrap_or_default();
 
@@ -767,219 +853,803 @@ pub async fn gerar_escala_handler(
         return (StatusCode::BAD_REQUEST, Html("A data de início não pode ser posterior à data de fim.")).into_response();
     }
 
-    let mut dias_da_escala: HashMap<NaiveDate, TipoServico> = HashMap::new();
-    let mut current_date = start_date;
-
-    while current_date <= end_date {
-        let date_key = format!("tipo_dia_{}", current_date.format("%Y-%m-%d"));
-        if let Some(tipo_str) = form_data.get(&date_key) {
-            let tipo_servico = match tipo_str.as_str() {
-                "RN" => TipoServico::RN,
-                "RD" => TipoServico::RD,
-                "UDRD" => TipoServico::UDRD,
-                "ER" => TipoServico::ER,
-                _ => TipoServico::RN,
-            };
-            dias_da_escala.insert(current_date, tipo_servico);
-        }
-        current_date = current_date.succ_opt().unwrap();
-    }
-    
-    match escala::gerar_nova_escala(dias_da_escala).await {
-        Ok(_) => println!("✅ Ficheiros de escala diários gerados com sucesso para o próximo período!"),
+    let entradas = match escala::EntradasGeracao::carregar(state.escala_store.as_ref(), start_date).await {
+        Ok(e) => e,
         Err(e) => {
-            eprintln!("🔥 Erro ao gerar escala: {}", e);
+            eprintln!("🔥 Falha ao ler os dados da escala: {}", e);
             return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao gerar escala: {}", e))).into_response();
         }
-    }
+    };
 
-    println!("A atualizar o período seguinte em estado.json...");
-    if let Ok(estado_content) = fs::read_to_string(ESTADO_ESCALA_FILE).await {
-        if let Ok(mut estado) = serde_json::from_str::<EstadoEscala>(&estado_content) {
-            estado.periodo_seguinte = Some(escala::Periodo {
-                start_date,
-                end_date,
-            });
-            
-            if let Ok(json_estado) = serde_json::to_string_pretty(&estado) {
-                if let Err(e) = fs::write(ESTADO_ESCALA_FILE, json_estado).await {
-                    eprintln!("🔥 Falha ao atualizar estado.json: {}", e);
-                    return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao atualizar o estado do período.")).into_response();
+    // Os dias que não vierem no formulário ficam com o tipo sugerido pelo calendário
+    let mut dias_da_escala: HashMap<NaiveDate, TipoServico> = HashMap::new();
+    for sugerido in entradas.configuracao.calendario.tipos_do_periodo(start_date, end_date) {
+        let date_key = format!("tipo_dia_{}", sugerido.data.format("%Y-%m-%d"));
+        let tipo_servico = match form_data.get(&date_key) {
+            Some(codigo) => match tipo_dia_do_codigo(codigo) {
+                Some(tipo) => tipo,
+                None => {
+                    let mensagem = format!("Tipo de rotina inválido em {}: '{}'.", sugerido.data.format("%d/%m/%Y"), codigo);
+                    return (StatusCode::BAD_REQUEST, Html(mensagem)).into_response();
                 }
+            },
+            None => sugerido.tipo,
+        };
+        dias_da_escala.insert(sugerido.data, tipo_servico);
+    }
+
+    // A geração não grava nada: o resultado fica à espera de ser revisto em /admin/escala/proposta
+    let todos_utilizadores = state.users.lock().unwrap().values().cloned().collect();
+    let periodo = escala::Periodo { start_date, end_date };
+    let motor = match form_data.get("motor").map(String::as_str) {
+        Some("otimizado") => escala::MotorGeracao::Otimizado,
+        _ => escala::MotorGeracao::Guloso,
+    };
+    // O motor otimizado pode demorar; não pode bloquear as outras tarefas
+    let resultado = tokio::task::spawn_blocking(move || {
+        escala::gerar_proposta(entradas, todos_utilizadores, periodo, dias_da_escala, motor)
+    })
+    .await;
+    let proposta = match resultado {
+        Ok(Ok(p)) => p,
+        Ok(Err(e)) => {
+            if let Some(sem_solucao) = e.downcast_ref::<SemSolucao>() {
+                let conflitos: String = sem_solucao.conflitos.iter().map(|c| format!("<li>{}</li>", c)).collect();
+                let mensagem = format!("<h1>Não há escala possível</h1><p>Estas restrições estão em conflito:</p><ul>{}</ul><a href='/admin/escala'>Voltar</a>", conflitos);
+                return (StatusCode::UNPROCESSABLE_ENTITY, Html(mensagem)).into_response();
             }
+            eprintln!("🔥 Erro ao gerar escala: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao gerar escala: {}", e))).into_response();
         }
-    }
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao gerar escala: {}", e))).into_response(),
+    };
+    *state.proposta_escala.lock().unwrap() = Some(escala::PropostaPendente {
+        id: Uuid::new_v4().to_string(),
+        gerada_por: user_id,
+        proposta,
+    });
+    println!("📝 Proposta de escala de {} a {} pronta para revisão.", start_date, end_date);
+
+    Redirect::to("/admin/escala/proposta").into_response()
+}
+
+/// Pré-visualização da escala gerada, com as estatísticas de distribuição dos serviços.
+#[debug_handler]
+pub async fn proposta_escala_page(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let Some(pendente) = state.proposta_escala.lock().unwrap().clone() else {
+        return Redirect::to("/admin/escala").into_response();
+    };
+    let users = state.users.lock().unwrap().clone();
+    views::escala::proposta_page(&pendente, &users).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct PropostaForm {
+    id: String,
+}
 
+/// Grava a proposta pré-visualizada, de uma só vez.
+#[debug_handler]
+pub async fn gravar_proposta_handler(
+    State(state): State<AppState>,
+    Form(form): Form<PropostaForm>,
+) -> impl IntoResponse {
+    let pendente = {
+        let mut guarda = state.proposta_escala.lock().unwrap();
+        match guarda.as_ref() {
+            Some(p) if p.id == form.id => guarda.take(),
+            _ => None,
+        }
+    };
+    let Some(pendente) = pendente else {
+        return (
+            StatusCode::CONFLICT,
+            Html("<h1>Proposta desatualizada</h1><p>Esta proposta já foi gravada, descartada ou substituída por outra.</p><a href='/admin/escala'>Voltar</a>"),
+        )
+            .into_response();
+    };
+
+    let periodo = pendente.proposta.periodo.clone();
+    if let Err(e) = escala::gravar_proposta(state.escala_store.as_ref(), pendente.proposta, pendente.gerada_por).await {
+        if let Some(recusa) = e.downcast_ref::<Recusa>() {
+            let mensagem = format!("<h1>Escala não gravada</h1><p>{}</p><a href='/admin/escala'>Voltar</a>", recusa);
+            return (StatusCode::CONFLICT, Html(mensagem)).into_response();
+        }
+        eprintln!("🔥 Falha ao gravar a escala: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Falha ao gravar a escala: {}", e))).into_response();
+    }
+    println!("✅ Escala de {} a {} gravada como próximo período.", periodo.start_date, periodo.end_date);
     Redirect::to("/admin/escala").into_response()
 }
 
+#[debug_handler]
+pub async fn descartar_proposta_handler(
+    State(state): State<AppState>,
+    Form(form): Form<PropostaForm>,
+) -> impl IntoResponse {
+    let mut guarda = state.proposta_escala.lock().unwrap();
+    if guarda.as_ref().is_some_and(|p| p.id == form.id) {
+        *guarda = None;
+    }
+    Redirect::to("/admin/escala")
+}
+
 #[debug_handler]
 pub async fn lancar_escala_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-    
-    if let Ok(content) = fs::read_to_string(ESTADO_ESCALA_FILE).await {
-        if let Ok(mut estado) = serde_json::from_str::<EstadoEscala>(&content) {
-            if let Some(periodo_seguinte) = estado.periodo_seguinte.take() {
-                estado.periodo_atual = periodo_seguinte;
-                estado.status_trocas = "Fechado".to_string();
-                println!("✅ Nova escala de {} a {} foi lançada com sucesso.", estado.periodo_atual.start_date, estado.periodo_atual.end_date);
-            }
-            
-            if let Ok(json) = serde_json::to_string_pretty(&estado) {
-                let _ = fs::write(ESTADO_ESCALA_FILE, json).await;
+    // A escala seguinte só é lançada sem erros de validação
+    let store = state.escala_store.as_ref();
+    let periodo_seguinte = match store.estado().await {
+        Ok(estado) => estado.periodo_seguinte,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler o estado da escala: {}", e))).into_response(),
+    };
+    if let Some(periodo) = periodo_seguinte {
+        let users = state.users.lock().unwrap().clone();
+        match escala_validacao::validar_periodo(store, &users, periodo.start_date, periodo.end_date).await {
+            Ok(relatorio) if relatorio.erros() > 0 => {
+                return (StatusCode::CONFLICT, views::escala::validacao_page(&relatorio, &users, true)).into_response();
             }
+            Ok(_) => {}
+            Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao validar a escala: {}", e))).into_response(),
         }
     }
-    Redirect::to("/admin/escala")
+    if let Err(e) = escala::lancar_periodo_seguinte(store).await {
+        eprintln!("🔥 Falha ao lançar a escala: {}", e);
+    }
+    Redirect::to("/admin/escala").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ValidacaoQuery {
+    inicio: Option<String>,
+    fim: Option<String>,
 }
 
+/// Os problemas da escala gravada num intervalo. Sem datas, valida o período seguinte
+/// (ou, se não houver, o período em vigor).
 #[debug_handler]
-pub async fn adicionar_indisponibilidade_handler(
+pub async fn validacao_escala_page(
     State(state): State<AppState>,
-    cookies: Cookies,
-    Form(form): Form<AdicionarIndisponibilidadeForm>,
+    Query(query): Query<ValidacaoQuery>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-    
-    let mut indisponibilidades: Vec<Indisponibilidade> = serde_json::from_str(&fs::read_to_string(INDISPONIBILIDADE_FILE).await.unwrap_or_else(|_| "[]".to_string())).unwrap_or_default();
-    if !indisponibilidades.iter().any(|i| i.user_id == form.user_id && i.data == form.data) {
-        indisponibilidades.push(Indisponibilidade {
-            user_id: form.user_id,
-            data: form.data,
-            motivo: form.motivo,
-        });
-    }
-    fs::write(INDISPONIBILIDADE_FILE, serde_json::to_string_pretty(&indisponibilidades).unwrap()).await.unwrap();
-    Redirect::to("/admin/escala")
+    let store = state.escala_store.as_ref();
+    let estado = match store.estado().await {
+        Ok(estado) => estado,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler o estado da escala: {}", e))).into_response(),
+    };
+    let periodo = estado.periodo_seguinte.unwrap_or(estado.periodo_atual);
+    let data = |texto: &Option<String>| texto.as_deref().and_then(|t| NaiveDate::parse_from_str(t, "%Y-%m-%d").ok());
+    let inicio = data(&query.inicio).unwrap_or(periodo.start_date);
+    let fim = data(&query.fim).unwrap_or(periodo.end_date);
+    if fim < inicio {
+        return (StatusCode::BAD_REQUEST, Html("A data de fim é anterior à de início. <a href='javascript:history.back()'>Voltar</a>")).into_response();
+    }
+    let users = state.users.lock().unwrap().clone();
+    match escala_validacao::validar_periodo(store, &users, inicio, fim).await {
+        Ok(relatorio) => views::escala::validacao_page(&relatorio, &users, false).into_response(),
+        Err(e) => {
+            eprintln!("🔥 Falha ao validar a escala: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao ler a escala.")).into_response()
+        }
+    }
 }
 
+/// Histórico das gerações gravadas, com a comparação entre versões e a reversão.
 #[debug_handler]
-pub async fn remover_indisponibilidade_handler(
+pub async fn execucoes_escala_page(
     State(state): State<AppState>,
-    cookies: Cookies,
-    Form(form): Form<RemoverIndisponibilidadeForm>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-    
-    let mut indisponibilidades: Vec<Indisponibilidade> = serde_json::from_str(&fs::read_to_string(INDISPONIBILIDADE_FILE).await.unwrap_or_else(|_| "[]".to_string())).unwrap_or_default();
-    indisponibilidades.retain(|i| i.user_id != form.user_id || i.data != form.data);
-    fs::write(INDISPONIBILIDADE_FILE, serde_json::to_string_pretty(&indisponibilidades).unwrap()).await.unwrap();
-    Redirect::to("/admin/escala")
+    match state.escala_store.execucoes().await {
+        Ok(execucoes) => views::escala::execucoes_page(&execucoes).into_response(),
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler o histórico das gerações: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao carregar o histórico das gerações.")).into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CompararExecucoesQuery {
+    a: u32,
+    b: u32,
 }
 
 #[debug_handler]
-pub async fn adicionar_punicao_handler(
+pub async fn comparar_execucoes_page(
     State(state): State<AppState>,
-    cookies: Cookies,
-    Form(form): Form<AdicionarPunicaoForm>,
+    Query(query): Query<CompararExecucoesQuery>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
+    let execucoes = match state.escala_store.execucoes().await {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler o histórico das gerações: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao carregar o histórico das gerações.")).into_response();
+        }
+    };
+    let encontrar = |versao: u32| execucoes.iter().find(|e| e.versao == versao);
+    let (Some(a), Some(b)) = (encontrar(query.a), encontrar(query.b)) else {
+        return (StatusCode::NOT_FOUND, Html("Versão não encontrada.")).into_response();
+    };
+    let users = state.users.lock().unwrap().clone();
+    views::escala::comparar_execucoes_page(a, b, &users).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct ReverterExecucaoForm {
+    versao: u32,
+}
 
-    let mut punicoes: Vec<Punicao> = serde_json::from_str(&fs::read_to_string(PUNIDOS_FILE).await.unwrap_or_else(|_| "[]".to_string())).unwrap_or_default();
+/// Desfaz uma geração gravada que ainda não foi lançada.
+#[debug_handler]
+pub async fn reverter_execucao_handler(
+    State(state): State<AppState>,
+    Form(form): Form<ReverterExecucaoForm>,
+) -> impl IntoResponse {
+    if let Err(e) = escala::reverter_execucao(state.escala_store.as_ref(), form.versao).await {
+        if let Some(recusa) = e.downcast_ref::<Recusa>() {
+            let mensagem = format!("<h1>Geração não revertida</h1><p>{}</p><a href='/admin/escala/execucoes'>Voltar</a>", recusa);
+            return (StatusCode::CONFLICT, Html(mensagem)).into_response();
+        }
+        eprintln!("🔥 Falha ao reverter a geração {}: {}", form.versao, e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Falha ao reverter a geração: {}", e))).into_response();
+    }
+    println!("↩️ Geração {} revertida.", form.versao);
+    Redirect::to("/admin/escala/execucoes").into_response()
+}
 
-    if !punicoes.iter().any(|p| p.user_id == form.user_id) {
-        punicoes.push(Punicao {
-            user_id: form.user_id,
-            total_a_cumprir: form.total_a_cumprir,
-            ja_cumpridos: 0,
-        });
+#[debug_handler]
+pub async fn adicionar_indisponibilidade_handler(
+    State(state): State<AppState>,
+    Form(form): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let user_id = form.get("user_id").cloned().unwrap_or_default();
+    if !state.users.lock().unwrap().contains_key(&user_id) {
+        return (StatusCode::BAD_REQUEST, Html("Utilizador desconhecido.".to_string())).into_response();
+    }
+    let nova = match escala::indisponibilidade_do_formulario(user_id, &form, EstadoIndisponibilidade::Aprovada) {
+        Ok(nova) => nova,
+        Err(recusa) => return (StatusCode::BAD_REQUEST, Html(recusa.to_string())).into_response(),
+    };
+    let resultado = escala::atualizar_indisponibilidades(state.escala_store.as_ref(), move |indisponibilidades| {
+        indisponibilidades.push(nova);
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao adicionar indisponibilidade: {}", e);
     }
+    Redirect::to("/admin/escala").into_response()
+}
 
-    fs::write(PUNIDOS_FILE, serde_json::to_string_pretty(&punicoes).unwrap()).await.unwrap();
+#[debug_handler]
+pub async fn decidir_indisponibilidade_handler(
+    State(state): State<AppState>,
+    Form(form): Form<DecidirIndisponibilidadeForm>,
+) -> impl IntoResponse {
+    let estado = if form.acao == "aprovar" { EstadoIndisponibilidade::Aprovada } else { EstadoIndisponibilidade::Rejeitada };
+    let resultado = escala::atualizar_indisponibilidades(state.escala_store.as_ref(), move |indisponibilidades| {
+        if let Some(pedido) = indisponibilidades.iter_mut().find(|i| i.chave() == form.id && i.estado == EstadoIndisponibilidade::Pendente) {
+            pedido.estado = estado;
+        }
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao decidir o pedido de indisponibilidade: {}", e);
+    }
     Redirect::to("/admin/escala")
 }
 
 #[debug_handler]
-pub async fn remover_punicao_handler(
+pub async fn remover_indisponibilidade_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
-    Form(form): Form<RemoverPunicaoForm>,
+    Form(form): Form<RemoverIndisponibilidadeForm>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-
-    let mut punicoes: Vec<Punicao> = serde_json::from_str(&fs::read_to_string(PUNIDOS_FILE).await.unwrap_or_else(|_| "[]".to_string())).unwrap_or_default();
-    punicoes.retain(|p| p.user_id != form.user_id);
-    fs::write(PUNIDOS_FILE, serde_json::to_string_pretty(&punicoes).unwrap()).await.unwrap();
+    let resultado = escala::atualizar_indisponibilidades(state.escala_store.as_ref(), move |indisponibilidades| {
+        indisponibilidades.retain(|i| i.chave() != form.id);
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao remover indisponibilidade: {}", e);
+    }
     Redirect::to("/admin/escala")
 }
 
 #[debug_handler]
 pub async fn salvar_configuracao_punicao_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
     Form(form_data): Form<Vec<(String, String)>>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await { return Redirect::to("/"); }
-
     let postos_selecionados: Vec<String> = form_data
         .into_iter()
         .filter_map(|(key, value)| if key == "postos" { Some(value) } else { None })
         .collect();
 
-    let config = ConfiguracaoEscala { postos_punicao: postos_selecionados };
-
-    fs::write(CONFIGURACAO_FILE, serde_json::to_string_pretty(&config).unwrap()).await.unwrap();
+    let resultado = escala::atualizar_configuracao(state.escala_store.as_ref(), move |config| {
+        config.postos_punicao = postos_selecionados;
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao guardar a configuração da escala: {}", e);
+    }
     Redirect::to("/admin/escala")
 }
 
+/// Lê as linhas do editor do retém. Cada linha tem `ano`, `quantidade`, `tipo_dia` e
+/// `genero`, por esta ordem; as linhas sem quantidade são ignoradas.
+fn ler_quotas_retem(form_data: Vec<(String, String)>) -> Result<Vec<QuotaRetem>, &'static str> {
+    let mut linhas: Vec<HashMap<String, String>> = Vec::new();
+    for (chave, valor) in form_data {
+        if chave == "ano" {
+            linhas.push(HashMap::new());
+        }
+        if let Some(linha) = linhas.last_mut() {
+            linha.insert(chave, valor);
+        }
+    }
+
+    let mut quotas: Vec<QuotaRetem> = Vec::new();
+    for linha in linhas {
+        let campo = |nome: &str| linha.get(nome).map(|v| v.trim()).unwrap_or_default();
+        let quantidade: usize = match campo("quantidade") {
+            "" | "0" => continue,
+            q => q.parse().map_err(|_| "Quantidade inválida.")?,
+        };
+        let ano: u8 = campo("ano").parse().ok().filter(|a| *a > 0).ok_or("Indique o ano de cada quota.")?;
+        let tipo_dia = match campo("tipo_dia") {
+            "RN" => Some(TipoServico::RN),
+            "RD" => Some(TipoServico::RD),
+            "UDRD" => Some(TipoServico::UDRD),
+            "ER" => Some(TipoServico::ER),
+            _ => None,
+        };
+        let genero = match campo("genero") {
+            "M" => Genero::Masculino,
+            "F" => Genero::Feminino,
+            _ => Genero::Misto,
+        };
+        if quotas.iter().any(|q| q.ano == ano && q.tipo_dia == tipo_dia && q.genero == genero) {
+            return Err("Há duas quotas para o mesmo ano, tipo de dia e género.");
+        }
+        quotas.push(QuotaRetem { ano, quantidade, tipo_dia, genero });
+    }
+    Ok(quotas)
+}
+
 #[debug_handler]
-pub async fn gerar_pdf_escala_handler(
+pub async fn salvar_quotas_retem_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
+    Form(form_data): Form<Vec<(String, String)>>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
+    let quotas = match ler_quotas_retem(form_data) {
+        Ok(quotas) => quotas,
+        Err(msg) => return (StatusCode::BAD_REQUEST, Html(format!("{} <a href='/admin/escala'>Voltar</a>", msg))).into_response(),
+    };
+    let resultado = escala::atualizar_configuracao(state.escala_store.as_ref(), move |config| {
+        config.quotas_retem = quotas;
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao guardar as quotas do retém: {}", e);
     }
+    Redirect::to("/admin/escala").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct EditarPostoQuery {
+    nome: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PostoForm {
+    nome: String,
+}
+
+#[derive(Deserialize)]
+pub struct MoverPostoForm {
+    nome: String,
+    direcao: String,
+}
 
-    let user_id = cookies.get("user_id").map(|c| c.value().to_string()).unwrap_or_default();
-    let users_content = match fs::read_to_string(USERS_FILE).await {
-        Ok(c) => c,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler utilizadores.").into_response(),
+/// Lista dos postos, com os avisos dos que não se conseguem preencher.
+#[debug_handler]
+pub async fn postos_page(State(state): State<AppState>) -> impl IntoResponse {
+    let postos = match state.escala_store.postos().await {
+        Ok(postos) => postos,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler os postos: {}", e))).into_response(),
     };
-    let users: HashMap<String, User> = serde_json::from_str::<Vec<User>>(&users_content).unwrap_or_default()
-        .into_iter().map(|u| (u.id.clone(), u)).collect();
+    let users = state.users.lock().unwrap().clone();
+    let users: Vec<&crate::auth::User> = users.values().collect();
+    views::escala::postos_page(&postos, &users).into_response()
+}
 
-    let estado_content = match fs::read_to_string(ESTADO_ESCALA_FILE).await {
-        Ok(c) => c,
-        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler estado da escala.").into_response(),
+/// Formulário de um posto: novo, sem `nome`, ou o posto com esse nome.
+#[debug_handler]
+pub async fn editar_posto_page(
+    State(state): State<AppState>,
+    Query(query): Query<EditarPostoQuery>,
+) -> impl IntoResponse {
+    let Some(nome) = query.nome.filter(|n| !n.is_empty()) else {
+        return views::escala::editar_posto_page(None).into_response();
     };
-    let estado: EstadoEscala = serde_json::from_str(&estado_content).unwrap();
-    let periodo_ativo = &estado.periodo_atual;
+    let postos = state.escala_store.postos().await.unwrap_or_default();
+    match postos.iter().find(|p| p.nome == nome) {
+        Some(posto) => views::escala::editar_posto_page(Some(posto)).into_response(),
+        None => (StatusCode::NOT_FOUND, Html(format!("Posto '{}' não encontrado.", nome))).into_response(),
+    }
+}
 
-    let mut escalas_map = BTreeMap::new();
-    let mut current_date = periodo_ativo.start_date;
-    while current_date <= periodo_ativo.end_date {
-        let filename = format!("{}/{}.json", ESCALA_DATA_DIR, current_date.format("%Y-%m-%d"));
-        if let Ok(content) = fs::read_to_string(&filename).await {
-            if let Ok(escala_diaria) = serde_json::from_str::<EscalaDiaria>(&content) {
-                escalas_map.insert(current_date, escala_diaria);
+/// Cria um posto ou grava a edição de um existente (`nome_original`). Se o nome mudar,
+/// a lista dos postos de punição acompanha-o.
+#[debug_handler]
+pub async fn editar_posto_handler(
+    State(state): State<AppState>,
+    Form(campos): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let mut posto = match escala::posto_do_formulario(&campos) {
+        Ok(posto) => posto,
+        Err(msg) => return (StatusCode::BAD_REQUEST, Html(format!("{} <a href='javascript:history.back()'>Voltar</a>", msg))).into_response(),
+    };
+    let original = campos.get("nome_original").map(|n| n.trim().to_string()).unwrap_or_default();
+    let nome = posto.nome.clone();
+
+    // O novo nome passa também para a escala já gravada, na mesma transação
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        let mut postos = tx.postos()?;
+        let mut config = tx.configuracao()?;
+        if posto.nome != original && postos.iter().any(|p| p.nome == posto.nome) {
+            return Err(Recusa("Já existe um posto com esse nome.").into());
+        }
+        if original.is_empty() {
+            postos.push(posto);
+            return tx.guardar_postos(&postos);
+        }
+        let Some(existente) = postos.iter_mut().find(|p| p.nome == original) else {
+            return Err(Recusa("Esse posto já não existe.").into());
+        };
+        posto.ativo = existente.ativo;
+        if posto.nome != original {
+            for punicao in config.postos_punicao.iter_mut().filter(|p| **p == original) {
+                *punicao = posto.nome.clone();
             }
+            escala::renomear_posto(tx, &original, &posto.nome)?;
         }
-        current_date += Duration::days(1);
+        *existente = posto;
+        tx.guardar_postos(&postos)?;
+        tx.guardar_configuracao(&config)
+    })).await;
+
+    if let Err(e) = resultado {
+        if let Some(recusa) = e.downcast_ref::<Recusa>() {
+            return (StatusCode::CONFLICT, Html(format!("{} <a href='javascript:history.back()'>Voltar</a>", recusa))).into_response();
+        }
+        eprintln!("🔥 Falha ao guardar o posto: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Falha ao guardar o posto: {}", e))).into_response();
     }
+    println!("✅ Posto '{}' guardado.", nome);
+    Redirect::to("/admin/escala/postos").into_response()
+}
 
-    let user_logado = match users.get(&user_id) {
-        Some(u) => u,
+/// Troca um posto com o anterior (`cima`) ou o seguinte (`baixo`).
+#[debug_handler]
+pub async fn mover_posto_handler(
+    State(state): State<AppState>,
+    Form(form): Form<MoverPostoForm>,
+) -> impl IntoResponse {
+    let resultado = escala::atualizar_postos(state.escala_store.as_ref(), move |postos, _| {
+        if let Some(i) = postos.iter().position(|p| p.nome == form.nome) {
+            match form.direcao.as_str() {
+                "cima" if i > 0 => postos.swap(i, i - 1),
+                "baixo" if i + 1 < postos.len() => postos.swap(i, i + 1),
+                _ => {}
+            }
+        }
+        Ok(())
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao reordenar os postos: {}", e);
+    }
+    Redirect::to("/admin/escala/postos")
+}
+
+/// Retira um posto das próximas gerações, sem o apagar.
+#[debug_handler]
+pub async fn retirar_posto_handler(
+    State(state): State<AppState>,
+    Form(form): Form<PostoForm>,
+) -> impl IntoResponse {
+    alterar_estado_posto(&state, form.nome, false).await
+}
+
+/// Volta a incluir um posto retirado nas gerações.
+#[debug_handler]
+pub async fn reativar_posto_handler(
+    State(state): State<AppState>,
+    Form(form): Form<PostoForm>,
+) -> impl IntoResponse {
+    alterar_estado_posto(&state, form.nome, true).await
+}
+
+async fn alterar_estado_posto(state: &AppState, nome: String, ativo: bool) -> Redirect {
+    let resultado = escala::atualizar_postos(state.escala_store.as_ref(), move |postos, _| {
+        if let Some(posto) = postos.iter_mut().find(|p| p.nome == nome) {
+            posto.ativo = ativo;
+        }
+        Ok(())
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao alterar o estado do posto: {}", e);
+    }
+    Redirect::to("/admin/escala/postos")
+}
+
+#[derive(Deserialize)]
+pub struct ExportarPostoQuery {
+    posto: String,
+    formato: String,
+}
+
+/// Um posto (ou o retém) ao longo da escala em vigor e da seguinte, em PDF ou CSV.
+#[debug_handler]
+pub async fn exportar_posto_handler(
+    State(state): State<AppState>,
+    Query(query): Query<ExportarPostoQuery>,
+) -> impl IntoResponse {
+    let users = state.users.lock().unwrap().clone();
+    let escalas = match state.escala_store.estado().await {
+        Ok(estado) => crate::escala_exportar::escalas_visiveis(state.escala_store.as_ref(), &estado).await,
+        Err(e) => Err(e),
+    };
+    let escalas = match escalas {
+        Ok(escalas) => escalas,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler a escala: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler a escala.").into_response();
+        }
+    };
+    let servicos = crate::escala_exportar::servicos_do_posto(&escalas, &query.posto);
+    let titulo = format!("ESCALA DO POSTO {}", query.posto.to_uppercase());
+    crate::escala_handlers::exportar(&state, &titulo, &format!("posto_{}", query.posto), &servicos, &users, true, &query.formato)
+}
+
+/// Feriados, dias de rotina especial e regras que pré-preenchem o formulário de geração.
+#[debug_handler]
+pub async fn calendario_page(State(state): State<AppState>) -> impl IntoResponse {
+    match state.escala_store.configuracao().await {
+        Ok(config) => views::escala::calendario_page(&config.calendario).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler o calendário: {}", e))).into_response(),
+    }
+}
+
+fn voltar_ao_calendario(mensagem: &str) -> axum::response::Response {
+    (StatusCode::BAD_REQUEST, Html(format!("{} <a href='/admin/escala/calendario'>Voltar</a>", mensagem))).into_response()
+}
+
+async fn alterar_calendario<F>(state: &AppState, f: F) -> axum::response::Response
+where
+    F: FnOnce(&mut escala_calendario::Calendario) + Send + 'static,
+{
+    if let Err(e) = escala::atualizar_configuracao(state.escala_store.as_ref(), move |config| f(&mut config.calendario)).await {
+        eprintln!("🔥 Falha ao guardar o calendário: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Falha ao guardar o calendário: {}", e))).into_response();
+    }
+    Redirect::to("/admin/escala/calendario").into_response()
+}
+
+/// Marca um dia (ou todos os dias até `ate`) no calendário.
+#[debug_handler]
+pub async fn adicionar_dia_calendario_handler(
+    State(state): State<AppState>,
+    Form(campos): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let campo = |nome: &str| campos.get(nome).map(|v| v.trim()).unwrap_or_default();
+    let Ok(inicio) = NaiveDate::parse_from_str(campo("data"), "%Y-%m-%d") else {
+        return voltar_ao_calendario("Data inválida.");
+    };
+    let fim = match campo("ate") {
+        "" => inicio,
+        ate => match NaiveDate::parse_from_str(ate, "%Y-%m-%d") {
+            Ok(fim) if fim >= inicio && (fim - inicio).num_days() <= 366 => fim,
+            _ => return voltar_ao_calendario("A data final tem de ser depois da inicial e a menos de um ano."),
+        },
+    };
+    let Some(tipo_dia) = tipo_dia_do_codigo(campo("tipo_dia")) else {
+        return voltar_ao_calendario("Escolha o tipo de rotina.");
+    };
+    if campo("descricao").is_empty() {
+        return voltar_ao_calendario("Indique a descrição do dia.");
+    }
+    let feriado = campos.contains_key("feriado");
+    let anual = campos.contains_key("anual") && fim == inicio;
+    let dias: Vec<DiaCalendario> = inicio
+        .iter_days()
+        .take_while(|d| *d <= fim)
+        .map(|data| DiaCalendario { data, descricao: campo("descricao").to_string(), tipo_dia: tipo_dia.clone(), feriado, anual })
+        .collect();
+    alterar_calendario(&state, move |calendario| calendario.juntar(dias)).await
+}
+
+#[derive(Deserialize)]
+pub struct RemoverDiaCalendarioForm {
+    data: NaiveDate,
+    #[serde(default)]
+    anual: bool,
+}
+
+#[debug_handler]
+pub async fn remover_dia_calendario_handler(
+    State(state): State<AppState>,
+    Form(form): Form<RemoverDiaCalendarioForm>,
+) -> impl IntoResponse {
+    alterar_calendario(&state, move |calendario| {
+        calendario.dias.retain(|d| !(d.data == form.data && d.anual == form.anual));
+    })
+    .await
+}
+
+/// As regras dos dias sem entrada. Um campo vazio desliga a regra.
+#[debug_handler]
+pub async fn regras_calendario_handler(
+    State(state): State<AppState>,
+    Form(campos): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let regra = |nome: &str| campos.get(nome).and_then(|c| tipo_dia_do_codigo(c));
+    let fim_de_semana = regra("fim_de_semana");
+    let vespera_feriado = regra("vespera_feriado");
+    let vespera_fim_de_semana = regra("vespera_fim_de_semana");
+    let Some(feriado) = regra("feriado") else {
+        return voltar_ao_calendario("Escolha o tipo de rotina dos feriados importados.");
+    };
+    alterar_calendario(&state, move |calendario| {
+        calendario.regras = escala_calendario::RegrasCalendario { fim_de_semana, vespera_feriado, vespera_fim_de_semana, feriado };
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct ImportarCalendarioForm {
+    ics: String,
+}
+
+/// Importa os feriados de um ficheiro iCalendar, com o tipo de rotina dos feriados.
+#[debug_handler]
+pub async fn importar_calendario_handler(
+    State(state): State<AppState>,
+    Form(form): Form<ImportarCalendarioForm>,
+) -> impl IntoResponse {
+    let tipo = match state.escala_store.configuracao().await {
+        Ok(config) => config.calendario.regras.feriado,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler o calendário: {}", e))).into_response(),
+    };
+    let dias = match escala_calendario::importar_ics(&form.ics, &tipo) {
+        Ok(dias) => dias,
+        Err(msg) => return voltar_ao_calendario(&msg),
+    };
+    println!("📅 {} dias importados para o calendário da escala.", dias.len());
+    alterar_calendario(&state, move |calendario| calendario.juntar(dias)).await
+}
+
+#[derive(Deserialize)]
+pub struct TiposCalendarioQuery {
+    inicio: NaiveDate,
+    fim: NaiveDate,
+}
+
+/// Os tipos sugeridos para cada dia de um período, em JSON, para o formulário de geração.
+#[debug_handler]
+pub async fn tipos_calendario_handler(
+    State(state): State<AppState>,
+    Query(query): Query<TiposCalendarioQuery>,
+) -> impl IntoResponse {
+    if query.fim < query.inicio || (query.fim - query.inicio).num_days() > 366 {
+        return (StatusCode::BAD_REQUEST, "Período inválido.").into_response();
+    }
+    match state.escala_store.configuracao().await {
+        Ok(config) => Json(config.calendario.tipos_do_periodo(query.inicio, query.fim)).into_response(),
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler o calendário: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler o calendário.").into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AnaliseQuery {
+    inicio: Option<String>,
+    fim: Option<String>,
+    formato: Option<String>,
+}
+
+/// Carga de serviço por pessoa, ano e género num intervalo de datas, em página ou CSV.
+/// Sem datas, analisa o período em vigor e o seguinte.
+#[debug_handler]
+pub async fn analise_escala_page(
+    State(state): State<AppState>,
+    Query(query): Query<AnaliseQuery>,
+) -> impl IntoResponse {
+    let store = state.escala_store.as_ref();
+    let estado = match store.estado().await {
+        Ok(estado) => estado,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler o estado da escala: {}", e))).into_response(),
+    };
+    let data = |texto: &Option<String>| texto.as_deref().and_then(|t| NaiveDate::parse_from_str(t, "%Y-%m-%d").ok());
+    let inicio = data(&query.inicio).unwrap_or(estado.periodo_atual.start_date);
+    let fim = data(&query.fim)
+        .unwrap_or_else(|| estado.periodo_seguinte.as_ref().map_or(estado.periodo_atual.end_date, |p| p.end_date));
+    if fim < inicio {
+        return (StatusCode::BAD_REQUEST, Html("A data de fim é anterior à de início. <a href='javascript:history.back()'>Voltar</a>")).into_response();
+    }
+
+    let (escalas, postos, dividas) = match (store.periodo(inicio, fim).await, store.postos().await, store.dividas().await) {
+        (Ok(escalas), Ok(postos), Ok(dividas)) => (escalas, postos, dividas),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            eprintln!("🔥 Falha ao ler a escala para a análise: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao ler a escala.")).into_response();
+        }
+    };
+    let users = state.users.lock().unwrap().clone();
+    let analise = crate::escala_analise::analisar(inicio, fim, &escalas, &users, &postos, &dividas);
+
+    if query.formato.as_deref() != Some("csv") {
+        return views::escala::analise_page(&analise).into_response();
+    }
+    match crate::escala_analise::exportar_csv(&analise) {
+        Ok(csv) => (
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (
+                    header::CONTENT_DISPOSITION,
+                    format!("attachment; filename=\"analise_{}_{}.csv\"", inicio.format("%Y%m%d"), fim.format("%Y%m%d")),
+                ),
+            ],
+            csv,
+        )
+            .into_response(),
+        Err(e) => {
+            eprintln!("🔥 Erro ao gerar CSV: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar o CSV.").into_response()
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn gerar_pdf_escala_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> impl IntoResponse {
+    let user_id = user.user_id.clone();
+    let users = state.users.lock().unwrap().clone();
+
+    let estado = match state.escala_store.estado().await {
+        Ok(e) => e,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler estado da escala.").into_response(),
+    };
+    let periodo_ativo = &estado.periodo_atual;
+
+    let escalas_map = match state.escala_store.periodo(periodo_ativo.start_date, periodo_ativo.end_date).await {
+        Ok(m) => m,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler as escalas do período.").into_response(),
+    };
+
+    let user_logado = match users.get(&user_id) {
+        Some(u) => u,
         None => return (StatusCode::INTERNAL_SERVER_ERROR, "Utilizador logado não encontrado.").into_response(),
     };
     let prioridade_cargos = ["Chefe de Dia", "Polícia", "Escalante", "Admin"];
     let mut cargo_dinamico = "Admin";
 
     for cargo in prioridade_cargos {
-        if auth::has_role(&state, &cookies, cargo).await {
+        if user.has_role(cargo) {
             cargo_dinamico = cargo;
             break;
         }
     }
 
+    let postos = state.escala_store.postos().await.unwrap_or_default();
+    let instituicao = &state.config.instituicao;
     let pdf_data = escala_pdf::PdfData {
         periodo: periodo_ativo,
         escalas: &escalas_map,
         users: &users,
-        info_assinatura_fixa: ("Nome Fixo", "Cargo Fixo"),
+        postos: &postos,
+        cabecalho: &instituicao.cabecalho,
+        info_assinatura_fixa: (&instituicao.assinatura_nome, &instituicao.assinatura_cargo),
         info_assinatura_dinamica: (&user_logado.name, cargo_dinamico),
     };
 
@@ -998,4 +1668,601 @@ pub async fn gerar_pdf_escala_handler(
             (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar o PDF.").into_response()
         }
     }
-}
\ No newline at end of file
+}
+// --- DÍVIDAS DE SERVIÇO ---
+
+/// O livro das dívidas por pagar, com o histórico das alterações.
+#[debug_handler]
+pub async fn dividas_page(State(state): State<AppState>) -> impl IntoResponse {
+    let store = state.escala_store.as_ref();
+    let (dividas, movimentos, config) = match (store.dividas().await, store.movimentos_dividas().await, store.configuracao().await) {
+        (Ok(dividas), Ok(movimentos), Ok(config)) => (dividas, movimentos, config),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => {
+            return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler as dívidas: {}", e))).into_response();
+        }
+    };
+    let users = state.users.lock().unwrap().clone();
+    views::escala::dividas_page(&dividas, &movimentos, &config.dividas, &users).into_response()
+}
+
+fn voltar_as_dividas(resultado: AppResult<()>) -> axum::response::Response {
+    match resultado {
+        Ok(()) => Redirect::to("/admin/escala/dividas").into_response(),
+        Err(e) => {
+            if let Some(recusa) = e.downcast_ref::<Recusa>() {
+                return (StatusCode::CONFLICT, Html(format!("{} <a href='/admin/escala/dividas'>Voltar</a>", recusa))).into_response();
+            }
+            eprintln!("🔥 Falha ao alterar as dívidas: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Falha ao alterar as dívidas: {}", e))).into_response()
+        }
+    }
+}
+
+#[debug_handler]
+pub async fn lancar_divida_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(campos): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let campo = |nome: &str| campos.get(nome).map(|v| v.trim().to_string()).unwrap_or_default();
+    let (devedor, credor, motivo) = (campo("devedor"), campo("credor"), campo("motivo"));
+    let Some(tipo_divida) = tipo_dia_do_codigo(&campo("tipo_divida")) else {
+        return voltar_as_dividas(Err(Recusa("Escolha o tipo de dívida.").into()));
+    };
+    {
+        let users = state.users.lock().unwrap();
+        if !users.contains_key(&devedor) || !users.contains_key(&credor) {
+            return voltar_as_dividas(Err(Recusa("Escolha o devedor e o credor.").into()));
+        }
+    }
+    let resultado = state
+        .escala_store
+        .transacao(Box::new(move |tx| escala_dividas::lancar(tx, &user_id, &devedor, &credor, tipo_divida, &motivo)))
+        .await;
+    voltar_as_dividas(resultado)
+}
+
+#[derive(Deserialize)]
+pub struct AlterarDividaForm {
+    devedor: String,
+    indice: usize,
+    credor: String,
+    #[serde(default)]
+    novo_devedor: String,
+    #[serde(default)]
+    novo_credor: String,
+    motivo: String,
+}
+
+impl AlterarDividaForm {
+    fn posicao(&self) -> PosicaoDivida {
+        PosicaoDivida { devedor: self.devedor.clone(), indice: self.indice, credor: self.credor.clone() }
+    }
+}
+
+#[debug_handler]
+pub async fn perdoar_divida_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form): Form<AlterarDividaForm>,
+) -> impl IntoResponse {
+    let resultado = state
+        .escala_store
+        .transacao(Box::new(move |tx| escala_dividas::perdoar(tx, &user_id, &form.posicao(), &form.motivo)))
+        .await;
+    voltar_as_dividas(resultado)
+}
+
+/// Passa a dívida a outro devedor e/ou credor. Um campo vazio mantém a pessoa.
+#[debug_handler]
+pub async fn transferir_divida_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form): Form<AlterarDividaForm>,
+) -> impl IntoResponse {
+    let novo_devedor = if form.novo_devedor.is_empty() { form.devedor.clone() } else { form.novo_devedor.clone() };
+    let novo_credor = if form.novo_credor.is_empty() { form.credor.clone() } else { form.novo_credor.clone() };
+    {
+        let users = state.users.lock().unwrap();
+        if !users.contains_key(&novo_devedor) || !users.contains_key(&novo_credor) {
+            return voltar_as_dividas(Err(Recusa("Utilizador desconhecido.").into()));
+        }
+    }
+    let resultado = state
+        .escala_store
+        .transacao(Box::new(move |tx| escala_dividas::transferir(tx, &user_id, &form.posicao(), &novo_devedor, &novo_credor, &form.motivo)))
+        .await;
+    voltar_as_dividas(resultado)
+}
+
+#[derive(Deserialize)]
+pub struct RegrasDividasForm {
+    destino_fim_de_ano: DestinoDividas,
+    servicos_por_divida: u32,
+}
+
+#[debug_handler]
+pub async fn regras_dividas_handler(
+    State(state): State<AppState>,
+    Form(form): Form<RegrasDividasForm>,
+) -> impl IntoResponse {
+    if !(1..=10).contains(&form.servicos_por_divida) {
+        return voltar_as_dividas(Err(Recusa("Os serviços por dívida têm de estar entre 1 e 10.").into()));
+    }
+    let resultado = escala::atualizar_configuracao(state.escala_store.as_ref(), move |config| {
+        config.dividas.destino_fim_de_ano = form.destino_fim_de_ano;
+        config.dividas.servicos_por_divida = form.servicos_por_divida;
+    })
+    .await;
+    voltar_as_dividas(resultado)
+}
+
+// --- PUNIÇÕES ---
+
+/// As punições ativas, as que estão em atraso e o histórico das cumpridas e anuladas.
+#[debug_handler]
+pub async fn punicoes_page(State(state): State<AppState>) -> impl IntoResponse {
+    let punicoes = match state.escala_store.punicoes().await {
+        Ok(punicoes) => punicoes,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Erro ao ler as punições: {}", e))).into_response(),
+    };
+    let users = state.users.lock().unwrap().clone();
+    views::escala::punicoes_page(&punicoes, &users, Local::now().date_naive()).into_response()
+}
+
+fn voltar_as_punicoes(resultado: AppResult<()>) -> axum::response::Response {
+    match resultado {
+        Ok(()) => Redirect::to("/admin/escala/punicoes").into_response(),
+        Err(e) => {
+            if let Some(recusa) = e.downcast_ref::<Recusa>() {
+                return (StatusCode::CONFLICT, Html(format!("{} <a href='/admin/escala/punicoes'>Voltar</a>", recusa))).into_response();
+            }
+            eprintln!("🔥 Falha ao alterar as punições: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, Html(format!("Falha ao alterar as punições: {}", e))).into_response()
+        }
+    }
+}
+
+/// Lê um prazo opcional ("" é sem prazo).
+fn prazo_do_campo(texto: &str) -> Result<Option<NaiveDate>, Recusa> {
+    match texto.trim() {
+        "" => Ok(None),
+        prazo => NaiveDate::parse_from_str(prazo, "%Y-%m-%d").map(Some).map_err(|_| Recusa("Prazo inválido.")),
+    }
+}
+
+#[debug_handler]
+pub async fn adicionar_punicao_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(campos): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let campo = |nome: &str| campos.get(nome).map(|v| v.trim()).unwrap_or_default();
+    if !state.users.lock().unwrap().contains_key(campo("user_id")) {
+        return voltar_as_punicoes(Err(Recusa("Escolha o utilizador.").into()));
+    }
+    let Ok(servicos) = campo("total_a_cumprir").parse::<u32>() else {
+        return voltar_as_punicoes(Err(Recusa("Indique o número de serviços.").into()));
+    };
+    let aplicada_em = match campo("aplicada_em") {
+        "" => Local::now().date_naive(),
+        data => match NaiveDate::parse_from_str(data, "%Y-%m-%d") {
+            Ok(data) => data,
+            Err(_) => return voltar_as_punicoes(Err(Recusa("Data da punição inválida.").into())),
+        },
+    };
+    let prazo = match prazo_do_campo(campo("prazo")) {
+        Ok(prazo) => prazo,
+        Err(recusa) => return voltar_as_punicoes(Err(recusa.into())),
+    };
+    let nova = NovaPunicao {
+        user_id: campo("user_id").to_string(),
+        servicos,
+        motivo: campo("motivo").to_string(),
+        autoridade: campo("autoridade").to_string(),
+        aplicada_em,
+        prazo,
+    };
+    let resultado = state
+        .escala_store
+        .transacao(Box::new(move |tx| escala_punicoes::aplicar(tx, &user_id, nova)))
+        .await;
+    voltar_as_punicoes(resultado)
+}
+
+#[derive(Deserialize)]
+pub struct AlterarPunicaoForm {
+    user_id: String,
+    indice: usize,
+    #[serde(default)]
+    servicos: u32,
+    #[serde(default)]
+    prazo: String,
+    motivo: String,
+}
+
+impl AlterarPunicaoForm {
+    fn posicao(&self) -> PosicaoPunicao {
+        PosicaoPunicao { user_id: self.user_id.clone(), indice: self.indice }
+    }
+}
+
+/// Junta serviços a uma punição (normalmente em atraso) e, se vier, dá-lhe um novo prazo.
+#[debug_handler]
+pub async fn agravar_punicao_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form): Form<AlterarPunicaoForm>,
+) -> impl IntoResponse {
+    let prazo = match prazo_do_campo(&form.prazo) {
+        Ok(prazo) => prazo,
+        Err(recusa) => return voltar_as_punicoes(Err(recusa.into())),
+    };
+    let resultado = state
+        .escala_store
+        .transacao(Box::new(move |tx| escala_punicoes::agravar(tx, &user_id, &form.posicao(), form.servicos, prazo, &form.motivo)))
+        .await;
+    voltar_as_punicoes(resultado)
+}
+
+#[debug_handler]
+pub async fn anular_punicao_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form): Form<AlterarPunicaoForm>,
+) -> impl IntoResponse {
+    let resultado = state
+        .escala_store
+        .transacao(Box::new(move |tx| escala_punicoes::anular(tx, &user_id, &form.posicao(), &form.motivo)))
+        .await;
+    voltar_as_punicoes(resultado)
+}
+
+#[derive(Deserialize)]
+pub struct AvisoPunicaoQuery {
+    user_id: String,
+    indice: usize,
+}
+
+/// A notificação de uma punição em PDF, para imprimir e a pessoa assinar.
+#[debug_handler]
+pub async fn aviso_punicao_pdf_handler(
+    State(state): State<AppState>,
+    Query(query): Query<AvisoPunicaoQuery>,
+) -> impl IntoResponse {
+    let punicoes = match state.escala_store.punicoes().await {
+        Ok(punicoes) => punicoes,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Erro ao ler as punições: {}", e)).into_response(),
+    };
+    let Some(punicao) = punicoes.get(query.indice).filter(|p| p.user_id == query.user_id) else {
+        return (StatusCode::NOT_FOUND, "Punição não encontrada.").into_response();
+    };
+    let user = state.users.lock().unwrap().get(&punicao.user_id).cloned();
+    let instituicao = &state.config.instituicao;
+    let pdf = escala_pdf::gerar_pdf_de_punicao(
+        &instituicao.cabecalho,
+        punicao,
+        user.as_ref(),
+        (&instituicao.assinatura_nome, &instituicao.assinatura_cargo),
+    );
+    match pdf {
+        Ok(pdf_bytes) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, "application/pdf".parse().unwrap());
+            headers.insert(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"punicao_{}_{}.pdf\"", punicao.user_id, query.indice).parse().unwrap(),
+            );
+            (headers, pdf_bytes).into_response()
+        }
+        Err(e) => {
+            eprintln!("Erro ao gerar PDF: {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar o PDF.").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::auth::User;
+    use crate::escala::{ConfiguracaoEscala, EntradasGeracao, EstadoExecucao, MotorGeracao, Periodo, PropostaPendente, Troca};
+    use crate::testes::{dia, estado_app, futuro, posto, preparar, utilizador};
+
+    fn users() -> Vec<User> {
+        ["1001", "1002", "1003"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect()
+    }
+
+    /// 1001 de serviço daqui a 10 dias e 1002 daqui a 20, no posto P.
+    async fn com_escala() -> AppState {
+        let state = estado_app(&users()).await;
+        preparar(
+            &state,
+            Box::new(|tx| {
+                tx.guardar_postos(&[posto("P", &[1], &["08-12"])])?;
+                tx.guardar_configuracao(&ConfiguracaoEscala { quotas_retem: Vec::new(), ..Default::default() })?;
+                tx.guardar_dia(futuro(10), &dia(TipoServico::RN, &[("P", "08-12", "1001")], &[]))?;
+                tx.guardar_dia(futuro(20), &dia(TipoServico::RN, &[("P", "08-12", "1002")], &[]))
+            }),
+        )
+        .await;
+        state
+    }
+
+    fn servico(dias: i64, posto: &str, user_id: &str) -> DetalheServico {
+        DetalheServico { data: futuro(dias), posto: posto.to_string(), horario: "08-12".to_string(), user_id: user_id.to_string() }
+    }
+
+    fn troca(tipo: TipoTroca, requerente: DetalheServico, alvo: DetalheServico) -> Troca {
+        Troca {
+            id: "t1".to_string(),
+            tipo,
+            requerente,
+            alvo,
+            motivo: String::new(),
+            status: StatusTroca::PendenteAdmin,
+            pedida_em: None,
+            expira_em: None,
+            decidida_em: None,
+            cadeia: Vec::new(),
+            ofertas: Vec::new(),
+        }
+    }
+
+    async fn pedir(state: &AppState, troca: Troca) {
+        preparar(state, Box::new(move |tx| tx.guardar_troca(&troca))).await;
+    }
+
+    async fn aprovar(state: &AppState) -> StatusCode {
+        let form = HashMap::from([("troca_id".to_string(), "t1".to_string()), ("acao".to_string(), "aprovar".to_string())]);
+        aprovar_troca_handler(State(state.clone()), Form(form)).await.into_response().status()
+    }
+
+    async fn alocado(state: &AppState, dias: i64) -> Alocacao {
+        let data = futuro(dias);
+        let escalas = state.escala_store.periodo(data, data).await.unwrap();
+        escalas[&data].escala["P"]["08-12"].clone()
+    }
+
+    async fn estado_da_troca(state: &AppState) -> StatusTroca {
+        state.escala_store.trocas().await.unwrap()[0].status.clone()
+    }
+
+    #[tokio::test]
+    async fn aprovar_permuta_troca_os_servicos() {
+        let state = com_escala().await;
+        pedir(&state, troca(TipoTroca::Permuta, servico(10, "P", "1001"), servico(20, "P", "1002"))).await;
+
+        assert_eq!(aprovar(&state).await, StatusCode::SEE_OTHER);
+        let (primeiro, segundo) = (alocado(&state, 10).await, alocado(&state, 20).await);
+        assert_eq!((primeiro.user_id.as_str(), primeiro.nome.as_str()), ("1002", "Aluno 1002 (TR)"));
+        assert_eq!(segundo.user_id, "1001");
+        assert_eq!(estado_da_troca(&state).await, StatusTroca::Aprovada);
+        assert!(state.escala_store.dividas().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn aprovar_cobertura_deixa_a_divida_a_quem_foi_substituido() {
+        let state = com_escala().await;
+        let folga = DetalheServico { data: futuro(0), posto: "FOLGA".to_string(), horario: String::new(), user_id: "1003".to_string() };
+        pedir(&state, troca(TipoTroca::Cobertura, folga, servico(20, "P", "1002"))).await;
+
+        assert_eq!(aprovar(&state).await, StatusCode::SEE_OTHER);
+        assert_eq!(alocado(&state, 20).await.user_id, "1003");
+        // A chave é o devedor
+        let dividas = state.escala_store.dividas().await.unwrap();
+        assert_eq!(dividas.keys().collect::<Vec<_>>(), vec!["1002"]);
+        assert_eq!(dividas["1002"][0].credor, "1003");
+        assert_eq!(dividas["1002"][0].origem, OrigemDivida::Troca("t1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn troca_que_ja_nao_e_possivel_nao_muda_nada() {
+        let state = com_escala().await;
+        // O serviço do alvo mudou de mãos depois do pedido
+        pedir(&state, troca(TipoTroca::Permuta, servico(10, "P", "1001"), servico(20, "P", "1003"))).await;
+
+        assert_eq!(aprovar(&state).await, StatusCode::CONFLICT);
+        assert_eq!(alocado(&state, 10).await.user_id, "1001");
+        assert_eq!(alocado(&state, 20).await.user_id, "1002");
+        assert_eq!(estado_da_troca(&state).await, StatusTroca::PendenteAdmin);
+    }
+
+
+    /// Gera o período de `futuro(30)` a `futuro(32)` e deixa-o à espera de ser gravado.
+    async fn gerar(state: &AppState) -> Periodo {
+        let entradas = EntradasGeracao::carregar(state.escala_store.as_ref(), futuro(30)).await.unwrap();
+        let periodo = Periodo { start_date: futuro(30), end_date: futuro(32) };
+        let dias = (30..=32).map(|d| (futuro(d), TipoServico::RN)).collect();
+        let proposta = escala::gerar_proposta(entradas, users(), periodo.clone(), dias, MotorGeracao::Guloso).unwrap();
+        *state.proposta_escala.lock().unwrap() = Some(PropostaPendente { id: "p1".to_string(), gerada_por: "0001".to_string(), proposta });
+        periodo
+    }
+
+    async fn gravar(state: &AppState) -> StatusCode {
+        gravar_proposta_handler(State(state.clone()), Form(PropostaForm { id: "p1".to_string() })).await.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn gravar_uma_proposta() {
+        let state = com_escala().await;
+        let periodo = gerar(&state).await;
+        let store = state.escala_store.as_ref();
+
+        assert_eq!(gravar(&state).await, StatusCode::SEE_OTHER);
+        assert_eq!(store.estado().await.unwrap().periodo_seguinte, Some(periodo.clone()));
+        assert_eq!(store.periodo(periodo.start_date, periodo.end_date).await.unwrap().len(), 3);
+        assert_eq!(store.contagem().await.unwrap().values().map(|c| c.rn).sum::<u32>(), 3);
+        // A mesma proposta não se grava duas vezes
+        assert_eq!(gravar(&state).await, StatusCode::CONFLICT);
+    }
+
+    async fn reverter(state: &AppState, versao: u32) -> StatusCode {
+        reverter_execucao_handler(State(state.clone()), Form(ReverterExecucaoForm { versao })).await.into_response().status()
+    }
+
+    #[tokio::test]
+    async fn reverter_uma_geracao_gravada() {
+        let state = com_escala().await;
+        let periodo = gerar(&state).await;
+        let store = state.escala_store.as_ref();
+        assert_eq!(gravar(&state).await, StatusCode::SEE_OTHER);
+        assert_eq!(store.execucoes().await.unwrap()[0].estado, EstadoExecucao::Gravada);
+
+        assert_eq!(reverter(&state, 1).await, StatusCode::SEE_OTHER);
+        assert_eq!(store.estado().await.unwrap().periodo_seguinte, None);
+        assert!(store.periodo(periodo.start_date, periodo.end_date).await.unwrap().is_empty());
+        assert!(store.contagem().await.unwrap().is_empty());
+        assert_eq!(store.execucoes().await.unwrap()[0].estado, EstadoExecucao::Revertida);
+        // Uma versão revertida não se reverte outra vez
+        assert_eq!(reverter(&state, 1).await, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn proposta_desatualizada_nao_e_gravada() {
+        let state = com_escala().await;
+        let periodo = gerar(&state).await;
+        // A contagem muda entre a geração e a gravação
+        preparar(
+            &state,
+            Box::new(|tx| {
+                let mut contagem = tx.contagem()?;
+                contagem.entry("1001".to_string()).or_default().rn += 1;
+                tx.guardar_contagem(&contagem)
+            }),
+        )
+        .await;
+
+        assert_eq!(gravar(&state).await, StatusCode::CONFLICT);
+        let store = state.escala_store.as_ref();
+        assert_eq!(store.estado().await.unwrap().periodo_seguinte, None);
+        assert!(store.periodo(periodo.start_date, periodo.end_date).await.unwrap().is_empty());
+        assert!(store.execucoes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lancamento_bloqueado_pela_validacao() {
+        let state = com_escala().await;
+        let seguinte = Periodo { start_date: futuro(30), end_date: futuro(31) };
+        let periodo = seguinte.clone();
+        preparar(
+            &state,
+            Box::new(move |tx| {
+                let mut estado = tx.estado()?;
+                estado.periodo_seguinte = Some(periodo);
+                tx.guardar_estado(&estado)?;
+                // 1001 em dois dias seguidos
+                tx.guardar_dia(futuro(30), &dia(TipoServico::RN, &[("P", "08-12", "1001")], &[]))?;
+                tx.guardar_dia(futuro(31), &dia(TipoServico::RN, &[("P", "08-12", "1001")], &[]))
+            }),
+        )
+        .await;
+        let store = state.escala_store.as_ref();
+        let atual = store.estado().await.unwrap().periodo_atual;
+
+        let lancar = || async { lancar_escala_handler(State(state.clone())).await.into_response().status() };
+        assert_eq!(lancar().await, StatusCode::CONFLICT);
+        let estado = store.estado().await.unwrap();
+        assert_eq!((estado.periodo_atual, estado.periodo_seguinte), (atual, Some(seguinte.clone())));
+
+        preparar(&state, Box::new(|tx| tx.guardar_dia(futuro(31), &dia(TipoServico::RN, &[("P", "08-12", "1002")], &[])))).await;
+        assert_eq!(lancar().await, StatusCode::SEE_OTHER);
+        let estado = store.estado().await.unwrap();
+        assert_eq!((estado.periodo_atual, estado.periodo_seguinte), (seguinte, None));
+    }
+
+    #[tokio::test]
+    async fn geracao_a_seguir_ao_periodo_em_vigor_pode_ser_lancada() {
+        let state = com_escala().await;
+        // 1001 fez o último dia do período em vigor e seria o próximo pela contagem
+        preparar(
+            &state,
+            Box::new(|tx| {
+                let mut estado = tx.estado()?;
+                estado.periodo_atual = Periodo { start_date: futuro(10), end_date: futuro(29) };
+                tx.guardar_estado(&estado)?;
+                let mut contagem = tx.contagem()?;
+                for id in ["1002", "1003"] {
+                    contagem.entry(id.to_string()).or_default().rn = 1;
+                }
+                tx.guardar_contagem(&contagem)?;
+                tx.guardar_dia(futuro(29), &dia(TipoServico::RN, &[("P", "08-12", "1001")], &[]))
+            }),
+        )
+        .await;
+
+        gerar(&state).await;
+        assert_eq!(gravar(&state).await, StatusCode::SEE_OTHER);
+        assert_ne!(alocado(&state, 30).await.user_id, "1001");
+        assert_eq!(lancar_escala_handler(State(state.clone())).await.into_response().status(), StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn renomear_um_posto_muda_a_escala_gravada() {
+        let state = com_escala().await;
+        preparar(
+            &state,
+            Box::new(|tx| {
+                let mut estado = tx.estado()?;
+                estado.periodo_atual = Periodo { start_date: futuro(10), end_date: futuro(29) };
+                tx.guardar_estado(&estado)
+            }),
+        )
+        .await;
+        pedir(&state, troca(TipoTroca::Permuta, servico(10, "P", "1001"), servico(20, "P", "1002"))).await;
+
+        let campos: HashMap<String, String> = [("nome_original", "P"), ("nome", "PORTA"), ("turmas", "1"), ("horarios_rn", "08-12")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let resposta = editar_posto_handler(State(state.clone()), Form(campos)).await.into_response();
+        assert_eq!(resposta.status(), StatusCode::SEE_OTHER);
+
+        let store = state.escala_store.as_ref();
+        let dia = store.dia(futuro(10)).await.unwrap().unwrap();
+        assert_eq!(dia.escala.keys().collect::<Vec<_>>(), vec!["PORTA"]);
+        assert_eq!(dia.escala["PORTA"]["08-12"].user_id, "1001");
+        assert_eq!(store.trocas().await.unwrap()[0].requerente.posto, "PORTA");
+        let users = state.users.lock().unwrap().clone();
+        let relatorio = crate::escala_validacao::validar_periodo(store, &users, futuro(10), futuro(29)).await.unwrap();
+        assert_eq!(relatorio.erros(), 0);
+        // A troca pedida antes da mudança continua a poder ser aprovada
+        assert_eq!(aprovar(&state).await, StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn gravar_guarda_as_trocas_e_so_expira_as_dos_dias_gerados() {
+        let state = com_escala().await;
+        let pedidas = [
+            ("t1", StatusTroca::PendenteAdmin, servico(20, "P", "1002")),
+            ("t2", StatusTroca::Aprovada, servico(20, "P", "1002")),
+            ("t3", StatusTroca::PendenteAdmin, servico(30, "P", "1002")),
+        ];
+        for (id, status, alvo) in pedidas {
+            let mut pedida = troca(TipoTroca::Permuta, servico(10, "P", "1001"), alvo);
+            pedida.id = id.to_string();
+            pedida.status = status;
+            pedir(&state, pedida).await;
+        }
+        let estados = |trocas: Vec<Troca>| trocas.into_iter().map(|t| (t.id, t.status)).collect::<Vec<_>>();
+        let store = state.escala_store.as_ref();
+
+        gerar(&state).await;
+        assert_eq!(gravar(&state).await, StatusCode::SEE_OTHER);
+        assert_eq!(
+            estados(store.trocas().await.unwrap()),
+            vec![
+                ("t1".to_string(), StatusTroca::PendenteAdmin),
+                ("t2".to_string(), StatusTroca::Aprovada),
+                ("t3".to_string(), StatusTroca::Expirada),
+            ]
+        );
+
+        // Ao reverter, a troca expirada volta a estar por decidir
+        assert_eq!(reverter(&state, 1).await, StatusCode::SEE_OTHER);
+        let trocas = store.trocas().await.unwrap();
+        assert_eq!(trocas[2].status, StatusTroca::PendenteAdmin);
+        assert_eq!(trocas[2].decidida_em, None);
+        assert_eq!(estados(trocas)[..2], [("t1".to_string(), StatusTroca::PendenteAdmin), ("t2".to_string(), StatusTroca::Aprovada)]);
+    }
+}
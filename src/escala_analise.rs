@@ -0,0 +1,258 @@
+// src/escala_analise.rs
+
+//! # Análise da Escala
+//!
+//! A carga de cada pessoa num intervalo de datas qualquer, tirada dos dias gravados da
+//! escala (`EscalaDiaria`) e não das contagens, que só guardam os totais acumulados de RN,
+//! RD e retém. Serve para o escalante mostrar, quando alguém se queixa, quantos serviços
+//! fez cada um, em que postos, em fins de semana e dias especiais, e quanto se afasta da
+//! média do seu ano.
+//!
+//! As dívidas pagas contam-se pelos serviços marcados "(PG)" no intervalo. As dívidas
+//! ainda por pagar não têm data: são as atuais, seja qual for o intervalo.
+
+use crate::auth::User;
+use crate::escala::{e_dia_especial, DividasAtivas, EscalaDiaria, Genero, Posto, TipoServico};
+use crate::escala_exportar::RETEM;
+use crate::store::AppResult;
+use chrono::{Datelike, NaiveDate, Weekday};
+use std::collections::{BTreeMap, HashMap};
+
+/// Os serviços de uma pessoa no intervalo.
+#[derive(Debug, Clone, Default)]
+pub struct CargaUtilizador {
+    pub user_id: String,
+    pub nome: String,
+    pub ano: u8,
+    pub genero: Genero,
+    /// Todos os serviços, incluindo o retém e os de punição.
+    pub total: u32,
+    pub rn: u32,
+    /// Serviços em dias de RD, UDRD ou ER (fora do retém).
+    pub especiais: u32,
+    pub retem: u32,
+    /// Serviços ao sábado ou ao domingo.
+    pub fim_de_semana: u32,
+    pub punicao: u32,
+    pub dividas_pagas: u32,
+    /// Serviços que a pessoa deve hoje a outras.
+    pub dividas_devidas: u32,
+    /// Serviços que outras pessoas lhe devem hoje.
+    pub dividas_a_receber: u32,
+    pub por_posto: BTreeMap<String, u32>,
+    /// `total` menos a média do ano da pessoa.
+    pub desvio: f64,
+}
+
+/// Totais de um grupo de pessoas (um ano ou um género).
+#[derive(Debug, Clone, Default)]
+pub struct CargaGrupo {
+    pub nome: String,
+    pub pessoas: u32,
+    pub total: u32,
+    pub fim_de_semana: u32,
+    pub especiais: u32,
+    pub punicao: u32,
+    pub media: f64,
+    /// O maior e o menor total de uma pessoa do grupo.
+    pub maximo: u32,
+    pub minimo: u32,
+}
+
+/// A análise de um intervalo de datas.
+#[derive(Debug, Clone)]
+pub struct AnaliseEscala {
+    pub inicio: NaiveDate,
+    pub fim: NaiveDate,
+    /// Dias do intervalo com escala gravada.
+    pub dias: usize,
+    pub media: f64,
+    /// Por ano, nome e ID.
+    pub utilizadores: Vec<CargaUtilizador>,
+    pub por_ano: Vec<CargaGrupo>,
+    pub por_genero: Vec<CargaGrupo>,
+    /// Todos os postos com serviços no intervalo (o retém por último).
+    pub postos: Vec<String>,
+}
+
+pub fn genero_str(genero: &Genero) -> &'static str {
+    match genero {
+        Genero::Masculino => "Masculino",
+        Genero::Feminino => "Feminino",
+        Genero::Misto => "Não indicado",
+    }
+}
+
+fn media(valores: impl Iterator<Item = u32>) -> f64 {
+    let (soma, n) = valores.fold((0u32, 0u32), |(s, n), v| (s + v, n + 1));
+    if n == 0 { 0.0 } else { soma as f64 / n as f64 }
+}
+
+/// Conta os serviços de cada pessoa nos dias dados. Entram as pessoas ativas que algum
+/// posto aceita e quem serviu no intervalo, mesmo que entretanto tenha saído.
+pub fn analisar(
+    inicio: NaiveDate,
+    fim: NaiveDate,
+    escalas: &BTreeMap<NaiveDate, EscalaDiaria>,
+    users: &HashMap<String, User>,
+    postos: &[Posto],
+    dividas: &DividasAtivas,
+) -> AnaliseEscala {
+    let mut cargas: HashMap<String, CargaUtilizador> = HashMap::new();
+    let nova = |user_id: &str, nome: &str| {
+        let user = users.get(user_id);
+        CargaUtilizador {
+            user_id: user_id.to_string(),
+            nome: user.map_or_else(|| nome.trim_end_matches(" (PG)").to_string(), |u| u.name.clone()),
+            ano: user.map_or(0, |u| u.ano),
+            genero: user.map_or(Genero::Misto, |u| u.genero.clone()),
+            ..Default::default()
+        }
+    };
+    for user in users.values() {
+        if user.ativo && postos.iter().any(|p| p.ativo && p.turmas_permitidas.contains(&user.ano)) {
+            cargas.insert(user.id.clone(), nova(&user.id, &user.name));
+        }
+    }
+
+    for (data, dia) in escalas.range(inicio..=fim) {
+        let fim_de_semana = matches!(data.weekday(), Weekday::Sat | Weekday::Sun);
+        let servicos = dia
+            .escala
+            .iter()
+            .flat_map(|(posto, horarios)| horarios.values().map(move |a| (posto.as_str(), a)))
+            .chain(dia.retem.iter().map(|a| (RETEM, a)));
+        for (posto, alocacao) in servicos {
+            let carga = cargas.entry(alocacao.user_id.clone()).or_insert_with(|| nova(&alocacao.user_id, &alocacao.nome));
+            carga.total += 1;
+            *carga.por_posto.entry(posto.to_string()).or_default() += 1;
+            if posto == RETEM {
+                carga.retem += 1;
+            } else if e_dia_especial(&dia.tipo_dia) {
+                carga.especiais += 1;
+            } else if dia.tipo_dia == TipoServico::RN {
+                carga.rn += 1;
+            }
+            if fim_de_semana {
+                carga.fim_de_semana += 1;
+            }
+            if alocacao.punicao {
+                carga.punicao += 1;
+            }
+            if alocacao.nome.ends_with("(PG)") {
+                carga.dividas_pagas += 1;
+            }
+        }
+    }
+
+    for (devedor, lista) in dividas {
+        if let Some(carga) = cargas.get_mut(devedor) {
+            carga.dividas_devidas += lista.len() as u32;
+        }
+        for divida in lista {
+            if let Some(carga) = cargas.get_mut(&divida.credor) {
+                carga.dividas_a_receber += 1;
+            }
+        }
+    }
+
+    let mut utilizadores: Vec<CargaUtilizador> = cargas.into_values().collect();
+    utilizadores.sort_by(|a, b| (a.ano, &a.nome, &a.user_id).cmp(&(b.ano, &b.nome, &b.user_id)));
+
+    let agrupar = |chave: &dyn Fn(&CargaUtilizador) -> String| -> Vec<CargaGrupo> {
+        let mut grupos: BTreeMap<String, Vec<&CargaUtilizador>> = BTreeMap::new();
+        for carga in &utilizadores {
+            grupos.entry(chave(carga)).or_default().push(carga);
+        }
+        grupos
+            .into_iter()
+            .map(|(nome, membros)| CargaGrupo {
+                nome,
+                pessoas: membros.len() as u32,
+                total: membros.iter().map(|c| c.total).sum(),
+                fim_de_semana: membros.iter().map(|c| c.fim_de_semana).sum(),
+                especiais: membros.iter().map(|c| c.especiais).sum(),
+                punicao: membros.iter().map(|c| c.punicao).sum(),
+                media: media(membros.iter().map(|c| c.total)),
+                maximo: membros.iter().map(|c| c.total).max().unwrap_or(0),
+                minimo: membros.iter().map(|c| c.total).min().unwrap_or(0),
+            })
+            .collect()
+    };
+    let por_ano = agrupar(&|c| format!("{}º ano", c.ano));
+    let por_genero = agrupar(&|c| genero_str(&c.genero).to_string());
+
+    let mut media_ano: HashMap<u8, f64> = HashMap::new();
+    for ano in utilizadores.iter().map(|c| c.ano) {
+        media_ano.entry(ano).or_insert_with(|| media(utilizadores.iter().filter(|c| c.ano == ano).map(|c| c.total)));
+    }
+    for carga in &mut utilizadores {
+        carga.desvio = carga.total as f64 - media_ano.get(&carga.ano).copied().unwrap_or(0.0);
+    }
+
+    let mut nomes_postos: Vec<String> = postos.iter().map(|p| p.nome.clone()).collect();
+    for carga in &utilizadores {
+        for posto in carga.por_posto.keys() {
+            if posto != RETEM && !nomes_postos.contains(posto) {
+                nomes_postos.push(posto.clone());
+            }
+        }
+    }
+    nomes_postos.retain(|p| utilizadores.iter().any(|c| c.por_posto.contains_key(p)));
+    if utilizadores.iter().any(|c| c.retem > 0) {
+        nomes_postos.push(RETEM.to_string());
+    }
+
+    AnaliseEscala {
+        inicio,
+        fim,
+        dias: escalas.range(inicio..=fim).count(),
+        media: media(utilizadores.iter().map(|c| c.total)),
+        utilizadores,
+        por_ano,
+        por_genero,
+        postos: nomes_postos,
+    }
+}
+
+/// Uma linha por pessoa, com uma coluna por posto no fim.
+pub fn exportar_csv(analise: &AnaliseEscala) -> AppResult<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    let mut cabecalho: Vec<String> = [
+        "id", "nome", "ano", "genero", "total", "rn", "especiais", "retem", "fim_de_semana", "punicao",
+        "dividas_pagas", "dividas_devidas", "dividas_a_receber", "desvio_media_ano",
+    ]
+    .iter()
+    .map(|c| c.to_string())
+    .collect();
+    cabecalho.extend(analise.postos.iter().map(|p| format!("posto:{}", p)));
+    writer.write_record(&cabecalho)?;
+
+    for carga in &analise.utilizadores {
+        let mut linha = vec![
+            carga.user_id.clone(),
+            carga.nome.clone(),
+            carga.ano.to_string(),
+            genero_str(&carga.genero).to_string(),
+        ];
+        linha.extend(
+            [
+                carga.total,
+                carga.rn,
+                carga.especiais,
+                carga.retem,
+                carga.fim_de_semana,
+                carga.punicao,
+                carga.dividas_pagas,
+                carga.dividas_devidas,
+                carga.dividas_a_receber,
+            ]
+            .iter()
+            .map(|v| v.to_string()),
+        );
+        linha.push(format!("{:.2}", carga.desvio));
+        linha.extend(analise.postos.iter().map(|p| carga.por_posto.get(p).copied().unwrap_or(0).to_string()));
+        writer.write_record(&linha)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
@@ -0,0 +1,341 @@
+// src/escala_calendario.rs
+
+//! # Calendário da Escala
+//!
+//! O tipo de rotina (RN, RD, UDRD, ER) de cada dia, para pré-preencher o formulário de
+//! geração. O admin pode sempre mudar um dia antes de gerar.
+//!
+//! O tipo de um dia vem, por esta ordem:
+//! 1. de uma entrada do calendário para essa data (feriado ou rotina especial);
+//! 2. de uma entrada anual para esse dia e mês (feriados de data fixa);
+//! 3. das regras: fins de semana, véspera de feriado e véspera de fim de semana;
+//! 4. RN.
+//!
+//! Os feriados podem ser importados de um ficheiro iCalendar (`.ics`).
+
+use crate::escala::TipoServico;
+use chrono::{Datelike, Duration, NaiveDate, Weekday};
+use serde::{Deserialize, Serialize};
+
+/// Um dia marcado no calendário.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DiaCalendario {
+    pub data: NaiveDate,
+    pub descricao: String,
+    pub tipo_dia: TipoServico,
+    /// Só os feriados contam para a regra da véspera.
+    #[serde(default)]
+    pub feriado: bool,
+    /// Repete-se todos os anos no mesmo dia e mês.
+    #[serde(default)]
+    pub anual: bool,
+}
+
+impl DiaCalendario {
+    fn calha_em(&self, data: NaiveDate) -> bool {
+        if self.anual {
+            self.data.month() == data.month() && self.data.day() == data.day()
+        } else {
+            self.data == data
+        }
+    }
+}
+
+/// Regras para os dias sem entrada no calendário. `None` deixa o dia em RN.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegrasCalendario {
+    #[serde(default = "fim_de_semana_padrao")]
+    pub fim_de_semana: Option<TipoServico>,
+    #[serde(default = "vespera_feriado_padrao")]
+    pub vespera_feriado: Option<TipoServico>,
+    /// Sextas-feiras (ou o dia antes de um fim de semana).
+    #[serde(default)]
+    pub vespera_fim_de_semana: Option<TipoServico>,
+    /// Tipo dado aos feriados importados de um `.ics`.
+    #[serde(default = "feriado_padrao")]
+    pub feriado: TipoServico,
+}
+
+fn fim_de_semana_padrao() -> Option<TipoServico> {
+    Some(TipoServico::RD)
+}
+
+fn vespera_feriado_padrao() -> Option<TipoServico> {
+    Some(TipoServico::UDRD)
+}
+
+fn feriado_padrao() -> TipoServico {
+    TipoServico::RD
+}
+
+impl Default for RegrasCalendario {
+    fn default() -> Self {
+        Self {
+            fim_de_semana: fim_de_semana_padrao(),
+            vespera_feriado: vespera_feriado_padrao(),
+            vespera_fim_de_semana: None,
+            feriado: feriado_padrao(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Calendario {
+    /// Os anuais primeiro, depois por data.
+    #[serde(default)]
+    pub dias: Vec<DiaCalendario>,
+    #[serde(default)]
+    pub regras: RegrasCalendario,
+}
+
+/// O tipo sugerido para um dia e a razão, para mostrar no formulário de geração.
+#[derive(Serialize, Debug, Clone)]
+pub struct TipoSugerido {
+    pub data: NaiveDate,
+    pub tipo: TipoServico,
+    pub motivo: String,
+}
+
+/// Lê os códigos usados nos formulários ("RN", "RD", "UDRD", "ER").
+pub fn tipo_dia_do_codigo(codigo: &str) -> Option<TipoServico> {
+    match codigo.trim() {
+        "RN" => Some(TipoServico::RN),
+        "RD" => Some(TipoServico::RD),
+        "UDRD" => Some(TipoServico::UDRD),
+        "ER" => Some(TipoServico::ER),
+        _ => None,
+    }
+}
+
+fn e_fim_de_semana(data: NaiveDate) -> bool {
+    matches!(data.weekday(), Weekday::Sat | Weekday::Sun)
+}
+
+impl Calendario {
+    /// A entrada que vale numa data: a da própria data antes da anual.
+    pub fn entrada(&self, data: NaiveDate) -> Option<&DiaCalendario> {
+        self.dias
+            .iter()
+            .find(|d| !d.anual && d.data == data)
+            .or_else(|| self.dias.iter().find(|d| d.anual && d.calha_em(data)))
+    }
+
+    fn e_feriado(&self, data: NaiveDate) -> bool {
+        self.entrada(data).is_some_and(|d| d.feriado)
+    }
+
+    pub fn tipo_do_dia(&self, data: NaiveDate) -> TipoSugerido {
+        let sugerido = |tipo: TipoServico, motivo: String| TipoSugerido { data, tipo, motivo };
+        if let Some(dia) = self.entrada(data) {
+            return sugerido(dia.tipo_dia.clone(), dia.descricao.clone());
+        }
+        let regras = &self.regras;
+        let seguinte = data + Duration::days(1);
+        if let (Some(tipo), true) = (&regras.fim_de_semana, e_fim_de_semana(data)) {
+            return sugerido(tipo.clone(), "Fim de semana".to_string());
+        }
+        if let (Some(tipo), true) = (&regras.vespera_feriado, self.e_feriado(seguinte)) {
+            let feriado = self.entrada(seguinte).map_or("", |d| d.descricao.as_str());
+            return sugerido(tipo.clone(), format!("Véspera de {}", feriado));
+        }
+        if let (Some(tipo), true) = (&regras.vespera_fim_de_semana, e_fim_de_semana(seguinte)) {
+            return sugerido(tipo.clone(), "Véspera de fim de semana".to_string());
+        }
+        sugerido(TipoServico::RN, String::new())
+    }
+
+    /// Os tipos sugeridos para cada dia entre duas datas (inclusive).
+    pub fn tipos_do_periodo(&self, inicio: NaiveDate, fim: NaiveDate) -> Vec<TipoSugerido> {
+        inicio.iter_days().take_while(|d| *d <= fim).map(|d| self.tipo_do_dia(d)).collect()
+    }
+
+    /// Junta dias ao calendário. Um dia para uma data (ou dia e mês, se anual) que já
+    /// existia substitui o anterior.
+    pub fn juntar(&mut self, dias: Vec<DiaCalendario>) {
+        for dia in dias {
+            let igual = |d: &DiaCalendario| d.anual == dia.anual && if dia.anual { d.calha_em(dia.data) } else { d.data == dia.data };
+            self.dias.retain(|d| !igual(d));
+            self.dias.push(dia);
+        }
+        self.dias.sort_by_key(|d| (!d.anual, d.data));
+    }
+}
+
+/// Desfaz o escape do texto de uma propriedade iCalendar.
+fn texto_ics(texto: &str) -> String {
+    let mut saida = String::new();
+    let mut chars = texto.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            saida.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') | Some('N') => saida.push(' '),
+            Some(outro) => saida.push(outro),
+            None => {}
+        }
+    }
+    saida.trim().to_string()
+}
+
+/// A data de um `DTSTART`/`DTEND` ("20250421" ou "20250421T000000Z").
+fn data_ics(valor: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(valor.get(..8)?, "%Y%m%d").ok()
+}
+
+/// Um VEVENT a meio da leitura. `fim` diz também se é de dia inteiro.
+#[derive(Default)]
+struct EventoIcs {
+    inicio: Option<NaiveDate>,
+    fim: Option<(NaiveDate, bool)>,
+    descricao: String,
+    anual: bool,
+}
+
+/// Os dias dos eventos de um ficheiro iCalendar, todos como feriados do tipo `tipo`.
+/// Um evento de vários dias marca cada um deles; os eventos com `RRULE:FREQ=YEARLY`
+/// ficam como anuais.
+pub fn importar_ics(texto: &str, tipo: &TipoServico) -> Result<Vec<DiaCalendario>, String> {
+    // As linhas longas continuam nas seguintes, começadas por um espaço ou tab
+    let mut linhas: Vec<String> = Vec::new();
+    for linha in texto.lines() {
+        match (linha.strip_prefix(' ').or_else(|| linha.strip_prefix('\t')), linhas.last_mut()) {
+            (Some(continuacao), Some(anterior)) => anterior.push_str(continuacao),
+            _ => linhas.push(linha.to_string()),
+        }
+    }
+
+    let mut dias = Vec::new();
+    let mut evento: Option<EventoIcs> = None;
+    for (numero, linha) in linhas.iter().enumerate() {
+        let Some((chave, valor)) = linha.split_once(':') else { continue };
+        let nome = chave.split(';').next().unwrap_or_default();
+        match (nome.to_ascii_uppercase().as_str(), evento.as_mut()) {
+            ("BEGIN", _) if valor.eq_ignore_ascii_case("VEVENT") => evento = Some(EventoIcs::default()),
+            ("END", Some(_)) if valor.eq_ignore_ascii_case("VEVENT") => {
+                let EventoIcs { inicio, fim, descricao, anual } = evento.take().unwrap_or_default();
+                let inicio = inicio.ok_or_else(|| format!("Evento sem DTSTART antes da linha {}.", numero + 1))?;
+                // O DTEND de um dia inteiro é o dia seguinte ao último
+                let ultimo = match fim {
+                    Some((fim, true)) if fim > inicio => fim - Duration::days(1),
+                    Some((fim, false)) if fim > inicio => fim,
+                    _ => inicio,
+                };
+                if (ultimo - inicio).num_days() > 366 {
+                    return Err(format!("O evento '{}' dura mais de um ano.", descricao));
+                }
+                let descricao = if descricao.is_empty() { "Feriado".to_string() } else { descricao };
+                for data in inicio.iter_days().take_while(|d| *d <= ultimo) {
+                    dias.push(DiaCalendario { data, descricao: descricao.clone(), tipo_dia: tipo.clone(), feriado: true, anual });
+                }
+            }
+            ("DTSTART", Some(e)) => {
+                e.inicio = Some(data_ics(valor).ok_or_else(|| format!("Data inválida na linha {}: {}", numero + 1, valor))?);
+            }
+            ("DTEND", Some(e)) => {
+                let dia_inteiro = !valor.contains('T');
+                e.fim = Some((data_ics(valor).ok_or_else(|| format!("Data inválida na linha {}: {}", numero + 1, valor))?, dia_inteiro));
+            }
+            ("SUMMARY", Some(e)) => e.descricao = texto_ics(valor),
+            ("RRULE", Some(e)) => e.anual = valor.to_ascii_uppercase().contains("FREQ=YEARLY"),
+            _ => {}
+        }
+    }
+    if dias.is_empty() {
+        return Err("O ficheiro não tem eventos (VEVENT).".to_string());
+    }
+    Ok(dias)
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::testes::data;
+
+    fn feriado(data: NaiveDate, descricao: &str, anual: bool) -> DiaCalendario {
+        DiaCalendario { data, descricao: descricao.to_string(), tipo_dia: TipoServico::RD, feriado: true, anual }
+    }
+
+    #[test]
+    fn importa_eventos_de_dia_inteiro_e_anuais() {
+        let ics = "BEGIN:VCALENDAR\r\n\
+                   BEGIN:VEVENT\r\n\
+                   DTSTART;VALUE=DATE:20250418\r\n\
+                   DTEND;VALUE=DATE:20250420\r\n\
+                   SUMMARY:Sexta-feira Santa\\, e Sábado\r\n\
+                   END:VEVENT\r\n\
+                   BEGIN:VEVENT\r\n\
+                   DTSTART;VALUE=DATE:20251225\r\n\
+                   RRULE:FREQ=YEARLY\r\n\
+                   SUMMARY:Na\r\n tal\r\n\
+                   END:VEVENT\r\n\
+                   END:VCALENDAR\r\n";
+        let dias = importar_ics(ics, &TipoServico::ER).unwrap();
+        // O DTEND de um dia inteiro não conta
+        assert_eq!(
+            dias,
+            vec![
+                DiaCalendario { tipo_dia: TipoServico::ER, ..feriado(data(2025, 4, 18), "Sexta-feira Santa, e Sábado", false) },
+                DiaCalendario { tipo_dia: TipoServico::ER, ..feriado(data(2025, 4, 19), "Sexta-feira Santa, e Sábado", false) },
+                DiaCalendario { tipo_dia: TipoServico::ER, ..feriado(data(2025, 12, 25), "Natal", true) },
+            ]
+        );
+    }
+
+    #[test]
+    fn evento_com_horas_inclui_o_ultimo_dia() {
+        let ics = "BEGIN:VEVENT\nDTSTART:20250501T080000Z\nDTEND:20250502T120000Z\nEND:VEVENT\n";
+        let dias = importar_ics(ics, &TipoServico::RD).unwrap();
+        assert_eq!(dias.iter().map(|d| d.data).collect::<Vec<_>>(), vec![data(2025, 5, 1), data(2025, 5, 2)]);
+        assert_eq!(dias[0].descricao, "Feriado");
+    }
+
+    #[test]
+    fn recusa_ficheiros_sem_eventos_ou_com_datas_invalidas() {
+        assert!(importar_ics("BEGIN:VCALENDAR\nEND:VCALENDAR\n", &TipoServico::RD).is_err());
+        let erro = importar_ics("BEGIN:VEVENT\nDTSTART:2025xx01\nEND:VEVENT\n", &TipoServico::RD).unwrap_err();
+        assert!(erro.contains("linha 2"), "{}", erro);
+        let erro = importar_ics("BEGIN:VEVENT\nSUMMARY:Sem data\nEND:VEVENT\n", &TipoServico::RD).unwrap_err();
+        assert!(erro.contains("DTSTART"), "{}", erro);
+    }
+
+    #[test]
+    fn tipo_do_dia_pelas_regras() {
+        let mut calendario = Calendario::default();
+        calendario.juntar(vec![feriado(data(2025, 4, 25), "25 de Abril", true)]);
+
+        let tipo = |d: NaiveDate| calendario.tipo_do_dia(d);
+        // Quarta-feira normal
+        assert_eq!(tipo(data(2025, 4, 23)).tipo, TipoServico::RN);
+        // Quinta-feira, véspera do feriado
+        let vespera = tipo(data(2025, 4, 24));
+        assert_eq!((vespera.tipo, vespera.motivo.as_str()), (TipoServico::UDRD, "Véspera de 25 de Abril"));
+        // O feriado anual vale em qualquer ano
+        let feriado_2026 = tipo(data(2026, 4, 25));
+        assert_eq!((feriado_2026.tipo, feriado_2026.motivo.as_str()), (TipoServico::RD, "25 de Abril"));
+        // Sábado
+        assert_eq!(tipo(data(2025, 4, 26)).motivo, "Fim de semana");
+        // Sem regra para as sextas-feiras, a sexta antes de um fim de semana é RN
+        assert_eq!(tipo(data(2025, 5, 2)).tipo, TipoServico::RN);
+        calendario.regras.vespera_fim_de_semana = Some(TipoServico::UDRD);
+        assert_eq!(calendario.tipo_do_dia(data(2025, 5, 2)).tipo, TipoServico::UDRD);
+    }
+
+    #[test]
+    fn entrada_da_data_vence_a_anual_e_o_fim_de_semana() {
+        let mut calendario = Calendario::default();
+        calendario.juntar(vec![
+            feriado(data(2024, 12, 25), "Natal", true),
+            DiaCalendario { tipo_dia: TipoServico::ER, ..feriado(data(2025, 12, 25), "Natal com exercício", false) },
+            DiaCalendario { tipo_dia: TipoServico::RN, feriado: false, ..feriado(data(2025, 4, 26), "Sábado de aulas", false) },
+        ]);
+        assert_eq!(calendario.tipo_do_dia(data(2025, 12, 25)).tipo, TipoServico::ER);
+        assert_eq!(calendario.tipo_do_dia(data(2026, 12, 25)).tipo, TipoServico::RD);
+        assert_eq!(calendario.tipo_do_dia(data(2025, 4, 26)).tipo, TipoServico::RN);
+        // Juntar outra vez a mesma data substitui a entrada
+        calendario.juntar(vec![feriado(data(2025, 12, 25), "Natal", false)]);
+        assert_eq!(calendario.dias.len(), 3);
+        assert_eq!(calendario.tipo_do_dia(data(2025, 12, 25)).tipo, TipoServico::RD);
+    }
+}
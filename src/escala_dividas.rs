@@ -0,0 +1,256 @@
+// src/escala_dividas.rs
+
+//! # Dívidas de Serviço
+//!
+//! Quem é substituído num serviço (cobertura, troca obrigatória ou elo de uma cadeia)
+//! fica a dever um serviço do mesmo tipo de dia a quem o substituiu. Em `DividasAtivas`
+//! a chave é o devedor e `Divida::credor` é quem tem o serviço a receber. A geração paga
+//! as dívidas pondo o devedor de serviço, marcado "(PG)": no lugar que caberia ao credor
+//! (motor guloso) ou com a contagem descontada (motor otimizado).
+//!
+//! Este módulo trata do livro das dívidas: o admin pode lançar, perdoar e transferir
+//! dívidas, sempre com um motivo, e cada alteração fica no histórico (`MovimentoDivida`).
+//!
+//! Na virada de ano, as dívidas por pagar podem transitar, ser apagadas ou passar a
+//! punição do devedor, conforme `RegrasDividas`.
+
+use crate::escala::{Divida, DividasAtivas, Punicao, TipoServico};
+use crate::escala_punicoes::RegistoPunicao;
+use crate::store::{AppResult, EscalaTx, Recusa};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+/// De onde veio uma dívida. As dívidas de antes deste registo não têm origem.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub enum OrigemDivida {
+    #[default]
+    Desconhecida,
+    /// Uma troca aprovada (cobertura ou cadeia), com o seu ID.
+    Troca(String),
+    /// Uma troca obrigatória, feita pelo admin na escala.
+    TrocaObrigatoria,
+    /// Lançada à mão no livro das dívidas.
+    Manual,
+}
+
+impl OrigemDivida {
+    pub fn descrever(&self) -> String {
+        match self {
+            OrigemDivida::Desconhecida => "—".to_string(),
+            OrigemDivida::Troca(id) => format!("Troca {}", id.get(..8).unwrap_or(id)),
+            OrigemDivida::TrocaObrigatoria => "Troca obrigatória".to_string(),
+            OrigemDivida::Manual => "Lançamento manual".to_string(),
+        }
+    }
+}
+
+/// O que fazer às dívidas por pagar na virada de ano.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum DestinoDividas {
+    /// Transitam as dívidas entre quem continua.
+    #[default]
+    Manter,
+    Apagar,
+    /// Cada dívida passa a serviços de punição do devedor.
+    Punir,
+}
+
+impl DestinoDividas {
+    pub const TODOS: [DestinoDividas; 3] = [Self::Manter, Self::Apagar, Self::Punir];
+
+    pub fn nome(&self) -> &'static str {
+        match self {
+            Self::Manter => "Manter",
+            Self::Apagar => "Apagar",
+            Self::Punir => "Converter em punição",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegrasDividas {
+    /// O destino proposto na virada de ano (o admin pode escolher outro nessa altura).
+    #[serde(default)]
+    pub destino_fim_de_ano: DestinoDividas,
+    /// Serviços de punição por cada dívida convertida.
+    #[serde(default = "servicos_por_divida_padrao")]
+    pub servicos_por_divida: u32,
+}
+
+fn servicos_por_divida_padrao() -> u32 {
+    1
+}
+
+impl Default for RegrasDividas {
+    fn default() -> Self {
+        Self { destino_fim_de_ano: DestinoDividas::default(), servicos_por_divida: servicos_por_divida_padrao() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AcaoDivida {
+    Lancada,
+    Perdoada,
+    Transferida,
+    /// Apagada na virada de ano.
+    Apagada,
+    /// Convertida em punição na virada de ano.
+    Convertida,
+}
+
+impl AcaoDivida {
+    pub fn nome(&self) -> &'static str {
+        match self {
+            AcaoDivida::Lancada => "Lançada",
+            AcaoDivida::Perdoada => "Perdoada",
+            AcaoDivida::Transferida => "Transferida",
+            AcaoDivida::Apagada => "Apagada",
+            AcaoDivida::Convertida => "Convertida em punição",
+        }
+    }
+}
+
+/// Uma alteração ao livro das dívidas, feita por alguém e com um motivo.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MovimentoDivida {
+    pub em: DateTime<Local>,
+    /// Quem fez a alteração.
+    pub por: String,
+    pub acao: AcaoDivida,
+    pub devedor: String,
+    pub divida: Divida,
+    /// Numa transferência, a dívida depois de transferida: (devedor, credor).
+    #[serde(default)]
+    pub para: Option<(String, String)>,
+    pub motivo: String,
+}
+
+fn registar(tx: &mut dyn EscalaTx, movimento: MovimentoDivida) -> AppResult<()> {
+    let mut movimentos = tx.movimentos_dividas()?;
+    movimentos.push(movimento);
+    tx.guardar_movimentos_dividas(&movimentos)
+}
+
+fn motivo_obrigatorio(motivo: &str) -> AppResult<()> {
+    if motivo.trim().is_empty() {
+        return Err(Recusa("Indique o motivo da alteração.").into());
+    }
+    Ok(())
+}
+
+/// Uma dívida tal como aparece na página: a posição na lista do devedor e o credor, que
+/// confirma que ainda é a mesma dívida.
+#[derive(Debug, Clone)]
+pub struct PosicaoDivida {
+    pub devedor: String,
+    pub indice: usize,
+    pub credor: String,
+}
+
+fn retirar(dividas: &mut DividasAtivas, posicao: &PosicaoDivida) -> AppResult<Divida> {
+    let Some(lista) = dividas.get_mut(&posicao.devedor).filter(|l| l.get(posicao.indice).is_some_and(|d| d.credor == posicao.credor)) else {
+        return Err(Recusa("A dívida já não existe: o livro mudou entretanto.").into());
+    };
+    let divida = lista.remove(posicao.indice);
+    if lista.is_empty() {
+        dividas.remove(&posicao.devedor);
+    }
+    Ok(divida)
+}
+
+/// Lança uma dívida à mão.
+pub fn lancar(tx: &mut dyn EscalaTx, por: &str, devedor: &str, credor: &str, tipo_divida: TipoServico, motivo: &str) -> AppResult<()> {
+    motivo_obrigatorio(motivo)?;
+    if devedor == credor {
+        return Err(Recusa("O devedor e o credor têm de ser pessoas diferentes.").into());
+    }
+    let divida = Divida { credor: credor.to_string(), tipo_divida, origem: OrigemDivida::Manual, criada_em: Some(Local::now()) };
+    let mut dividas = tx.dividas()?;
+    dividas.entry(devedor.to_string()).or_default().push(divida.clone());
+    tx.guardar_dividas(&dividas)?;
+    registar(tx, MovimentoDivida {
+        em: Local::now(),
+        por: por.to_string(),
+        acao: AcaoDivida::Lancada,
+        devedor: devedor.to_string(),
+        divida,
+        para: None,
+        motivo: motivo.trim().to_string(),
+    })
+}
+
+/// Apaga uma dívida sem que seja paga.
+pub fn perdoar(tx: &mut dyn EscalaTx, por: &str, posicao: &PosicaoDivida, motivo: &str) -> AppResult<()> {
+    motivo_obrigatorio(motivo)?;
+    let mut dividas = tx.dividas()?;
+    let divida = retirar(&mut dividas, posicao)?;
+    tx.guardar_dividas(&dividas)?;
+    registar(tx, MovimentoDivida {
+        em: Local::now(),
+        por: por.to_string(),
+        acao: AcaoDivida::Perdoada,
+        devedor: posicao.devedor.clone(),
+        divida,
+        para: None,
+        motivo: motivo.trim().to_string(),
+    })
+}
+
+/// Passa uma dívida para outro devedor e/ou outro credor. A origem mantém-se.
+pub fn transferir(tx: &mut dyn EscalaTx, por: &str, posicao: &PosicaoDivida, novo_devedor: &str, novo_credor: &str, motivo: &str) -> AppResult<()> {
+    motivo_obrigatorio(motivo)?;
+    if novo_devedor == novo_credor {
+        return Err(Recusa("O devedor e o credor têm de ser pessoas diferentes.").into());
+    }
+    if novo_devedor == posicao.devedor && novo_credor == posicao.credor {
+        return Err(Recusa("Escolha outro devedor ou outro credor.").into());
+    }
+    let mut dividas = tx.dividas()?;
+    let antes = retirar(&mut dividas, posicao)?;
+    let depois = Divida { credor: novo_credor.to_string(), ..antes.clone() };
+    dividas.entry(novo_devedor.to_string()).or_default().push(depois);
+    tx.guardar_dividas(&dividas)?;
+    registar(tx, MovimentoDivida {
+        em: Local::now(),
+        por: por.to_string(),
+        acao: AcaoDivida::Transferida,
+        devedor: posicao.devedor.clone(),
+        divida: antes,
+        para: Some((novo_devedor.to_string(), novo_credor.to_string())),
+        motivo: motivo.trim().to_string(),
+    })
+}
+
+/// Dá a uma pessoa uma punição pelas dívidas convertidas na virada de ano.
+pub fn acrescentar_punicao(punicoes: &mut Vec<Punicao>, user_id: &str, servicos: u32) {
+    if servicos == 0 {
+        return;
+    }
+    let texto = format!("Aplicada na virada de ano: {} serviço(s).", servicos);
+    punicoes.push(Punicao {
+        user_id: user_id.to_string(),
+        total_a_cumprir: servicos,
+        motivo: "Dívidas de serviço por pagar na virada de ano".to_string(),
+        autoridade: "Virada de ano".to_string(),
+        aplicada_em: Some(Local::now().date_naive()),
+        historico: vec![RegistoPunicao::novo("", texto)],
+        ..Default::default()
+    });
+}
+
+/// Regista as dívidas que a virada de ano apagou ou converteu em punição.
+pub fn registar_virada(tx: &mut dyn EscalaTx, por: &str, fechadas: &[(String, Divida)], destino: DestinoDividas) -> AppResult<()> {
+    let mut movimentos = tx.movimentos_dividas()?;
+    for (devedor, divida) in fechadas {
+        movimentos.push(MovimentoDivida {
+            em: Local::now(),
+            por: por.to_string(),
+            acao: if destino == DestinoDividas::Punir { AcaoDivida::Convertida } else { AcaoDivida::Apagada },
+            devedor: devedor.clone(),
+            divida: divida.clone(),
+            para: None,
+            motivo: "Virada de ano".to_string(),
+        });
+    }
+    tx.guardar_movimentos_dividas(&movimentos)
+}
@@ -0,0 +1,264 @@
+// src/escala_exportar.rs
+
+//! # Exportação da Escala
+//!
+//! Os serviços de uma pessoa ou de um posto, tirados dos dias gravados da escala
+//! (`EscalaDiaria`), em CSV ou num calendário iCalendar. O PDF fica em `escala_pdf`.
+//!
+//! Os horários seguem o formato dos postos ("08:00-12:00", "0800-1200/1400-1800"): um
+//! serviço vira um único evento, do início do primeiro período ao fim do último. Um fim
+//! antes do início passa para o dia seguinte. Os serviços sem horas (DIARIO e retém) são
+//! eventos de dia inteiro.
+
+use crate::auth::User;
+use crate::escala::{EscalaDiaria, EstadoEscala, TipoServico};
+use crate::store::{AppResult, EscalaStore};
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// Nome do "posto" dos serviços de retém.
+pub const RETEM: &str = "RETÉM";
+
+/// Um serviço de uma pessoa num dia.
+#[derive(Debug, Clone)]
+pub struct Servico {
+    pub data: NaiveDate,
+    pub tipo_dia: TipoServico,
+    pub posto: String,
+    /// Vazio no retém.
+    pub horario: String,
+    pub user_id: String,
+    pub nome: String,
+}
+
+impl Servico {
+    /// Início e fim do serviço, ou `None` se não tiver horas (dia inteiro).
+    pub fn intervalo(&self) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        let mut inicio: Option<NaiveDateTime> = None;
+        let mut fim: Option<NaiveDateTime> = None;
+        for periodo in self.horario.split('/') {
+            let (de, ate) = periodo.split_once('-')?;
+            let de = self.data.and_time(hora(de)?);
+            let mut ate = self.data.and_time(hora(ate)?);
+            if ate <= de {
+                ate += Duration::days(1);
+            }
+            inicio = Some(inicio.map_or(de, |i| i.min(de)));
+            fim = Some(fim.map_or(ate, |f| f.max(ate)));
+        }
+        Some((inicio?, fim?))
+    }
+}
+
+/// Lê uma hora como o PDF a mostra: "8:00", "0800" e "800" são todas 08:00.
+fn hora(texto: &str) -> Option<NaiveTime> {
+    let limpa = texto.trim().replace(":", "");
+    if limpa.is_empty() || limpa.len() > 4 || !limpa.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let valor: u32 = limpa.parse().ok()?;
+    if valor == 2400 {
+        return NaiveTime::from_hms_opt(23, 59, 59);
+    }
+    NaiveTime::from_hms_opt(valor / 100, valor % 100, 0)
+}
+
+/// Os dias que os utilizadores já podem ver: o período em vigor e o seguinte, se houver.
+pub async fn escalas_visiveis(store: &dyn EscalaStore, estado: &EstadoEscala) -> AppResult<BTreeMap<NaiveDate, EscalaDiaria>> {
+    let fim = estado.periodo_seguinte.as_ref().map_or(estado.periodo_atual.end_date, |p| p.end_date);
+    store.periodo(estado.periodo_atual.start_date, fim).await
+}
+
+/// Todos os serviços dos dias dados, por data, posto e horário.
+fn servicos(escalas: &BTreeMap<NaiveDate, EscalaDiaria>) -> Vec<Servico> {
+    let mut servicos = Vec::new();
+    for (data, dia) in escalas {
+        let mut do_dia: Vec<Servico> = dia
+            .escala
+            .iter()
+            .flat_map(|(posto, horarios)| horarios.iter().map(move |(horario, alocacao)| (posto, horario, alocacao)))
+            .map(|(posto, horario, alocacao)| Servico {
+                data: *data,
+                tipo_dia: dia.tipo_dia.clone(),
+                posto: posto.clone(),
+                horario: horario.clone(),
+                user_id: alocacao.user_id.clone(),
+                nome: alocacao.nome.clone(),
+            })
+            .collect();
+        do_dia.extend(dia.retem.iter().map(|alocacao| Servico {
+            data: *data,
+            tipo_dia: dia.tipo_dia.clone(),
+            posto: RETEM.to_string(),
+            horario: String::new(),
+            user_id: alocacao.user_id.clone(),
+            nome: alocacao.nome.clone(),
+        }));
+        do_dia.sort_by(|a, b| (&a.posto, &a.horario).cmp(&(&b.posto, &b.horario)));
+        servicos.extend(do_dia);
+    }
+    servicos
+}
+
+/// Os serviços de uma pessoa, por data.
+pub fn servicos_do_utilizador(escalas: &BTreeMap<NaiveDate, EscalaDiaria>, user_id: &str) -> Vec<Servico> {
+    servicos(escalas).into_iter().filter(|s| s.user_id == user_id).collect()
+}
+
+/// Os serviços de um posto (ou do retém, com `RETEM`), por data e horário.
+pub fn servicos_do_posto(escalas: &BTreeMap<NaiveDate, EscalaDiaria>, posto: &str) -> Vec<Servico> {
+    servicos(escalas).into_iter().filter(|s| s.posto == posto).collect()
+}
+
+#[derive(Serialize)]
+struct LinhaCsv<'a> {
+    data: String,
+    tipo_dia: String,
+    posto: &'a str,
+    horario: &'a str,
+    id: &'a str,
+    nome: &'a str,
+    turma: &'a str,
+}
+
+/// Os serviços em CSV, com cabeçalho.
+pub fn exportar_csv(servicos: &[Servico], users: &HashMap<String, User>) -> AppResult<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for servico in servicos {
+        writer.serialize(LinhaCsv {
+            data: servico.data.format("%Y-%m-%d").to_string(),
+            tipo_dia: format!("{:?}", servico.tipo_dia),
+            posto: &servico.posto,
+            horario: &servico.horario,
+            id: &servico.user_id,
+            nome: &servico.nome,
+            turma: users.get(&servico.user_id).map_or("", |u| u.turma.as_str()),
+        })?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Texto de uma propriedade iCalendar, com os caracteres especiais escapados.
+fn texto_ics(texto: &str) -> String {
+    texto.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n")
+}
+
+/// Parte as linhas com mais de 75 octetos, como pede o RFC 5545.
+fn dobrar_linha(linha: &str, saida: &mut String) {
+    let mut tamanho = 0;
+    for c in linha.chars() {
+        if tamanho + c.len_utf8() > 75 {
+            saida.push_str("\r\n ");
+            tamanho = 1;
+        }
+        saida.push(c);
+        tamanho += c.len_utf8();
+    }
+    saida.push_str("\r\n");
+}
+
+/// Um calendário com um evento por serviço, para subscrever numa aplicação de calendário.
+/// As horas são locais (sem fuso), como na escala.
+pub fn exportar_ics(nome_calendario: &str, servicos: &[Servico]) -> String {
+    let agora = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut linhas = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//MercAl//Escala//PT".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        format!("X-WR-CALNAME:{}", texto_ics(nome_calendario)),
+    ];
+    for servico in servicos {
+        let uid: String = format!("{}-{}-{}-{}", servico.data.format("%Y%m%d"), servico.posto, servico.horario, servico.user_id)
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        linhas.push("BEGIN:VEVENT".to_string());
+        linhas.push(format!("UID:{}@mercal", uid));
+        linhas.push(format!("DTSTAMP:{}", agora));
+        match servico.intervalo() {
+            Some((inicio, fim)) => {
+                linhas.push(format!("DTSTART:{}", inicio.format("%Y%m%dT%H%M%S")));
+                linhas.push(format!("DTEND:{}", fim.format("%Y%m%dT%H%M%S")));
+            }
+            None => {
+                linhas.push(format!("DTSTART;VALUE=DATE:{}", servico.data.format("%Y%m%d")));
+                linhas.push(format!("DTEND;VALUE=DATE:{}", (servico.data + Duration::days(1)).format("%Y%m%d")));
+            }
+        }
+        linhas.push(format!("SUMMARY:{}", texto_ics(&format!("Serviço: {}", servico.posto))));
+        let descricao = if servico.horario.is_empty() {
+            format!("Dia {:?}", servico.tipo_dia)
+        } else {
+            format!("Dia {:?}, horário {}", servico.tipo_dia, servico.horario)
+        };
+        linhas.push(format!("DESCRIPTION:{}", texto_ics(&descricao)));
+        linhas.push("END:VEVENT".to_string());
+    }
+    linhas.push("END:VCALENDAR".to_string());
+
+    let mut saida = String::new();
+    for linha in linhas {
+        dobrar_linha(&linha, &mut saida);
+    }
+    saida
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::testes::data;
+
+    fn servico(posto: &str, horario: &str) -> Servico {
+        Servico {
+            data: data(2025, 3, 3),
+            tipo_dia: TipoServico::RN,
+            posto: posto.to_string(),
+            horario: horario.to_string(),
+            user_id: "1001".to_string(),
+            nome: "Aluno 1001".to_string(),
+        }
+    }
+
+    fn horas(servico: &Servico) -> Option<(String, String)> {
+        servico.intervalo().map(|(i, f)| (i.format("%d %H:%M").to_string(), f.format("%d %H:%M").to_string()))
+    }
+
+    #[test]
+    fn intervalo_dos_horarios() {
+        let par = |i: &str, f: &str| Some((i.to_string(), f.to_string()));
+        assert_eq!(horas(&servico("P", "08:00-12:00")), par("03 08:00", "03 12:00"));
+        assert_eq!(horas(&servico("P", "800-1200")), par("03 08:00", "03 12:00"));
+        // Vários períodos: do início do primeiro ao fim do último
+        assert_eq!(horas(&servico("P", "0800-1200/1400-1800")), par("03 08:00", "03 18:00"));
+        // Um fim antes do início passa para o dia seguinte
+        assert_eq!(horas(&servico("P", "22:00-02:00")), par("03 22:00", "04 02:00"));
+        assert_eq!(horas(&servico("P", "20:00-24:00")), par("03 20:00", "03 23:59"));
+    }
+
+    #[test]
+    fn sem_horas_e_dia_inteiro() {
+        assert_eq!(horas(&servico(RETEM, "")), None);
+        assert_eq!(horas(&servico("P", "DIARIO")), None);
+        assert_eq!(horas(&servico("P", "08:00-1x:00")), None);
+        assert_eq!(horas(&servico("P", "25:00-26:00")), None);
+    }
+
+    #[test]
+    fn calendario_ics() {
+        let nome = "Escala de um aluno com um nome bastante comprido, para obrigar a dobrar a linha";
+        let ics = exportar_ics(nome, &[servico("PORTARIA", "08:00-12:00"), servico(RETEM, "")]);
+
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT").count(), 2);
+        assert!(ics.contains("DTSTART:20250303T080000\r\nDTEND:20250303T120000\r\n"));
+        assert!(ics.contains("DTSTART;VALUE=DATE:20250303\r\nDTEND;VALUE=DATE:20250304\r\n"));
+        assert!(ics.contains("UID:20250303-PORTARIA-08_00-12_00-1001@mercal"));
+        // As linhas longas são dobradas a 75 bytes e continuam com um espaço
+        assert!(ics.split("\r\n").all(|linha| linha.len() <= 75));
+        let desdobrado = ics.replace("\r\n ", "");
+        assert!(desdobrado.contains(&format!("X-WR-CALNAME:{}", nome.replace(',', "\\,"))));
+    }
+}
@@ -1,26 +1,20 @@
 // src/escala_handlers.rs
 
-use crate::auth::{self, AppState, User};
-use crate::escala::{Alocacao, EstadoEscala, EscalaDiaria, Posto, TipoServico, DetalheServico, TipoTroca, StatusTroca, Troca};
+use crate::auth::{AppState, AuthUser, User};
+use crate::store::{AppResult, EscalaStore, Recusa};
+use crate::escala::{self, Alocacao, EscalaDiaria, EstadoIndisponibilidade, EstadoOferta, OfertaServico, TipoServico, DetalheServico, TipoTroca, StatusTroca, Troca};
+use crate::{escala_exportar, escala_pdf, users, views};
 use axum::{
     debug_handler,
-    extract::{State, Form},
-    http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    extract::{Form, Path, Query, State},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
 };
-use chrono::{NaiveDate, Duration, Datelike, Weekday};
+use serde::Deserialize;
+use chrono::{NaiveDate, NaiveDateTime, Duration, Datelike, Local, Weekday};
 use std::collections::{HashMap, BTreeMap, HashSet};
-use tokio::fs;
-use tower_cookies::Cookies;
 use uuid::Uuid;
 
-// Constantes usadas pelos handlers de utilizador
-const ESTADO_ESCALA_FILE: &str = "data/escala/estado.json";
-const POSTOS_FILE: &str = "data/escala/postos.json";
-const ESCALA_DATA_DIR: &str = "data/escala";
-const TROCAS_FILE: &str = "data/escala/trocas.json";
-const USERS_FILE: &str = "users.json";
-
 // --- MÓDULO DE VISUALIZAÇÃO (HTML e CSS) ---
 mod view {
     use axum::response::Html;
@@ -99,7 +93,7 @@ mod view {
     "#;
 
     // 2. FUNÇÃO DE LAYOUT
-    fn render_page(title: &str, content: String) -> Html<String> {
+    pub fn render_page(title: &str, content: String) -> Html<String> {
         Html(format!(
             r#"
             <!DOCTYPE html>
@@ -126,11 +120,18 @@ mod view {
         ))
     }
 
+    /// O conteúdo já renderizado de cada separador da página.
+    pub struct Separadores<'a> {
+        pub escala_atual: &'a str,
+        pub escala_seguinte: &'a str,
+        pub indisponibilidades: &'a str,
+        pub exportar: &'a str,
+    }
+
     // 3. FUNÇÃO DE RENDERIZAÇÃO DA PÁGINA DE ESCALA
     pub fn render_escala_page(
         is_admin: bool,
-        html_escala_atual: &str,
-        html_escala_seguinte: &str,
+        separadores: Separadores,
         escala_json_for_script: &str,
         users_json_for_script: &str,
         status_trocas: &str,
@@ -144,8 +145,10 @@ mod view {
                 <div class="tab-buttons">
                     <button class="tab-btn active" onclick="openTab(event, 'Atual')" id="defaultOpen">Escala Atual</button>
                     <button class="tab-btn" onclick="openTab(event, 'Proxima')">Próxima Escala</button>
+                    <button class="tab-btn" onclick="openTab(event, 'Indisponibilidades')">Indisponibilidades</button>
+                    <button class="tab-btn" onclick="openTab(event, 'Exportar')">Exportar</button>
                 </div>
-                <a href="/dashboard" class="tab-link">← Voltar ao Dashboard</a>
+                <span><a href="/escala/ofertas" class="tab-link">Quadro de Ofertas</a><a href="/escala/trocas" class="tab-link">Minhas Trocas</a><a href="/dashboard" class="tab-link">← Voltar ao Dashboard</a></span>
             </div>
 
             <div id="Atual" class="tabcontent" style="display: block;">
@@ -158,6 +161,14 @@ mod view {
                 {html_escala_seguinte}
             </div>
 
+            <div id="Indisponibilidades" class="tabcontent">
+                {html_indisponibilidades}
+            </div>
+
+            <div id="Exportar" class="tabcontent">
+                {html_exportar}
+            </div>
+
             <!-- Modals e Scripts -->
             <div id="tradeModal" class="modal">
               <div class="modal-content">
@@ -171,7 +182,8 @@ mod view {
                     <p><strong>Seu Serviço Envolvido:</strong> <span id="requester_service_text"></span></p>
                     <label for="motivo">Motivo do Pedido:</label>
                     <textarea name="motivo" required style="width: 100%; height: 60px;"></textarea>
-                    <br><br>
+                    <p><label for="prazo">Prazo para a resposta (opcional; no máximo até ao dia do serviço):</label>
+                    <input type="datetime-local" id="prazo" name="prazo"></p>
                     <button type="submit" style="padding: 10px 15px;">Confirmar e Enviar Pedido</button>
                 </form>
               </div>
@@ -280,8 +292,10 @@ mod view {
             </script>
             "#,
             escala_atual_subtitulo = if is_admin { "Modo TO" } else { "Apenas Consulta" },
-            html_escala_atual = html_escala_atual,
-            html_escala_seguinte = html_escala_seguinte,
+            html_escala_atual = separadores.escala_atual,
+            html_escala_seguinte = separadores.escala_seguinte,
+            html_indisponibilidades = separadores.indisponibilidades,
+            html_exportar = separadores.exportar,
             escala_json_for_script = escala_json_for_script,
             users_json_for_script = users_json_for_script,
             status_trocas = status_trocas,
@@ -317,6 +331,7 @@ fn formatar_tipo_servico(tipo: &TipoServico) -> &'static str {
 
 /// Gera o HTML para uma tabela de escala de um período específico.
 async fn gerar_html_escala_inner(
+    store: &dyn EscalaStore,
     periodo: &crate::escala::Periodo,
     _postos: &[crate::escala::Posto],
     users: &HashMap<String, User>,
@@ -326,84 +341,52 @@ async fn gerar_html_escala_inner(
 ) -> (String, BTreeMap<NaiveDate, EscalaDiaria>) {
     let mut html_output = String::new();
     let mut escalas_map = BTreeMap::new();
+    let mut escalas_periodo = store.periodo(periodo.start_date, periodo.end_date)
+        .await
+        .unwrap_or_default();
     
     let mut current_date = periodo.start_date;
     while current_date <= periodo.end_date {
-        let filename = format!("{}/{}.json", ESCALA_DATA_DIR, current_date.format("%Y-%m-%d"));
-        if let Ok(content) = fs::read_to_string(&filename).await {
-            if let Ok(escala_diaria) = serde_json::from_str::<EscalaDiaria>(&content) {
-                
-                let dia_semana_formatado = formatar_dia_semana_completo(current_date.weekday());
-                let data_formatada = current_date.format("%d/%m/%Y");
-                let tipo_servico_formatado = formatar_tipo_servico(&escala_diaria.tipo_dia);
-                let titulo_dia = format!("Detalhe de {}, {}, {}", dia_semana_formatado, data_formatada, tipo_servico_formatado);
-
-                let mut daily_html = String::new();
-
-                let seccoes = vec![
-                    ("3º ANO", vec!["AJOSCA", "RANCHEIRO", "CHEFE DE DIA"]),
-                    ("2º ANO", vec!["SALÃO DE VÍDEO", "SALÃO DE RECREIO", "LOJA SAMM", "SUBCHEFE", "CONFERÊNCIA", "GARAGEM", "POLÍCIA", "COPA"]),
-                    ("1º ANO", vec!["ENTREGADOR", "PAV 3A", "PAV 3B", "PAV 2", "GUARDA PAV FEM", "RONDA", "CLAVICULÁRIO"]),
-                    ("PAV FEM", vec!["PAV 2 - FEM", "LAVANDERIA"]),
-                ];
-
-                for (titulo_seccao, nomes_postos) in seccoes {
-                    let mut postos_diario = Vec::new();
-                    let mut postos_turnos = Vec::new();
-                    let mut horarios_seccao = HashSet::new();
-
-                    for nome_posto in &nomes_postos {
-                        if let Some(horarios_do_posto) = escala_diaria.escala.get(*nome_posto) {
-                            if horarios_do_posto.contains_key("DIARIO") { postos_diario.push(nome_posto); } else { postos_turnos.push(nome_posto); for horario in horarios_do_posto.keys() { horarios_seccao.insert(horario.clone()); } }
-                        }
-                    }
+        if let Some(escala_diaria) = escalas_periodo.remove(&current_date) {
+            
+            let dia_semana_formatado = formatar_dia_semana_completo(current_date.weekday());
+            let data_formatada = current_date.format("%d/%m/%Y");
+            let tipo_servico_formatado = formatar_tipo_servico(&escala_diaria.tipo_dia);
+            let titulo_dia = format!("Detalhe de {}, {}, {}", dia_semana_formatado, data_formatada, tipo_servico_formatado);
 
-                    if postos_diario.is_empty() && postos_turnos.is_empty() { continue; }
-
-                    daily_html.push_str(&format!("<div class='section-header'>{}</div>", titulo_seccao));
-
-                    if !postos_diario.is_empty() {
-                        daily_html.push_str("<table><thead><tr><th>Posto</th><th>Serviço</th><th>Posto</th><th>Serviço</th><th>Posto</th><th>Serviço</th></tr></thead><tbody>");
-                        let mut i = 0;
-                        while i < postos_diario.len() {
-                            daily_html.push_str("<tr>");
-                            for j in 0..3 {
-                                if i + j < postos_diario.len() {
-                                    let nome_posto = postos_diario[i + j];
-                                    if let Some(alocacao) = escala_diaria.escala.get(*nome_posto).and_then(|h| h.get("DIARIO")) {
-                                        let (cell_class, on_click_attr) = if interativo_atual_admin {
-                                            ("person-cell".to_string(), format!("onclick=\"openAdminTradeModal(this)\""))
-                                        } else if interativo_proxima && !alocacao.punicao {
-                                            ("person-cell".to_string(), format!("onclick=\"openTradeModal(this)\""))
-                                        } else if alocacao.punicao {
-                                            ("punicao-cell".to_string(), "".to_string())
-                                        } else {
-                                            ("".to_string(), "".to_string())
-                                        };
-
-                                        let display_name = users.get(&alocacao.user_id).map(|u| format!("{}{}", u.curso, u.id)).map(|p| format!("{} {}", p, &alocacao.nome)).unwrap_or_else(|| alocacao.nome.clone());
-                                        let cell_content = if alocacao.user_id == user_id { format!("<div class='meu-servico'>{}</div>", display_name) } else { display_name };
-                                        
-                                        daily_html.push_str(&format!( "<td><strong>{}</strong></td><td class='{_class}' data-date='{_date}' data-posto='{_posto}' data-horario='DIARIO' data-alvo-id='{_id}' data-alvo-nome='{_nome}' data-punicao='{_punicao}' {_onclick}>{_content}</td>", nome_posto, _class = cell_class.trim(), _date = current_date, _posto = nome_posto, _id = alocacao.user_id, _nome = alocacao.nome.clone(), _punicao = alocacao.punicao, _onclick = on_click_attr, _content = cell_content ));
-                                    } else { daily_html.push_str(&format!("<td><strong>{}</strong></td><td>---</td>", nome_posto)); }
-                                } else { daily_html.push_str(r#"<td class="empty-cell"></td><td class="empty-cell"></td>"#); }
-                            }
-                            daily_html.push_str("</tr>");
-                            i += 3;
-                        }
-                        daily_html.push_str("</tbody></table>");
+            let mut daily_html = String::new();
+
+            let seccoes = vec![
+                ("3º ANO", vec!["AJOSCA", "RANCHEIRO", "CHEFE DE DIA"]),
+                ("2º ANO", vec!["SALÃO DE VÍDEO", "SALÃO DE RECREIO", "LOJA SAMM", "SUBCHEFE", "CONFERÊNCIA", "GARAGEM", "POLÍCIA", "COPA"]),
+                ("1º ANO", vec!["ENTREGADOR", "PAV 3A", "PAV 3B", "PAV 2", "GUARDA PAV FEM", "RONDA", "CLAVICULÁRIO"]),
+                ("PAV FEM", vec!["PAV 2 - FEM", "LAVANDERIA"]),
+            ];
+
+            for (titulo_seccao, nomes_postos) in seccoes {
+                let mut postos_diario = Vec::new();
+                let mut postos_turnos = Vec::new();
+                let mut horarios_seccao = HashSet::new();
+
+                for nome_posto in &nomes_postos {
+                    if let Some(horarios_do_posto) = escala_diaria.escala.get(*nome_posto) {
+                        if horarios_do_posto.contains_key("DIARIO") { postos_diario.push(nome_posto); } else { postos_turnos.push(nome_posto); for horario in horarios_do_posto.keys() { horarios_seccao.insert(horario.clone()); } }
                     }
+                }
+
+                if postos_diario.is_empty() && postos_turnos.is_empty() { continue; }
+
+                daily_html.push_str(&format!("<div class='section-header'>{}</div>", titulo_seccao));
 
-                    if !postos_turnos.is_empty() {
-                        let mut horarios_ordenados: Vec<String> = horarios_seccao.into_iter().collect();
-                        horarios_ordenados.sort();
-                        let mut table_header = String::from("<th>Posto</th>");
-                        for horario in &horarios_ordenados { table_header.push_str(&format!("<th>{}</th>", horario.replace("/", "<br>"))); }
-                        daily_html.push_str(&format!("<table><thead><tr>{}</tr></thead><tbody>", table_header));
-                        for nome_posto in postos_turnos {
-                            daily_html.push_str(&format!("<tr><td><strong>{}</strong></td>", nome_posto));
-                            for horario in &horarios_ordenados {
-                                if let Some(alocacao) = escala_diaria.escala.get(*nome_posto).and_then(|h| h.get(horario)) {
+                if !postos_diario.is_empty() {
+                    daily_html.push_str("<table><thead><tr><th>Posto</th><th>Serviço</th><th>Posto</th><th>Serviço</th><th>Posto</th><th>Serviço</th></tr></thead><tbody>");
+                    let mut i = 0;
+                    while i < postos_diario.len() {
+                        daily_html.push_str("<tr>");
+                        for j in 0..3 {
+                            if i + j < postos_diario.len() {
+                                let nome_posto = postos_diario[i + j];
+                                if let Some(alocacao) = escala_diaria.escala.get(*nome_posto).and_then(|h| h.get("DIARIO")) {
                                     let (cell_class, on_click_attr) = if interativo_atual_admin {
                                         ("person-cell".to_string(), format!("onclick=\"openAdminTradeModal(this)\""))
                                     } else if interativo_proxima && !alocacao.punicao {
@@ -413,103 +396,228 @@ async fn gerar_html_escala_inner(
                                     } else {
                                         ("".to_string(), "".to_string())
                                     };
-                                    
+
                                     let display_name = users.get(&alocacao.user_id).map(|u| format!("{}{}", u.curso, u.id)).map(|p| format!("{} {}", p, &alocacao.nome)).unwrap_or_else(|| alocacao.nome.clone());
                                     let cell_content = if alocacao.user_id == user_id { format!("<div class='meu-servico'>{}</div>", display_name) } else { display_name };
                                     
-                                    daily_html.push_str(&format!("<td class='{_class}' data-date='{_date}' data-posto='{_posto}' data-horario='{_horario}' data-alvo-id='{_id}' data-alvo-nome='{_nome}' data-punicao='{_punicao}' {_onclick}>{_content}</td>", _class = cell_class.trim(), _date = current_date, _posto = nome_posto, _horario = horario, _id = alocacao.user_id, _nome = alocacao.nome, _punicao = alocacao.punicao, _onclick = on_click_attr, _content = cell_content));
-                                } else { daily_html.push_str("<td>---</td>"); }
-                            }
-                            daily_html.push_str("</tr>");
+                                    daily_html.push_str(&format!( "<td><strong>{}</strong></td><td class='{_class}' data-date='{_date}' data-posto='{_posto}' data-horario='DIARIO' data-alvo-id='{_id}' data-alvo-nome='{_nome}' data-punicao='{_punicao}' {_onclick}>{_content}</td>", nome_posto, _class = cell_class.trim(), _date = current_date, _posto = nome_posto, _id = alocacao.user_id, _nome = alocacao.nome.clone(), _punicao = alocacao.punicao, _onclick = on_click_attr, _content = cell_content ));
+                                } else { daily_html.push_str(&format!("<td><strong>{}</strong></td><td>---</td>", nome_posto)); }
+                            } else { daily_html.push_str(r#"<td class="empty-cell"></td><td class="empty-cell"></td>"#); }
                         }
-                        daily_html.push_str("</tbody></table>");
+                        daily_html.push_str("</tr>");
+                        i += 3;
                     }
+                    daily_html.push_str("</tbody></table>");
                 }
-                
-                if !escala_diaria.retem.is_empty() {
-                    let mut retem_por_ano: BTreeMap<u8, Vec<&Alocacao>> = BTreeMap::new();
-                    for alocacao in &escala_diaria.retem { if let Some(user) = users.get(&alocacao.user_id) { retem_por_ano.entry(user.ano).or_default().push(alocacao); } }
-                    
-                    daily_html.push_str("<div class='section-header'>EQUIPE DE RETÉM</div>");
-                    daily_html.push_str("<table><thead><tr><th colspan='8'>Membros de Sobreaviso</th></tr></thead><tbody>");
 
-                    let mut all_retem_alocacoes: Vec<&Alocacao> = Vec::new();
-                    for ano_num in [3, 2, 1] { if let Some(alocacoes) = retem_por_ano.get(&ano_num) { all_retem_alocacoes.extend(alocacoes.iter()); } }
-
-                    let mut i = 0;
-                    while i < all_retem_alocacoes.len() {
-                        daily_html.push_str("<tr>");
-                        for j in 0..4 {
-                            if i + j < all_retem_alocacoes.len() {
-                                let alocacao = all_retem_alocacoes[i + j];
+                if !postos_turnos.is_empty() {
+                    let mut horarios_ordenados: Vec<String> = horarios_seccao.into_iter().collect();
+                    horarios_ordenados.sort();
+                    let mut table_header = String::from("<th>Posto</th>");
+                    for horario in &horarios_ordenados { table_header.push_str(&format!("<th>{}</th>", horario.replace("/", "<br>"))); }
+                    daily_html.push_str(&format!("<table><thead><tr>{}</tr></thead><tbody>", table_header));
+                    for nome_posto in postos_turnos {
+                        daily_html.push_str(&format!("<tr><td><strong>{}</strong></td>", nome_posto));
+                        for horario in &horarios_ordenados {
+                            if let Some(alocacao) = escala_diaria.escala.get(*nome_posto).and_then(|h| h.get(horario)) {
                                 let (cell_class, on_click_attr) = if interativo_atual_admin {
                                     ("person-cell".to_string(), format!("onclick=\"openAdminTradeModal(this)\""))
-                                } else if interativo_proxima {
+                                } else if interativo_proxima && !alocacao.punicao {
                                     ("person-cell".to_string(), format!("onclick=\"openTradeModal(this)\""))
+                                } else if alocacao.punicao {
+                                    ("punicao-cell".to_string(), "".to_string())
                                 } else {
                                     ("".to_string(), "".to_string())
                                 };
-
-                                let display_name = users.get(&alocacao.user_id).map(|u| format!("{}{} {}", u.curso, u.id, &alocacao.nome)).unwrap_or_else(|| alocacao.nome.clone());
+                                
+                                let display_name = users.get(&alocacao.user_id).map(|u| format!("{}{}", u.curso, u.id)).map(|p| format!("{} {}", p, &alocacao.nome)).unwrap_or_else(|| alocacao.nome.clone());
                                 let cell_content = if alocacao.user_id == user_id { format!("<div class='meu-servico'>{}</div>", display_name) } else { display_name };
-
-                                daily_html.push_str(&format!("<td colspan='2' class='{_class}' data-date='{_date}' data-posto='RETEM' data-horario='SOBREAVISO' data-alvo-id='{_id}' data-alvo-nome='{_nome}' data-punicao='false' {_onclick}>{_content}</td>", _class = cell_class.trim(), _date = current_date, _id = alocacao.user_id, _nome = alocacao.nome, _onclick = on_click_attr, _content = cell_content));
-                            } else { daily_html.push_str(r#"<td colspan='2' class="empty-cell"></td>"#); }
+                                
+                                daily_html.push_str(&format!("<td class='{_class}' data-date='{_date}' data-posto='{_posto}' data-horario='{_horario}' data-alvo-id='{_id}' data-alvo-nome='{_nome}' data-punicao='{_punicao}' {_onclick}>{_content}</td>", _class = cell_class.trim(), _date = current_date, _posto = nome_posto, _horario = horario, _id = alocacao.user_id, _nome = alocacao.nome, _punicao = alocacao.punicao, _onclick = on_click_attr, _content = cell_content));
+                            } else { daily_html.push_str("<td>---</td>"); }
                         }
                         daily_html.push_str("</tr>");
-                        i += 4;
                     }
                     daily_html.push_str("</tbody></table>");
                 }
+            }
+            
+            if !escala_diaria.retem.is_empty() {
+                let mut retem_por_ano: BTreeMap<u8, Vec<&Alocacao>> = BTreeMap::new();
+                for alocacao in &escala_diaria.retem { if let Some(user) = users.get(&alocacao.user_id) { retem_por_ano.entry(user.ano).or_default().push(alocacao); } }
                 
-                html_output.push_str(&format!("<div class='day-card'><h2>{}</h2>{}</div>", titulo_dia, daily_html));
-                escalas_map.insert(current_date, escala_diaria);
+                daily_html.push_str("<div class='section-header'>EQUIPE DE RETÉM</div>");
+                daily_html.push_str("<table><thead><tr><th colspan='8'>Membros de Sobreaviso</th></tr></thead><tbody>");
+
+                let mut all_retem_alocacoes: Vec<&Alocacao> = Vec::new();
+                for ano_num in [3, 2, 1] { if let Some(alocacoes) = retem_por_ano.get(&ano_num) { all_retem_alocacoes.extend(alocacoes.iter()); } }
+
+                let mut i = 0;
+                while i < all_retem_alocacoes.len() {
+                    daily_html.push_str("<tr>");
+                    for j in 0..4 {
+                        if i + j < all_retem_alocacoes.len() {
+                            let alocacao = all_retem_alocacoes[i + j];
+                            let (cell_class, on_click_attr) = if interativo_atual_admin {
+                                ("person-cell".to_string(), format!("onclick=\"openAdminTradeModal(this)\""))
+                            } else if interativo_proxima {
+                                ("person-cell".to_string(), format!("onclick=\"openTradeModal(this)\""))
+                            } else {
+                                ("".to_string(), "".to_string())
+                            };
+
+                            let display_name = users.get(&alocacao.user_id).map(|u| format!("{}{} {}", u.curso, u.id, &alocacao.nome)).unwrap_or_else(|| alocacao.nome.clone());
+                            let cell_content = if alocacao.user_id == user_id { format!("<div class='meu-servico'>{}</div>", display_name) } else { display_name };
+
+                            daily_html.push_str(&format!("<td colspan='2' class='{_class}' data-date='{_date}' data-posto='RETEM' data-horario='SOBREAVISO' data-alvo-id='{_id}' data-alvo-nome='{_nome}' data-punicao='false' {_onclick}>{_content}</td>", _class = cell_class.trim(), _date = current_date, _id = alocacao.user_id, _nome = alocacao.nome, _onclick = on_click_attr, _content = cell_content));
+                        } else { daily_html.push_str(r#"<td colspan='2' class="empty-cell"></td>"#); }
+                    }
+                    daily_html.push_str("</tr>");
+                    i += 4;
+                }
+                daily_html.push_str("</tbody></table>");
             }
+            
+            html_output.push_str(&format!("<div class='day-card'><h2>{}</h2>{}</div>", titulo_dia, daily_html));
+            escalas_map.insert(current_date, escala_diaria);
         }
         current_date += Duration::days(1);
     }
     (html_output, escalas_map)
 }
 
+/// Os pedidos de indisponibilidade do utilizador e o formulário para fazer um novo.
+async fn gerar_html_indisponibilidades(store: &dyn EscalaStore, user_id: &str) -> String {
+    let minhas: Vec<_> = store
+        .indisponibilidades()
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|i| i.user_id == user_id)
+        .collect();
+
+    let mut html = String::from("<div class='card'><h2>Os Meus Pedidos</h2>");
+    if minhas.is_empty() {
+        html.push_str("<p>Não tem indisponibilidades registadas.</p>");
+    } else {
+        html.push_str("<table><thead><tr><th>Datas</th><th>Categoria</th><th>Motivo</th><th>Estado</th><th></th></tr></thead><tbody>");
+        for ind in &minhas {
+            let (estado, acao) = match ind.estado {
+                EstadoIndisponibilidade::Pendente => (
+                    "Por decidir",
+                    format!(
+                        r#"<form action="/escala/indisponibilidade/cancelar" method="post" style="margin:0;"><input type="hidden" name="id" value="{}"><button type="submit">Cancelar</button></form>"#,
+                        ind.chave()
+                    ),
+                ),
+                EstadoIndisponibilidade::Aprovada => ("Aprovada", String::new()),
+                EstadoIndisponibilidade::Rejeitada => ("Rejeitada", String::new()),
+            };
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                ind.descrever_datas(), ind.categoria.nome(), ind.motivo, estado, acao
+            ));
+        }
+        html.push_str("</tbody></table>");
+    }
+    html.push_str("</div>");
+
+    html.push_str(&format!(
+        r#"<div class='card'><h2>Pedir Indisponibilidade</h2>
+        <p>O pedido só conta para as próximas escalas depois de aprovado pelo admin.</p>
+        <form action="/escala/indisponibilidade/pedir" method="post">{}<button type="submit">Enviar Pedido</button></form></div>"#,
+        views::escala::campos_indisponibilidade()
+    ));
+    html
+}
+
+/// Os serviços do utilizador em PDF e CSV, e o endereço secreto do calendário. Quem
+/// chefia o serviço (`postos` não vazio) pode também exportar um posto inteiro.
+fn gerar_html_exportar(user: Option<&User>, postos: &[String]) -> String {
+    let calendario = match user.and_then(|u| u.token_calendario.as_deref()) {
+        Some(token) => format!(
+            r#"<p>Subscreva este endereço na aplicação de calendário (Google, Outlook, telemóvel) para ver os seus serviços.
+            Quem tiver o endereço vê a sua escala: se o partilhar por engano, gere um novo.</p>
+            <p><input type="text" id="url_calendario" readonly style="width: 100%;" onclick="this.select()"></p>
+            <script>document.getElementById('url_calendario').value = location.origin + '/calendario/{token}';</script>
+            <form action="/escala/calendario/gerar" method="post" onsubmit="return confirm('O endereço atual deixa de funcionar. Continuar?');"><button type="submit">Gerar Novo Endereço</button></form>"#
+        ),
+        None => r#"<p>Ainda não tem um endereço de calendário.</p>
+            <form action="/escala/calendario/gerar" method="post"><button type="submit">Gerar Endereço</button></form>"#
+            .to_string(),
+    };
+    let por_posto = if postos.is_empty() {
+        String::new()
+    } else {
+        let opcoes: String = postos.iter().map(|nome| format!("<option value='{0}'>{0}</option>", nome)).collect();
+        format!(
+            r#"<div class='card'><h2>Serviços de um Posto</h2>
+            <form action="/escala/posto/exportar" method="get"><select name="posto">{opcoes}</select>
+            <button type="submit" name="formato" value="pdf">PDF</button> <button type="submit" name="formato" value="csv">CSV</button></form></div>"#
+        )
+    };
+    format!(
+        r#"<div class='card'><h2>Os Meus Serviços</h2>
+        <p>Os seus serviços na escala em vigor e na próxima.</p>
+        <p><a href="/escala/exportar?formato=pdf">Descarregar PDF</a> · <a href="/escala/exportar?formato=csv">Descarregar CSV</a></p></div>
+        <div class='card'><h2>Calendário</h2>{calendario}</div>{por_posto}"#
+    )
+}
+
 #[debug_handler]
 pub async fn user_escala_page(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
 ) -> impl IntoResponse {
-    let user_id = cookies.get("user_id").map(|c| c.value().to_string());
-    if user_id.is_none() {
-        return (StatusCode::UNAUTHORIZED, Html("<h1>Não autorizado</h1>")).into_response();
-    }
-    let user_id = user_id.unwrap();
-    let is_admin = auth::has_role(&state, &cookies, "admin").await;
+    let is_admin = user.has_role("admin");
+    let chefia = is_admin || user.has_role("chefe de dia");
+    let user_id = user.user_id;
 
-    let estado_content = fs::read_to_string(ESTADO_ESCALA_FILE).await.unwrap_or_default();
-    let estado: EstadoEscala = serde_json::from_str(&estado_content).unwrap();
+    let estado = match state.escala_store.estado().await {
+        Ok(e) => e,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler o estado da escala: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao carregar a escala.").into_response();
+        }
+    };
     
-    let postos_content = fs::read_to_string(POSTOS_FILE).await.unwrap_or_default();
-    let postos: Vec<Posto> = serde_json::from_str(&postos_content).unwrap_or_default();
+    let postos = state.escala_store.postos().await.unwrap_or_default();
     
-    let users_content = fs::read_to_string(USERS_FILE).await.unwrap_or_else(|_| "[]".to_string());
-    let users_vec: Vec<User> = serde_json::from_str(&users_content).unwrap_or_default();
-    let users: HashMap<String, User> = users_vec.iter().map(|u| (u.id.clone(), u.clone())).collect();
+    let users: HashMap<String, User> = state.users.lock().unwrap().clone();
+    let mut users_vec: Vec<&User> = users.values().filter(|u| u.ativo).collect();
+    users_vec.sort_by(|a, b| a.id.cmp(&b.id));
 
-    let (html_escala_atual, mut escalas_completas) = gerar_html_escala_inner(&estado.periodo_atual, &postos, &users, &user_id, false, is_admin).await;
+    let (html_escala_atual, mut escalas_completas) = gerar_html_escala_inner(state.escala_store.as_ref(), &estado.periodo_atual, &postos, &users, &user_id, false, is_admin).await;
 
     let html_escala_seguinte = if let Some(periodo_seguinte) = &estado.periodo_seguinte {
-        let (html, escalas_seguinte) = gerar_html_escala_inner(periodo_seguinte, &postos, &users, &user_id, true, false).await;
+        let (html, escalas_seguinte) = gerar_html_escala_inner(state.escala_store.as_ref(), periodo_seguinte, &postos, &users, &user_id, true, false).await;
         escalas_completas.extend(escalas_seguinte);
         html
     } else {
         "<p>Nenhuma próxima escala foi gerada ainda.</p>".to_string()
     };
     
+    let html_indisponibilidades = gerar_html_indisponibilidades(state.escala_store.as_ref(), &user_id).await;
+    let postos_exportaveis: Vec<String> = if chefia {
+        postos.iter().map(|p| p.nome.clone()).chain(std::iter::once(escala_exportar::RETEM.to_string())).collect()
+    } else {
+        Vec::new()
+    };
+    let html_exportar = gerar_html_exportar(users.get(&user_id), &postos_exportaveis);
+
     let escala_json_for_script = serde_json::to_string(&escalas_completas).unwrap_or_else(|_| "{}".to_string());
-    let users_json_for_script = serde_json::to_string(&users_vec).unwrap_or_else(|_| "[]".to_string());
+    // Só o número e o nome: o resto do registo (senha, token do calendário) não sai do servidor
+    let users_para_script: Vec<serde_json::Value> = users_vec.iter().map(|u| serde_json::json!({ "id": u.id, "name": u.name })).collect();
+    let users_json_for_script = serde_json::to_string(&users_para_script).unwrap_or_else(|_| "[]".to_string());
 
     view::render_escala_page(
         is_admin,
-        &html_escala_atual,
-        &html_escala_seguinte,
+        view::Separadores {
+            escala_atual: &html_escala_atual,
+            escala_seguinte: &html_escala_seguinte,
+            indisponibilidades: &html_indisponibilidades,
+            exportar: &html_exportar,
+        },
         &escala_json_for_script,
         &users_json_for_script,
         &estado.status_trocas,
@@ -518,98 +626,805 @@ pub async fn user_escala_page(
 }
 
 
+/// A resposta a uma troca que não se fez: a razão, se for uma regra de negócio, ou um erro interno.
+fn erro_troca(e: Box<dyn std::error::Error + Send + Sync>, voltar: &str) -> Response {
+    if let Some(recusa) = e.downcast_ref::<Recusa>() {
+        return (StatusCode::CONFLICT, Html(format!("{} <a href='{}'>Voltar</a>", recusa, voltar))).into_response();
+    }
+    eprintln!("🔥 Falha ao processar a troca: {}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, Html("Falha ao processar a troca.".to_string())).into_response()
+}
+
+fn pedido_invalido(mensagem: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Html(format!("{} <a href='/escala'>Voltar</a>", mensagem))).into_response()
+}
+
+/// Pede uma permuta (serviço por serviço) ou uma cobertura. Os dois serviços são
+/// confirmados contra a escala gravada antes de o pedido ser guardado.
 #[debug_handler]
 pub async fn pedir_troca_handler(
-    State(_state): State<AppState>,
-    cookies: Cookies,
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
     Form(form_data): Form<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    if cookies.get("user_id").is_none() {
-        return Redirect::to("/");
-    }
+    let campo = |nome: &str| form_data.get(nome).map(String::as_str).unwrap_or_default();
 
-    let tipo_troca_str = form_data.get("tipo_troca").unwrap();
-    let motivo = form_data.get("motivo").unwrap().clone();
-    let target_service: DetalheServico = serde_json::from_str(form_data.get("target_service_json").unwrap()).unwrap();
-    
-    let tipo = match tipo_troca_str.as_str() {
+    let Ok(target_service) = serde_json::from_str::<DetalheServico>(campo("target_service_json")) else {
+        return pedido_invalido("Serviço pedido inválido.");
+    };
+    let motivo = campo("motivo").trim().to_string();
+    if motivo.is_empty() {
+        return pedido_invalido("Indique o motivo do pedido.");
+    }
+    let tipo = match campo("tipo_troca") {
         "Permuta" => TipoTroca::Permuta,
-        _ => TipoTroca::Cobertura,
+        "Cobertura" => TipoTroca::Cobertura,
+        _ => return pedido_invalido("Tipo de troca inválido."),
     };
 
-    let requerente_json_str = form_data.get("requester_service_json").unwrap();
-    let requerente = if tipo == TipoTroca::Permuta {
-        serde_json::from_str(requerente_json_str).unwrap()
+    let requerente: DetalheServico = if tipo == TipoTroca::Permuta {
+        match serde_json::from_str(campo("requester_service_json")) {
+            Ok(servico) => servico,
+            Err(_) => return pedido_invalido("Serviço oferecido inválido."),
+        }
     } else {
-        let user_info: HashMap<String, String> = serde_json::from_str(requerente_json_str).unwrap();
+        // O requerente de uma cobertura é sempre o dono da sessão
         DetalheServico {
             data: NaiveDate::from_ymd_opt(1970, 1, 1).unwrap(),
             posto: "FOLGA".to_string(),
             horario: "".to_string(),
-            user_id: user_info.get("user_id").unwrap().clone(),
+            user_id: user_id.clone(),
         }
     };
-    
-    let nova_troca = Troca {
+
+    // Só é possível oferecer um serviço próprio numa permuta
+    if requerente.user_id != user_id {
+        return (StatusCode::FORBIDDEN, Html("Só pode oferecer um serviço seu.".to_string())).into_response();
+    }
+    let prazo_pedido = match campo("prazo") {
+        "" => None,
+        prazo => match NaiveDateTime::parse_from_str(prazo, "%Y-%m-%dT%H:%M") {
+            Ok(prazo) => Some(prazo),
+            Err(_) => return pedido_invalido("Prazo inválido."),
+        },
+    };
+
+    let agora = Local::now();
+    let mut nova_troca = Troca {
         id: Uuid::new_v4().to_string(),
         tipo,
         requerente,
         alvo: target_service,
         motivo,
         status: StatusTroca::PendenteAlvo,
+        pedida_em: Some(agora),
+        expira_em: None,
+        decidida_em: None,
+        cadeia: Vec::new(),
+        ofertas: Vec::new(),
     };
-
-    let mut trocas: Vec<Troca> = fs::read_to_string(TROCAS_FILE)
-        .await
-        .ok()
-        .and_then(|content| serde_json::from_str(&content).ok())
-        .unwrap_or_default();
-        
-    trocas.push(nova_troca);
-    
-    if let Ok(json) = serde_json::to_string_pretty(&trocas) {
-        let _ = fs::write(TROCAS_FILE, json).await;
+    nova_troca.expira_em = nova_troca.prazo(prazo_pedido);
+    if nova_troca.expira_em.is_some_and(|prazo| prazo <= agora) {
+        return pedido_invalido("O prazo já passou: tem de ser antes do dia do serviço, mas no futuro.");
     }
 
-    Redirect::to("/escala")
+    let users = state.users.lock().unwrap().clone();
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        if tx.estado()?.status_trocas != "Aberto" {
+            return Err(Recusa("O período de trocas está fechado.").into());
+        }
+        let repetida = tx.trocas()?.iter().any(|t| {
+            t.status.pendente() && t.requerente.user_id == nova_troca.requerente.user_id && t.alvo == nova_troca.alvo
+        });
+        if repetida {
+            return Err(Recusa("Já tem um pedido por decidir para este serviço.").into());
+        }
+        escala::validar_troca(tx, &nova_troca, &users)?;
+        tx.guardar_troca(&nova_troca)
+    })).await;
+
+    match resultado {
+        Ok(()) => Redirect::to("/escala/trocas").into_response(),
+        Err(e) => erro_troca(e, "/escala/trocas"),
+    }
 }
 
+/// A resposta de quem recebeu o pedido. Aceite, a troca é confirmada de novo contra a
+/// escala e segue para o escalante.
 #[debug_handler]
 pub async fn responder_troca_handler(
-    cookies: Cookies,
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
     Form(form_data): Form<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    let user_id = match cookies.get("user_id") {
-        Some(cookie) => cookie.value().to_string(),
-        None => return Redirect::to("/"),
+
+    let troca_id = form_data.get("troca_id").cloned().unwrap_or_default();
+    let aprovar = form_data.get("acao").map(String::as_str) == Some("aprovar");
+    let users = state.users.lock().unwrap().clone();
+
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        let Some(mut troca) = tx.troca(&troca_id)? else { return Ok(()) };
+        if troca.alvo.user_id != user_id || troca.status != StatusTroca::PendenteAlvo {
+            return Ok(());
+        }
+        let agora = Local::now();
+        if troca.expirou(agora) {
+            troca.status = StatusTroca::Expirada;
+        } else if aprovar {
+            escala::validar_troca(tx, &troca, &users)?;
+            troca.status = StatusTroca::PendenteAdmin;
+            return tx.guardar_troca(&troca);
+        } else {
+            troca.status = StatusTroca::Recusada;
+        }
+        troca.decidida_em = Some(agora);
+        tx.guardar_troca(&troca)
+    })).await;
+
+    match resultado {
+        Ok(()) => Redirect::to("/dashboard").into_response(),
+        Err(e) => erro_troca(e, "/escala/trocas"),
+    }
+}
+
+/// Quem pediu a troca pode desistir enquanto ela não for decidida.
+#[debug_handler]
+pub async fn cancelar_troca_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let troca_id = form_data.get("troca_id").cloned().unwrap_or_default();
+
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        let Some(mut troca) = tx.troca(&troca_id)? else { return Ok(()) };
+        if troca.requerente.user_id != user_id || !troca.status.pendente() {
+            return Ok(());
+        }
+        troca.status = StatusTroca::Cancelada;
+        troca.decidida_em = Some(Local::now());
+        tx.guardar_troca(&troca)
+    })).await;
+
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao cancelar a troca: {}", e);
+    }
+    Redirect::to("/escala/trocas")
+}
+
+fn estado_troca_str(status: &StatusTroca) -> &'static str {
+    match status {
+        StatusTroca::PendenteAlvo => "Aguarda resposta",
+        StatusTroca::PendenteAdmin => "Aguarda o escalante",
+        StatusTroca::Aprovada => "Aprovada",
+        StatusTroca::Recusada => "Recusada",
+        StatusTroca::Cancelada => "Cancelada",
+        StatusTroca::Expirada => "Expirada",
+    }
+}
+
+/// Todas as trocas pedidas e recebidas pela pessoa no período de trocas atual.
+#[debug_handler]
+pub async fn minhas_trocas_page(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+) -> impl IntoResponse {
+    if let Err(e) = escala::expirar_trocas(state.escala_store.as_ref()).await {
+        eprintln!("🔥 Falha ao expirar as trocas: {}", e);
+    }
+    let trocas = match state.escala_store.trocas().await {
+        Ok(trocas) => trocas,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler as trocas: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao carregar as trocas.").into_response();
+        }
     };
+    let users = state.users.lock().unwrap().clone();
+    let nome = |id: &str| users.get(id).map_or(id.to_string(), |u| u.name.clone());
+    let data_hora = |d: Option<chrono::DateTime<Local>>| d.map_or("—".to_string(), |d| d.format("%d/%m %H:%M").to_string());
 
-    let troca_id = form_data.get("troca_id").unwrap();
-    let acao = form_data.get("acao").unwrap();
+    let linhas: String = trocas
+        .iter()
+        .rev()
+        .filter(|t| t.envolve(&user_id))
+        .map(|t| {
+            let pedida = t.requerente.user_id == user_id;
+            let servico = |s: Option<&DetalheServico>| s.map_or("—".to_string(), views::dashboard::servico_troca_str);
+            let dou = t.servicos().into_iter().find(|s| s.user_id == user_id);
+            let com = match t.tipo {
+                TipoTroca::Cadeia => t.cadeia.iter().filter(|s| s.user_id != user_id).map(|s| nome(&s.user_id)).collect::<Vec<_>>().join(", "),
+                _ if pedida => nome(&t.alvo.user_id),
+                _ => nome(&t.requerente.user_id),
+            };
+            let acao = if pedida && t.status.pendente() {
+                format!(r#"<form action="/escala/cancelar_troca" method="post" onsubmit="return confirm('Cancelar este pedido de troca?');"><input type="hidden" name="troca_id" value="{}"><button type="submit">Cancelar</button></form>"#, t.id)
+            } else if !pedida && t.status == StatusTroca::PendenteAlvo {
+                format!(
+                    r#"<form action="/escala/responder_troca" method="post" style="display: inline;"><input type="hidden" name="troca_id" value="{id}"><input type="hidden" name="acao" value="aprovar"><button type="submit">Aceitar</button></form>
+                    <form action="/escala/responder_troca" method="post" style="display: inline;"><input type="hidden" name="troca_id" value="{id}"><input type="hidden" name="acao" value="recusar"><button type="submit">Recusar</button></form>"#,
+                    id = t.id
+                )
+            } else {
+                String::new()
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                data_hora(t.pedida_em),
+                if pedida { "Enviada" } else { "Recebida" },
+                t.tipo,
+                com,
+                servico(dou),
+                servico(t.recebe(&user_id)),
+                t.motivo,
+                data_hora(t.expira_em),
+                if t.status.pendente() { estado_troca_str(&t.status).to_string() } else { format!("{} ({})", estado_troca_str(&t.status), data_hora(t.decidida_em)) },
+                acao,
+            )
+        })
+        .collect();
+
+    let conteudo = if linhas.is_empty() {
+        "<p>Ainda não pediu nem recebeu trocas.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>Pedida em</th><th></th><th>Tipo</th><th>Com</th><th>Serviço que dá</th><th>Serviço que recebe</th><th>Motivo</th><th>Prazo</th><th>Estado</th><th></th></tr></thead><tbody>{}</tbody></table>",
+            linhas
+        )
+    };
+    view::render_page(
+        "Minhas Trocas",
+        format!(
+            r#"<h1>🔄 Minhas Trocas</h1>
+            <div class="card">
+                <p>Os pedidos por decidir podem ser cancelados por quem os fez. Um pedido que não for decidido até ao prazo expira.
+                As trocas do período anterior deixam de aparecer quando é gravada uma nova escala.</p>
+                {}
+            </div>
+            <a href="/escala">← Voltar à Escala</a> · <a href="/dashboard">Dashboard</a>"#,
+            conteudo
+        ),
+    )
+    .into_response()
+}
 
-    let mut trocas: Vec<Troca> = fs::read_to_string(TROCAS_FILE).await
-        .ok()
-        .and_then(|c| serde_json::from_str(&c).ok())
+/// O serviço escolhido numa lista do quadro: "AAAA-MM-DD|posto|horário" da própria pessoa.
+fn servico_do_campo(valor: &str, user_id: &str) -> Option<DetalheServico> {
+    let mut partes = valor.splitn(3, '|');
+    let data = NaiveDate::parse_from_str(partes.next()?, "%Y-%m-%d").ok()?;
+    Some(DetalheServico {
+        data,
+        posto: partes.next()?.to_string(),
+        horario: partes.next()?.to_string(),
+        user_id: user_id.to_string(),
+    })
+}
+
+/// Publica no quadro um serviço que a pessoa quer dar.
+#[debug_handler]
+pub async fn publicar_oferta_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let Some(servico) = form_data.get("servico").and_then(|v| servico_do_campo(v, &user_id)) else {
+        return pedido_invalido("Escolha um dos seus serviços.");
+    };
+    let motivo = form_data.get("motivo").map(|m| m.trim().to_string()).unwrap_or_default();
+    if motivo.is_empty() {
+        return pedido_invalido("Indique o motivo da oferta.");
+    }
+    let oferta = OfertaServico {
+        id: Uuid::new_v4().to_string(),
+        servico,
+        motivo,
+        aceita_permuta: form_data.contains_key("aceita_permuta"),
+        criada_em: Local::now(),
+        retirada_em: None,
+    };
+
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        if tx.estado()?.status_trocas != "Aberto" {
+            return Err(Recusa("O período de trocas está fechado.").into());
+        }
+        if oferta.servico.data < Local::now().date_naive() {
+            return Err(Recusa("Só se oferecem serviços que ainda não passaram.").into());
+        }
+        let dia = tx.dia(oferta.servico.data)?;
+        match dia.as_ref().and_then(|d| escala::alocacao_do_servico(d, &oferta.servico)) {
+            None => return Err(Recusa("O serviço já não está na escala.").into()),
+            Some(a) if a.punicao => return Err(Recusa("Não é possível oferecer um serviço de punição.").into()),
+            Some(_) => {}
+        }
+        let trocas = tx.trocas()?;
+        let mut ofertas = tx.ofertas()?;
+        let repetida = ofertas
+            .iter()
+            .any(|o| o.servico == oferta.servico && matches!(o.estado(&trocas), EstadoOferta::Aberta | EstadoOferta::Reservada));
+        if repetida {
+            return Err(Recusa("Este serviço já está no quadro.").into());
+        }
+        ofertas.push(oferta);
+        tx.guardar_ofertas(&ofertas)
+    })).await;
+
+    match resultado {
+        Ok(()) => Redirect::to("/escala/ofertas").into_response(),
+        Err(e) => erro_troca(e, "/escala/ofertas"),
+    }
+}
+
+/// Tira uma oferta do quadro, se ainda ninguém a tiver reservado.
+#[debug_handler]
+pub async fn retirar_oferta_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let oferta_id = form_data.get("oferta_id").cloned().unwrap_or_default();
+
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        let trocas = tx.trocas()?;
+        let mut ofertas = tx.ofertas()?;
+        let Some(oferta) = ofertas.iter_mut().find(|o| o.id == oferta_id && o.servico.user_id == user_id) else { return Ok(()) };
+        if oferta.estado(&trocas) == EstadoOferta::Reservada {
+            return Err(Recusa("A oferta está numa troca que aguarda o escalante: cancele-a primeiro.").into());
+        }
+        if oferta.estado(&trocas) != EstadoOferta::Aberta {
+            return Ok(());
+        }
+        oferta.retirada_em = Some(Local::now());
+        tx.guardar_ofertas(&ofertas)
+    })).await;
+
+    match resultado {
+        Ok(()) => Redirect::to("/escala/ofertas").into_response(),
+        Err(e) => erro_troca(e, "/escala/ofertas"),
+    }
+}
+
+/// Assume o serviço de uma oferta: fica uma cobertura à espera do escalante e, depois de
+/// aprovada, quem publicou a oferta fica a dever o serviço.
+#[debug_handler]
+pub async fn assumir_oferta_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let oferta_id = form_data.get("oferta_id").cloned().unwrap_or_default();
+    let users = state.users.lock().unwrap().clone();
+
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        if tx.estado()?.status_trocas != "Aberto" {
+            return Err(Recusa("O período de trocas está fechado.").into());
+        }
+        let trocas = tx.trocas()?;
+        let ofertas = tx.ofertas()?;
+        let Some(oferta) = ofertas.iter().find(|o| o.id == oferta_id) else {
+            return Err(Recusa("A oferta já não existe.").into());
+        };
+        if oferta.estado(&trocas) != EstadoOferta::Aberta {
+            return Err(Recusa("A oferta já não está disponível.").into());
+        }
+        let troca = escala::cobertura_da_oferta(oferta, &user_id, Local::now());
+        escala::validar_troca(tx, &troca, &users)?;
+        tx.guardar_troca(&troca)
+    })).await;
+
+    match resultado {
+        Ok(()) => Redirect::to("/escala/trocas").into_response(),
+        Err(e) => erro_troca(e, "/escala/ofertas"),
+    }
+}
+
+/// Envia ao escalante uma cadeia encontrada no quadro. Pode propô-la quem nela entra ou
+/// quem aprova trocas.
+#[debug_handler]
+pub async fn propor_cadeia_handler(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let ids: Vec<String> = form_data
+        .get("ofertas")
+        .map(|v| v.split(',').map(str::trim).filter(|id| !id.is_empty()).map(str::to_string).collect())
         .unwrap_or_default();
+    if ids.len() < 2 {
+        return erro_troca(Recusa("Uma cadeia precisa de pelo menos duas ofertas.").into(), "/escala/ofertas");
+    }
+    let aprova_trocas = auth.has_role("admin") || auth.has_role("escalante");
+    let user_id = auth.user_id;
+    let users = state.users.lock().unwrap().clone();
 
-    if let Some(troca) = trocas.iter_mut().find(|t| &t.id == troca_id) {
-        if troca.alvo.user_id != user_id {
-            return Redirect::to("/dashboard");
+    let resultado = state.escala_store.transacao(Box::new(move |tx| {
+        if tx.estado()?.status_trocas != "Aberto" {
+            return Err(Recusa("O período de trocas está fechado.").into());
         }
-        if troca.status != StatusTroca::PendenteAlvo {
-            return Redirect::to("/dashboard");
+        let trocas = tx.trocas()?;
+        let todas = tx.ofertas()?;
+        let mut ofertas = Vec::new();
+        for id in &ids {
+            match todas.iter().find(|o| &o.id == id) {
+                Some(o) if o.aceita_permuta && o.estado(&trocas) == EstadoOferta::Aberta => ofertas.push(o),
+                _ => return Err(Recusa("Uma das ofertas da cadeia já não está disponível.").into()),
+            }
+        }
+        // Quem propõe a cadeia fica como requerente, para a poder cancelar
+        match ofertas.iter().position(|o| o.servico.user_id == user_id) {
+            Some(posicao) => ofertas.rotate_left(posicao),
+            None if aprova_trocas => {}
+            None => return Err(Recusa("Só pode propor uma cadeia em que entre.").into()),
         }
+        let troca = escala::cadeia_das_ofertas(&ofertas, Local::now())
+            .ok_or(Recusa("Uma cadeia precisa de pelo menos duas ofertas."))?;
+        escala::validar_troca(tx, &troca, &users)?;
+        tx.guardar_troca(&troca)
+    })).await;
+
+    match resultado {
+        Ok(()) => Redirect::to("/escala/trocas").into_response(),
+        Err(e) => erro_troca(e, "/escala/ofertas"),
+    }
+}
 
-        troca.status = if acao == "aprovar" {
-            StatusTroca::PendenteAdmin
+/// O quadro de ofertas: os serviços que outras pessoas querem dar, as cadeias de trocas
+/// possíveis entre as ofertas que aceitam permuta e as ofertas da própria pessoa.
+#[debug_handler]
+pub async fn quadro_ofertas_page(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> impl IntoResponse {
+    let user_id = auth.user_id.clone();
+    let aprova_trocas = auth.has_role("admin") || auth.has_role("escalante");
+    if let Err(e) = escala::expirar_trocas(state.escala_store.as_ref()).await {
+        eprintln!("🔥 Falha ao expirar as trocas: {}", e);
+    }
+    let hoje = Local::now().date_naive();
+    let dados = async {
+        let estado = state.escala_store.estado().await?;
+        let ofertas = state.escala_store.ofertas().await?;
+        let trocas = state.escala_store.trocas().await?;
+        let postos = state.escala_store.postos().await?;
+        let config = state.escala_store.configuracao().await?;
+        let escalas = state.escala_store.periodo(hoje - Duration::days(1), hoje + Duration::days(366)).await?;
+        AppResult::Ok((estado, ofertas, trocas, postos, config, escalas))
+    };
+    let (estado, ofertas, trocas, postos, config, escalas) = match dados.await {
+        Ok(dados) => dados,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler o quadro de ofertas: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao carregar o quadro de ofertas.").into_response();
+        }
+    };
+    let users = state.users.lock().unwrap().clone();
+    let nome = |id: &str| users.get(id).map_or(id.to_string(), |u| u.name.clone());
+    let aberto = estado.status_trocas == "Aberto";
+
+    // Uma oferta aberta só conta enquanto o serviço ainda for de quem a publicou
+    let em_vigor = |o: &OfertaServico| {
+        o.servico.data >= hoje && escalas.get(&o.servico.data).and_then(|d| escala::alocacao_do_servico(d, &o.servico)).is_some()
+    };
+    let abertas: Vec<&OfertaServico> = ofertas.iter().filter(|o| o.estado(&trocas) == EstadoOferta::Aberta && em_vigor(o)).collect();
+
+    let mut ler_dia = |data: NaiveDate| -> AppResult<Option<EscalaDiaria>> { Ok(escalas.get(&data).cloned()) };
+    let linhas_ofertas: String = abertas
+        .iter()
+        .filter(|o| o.servico.user_id != user_id)
+        .map(|o| {
+            let troca = escala::cobertura_da_oferta(o, &user_id, Local::now());
+            let acao = match escala::verificar_troca(&mut ler_dia, &troca, &users, &postos, &config) {
+                _ if !aberto => String::new(),
+                Ok(()) => format!(
+                    r#"<form action="/escala/ofertas/assumir" method="post" onsubmit="return confirm('Assumir este serviço? Depois de aprovado, {} fica a dever-lhe um serviço.');"><input type="hidden" name="oferta_id" value="{}"><button type="submit">Assumir</button></form>"#,
+                    nome(&o.servico.user_id), o.id
+                ),
+                Err(e) => format!("<small>{}</small>", e),
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                nome(&o.servico.user_id),
+                views::dashboard::servico_troca_str(&o.servico),
+                o.motivo,
+                if o.aceita_permuta { "Sim" } else { "Não" },
+                acao,
+            )
+        })
+        .collect();
+
+    let permutaveis: Vec<&OfertaServico> = abertas.iter().copied().filter(|o| o.aceita_permuta).collect();
+    let cadeias = escala::procurar_cadeias(&permutaveis, &escalas, &users, &postos, &config, 20);
+    let linhas_cadeias: String = cadeias
+        .iter()
+        .map(|cadeia| {
+            let elos: Vec<String> = cadeia
+                .iter()
+                .enumerate()
+                .map(|(i, &oferta)| {
+                    let recebida = permutaveis[cadeia[(i + 1) % cadeia.len()]];
+                    format!("<li><strong>{}</strong> fica com {}</li>", nome(&permutaveis[oferta].servico.user_id), views::dashboard::servico_troca_str(&recebida.servico))
+                })
+                .collect();
+            let entra = cadeia.iter().any(|&i| permutaveis[i].servico.user_id == user_id);
+            let acao = if aberto && (entra || aprova_trocas) {
+                let ids: Vec<&str> = cadeia.iter().map(|&i| permutaveis[i].id.as_str()).collect();
+                format!(
+                    r#"<form action="/escala/ofertas/cadeia" method="post"><input type="hidden" name="ofertas" value="{}"><button type="submit">Propor ao escalante</button></form>"#,
+                    ids.join(",")
+                )
+            } else {
+                String::new()
+            };
+            format!("<tr><td>{}</td><td><ul>{}</ul></td><td>{}</td></tr>", cadeia.len(), elos.concat(), acao)
+        })
+        .collect();
+
+    let mut meus_servicos: Vec<DetalheServico> = escalas
+        .iter()
+        .filter(|(data, _)| **data >= hoje)
+        .flat_map(|(data, dia)| escala::servicos_no_dia(*data, dia, &user_id))
+        .filter(|s| escalas.get(&s.data).and_then(|d| escala::alocacao_do_servico(d, s)).is_some_and(|a| !a.punicao))
+        .collect();
+    meus_servicos.retain(|s| !ofertas.iter().any(|o| &o.servico == s && matches!(o.estado(&trocas), EstadoOferta::Aberta | EstadoOferta::Reservada)));
+    let opcoes: String = meus_servicos
+        .iter()
+        .map(|s| format!(r#"<option value="{}|{}|{}">{}</option>"#, s.data.format("%Y-%m-%d"), s.posto, s.horario, views::dashboard::servico_troca_str(s)))
+        .collect();
+    let publicar = if !aberto {
+        "<p>O período de trocas está fechado.</p>".to_string()
+    } else if opcoes.is_empty() {
+        "<p>Não tem serviços futuros para oferecer.</p>".to_string()
+    } else {
+        format!(
+            r#"<form action="/escala/ofertas/publicar" method="post">
+                <p><label>Serviço: <select name="servico" required>{}</select></label></p>
+                <p><label>Motivo: <input type="text" name="motivo" required style="width: 60%;"></label></p>
+                <p><label><input type="checkbox" name="aceita_permuta" checked> Aceito outro serviço em troca (pode entrar numa cadeia)</label></p>
+                <button type="submit">Publicar</button>
+            </form>"#,
+            opcoes
+        )
+    };
+
+    let linhas_minhas: String = ofertas
+        .iter()
+        .rev()
+        .filter(|o| o.servico.user_id == user_id)
+        .map(|o| {
+            let estado = match o.estado(&trocas) {
+                EstadoOferta::Aberta if !em_vigor(o) => "Caducada",
+                EstadoOferta::Aberta => "Aberta",
+                EstadoOferta::Reservada => "Aguarda o escalante",
+                EstadoOferta::Concluida => "Concluída",
+                EstadoOferta::Retirada => "Retirada",
+            };
+            let acao = if o.estado(&trocas) == EstadoOferta::Aberta && em_vigor(o) {
+                format!(r#"<form action="/escala/ofertas/retirar" method="post"><input type="hidden" name="oferta_id" value="{}"><button type="submit">Retirar</button></form>"#, o.id)
+            } else {
+                String::new()
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                views::dashboard::servico_troca_str(&o.servico),
+                o.motivo,
+                if o.aceita_permuta { "Sim" } else { "Não" },
+                estado,
+                acao
+            )
+        })
+        .collect();
+
+    let tabela = |cabecalho: &str, linhas: &str, vazio: &str| {
+        if linhas.is_empty() {
+            format!("<p>{}</p>", vazio)
         } else {
-            StatusTroca::Recusada
-        };
+            format!("<table><thead><tr>{}</tr></thead><tbody>{}</tbody></table>", cabecalho, linhas)
+        }
+    };
+    view::render_page(
+        "Quadro de Ofertas",
+        format!(
+            r#"<h1>📋 Quadro de Ofertas</h1>
+            <div class="card">
+                <h2>Serviços oferecidos</h2>
+                <p>Quem assume um serviço fica com ele e quem o ofereceu passa a dever-lhe um serviço, depois de o escalante aprovar.</p>
+                {}
+            </div>
+            <div class="card">
+                <h2>Cadeias de trocas possíveis</h2>
+                <p>Trocas em círculo entre as ofertas que aceitam outro serviço (até {} pessoas), em que cada pessoa pode fazer o serviço que recebe.
+                O escalante aprova a cadeia inteira de uma vez, e cada elo fica registado como dívida.</p>
+                {}
+            </div>
+            <div class="card">
+                <h2>Oferecer um serviço</h2>
+                {}
+            </div>
+            <div class="card">
+                <h2>As minhas ofertas</h2>
+                {}
+            </div>
+            <a href="/escala">← Voltar à Escala</a> · <a href="/escala/trocas">Minhas Trocas</a>"#,
+            tabela("<th>Quem oferece</th><th>Serviço</th><th>Motivo</th><th>Aceita troca</th><th></th>", &linhas_ofertas, "Não há serviços oferecidos por outras pessoas."),
+            escala::MAXIMO_CADEIA,
+            tabela("<th>Pessoas</th><th>Elos</th><th></th>", &linhas_cadeias, "Nenhuma cadeia possível entre as ofertas atuais."),
+            publicar,
+            tabela("<th>Serviço</th><th>Motivo</th><th>Aceita troca</th><th>Estado</th><th></th>", &linhas_minhas, "Ainda não ofereceu nenhum serviço."),
+        ),
+    )
+    .into_response()
+}
+
+#[debug_handler]
+pub async fn pedir_indisponibilidade_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let pedido = match escala::indisponibilidade_do_formulario(user_id, &form_data, EstadoIndisponibilidade::Pendente) {
+        Ok(pedido) => pedido,
+        Err(recusa) => return (StatusCode::BAD_REQUEST, recusa.0).into_response(),
+    };
+
+    let resultado = escala::atualizar_indisponibilidades(state.escala_store.as_ref(), move |indisponibilidades| {
+        indisponibilidades.push(pedido);
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao guardar o pedido de indisponibilidade: {}", e);
     }
 
-    if let Ok(json) = serde_json::to_string_pretty(&trocas) {
-        let _ = fs::write(TROCAS_FILE, json).await;
+    Redirect::to("/escala").into_response()
+}
+
+#[debug_handler]
+pub async fn cancelar_indisponibilidade_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Form(form_data): Form<HashMap<String, String>>,
+) -> impl IntoResponse {
+    let id = form_data.get("id").cloned().unwrap_or_default();
+
+    // Só o próprio pode cancelar, e só enquanto o admin não decidiu
+    let resultado = escala::atualizar_indisponibilidades(state.escala_store.as_ref(), move |indisponibilidades| {
+        indisponibilidades.retain(|i| !(i.chave() == id && i.user_id == user_id && i.estado == EstadoIndisponibilidade::Pendente));
+    }).await;
+    if let Err(e) = resultado {
+        eprintln!("🔥 Falha ao cancelar o pedido de indisponibilidade: {}", e);
+    }
+
+    Redirect::to("/escala")
+}
+
+#[derive(Deserialize)]
+pub struct ExportarQuery {
+    formato: String,
+}
+
+/// Os serviços do próprio na escala em vigor e na seguinte, em PDF ou CSV.
+#[debug_handler]
+pub async fn exportar_meus_servicos_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+    Query(query): Query<ExportarQuery>,
+) -> impl IntoResponse {
+    let users = state.users.lock().unwrap().clone();
+    let Some(user) = users.get(&user_id) else {
+        return (StatusCode::NOT_FOUND, "Utilizador não encontrado.").into_response();
+    };
+    let escalas = match escalas_visiveis(&state).await {
+        Ok(escalas) => escalas,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler a escala: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler a escala.").into_response();
+        }
+    };
+    let servicos = escala_exportar::servicos_do_utilizador(&escalas, &user_id);
+    let titulo = format!("SERVIÇOS DE {}{} {}", user.curso, user.id, user.name.to_uppercase());
+    exportar(&state, &titulo, &format!("servicos_{}", user_id), &servicos, &users, false, &query.formato)
+}
+
+/// Gera (ou troca) o token secreto do calendário do próprio.
+#[debug_handler]
+pub async fn gerar_token_calendario_handler(
+    State(state): State<AppState>,
+    AuthUser { user_id, .. }: AuthUser,
+) -> impl IntoResponse {
+    let Some(mut user) = state.users.lock().unwrap().get(&user_id).cloned() else {
+        return (StatusCode::NOT_FOUND, "Utilizador não encontrado.").into_response();
+    };
+    user.token_calendario = Some(Uuid::new_v4().simple().to_string());
+    if let Err(e) = users::save_user(state.user_store.as_ref(), &user).await {
+        eprintln!("🔥 Falha ao guardar o token do calendário: {}", e);
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao gerar o endereço do calendário.").into_response();
     }
+    state.users.lock().unwrap().insert(user.id.clone(), user);
+    Redirect::to("/escala").into_response()
+}
 
-    Redirect::to("/dashboard")
+/// O calendário iCalendar de um utilizador, pelo token secreto. Não precisa de sessão,
+/// para que as aplicações de calendário o possam consultar periodicamente.
+#[debug_handler]
+pub async fn calendario_handler(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> impl IntoResponse {
+    let token = token.trim_end_matches(".ics");
+    let user = state
+        .users
+        .lock()
+        .unwrap()
+        .values()
+        .find(|u| u.ativo && u.token_calendario.as_deref() == Some(token))
+        .cloned();
+    let Some(user) = user else {
+        return (StatusCode::NOT_FOUND, "Calendário não encontrado.").into_response();
+    };
+    let escalas = match escalas_visiveis(&state).await {
+        Ok(escalas) => escalas,
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler a escala: {}", e);
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao ler a escala.").into_response();
+        }
+    };
+    let servicos = escala_exportar::servicos_do_utilizador(&escalas, &user.id);
+    let ics = escala_exportar::exportar_ics(&format!("Escala - {}", user.name), &servicos);
+    ([(header::CONTENT_TYPE, "text/calendar; charset=utf-8")], ics).into_response()
+}
+
+async fn escalas_visiveis(state: &AppState) -> AppResult<BTreeMap<NaiveDate, EscalaDiaria>> {
+    let estado = state.escala_store.estado().await?;
+    escala_exportar::escalas_visiveis(state.escala_store.as_ref(), &estado).await
+}
+
+/// Responde com os serviços em PDF (`formato=pdf`) ou CSV (qualquer outro), como anexo.
+pub fn exportar(
+    state: &AppState,
+    titulo: &str,
+    ficheiro: &str,
+    servicos: &[escala_exportar::Servico],
+    users: &HashMap<String, User>,
+    mostrar_pessoa: bool,
+    formato: &str,
+) -> Response {
+    let ficheiro: String = ficheiro.chars().map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' }).collect();
+    let (tipo, extensao, conteudo) = if formato == "pdf" {
+        match escala_pdf::gerar_pdf_de_servicos(titulo, &state.config.instituicao.cabecalho, servicos, users, mostrar_pessoa) {
+            Ok(pdf) => ("application/pdf", "pdf", pdf),
+            Err(e) => {
+                eprintln!("🔥 Erro ao gerar PDF: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar o PDF.").into_response();
+            }
+        }
+    } else {
+        match escala_exportar::exportar_csv(servicos, users) {
+            Ok(csv) => ("text/csv; charset=utf-8", "csv", csv.into_bytes()),
+            Err(e) => {
+                eprintln!("🔥 Erro ao gerar CSV: {}", e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar o CSV.").into_response();
+            }
+        }
+    };
+    (
+        [
+            (header::CONTENT_TYPE, tipo.to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", ficheiro, extensao)),
+        ],
+        conteudo,
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::testes::estado_app;
+
+    #[tokio::test]
+    async fn cadeia_com_menos_de_duas_ofertas_e_recusada() {
+        let state = estado_app(&[]).await;
+        let admin = AuthUser { user_id: "0001".to_string(), roles: vec!["admin".to_string()], postos: Vec::new() };
+        for ofertas in ["", "o1", "o1, "] {
+            let form = HashMap::from([("ofertas".to_string(), ofertas.to_string())]);
+            let resposta = propor_cadeia_handler(State(state.clone()), admin.clone(), Form(form)).await.into_response();
+            assert_eq!(resposta.status(), StatusCode::CONFLICT);
+        }
+    }
 }
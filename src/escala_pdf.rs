@@ -12,12 +12,17 @@ use genpdf::style::Style;
 
 use crate::auth::User;
 use crate::escala::TipoServico;
-use crate::escala::{EscalaDiaria, Periodo};
+use crate::escala::{EscalaDiaria, Periodo, Posto, Punicao};
+use crate::escala_exportar::Servico;
 
 pub struct PdfData<'a> {
     pub periodo: &'a Periodo,
     pub escalas: &'a BTreeMap<NaiveDate, EscalaDiaria>,
     pub users: &'a HashMap<String, User>,
+    /// Todos os postos, pela ordem configurada; definem as secções de cada página.
+    pub postos: &'a [Posto],
+    /// Linhas do cabeçalho da instituição, vindas da configuração.
+    pub cabecalho: &'a [String],
     pub info_assinatura_fixa: (&'a str, &'a str),
     pub info_assinatura_dinamica: (&'a str, &'a str),
 }
@@ -46,6 +51,62 @@ fn formatar_horario(horario: &str) -> String {
         .join("\n")
 }
 
+/// Confirma que um horário se pode mostrar com `formatar_horario`: "DIARIO", ou um ou mais
+/// períodos "início-fim" separados por '/', com horas como "800", "0800" ou "08:00".
+pub fn validar_horario(horario: &str) -> Result<(), String> {
+    if horario == "DIARIO" {
+        return Ok(());
+    }
+    for periodo in horario.split('/') {
+        let horas: Vec<&str> = periodo.split('-').collect();
+        if horas.len() != 2 {
+            return Err(format!("'{}': cada período deve ser início-fim, por exemplo 08:00-12:00.", horario));
+        }
+        for hora in horas {
+            let hora_limpa = hora.trim().replace(":", "");
+            let valida = (1..=4).contains(&hora_limpa.len())
+                && hora_limpa.chars().all(|c| c.is_ascii_digit())
+                && hora_limpa.parse::<u32>().is_ok_and(|h| (h / 100 < 24 && h % 100 < 60) || h == 2400);
+            if !valida {
+                return Err(format!("'{}': a hora '{}' não é válida.", horario, hora));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// As secções de uma página e os postos de cada uma, pela ordem dos postos: cada secção
+/// aparece onde está o seu primeiro posto. Postos da escala que já não estão configurados
+/// ficam numa secção "OUTROS" no fim, para não desaparecerem do documento.
+fn seccoes_do_dia<'a>(postos: &'a [Posto], escala_diaria: &'a EscalaDiaria) -> Vec<(String, Vec<&'a str>)> {
+    let mut seccoes: Vec<(String, Vec<&str>)> = Vec::new();
+    for posto in postos {
+        // Um posto retirado só aparece nos dias em que ainda foi escalado
+        if !posto.ativo && !escala_diaria.escala.contains_key(&posto.nome) {
+            continue;
+        }
+        let titulo = posto.seccao();
+        match seccoes.iter_mut().find(|(t, _)| *t == titulo) {
+            Some((_, nomes)) => nomes.push(&posto.nome),
+            None => seccoes.push((titulo, vec![&posto.nome])),
+        }
+    }
+    let mut outros: Vec<&str> = escala_diaria
+        .escala
+        .keys()
+        .filter(|nome| !postos.iter().any(|p| &p.nome == *nome))
+        .map(|nome| nome.as_str())
+        .collect();
+    if !outros.is_empty() {
+        outros.sort();
+        match seccoes.iter_mut().find(|(t, _)| t == "OUTROS") {
+            Some((_, nomes)) => nomes.extend(outros),
+            None => seccoes.push(("OUTROS".to_string(), outros)),
+        }
+    }
+    seccoes
+}
+
 fn weekday_pt_br(date: &NaiveDate) -> &'static str {
     match date.weekday() {
         chrono::Weekday::Mon => "SEGUNDA-FEIRA",
@@ -73,12 +134,11 @@ fn criar_estilos() -> (Style, Style, Style) {
     )
 }
 
-fn cabecalho_instituicao() -> Vec<impl Element> {
-    vec![
-        Paragraph::new("CENTRO DE INSTRUÇÃO ALMIRANTE GRAÇA ARANHA").aligned(Alignment::Center).styled(Style::new().bold().with_font_size(10)),
-        Paragraph::new("ESCOLA DE FORMAÇÃO DE OFICIAIS DA MARINHA MERCANTE").aligned(Alignment::Center).styled(Style::new().bold().with_font_size(10)),
-        Paragraph::new("DETALHE DE SERVIÇO DO CORPO DE ALUNOS DA EFOMM").aligned(Alignment::Center).styled(Style::new().bold().with_font_size(10)),
-    ]
+fn cabecalho_instituicao(linhas: &[String]) -> Vec<impl Element> {
+    linhas
+        .iter()
+        .map(|linha| Paragraph::new(linha.as_str()).aligned(Alignment::Center).styled(Style::new().bold().with_font_size(10)))
+        .collect()
 }
 
 fn bloco_assinatura(fixa: (&str, &str), dinamica: (&str, &str)) -> PaddedElement<TableLayout> {
@@ -207,8 +267,8 @@ fn tabela_retem(escala_diaria: &EscalaDiaria, users: &HashMap<String, User>, def
     Some(table)
 }
 
-pub fn gerar_pdf_da_escala_ativa(data: PdfData) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    // 1. Load font
+/// Documento vazio com as fontes e as margens dos PDFs da escala.
+fn novo_documento(titulo: &str) -> Result<Document, Box<dyn std::error::Error>> {
     let regular_data = include_bytes!("../fonts/LiberationSans-Regular.ttf").to_vec();
     let bold_data = include_bytes!("../fonts/LiberationSans-Bold.ttf").to_vec();
     let italic_data = include_bytes!("../fonts/LiberationSans-Italic.ttf").to_vec();
@@ -219,17 +279,157 @@ pub fn gerar_pdf_da_escala_ativa(data: PdfData) -> Result<Vec<u8>, Box<dyn std::
         italic: FontData::new(italic_data, None)?,
         bold_italic: FontData::new(bold_italic_data, None)?,
     };
-    // 2. Create document
     let mut doc = Document::new(font_family);
-    doc.set_title("Escala de Serviço");
+    doc.set_title(titulo);
     let mut decorator = SimplePageDecorator::new();
     decorator.set_margins(10);
     doc.set_page_decorator(decorator);
+    Ok(doc)
+}
+
+/// Lista de serviços (de uma pessoa ou de um posto) numa tabela, com o cabeçalho da
+/// instituição. Com `mostrar_pessoa`, cada linha diz quem faz o serviço.
+pub fn gerar_pdf_de_servicos(
+    titulo: &str,
+    cabecalho: &[String],
+    servicos: &[Servico],
+    users: &HashMap<String, User>,
+    mostrar_pessoa: bool,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut doc = novo_documento(titulo)?;
+    let (default_style, header_style, section_title_style) = criar_estilos();
+    for cab in cabecalho_instituicao(cabecalho) {
+        doc.push(cab);
+        doc.push(Break::new(0.1));
+    }
+    doc.push(Paragraph::new(titulo).aligned(Alignment::Center).styled(section_title_style));
+    doc.push(Break::new(1.5));
+
+    if servicos.is_empty() {
+        doc.push(Paragraph::new("Sem serviços na escala.").aligned(Alignment::Center).styled(default_style));
+    } else {
+        let mut colunas = vec!["DATA", "DIA", "ROTINA", "POSTO", "HORÁRIO"];
+        if mostrar_pessoa {
+            colunas.push("NOME");
+        }
+        let mut larguras = vec![2, 3, 1, 3, 3];
+        if mostrar_pessoa {
+            larguras.push(5);
+        }
+        let mut table = TableLayout::new(larguras);
+        table.set_cell_decorator(FrameCellDecorator::new(true, true, false));
+        let mut row = table.row();
+        for coluna in colunas {
+            row.push_element(Paragraph::new(coluna).styled(header_style));
+        }
+        row.push().expect("header row");
+        for servico in servicos {
+            let mut row = table.row();
+            row.push_element(Paragraph::new(servico.data.format("%d/%m/%Y").to_string()).styled(default_style));
+            row.push_element(Paragraph::new(weekday_pt_br(&servico.data)).styled(default_style));
+            row.push_element(Paragraph::new(format!("{:?}", servico.tipo_dia)).styled(default_style));
+            row.push_element(Paragraph::new(servico.posto.as_str()).styled(default_style));
+            let horario = if servico.horario.is_empty() { "---".to_string() } else { formatar_horario(&servico.horario).replace('\n', " / ") };
+            row.push_element(Paragraph::new(horario).styled(default_style));
+            if mostrar_pessoa {
+                let nome = users
+                    .get(&servico.user_id)
+                    .map(|u| format!("{}{} {}", u.curso, u.id, servico.nome))
+                    .unwrap_or_else(|| servico.nome.clone());
+                row.push_element(Paragraph::new(nome).styled(default_style.italic()));
+            }
+            row.push().expect("servico row");
+        }
+        doc.push(table);
+    }
+
+    let mut buf = Vec::new();
+    doc.render(&mut buf)?;
+    Ok(buf)
+}
+
+/// A notificação de uma punição: os dados da punição, os serviços já cumpridos e o espaço
+/// para a assinatura de quem a dá conhecimento e de quem foi punido.
+pub fn gerar_pdf_de_punicao(
+    cabecalho: &[String],
+    punicao: &Punicao,
+    user: Option<&User>,
+    assinatura: (&str, &str),
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let titulo = "NOTIFICAÇÃO DE PUNIÇÃO";
+    let mut doc = novo_documento(titulo)?;
+    let (default_style, header_style, section_title_style) = criar_estilos();
+    for cab in cabecalho_instituicao(cabecalho) {
+        doc.push(cab);
+        doc.push(Break::new(0.1));
+    }
+    doc.push(Paragraph::new(titulo).aligned(Alignment::Center).styled(section_title_style));
+    doc.push(Break::new(1.5));
+
+    let data = |d: Option<NaiveDate>| d.map_or("---".to_string(), |d| d.format("%d/%m/%Y").to_string());
+    let pessoa = user.map_or_else(|| punicao.user_id.clone(), |u| format!("{}{} {}", u.curso, u.id, u.name));
+    let estado = match punicao.anulada_em {
+        Some(em) => format!("ANULADA EM {}", em.format("%d/%m/%Y")),
+        None if punicao.ativa() => format!("FALTAM {} SERVIÇO(S)", punicao.restantes()),
+        None => "CUMPRIDA".to_string(),
+    };
+    let mut dados = TableLayout::new(vec![1, 3]);
+    dados.set_cell_decorator(FrameCellDecorator::new(true, true, false));
+    for (campo, valor) in [
+        ("PUNIDO", pessoa.clone()),
+        ("MOTIVO", punicao.motivo.clone()),
+        ("AUTORIDADE", punicao.autoridade.clone()),
+        ("APLICADA EM", data(punicao.aplicada_em)),
+        ("PRAZO", data(punicao.prazo)),
+        ("SERVIÇOS", format!("{} ({} cumprido(s))", punicao.total_a_cumprir, punicao.ja_cumpridos)),
+        ("SITUAÇÃO", estado),
+    ] {
+        let mut row = dados.row();
+        row.push_element(Paragraph::new(campo).styled(header_style));
+        row.push_element(Paragraph::new(valor).styled(default_style));
+        row.push().expect("dados row");
+    }
+    doc.push(dados);
+
+    if !punicao.cumpridos.is_empty() {
+        doc.push(Break::new(1.5));
+        doc.push(Paragraph::new("SERVIÇOS CUMPRIDOS").styled(header_style));
+        doc.push(Break::new(0.5));
+        let mut table = TableLayout::new(vec![2, 3, 3, 3]);
+        table.set_cell_decorator(FrameCellDecorator::new(true, true, false));
+        let mut row = table.row();
+        for coluna in ["DATA", "DIA", "POSTO", "HORÁRIO"] {
+            row.push_element(Paragraph::new(coluna).styled(header_style));
+        }
+        row.push().expect("header row");
+        for servico in &punicao.cumpridos {
+            let mut row = table.row();
+            row.push_element(Paragraph::new(servico.data.format("%d/%m/%Y").to_string()).styled(default_style));
+            row.push_element(Paragraph::new(weekday_pt_br(&servico.data)).styled(default_style));
+            row.push_element(Paragraph::new(servico.posto.as_str()).styled(default_style));
+            row.push_element(Paragraph::new(formatar_horario(&servico.horario).replace('\n', " / ")).styled(default_style));
+            row.push().expect("servico row");
+        }
+        doc.push(table);
+    }
+
+    doc.push(Break::new(1.5));
+    let hoje = chrono::Local::now().format("%d/%m/%Y").to_string();
+    doc.push(Paragraph::new(format!("Tomei conhecimento em {}.", hoje)).styled(default_style));
+    doc.push(bloco_assinatura(assinatura, (&pessoa, "Punido")));
+
+    let mut buf = Vec::new();
+    doc.render(&mut buf)?;
+    Ok(buf)
+}
+
+pub fn gerar_pdf_da_escala_ativa(data: PdfData) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut doc = novo_documento("Escala de Serviço")?;
     // 3. Build pages
     let mut first_day = true;
     for (date, escala_diaria) in data.escalas {
         let mut page_content = LinearLayout::vertical();
-        for cab in cabecalho_instituicao() { page_content.push(cab); page_content.push(Break::new(0.1)); }
+        for cab in cabecalho_instituicao(data.cabecalho) { page_content.push(cab); page_content.push(Break::new(0.1)); }
         let title = Paragraph::new(format!(
             "{} - {}, {}",
             tipo_rotina_str(&escala_diaria.tipo_dia),
@@ -240,14 +440,8 @@ pub fn gerar_pdf_da_escala_ativa(data: PdfData) -> Result<Vec<u8>, Box<dyn std::
         page_content.push(title);
         page_content.push(Break::new(2.0));
         let (default_style, header_style, section_title_style) = criar_estilos();
-        let seccoes = vec![
-            ("3º ANO", vec!["AJOSCA", "RANCHEIRO", "CHEFE DE DIA"]),
-            ("2º ANO", vec!["SALÃO DE VÍDEO", "SALÃO DE RECREIO", "LOJA SAMM", "SUBCHEFE", "CONFERÊNCIA", "GARAGEM", "POLÍCIA", "COPA",]),
-            ("1º ANO", vec!["ENTREGADOR", "PAV 3A", "PAV 3B", "PAV 2", "GUARDA PAV FEM", "RONDA", "CLAVICULÁRIO", "PAV 2 - FEM", "LAVANDERIA"]),
-            //("PAV FEM", vec!["PAV 2 - FEM", "LAVANDERIA"]),
-        ];
         let mut any_section = false;
-        for (titulo_seccao, nomes_postos) in seccoes {
+        for (titulo_seccao, nomes_postos) in seccoes_do_dia(data.postos, escala_diaria) {
             let mut postos_diario = Vec::new();
             let mut postos_turnos = Vec::new();
             let mut horarios_set  = BTreeMap::new();
@@ -274,7 +468,7 @@ pub fn gerar_pdf_da_escala_ativa(data: PdfData) -> Result<Vec<u8>, Box<dyn std::
             }
             any_section = true;
             // Título da seção alinhado à esquerda
-            page_content.push(Paragraph::new(titulo_seccao).aligned(Alignment::Left).styled(section_title_style.clone()));
+            page_content.push(Paragraph::new(titulo_seccao.as_str()).aligned(Alignment::Left).styled(section_title_style.clone()));
             page_content.push(Break::new(1.0));
             if !postos_diario.is_empty() {
                 // Serviços diários mais próximos: menos espaçamento após a tabela
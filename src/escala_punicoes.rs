@@ -0,0 +1,165 @@
+// src/escala_punicoes.rs
+
+//! # Punições
+//!
+//! Uma punição obriga a pessoa a fazer serviços extra nos postos de punição, em dias de
+//! RD, UDRD ou ER (`ConfiguracaoEscala::postos_punicao`). Cada punição guarda o motivo,
+//! quem a deu, a data, o prazo para a cumprir e os serviços da escala em que foi cumprida.
+//! Aplicar, agravar e anular ficam no histórico da própria punição (`RegistoPunicao`).
+//!
+//! Uma punição está em atraso quando o prazo passou e ainda faltam serviços. O admin pode
+//! então agravá-la: juntar serviços e, se quiser, dar um novo prazo.
+
+use crate::escala::Punicao;
+use crate::store::{AppResult, EscalaTx, Recusa};
+use chrono::{DateTime, Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+/// Limite de serviços que se podem dar ou juntar de uma vez.
+pub const MAXIMO_SERVICOS: u32 = 30;
+
+/// Um serviço da escala em que parte de uma punição foi cumprida.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServicoCumprido {
+    pub data: NaiveDate,
+    pub posto: String,
+    pub horario: String,
+}
+
+impl ServicoCumprido {
+    /// "12/03/2025 PORTARIA 08:00-12:00".
+    pub fn descrever(&self) -> String {
+        format!("{} {} {}", self.data.format("%d/%m/%Y"), self.posto, self.horario)
+    }
+}
+
+/// Uma alteração a uma punição, feita por alguém.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegistoPunicao {
+    pub em: DateTime<Local>,
+    /// Quem fez a alteração (vazio quando foi a virada de ano).
+    pub por: String,
+    pub texto: String,
+}
+
+impl RegistoPunicao {
+    pub fn novo(por: &str, texto: String) -> Self {
+        Self { em: Local::now(), por: por.to_string(), texto }
+    }
+}
+
+/// Os dados de uma punição nova, tal como vêm do formulário.
+#[derive(Debug, Clone)]
+pub struct NovaPunicao {
+    pub user_id: String,
+    pub servicos: u32,
+    pub motivo: String,
+    pub autoridade: String,
+    pub aplicada_em: NaiveDate,
+    pub prazo: Option<NaiveDate>,
+}
+
+/// Uma punição tal como aparece na página: a posição na lista e a pessoa, que confirma
+/// que ainda é a mesma punição.
+#[derive(Debug, Clone)]
+pub struct PosicaoPunicao {
+    pub user_id: String,
+    pub indice: usize,
+}
+
+fn validar_servicos(servicos: u32) -> AppResult<()> {
+    if servicos == 0 || servicos > MAXIMO_SERVICOS {
+        return Err(Recusa("O número de serviços tem de estar entre 1 e 30.").into());
+    }
+    Ok(())
+}
+
+fn motivo_obrigatorio(motivo: &str) -> AppResult<()> {
+    if motivo.trim().is_empty() {
+        return Err(Recusa("Indique o motivo.").into());
+    }
+    Ok(())
+}
+
+fn prazo_str(prazo: Option<NaiveDate>) -> String {
+    prazo.map_or("sem prazo".to_string(), |p| format!("prazo {}", p.format("%d/%m/%Y")))
+}
+
+/// A punição ativa na posição dada.
+fn ativa_em<'a>(punicoes: &'a mut [Punicao], posicao: &PosicaoPunicao) -> AppResult<&'a mut Punicao> {
+    match punicoes.get_mut(posicao.indice) {
+        Some(p) if p.user_id == posicao.user_id && p.ativa() => Ok(p),
+        Some(p) if p.user_id == posicao.user_id => Err(Recusa("Essa punição já foi cumprida ou anulada.").into()),
+        _ => Err(Recusa("A punição já não existe: a lista mudou entretanto.").into()),
+    }
+}
+
+/// Junta uma punição nova à lista.
+pub fn aplicar(tx: &mut dyn EscalaTx, por: &str, nova: NovaPunicao) -> AppResult<()> {
+    validar_servicos(nova.servicos)?;
+    motivo_obrigatorio(&nova.motivo)?;
+    if nova.autoridade.trim().is_empty() {
+        return Err(Recusa("Indique quem deu a punição.").into());
+    }
+    if nova.prazo.is_some_and(|p| p < nova.aplicada_em) {
+        return Err(Recusa("O prazo não pode ser anterior à data da punição.").into());
+    }
+    let texto = format!("Aplicada: {} serviço(s), {}.", nova.servicos, prazo_str(nova.prazo));
+    let mut punicoes = tx.punicoes()?;
+    punicoes.push(Punicao {
+        user_id: nova.user_id,
+        total_a_cumprir: nova.servicos,
+        ja_cumpridos: 0,
+        motivo: nova.motivo.trim().to_string(),
+        autoridade: nova.autoridade.trim().to_string(),
+        aplicada_em: Some(nova.aplicada_em),
+        prazo: nova.prazo,
+        historico: vec![RegistoPunicao::novo(por, texto)],
+        ..Default::default()
+    });
+    tx.guardar_punicoes(&punicoes)
+}
+
+/// Junta serviços a uma punição ativa e, se vier, muda o prazo.
+pub fn agravar(tx: &mut dyn EscalaTx, por: &str, posicao: &PosicaoPunicao, servicos: u32, prazo: Option<NaiveDate>, motivo: &str) -> AppResult<()> {
+    validar_servicos(servicos)?;
+    motivo_obrigatorio(motivo)?;
+    let mut punicoes = tx.punicoes()?;
+    let punicao = ativa_em(&mut punicoes, posicao)?;
+    if let (Some(prazo), Some(aplicada)) = (prazo, punicao.aplicada_em) {
+        if prazo < aplicada {
+            return Err(Recusa("O prazo não pode ser anterior à data da punição.").into());
+        }
+    }
+    punicao.total_a_cumprir += servicos;
+    if prazo.is_some() {
+        punicao.prazo = prazo;
+    }
+    let texto = format!(
+        "Agravada em {} serviço(s) (total {}), {}: {}",
+        servicos,
+        punicao.total_a_cumprir,
+        prazo_str(punicao.prazo),
+        motivo.trim()
+    );
+    punicao.historico.push(RegistoPunicao::novo(por, texto));
+    tx.guardar_punicoes(&punicoes)
+}
+
+/// Anula uma punição ativa. Os serviços já cumpridos mantêm-se no registo.
+pub fn anular(tx: &mut dyn EscalaTx, por: &str, posicao: &PosicaoPunicao, motivo: &str) -> AppResult<()> {
+    motivo_obrigatorio(motivo)?;
+    let mut punicoes = tx.punicoes()?;
+    let punicao = ativa_em(&mut punicoes, posicao)?;
+    punicao.anulada_em = Some(Local::now());
+    let texto = format!("Anulada com {} de {} serviço(s) cumpridos: {}", punicao.ja_cumpridos, punicao.total_a_cumprir, motivo.trim());
+    punicao.historico.push(RegistoPunicao::novo(por, texto));
+    tx.guardar_punicoes(&punicoes)
+}
+
+/// As punições em atraso, com a sua posição na lista, das mais atrasadas para as menos.
+pub fn em_atraso(punicoes: &[Punicao], hoje: NaiveDate) -> Vec<(usize, &Punicao)> {
+    let mut atrasadas: Vec<(usize, &Punicao)> = punicoes.iter().enumerate().filter(|(_, p)| p.em_atraso(hoje)).collect();
+    atrasadas.sort_by_key(|(_, p)| p.prazo);
+    atrasadas
+}
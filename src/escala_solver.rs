@@ -0,0 +1,824 @@
+// src/escala_solver.rs
+
+//! # Motor Otimizado da Escala
+//!
+//! Alternativa ao algoritmo guloso de `escala::gerar_proposta`, que preenche as vagas uma a
+//! uma sem voltar atrás e falha assim que uma vaga fica sem candidatos. Aqui a escala é
+//! tratada como um problema de restrições:
+//! - cada vaga (dia, posto, horário) é ocupada por alguém da turma, do género e com a função
+//!   que o posto pede, que não esteja indisponível nem já de serviço nesse dia;
+//! - ninguém faz serviço, nem retém, em dois dias seguidos, nem no primeiro dia se estava de
+//!   serviço na véspera;
+//! - nos dias de RD, UDRD e ER, quem tem punições por cumprir ocupa as vagas dos postos de
+//!   punição (o último serviço não é cumprido no primeiro dia de um bloco de dias especiais);
+//! - quem deve serviços é tratado como tendo feito menos um serviço, e o credor mais um,
+//!   por isso o devedor acaba por fazer mais; o serviço a mais fica marcado como pago (PG).
+//!
+//! A pesquisa é feita em profundidade e volta atrás quando uma vaga fica sem candidatos.
+//! Entre as escalas possíveis procura a mais justa: a que minimiza a soma dos quadrados das
+//! contagens (RN, RD e retém) de todos, o que equilibra a carga de todo o período e não só a
+//! de cada vaga. Punições por cumprir, dívidas por pagar e lugares vazios no retém são
+//! penalizados. No fim, uma melhoria local troca pessoas enquanto a distribuição melhorar.
+//!
+//! Os serviços de punição não contam para a contagem. Quando não há escala possível, o erro
+//! `SemSolucao` explica que restrições estão em conflito.
+
+use crate::auth::User;
+use crate::escala::{
+    dias_para_adiar, e_dia_especial, Alocacao, Divida, EntradasGeracao, EscalaDiaria, MotorGeracao, Periodo, Posto,
+    PropostaEscala, Punicao, TipoServico,
+};
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Número máximo de nós visitados na pesquisa. Chega para encontrar uma escala e melhorá-la;
+/// se esgotar sem nenhuma, a escala é dada como impossível.
+const LIMITE_NOS: u64 = 300_000;
+/// Custo de cada serviço de punição que fica por cumprir.
+const PESO_PUNICAO: i64 = 10_000;
+/// Custo de cada dívida que fica por pagar.
+const PESO_DIVIDA: i64 = 1_000;
+/// Custo de cada lugar do retém que fica vazio.
+const PESO_RETEM_VAGO: i64 = 100;
+
+// Índices das contagens de cada pessoa
+const RN: usize = 0;
+const RD: usize = 1;
+const RETEM: usize = 2;
+
+/// Não existe nenhuma escala que cumpra todas as restrições.
+#[derive(Debug)]
+pub struct SemSolucao {
+    /// Explicação de cada conflito encontrado, pronta a mostrar ao admin.
+    pub conflitos: Vec<String>,
+}
+
+impl fmt::Display for SemSolucao {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Não há escala possível. {}", self.conflitos.join(" "))
+    }
+}
+
+impl std::error::Error for SemSolucao {}
+
+enum TipoVaga {
+    Posto { posto: usize, horario: String, categoria: usize, punicao: bool },
+    Retem,
+}
+
+struct Vaga {
+    dia: usize,
+    tipo: TipoVaga,
+    /// Quem cumpre as restrições fixas da vaga: turma, género, função e disponibilidade.
+    candidatos: Vec<usize>,
+    /// Vagas seguidas do mesmo grupo são iguais entre si e são preenchidas pela ordem das
+    /// opções, para a pesquisa não repetir as mesmas escalas com as pessoas trocadas.
+    grupo: (usize, usize),
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Escolha {
+    utilizador: Option<usize>,
+    /// A punição cumprida nesta vaga.
+    punicao: Option<usize>,
+    /// A dívida paga nesta vaga.
+    divida: Option<usize>,
+    /// Posição da escolha entre as opções da vaga (custo, desempate, utilizador).
+    chave: (i64, i64, usize),
+}
+
+struct DividaModelo {
+    /// Quem deve o serviço (a chave em `DividasAtivas`).
+    chave: String,
+    divida: Divida,
+}
+
+/// As entradas já traduzidas para índices.
+struct Modelo {
+    utilizadores: Vec<User>,
+    dias: Vec<(NaiveDate, TipoServico)>,
+    /// O dia vem logo a seguir ao anterior no calendário.
+    seguido: Vec<bool>,
+    /// Quem está de serviço na véspera do primeiro dia, antes do período gerado.
+    na_vespera: Vec<bool>,
+    adiar: Vec<bool>,
+    postos: Vec<Posto>,
+    vagas: Vec<Vaga>,
+    punicoes: Vec<Punicao>,
+    punicoes_de: Vec<Vec<usize>>,
+    dividas: Vec<DividaModelo>,
+    dividas_de: Vec<Vec<usize>>,
+    /// Contagens de partida usadas no equilíbrio, já com as dívidas descontadas.
+    contagem_inicial: Vec<[i64; 3]>,
+    /// Quantas vagas de punição há da vaga `k` em diante.
+    vagas_punicao_depois: Vec<i64>,
+    /// Quem está indisponível em cada dia.
+    indisponiveis: Vec<HashSet<String>>,
+}
+
+/// Número usado para desempatar, como no algoritmo guloso.
+fn numero(id: &str) -> i64 {
+    id.chars().skip(1).collect::<String>().parse().unwrap_or(0)
+}
+
+/// Dívidas de RN só se pagam em RN; as outras em qualquer dia de rotina de fim de semana.
+fn divida_compativel(tipo: &TipoServico, categoria: usize) -> bool {
+    categoria == RD || *tipo == TipoServico::RN
+}
+
+impl Modelo {
+    fn new(entradas: &EntradasGeracao, todos_utilizadores: Vec<User>, dias_da_escala: &HashMap<NaiveDate, TipoServico>) -> Self {
+        let mut utilizadores: Vec<User> = todos_utilizadores.into_iter().filter(|u| u.ativo).collect();
+        utilizadores.sort_by(|a, b| a.id.cmp(&b.id));
+        let indice: HashMap<String, usize> = utilizadores.iter().enumerate().map(|(i, u)| (u.id.clone(), i)).collect();
+        let mut dias: Vec<(NaiveDate, TipoServico)> = dias_da_escala.iter().map(|(d, t)| (*d, t.clone())).collect();
+        dias.sort_by_key(|(d, _)| *d);
+        let indisponiveis: Vec<HashSet<String>> = dias
+            .iter()
+            .map(|(data, _)| {
+                entradas.indisponibilidades.iter().filter(|i| i.abrange(*data)).map(|i| i.user_id.clone()).collect()
+            })
+            .collect();
+        let seguido = (0..dias.len()).map(|i| i > 0 && dias[i - 1].0.succ_opt() == Some(dias[i].0)).collect();
+        let na_vespera = utilizadores.iter().map(|u| entradas.de_servico_na_vespera.contains(&u.id)).collect();
+        let a_adiar = dias_para_adiar(&dias);
+        let adiar = dias.iter().map(|(d, _)| a_adiar.contains(d)).collect();
+
+        let mut punicoes_de = vec![Vec::new(); utilizadores.len()];
+        for (i, p) in entradas.punicoes.iter().enumerate().filter(|(_, p)| p.ativa()) {
+            if let Some(&u) = indice.get(&p.user_id) {
+                punicoes_de[u].push(i);
+            }
+        }
+        let punidos: HashSet<&str> = entradas.punicoes.iter().filter(|p| p.ativa()).map(|p| p.user_id.as_str()).collect();
+
+        // Uma dívida desloca uma unidade da contagem do credor para o devedor (a chave)
+        let mut chaves: Vec<&String> = entradas.dividas.keys().collect();
+        chaves.sort();
+        let mut dividas = Vec::new();
+        let mut dividas_de = vec![Vec::new(); utilizadores.len()];
+        let mut deslocamento = vec![[0i64; 3]; utilizadores.len()];
+        for chave in chaves {
+            for divida in &entradas.dividas[chave] {
+                let categoria = if divida.tipo_divida == TipoServico::RN { RN } else { RD };
+                if let Some(&devedor) = indice.get(chave) {
+                    dividas_de[devedor].push(dividas.len());
+                    deslocamento[devedor][categoria] -= 1;
+                    if let Some(&credor) = indice.get(&divida.credor) {
+                        deslocamento[credor][categoria] += 1;
+                    }
+                }
+                dividas.push(DividaModelo { chave: chave.clone(), divida: divida.clone() });
+            }
+        }
+        let margem = dividas.len() as i64;
+        let contagem_inicial = utilizadores
+            .iter()
+            .zip(&deslocamento)
+            .map(|(u, d)| {
+                let c = entradas.contagem.get(&u.id).cloned().unwrap_or_default();
+                [c.rn as i64 + d[RN] + margem, c.rd as i64 + d[RD] + margem, c.retem as i64]
+            })
+            .collect();
+
+        // Primeiro os postos de todos os dias, depois o retém: como um lugar do retém pode
+        // ficar vazio, o retém nunca impede uma escala e a pesquisa não perde tempo com ele
+        let mut vagas = Vec::new();
+        let mut retem = Vec::new();
+        for (d, (_, tipo)) in dias.iter().enumerate() {
+            let categoria = if *tipo == TipoServico::RN { RN } else { RD };
+            let mut do_dia = Vec::new();
+            for (p, posto) in entradas.postos.iter().enumerate() {
+                let horarios = match tipo {
+                    TipoServico::RN => &posto.horarios_rn,
+                    TipoServico::RD => &posto.horarios_rd,
+                    TipoServico::UDRD => &posto.horarios_udrd,
+                    TipoServico::ER => &posto.horarios_er,
+                    TipoServico::Retem => continue,
+                };
+                let candidatos: Vec<usize> = utilizadores
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, u)| posto.aceita(u) && !indisponiveis[d].contains(&u.id))
+                    .map(|(i, _)| i)
+                    .collect();
+                let punicao = e_dia_especial(tipo) && entradas.configuracao.postos_punicao.contains(&posto.nome);
+                for horario in horarios {
+                    do_dia.push(Vaga {
+                        dia: d,
+                        tipo: TipoVaga::Posto { posto: p, horario: horario.clone(), categoria, punicao },
+                        candidatos: candidatos.clone(),
+                        grupo: (d, p),
+                    });
+                }
+            }
+            // Os postos com menos candidatos primeiro; a ordenação é estável, por isso os
+            // horários de um posto continuam juntos
+            do_dia.sort_by_key(|v| v.candidatos.len());
+            vagas.extend(do_dia);
+
+            for (q, quota) in entradas.configuracao.quotas_do_dia(tipo).into_iter().enumerate() {
+                let candidatos: Vec<usize> = utilizadores
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, u)| quota.aceita(u) && !indisponiveis[d].contains(&u.id) && !punidos.contains(u.id.as_str()))
+                    .map(|(i, _)| i)
+                    .collect();
+                for _ in 0..quota.quantidade {
+                    retem.push(Vaga {
+                        dia: d,
+                        tipo: TipoVaga::Retem,
+                        candidatos: candidatos.clone(),
+                        grupo: (d, entradas.postos.len() + q),
+                    });
+                }
+            }
+        }
+        vagas.extend(retem);
+
+        let mut vagas_punicao_depois = vec![0; vagas.len() + 1];
+        for k in (0..vagas.len()).rev() {
+            let punicao = matches!(vagas[k].tipo, TipoVaga::Posto { punicao: true, .. });
+            vagas_punicao_depois[k] = vagas_punicao_depois[k + 1] + punicao as i64;
+        }
+
+        Self {
+            utilizadores,
+            dias,
+            seguido,
+            na_vespera,
+            adiar,
+            postos: entradas.postos.clone(),
+            vagas,
+            punicoes: entradas.punicoes.clone(),
+            punicoes_de,
+            dividas,
+            dividas_de,
+            contagem_inicial,
+            vagas_punicao_depois,
+            indisponiveis,
+        }
+    }
+
+    fn descrever_vaga(&self, k: usize) -> String {
+        let vaga = &self.vagas[k];
+        let data = self.dias[vaga.dia].0.format("%d/%m/%Y");
+        match &vaga.tipo {
+            TipoVaga::Posto { posto, horario, .. } => format!("o posto {} ({}) do dia {}", self.postos[*posto].nome, horario, data),
+            TipoVaga::Retem => format!("o retém do dia {}", data),
+        }
+    }
+}
+
+/// A vaga mais avançada em que a pesquisa ficou sem candidatos, e porquê.
+struct Bloqueio {
+    vaga: usize,
+    ocupados: usize,
+    descanso: usize,
+}
+
+struct Pesquisa<'a> {
+    modelo: &'a Modelo,
+    escolhas: Vec<Escolha>,
+    de_servico: Vec<Vec<bool>>,
+    contagem: Vec<[i64; 3]>,
+    cumpridos: Vec<u32>,
+    paga: Vec<bool>,
+    custo: i64,
+    punicao_por_cumprir: i64,
+    dividas_por_pagar: i64,
+    nos: u64,
+    melhor: Option<(i64, Vec<Escolha>)>,
+    bloqueio: Option<Bloqueio>,
+}
+
+impl<'a> Pesquisa<'a> {
+    fn new(modelo: &'a Modelo) -> Self {
+        let cumpridos: Vec<u32> = modelo.punicoes.iter().map(|p| p.ja_cumpridos).collect();
+        // Só contam as punições de quem está ativo
+        let punicao_por_cumprir = modelo
+            .punicoes_de
+            .iter()
+            .flatten()
+            .map(|&p| modelo.punicoes[p].total_a_cumprir.saturating_sub(modelo.punicoes[p].ja_cumpridos) as i64)
+            .sum();
+        let dividas_por_pagar = modelo.dividas_de.iter().map(|l| l.len() as i64).sum();
+        Self {
+            modelo,
+            escolhas: vec![Escolha::default(); modelo.vagas.len()],
+            de_servico: vec![vec![false; modelo.utilizadores.len()]; modelo.dias.len()],
+            contagem: modelo.contagem_inicial.clone(),
+            cumpridos,
+            paga: vec![false; modelo.dividas.len()],
+            custo: 0,
+            punicao_por_cumprir,
+            dividas_por_pagar,
+            nos: 0,
+            melhor: None,
+            bloqueio: None,
+        }
+    }
+
+    fn punicao_pendente(&self, u: usize) -> Option<usize> {
+        self.modelo.punicoes_de[u].iter().copied().find(|&p| self.cumpridos[p] < self.modelo.punicoes[p].total_a_cumprir)
+    }
+
+    /// Não está de serviço no dia anterior nem no seguinte.
+    fn descansado(&self, dia: usize, u: usize) -> bool {
+        let m = self.modelo;
+        let anterior = if dia == 0 { m.na_vespera[u] } else { m.seguido[dia] && self.de_servico[dia - 1][u] };
+        let seguinte = dia + 1 < m.dias.len() && m.seguido[dia + 1] && self.de_servico[dia + 1][u];
+        !anterior && !seguinte
+    }
+
+    /// As escolhas possíveis para a vaga `k`, das mais baratas para as mais caras.
+    fn opcoes(&mut self, k: usize) -> Vec<(Escolha, i64)> {
+        let m = self.modelo;
+        let vaga = &m.vagas[k];
+        let anterior = (k > 0 && m.vagas[k - 1].grupo == vaga.grupo).then(|| self.escolhas[k - 1]);
+        let (mut ocupados, mut descanso) = (0, 0);
+        let mut opcoes: Vec<(Escolha, i64)> = Vec::new();
+        for &u in &vaga.candidatos {
+            // Depois de um lugar do retém vazio, os seguintes do mesmo grupo também ficam vazios
+            if anterior.is_some_and(|a| a.utilizador.is_none()) {
+                break;
+            }
+            if self.de_servico[vaga.dia][u] {
+                ocupados += 1;
+                continue;
+            }
+            if !self.descansado(vaga.dia, u) {
+                descanso += 1;
+                continue;
+            }
+            let desempate = numero(&m.utilizadores[u].id);
+            let (escolha, incremento, desempate) = match &vaga.tipo {
+                TipoVaga::Posto { categoria, punicao, .. } => match self.punicao_pendente(u).filter(|_| *punicao) {
+                    Some(p) => {
+                        if m.adiar[vaga.dia] && m.punicoes[p].total_a_cumprir - self.cumpridos[p] == 1 {
+                            continue;
+                        }
+                        (Escolha { utilizador: Some(u), punicao: Some(p), ..Escolha::default() }, 0, desempate)
+                    }
+                    None => {
+                        let divida = m.dividas_de[u]
+                            .iter()
+                            .copied()
+                            .find(|&i| !self.paga[i] && divida_compativel(&m.dividas[i].divida.tipo_divida, *categoria));
+                        let desempate = if *categoria == RN { desempate } else { -desempate };
+                        let escolha = Escolha { utilizador: Some(u), divida, ..Escolha::default() };
+                        (escolha, 2 * self.contagem[u][*categoria] + 1, desempate)
+                    }
+                },
+                TipoVaga::Retem => {
+                    (Escolha { utilizador: Some(u), ..Escolha::default() }, 2 * self.contagem[u][RETEM] + 1, -desempate)
+                }
+            };
+            // Nas vagas iguais do mesmo grupo, as escolhas seguem a ordem das opções
+            let chave = (incremento, desempate, u);
+            if anterior.is_some_and(|a| chave <= a.chave) {
+                continue;
+            }
+            opcoes.push((Escolha { chave, ..escolha }, incremento));
+        }
+        opcoes.sort_by_key(|(escolha, _)| escolha.chave);
+        if matches!(vaga.tipo, TipoVaga::Retem) {
+            opcoes.push((Escolha::default(), PESO_RETEM_VAGO));
+        }
+        if opcoes.is_empty() && self.bloqueio.as_ref().is_none_or(|b| k >= b.vaga) {
+            self.bloqueio = Some(Bloqueio { vaga: k, ocupados, descanso });
+        }
+        opcoes
+    }
+
+    fn aplicar(&mut self, k: usize, escolha: Escolha, incremento: i64) {
+        self.escolhas[k] = escolha;
+        self.custo += incremento;
+        let vaga = &self.modelo.vagas[k];
+        let Some(u) = escolha.utilizador else { return };
+        self.de_servico[vaga.dia][u] = true;
+        if let Some(p) = escolha.punicao {
+            self.cumpridos[p] += 1;
+            self.punicao_por_cumprir -= 1;
+            return;
+        }
+        match vaga.tipo {
+            TipoVaga::Posto { categoria, .. } => self.contagem[u][categoria] += 1,
+            TipoVaga::Retem => self.contagem[u][RETEM] += 1,
+        }
+        if let Some(i) = escolha.divida {
+            self.paga[i] = true;
+            self.dividas_por_pagar -= 1;
+        }
+    }
+
+    fn desfazer(&mut self, k: usize, incremento: i64) {
+        let escolha = std::mem::take(&mut self.escolhas[k]);
+        self.custo -= incremento;
+        let vaga = &self.modelo.vagas[k];
+        let Some(u) = escolha.utilizador else { return };
+        self.de_servico[vaga.dia][u] = false;
+        if let Some(p) = escolha.punicao {
+            self.cumpridos[p] -= 1;
+            self.punicao_por_cumprir += 1;
+            return;
+        }
+        match vaga.tipo {
+            TipoVaga::Posto { categoria, .. } => self.contagem[u][categoria] -= 1,
+            TipoVaga::Retem => self.contagem[u][RETEM] -= 1,
+        }
+        if let Some(i) = escolha.divida {
+            self.paga[i] = false;
+            self.dividas_por_pagar += 1;
+        }
+    }
+
+    fn procurar(&mut self, k: usize) {
+        self.nos += 1;
+        if self.nos > LIMITE_NOS {
+            return;
+        }
+        // Limite inferior: o custo já feito e as punições que já não cabem nas vagas que faltam
+        let sem_vaga = (self.punicao_por_cumprir - self.modelo.vagas_punicao_depois[k]).max(0);
+        if let Some((melhor, _)) = &self.melhor {
+            if self.custo + PESO_PUNICAO * sem_vaga >= *melhor {
+                return;
+            }
+        }
+        if k == self.modelo.vagas.len() {
+            let total = self.custo + PESO_PUNICAO * self.punicao_por_cumprir + PESO_DIVIDA * self.dividas_por_pagar;
+            self.melhor = Some((total, self.escolhas.clone()));
+            return;
+        }
+        for (escolha, incremento) in self.opcoes(k) {
+            self.aplicar(k, escolha, incremento);
+            self.procurar(k + 1);
+            self.desfazer(k, incremento);
+            if self.nos > LIMITE_NOS {
+                return;
+            }
+        }
+    }
+
+    /// Melhoria local sobre a melhor escala: troca quem está numa vaga normal por outra
+    /// pessoa livre sempre que isso reduz o desequilíbrio, até não haver mais trocas úteis.
+    fn melhorar(&mut self) -> Option<Vec<Escolha>> {
+        let (_, escolhas) = self.melhor.take()?;
+        for (k, escolha) in escolhas.iter().enumerate() {
+            self.aplicar(k, *escolha, 0);
+        }
+        let m = self.modelo;
+        let mut melhorou = true;
+        while melhorou {
+            melhorou = false;
+            for k in 0..m.vagas.len() {
+                let escolha = self.escolhas[k];
+                if escolha.punicao.is_some() || escolha.divida.is_some() {
+                    continue;
+                }
+                let vaga = &m.vagas[k];
+                let (categoria, custo_vazio) = match vaga.tipo {
+                    TipoVaga::Posto { categoria, .. } => (categoria, 0),
+                    TipoVaga::Retem => (RETEM, PESO_RETEM_VAGO),
+                };
+                // O que se poupa ao tirar quem lá está (ou ao preencher um retém vazio)
+                let poupanca = match escolha.utilizador {
+                    Some(u) => 2 * (self.contagem[u][categoria] - 1) + 1,
+                    None => custo_vazio,
+                };
+                let melhor_troca = vaga
+                    .candidatos
+                    .iter()
+                    .copied()
+                    .filter(|&v| Some(v) != escolha.utilizador && !self.de_servico[vaga.dia][v] && self.descansado(vaga.dia, v))
+                    .filter(|&v| categoria == RETEM || !m.dividas_de[v].iter().any(|&i| !self.paga[i]))
+                    .filter(|&v| !(matches!(vaga.tipo, TipoVaga::Posto { punicao: true, .. }) && self.punicao_pendente(v).is_some()))
+                    .map(|v| (2 * self.contagem[v][categoria] + 1, v))
+                    .filter(|(custo, _)| *custo < poupanca)
+                    .min();
+                if let Some((_, v)) = melhor_troca {
+                    self.desfazer(k, 0);
+                    self.aplicar(k, Escolha { utilizador: Some(v), ..Escolha::default() }, 0);
+                    melhorou = true;
+                }
+            }
+        }
+        Some(self.escolhas.clone())
+    }
+}
+
+/// Emparelhamento máximo entre vagas e pessoas (cada pessoa numa vaga no máximo).
+fn emparelhamento(modelo: &Modelo, vagas: &[usize]) -> usize {
+    fn aumentar(modelo: &Modelo, vagas: &[usize], i: usize, visto: &mut [bool], dono: &mut [Option<usize>]) -> bool {
+        for &u in &modelo.vagas[vagas[i]].candidatos {
+            if visto[u] {
+                continue;
+            }
+            visto[u] = true;
+            if dono[u].is_none_or(|j| aumentar(modelo, vagas, j, visto, dono)) {
+                dono[u] = Some(i);
+                return true;
+            }
+        }
+        false
+    }
+    let mut dono = vec![None; modelo.utilizadores.len()];
+    (0..vagas.len())
+        .filter(|&i| aumentar(modelo, vagas, i, &mut vec![false; modelo.utilizadores.len()], &mut dono))
+        .count()
+}
+
+/// Explica porque não foi encontrada nenhuma escala.
+fn diagnosticar(modelo: &Modelo, bloqueio: Option<&Bloqueio>, completa: bool) -> Vec<String> {
+    let mut conflitos = Vec::new();
+    let data = |d: usize| modelo.dias[d].0.format("%d/%m/%Y").to_string();
+
+    // 1. Postos que ninguém pode ocupar
+    let mut vistos = HashSet::new();
+    for (k, vaga) in modelo.vagas.iter().enumerate() {
+        let TipoVaga::Posto { posto, .. } = &vaga.tipo else { continue };
+        if !vaga.candidatos.is_empty() || !vistos.insert(vaga.grupo) {
+            continue;
+        }
+        let posto = &modelo.postos[*posto];
+        let us = &modelo.utilizadores;
+        let turma = us.iter().filter(|u| !posto.turmas_permitidas.contains(&u.ano)).count();
+        let funcao = posto.funcao_exclusiva.as_ref().map_or(0, |f| us.iter().filter(|u| !u.roles.contains(f)).count());
+        let genero = us.iter().filter(|u| !posto.genero.aceita(&u.genero)).count();
+        let indisponiveis = us.iter().filter(|u| modelo.indisponiveis[vaga.dia].contains(&u.id)).count();
+        conflitos.push(format!(
+            "Ninguém pode ocupar {}: das {} pessoas ativas, {} não são das turmas permitidas, {} não são do género pedido, {} não têm a função exigida e {} estão indisponíveis.",
+            modelo.descrever_vaga(k),
+            us.len(),
+            turma,
+            genero,
+            funcao,
+            indisponiveis
+        ));
+    }
+
+    // 2. Dias em que não há pessoas diferentes para todas as vagas
+    let vagas_do_dia = |d: usize| -> Vec<usize> {
+        (0..modelo.vagas.len())
+            .filter(|&k| modelo.vagas[k].dia == d && matches!(modelo.vagas[k].tipo, TipoVaga::Posto { .. }))
+            .collect()
+    };
+    let mut dia_impossivel = vec![false; modelo.dias.len()];
+    for (d, impossivel) in dia_impossivel.iter_mut().enumerate() {
+        let vagas = vagas_do_dia(d);
+        let possiveis = emparelhamento(modelo, &vagas);
+        if possiveis < vagas.len() {
+            *impossivel = true;
+            conflitos.push(format!(
+                "No dia {} há {} vagas nos postos e cada pessoa só faz um serviço por dia, mas só é possível ocupar {} ao mesmo tempo.",
+                data(d),
+                vagas.len(),
+                possiveis
+            ));
+        }
+    }
+
+    // 3. Dias seguidos: quem faz serviço num não pode fazer no outro
+    for d in 1..modelo.dias.len() {
+        if !modelo.seguido[d] || dia_impossivel[d - 1] || dia_impossivel[d] {
+            continue;
+        }
+        let mut vagas = vagas_do_dia(d - 1);
+        vagas.extend(vagas_do_dia(d));
+        let possiveis = emparelhamento(modelo, &vagas);
+        if possiveis < vagas.len() {
+            conflitos.push(format!(
+                "Os dias {} e {} são seguidos e ninguém faz serviço em dois dias seguidos: são precisas {} pessoas diferentes, mas só é possível ocupar {} vagas.",
+                data(d - 1),
+                data(d),
+                vagas.len(),
+                possiveis
+            ));
+        }
+    }
+
+    // 4. Nenhuma regra isolada explica: mostra onde a pesquisa ficou presa
+    if conflitos.is_empty() {
+        if let Some(b) = bloqueio {
+            conflitos.push(format!(
+                "A combinação das restrições não deixa ocupar {}: dos {} candidatos, {} já estavam de serviço nesse dia e {} estavam de serviço no dia anterior ou no seguinte.",
+                modelo.descrever_vaga(b.vaga),
+                modelo.vagas[b.vaga].candidatos.len(),
+                b.ocupados,
+                b.descanso
+            ));
+        }
+    }
+    if !completa {
+        conflitos.push(format!("A pesquisa parou ao fim de {} tentativas sem encontrar nenhuma escala.", LIMITE_NOS));
+    }
+    conflitos
+}
+
+/// Gera a escala com o motor otimizado. Tem a mesma entrada e saída que `escala::gerar_proposta`.
+pub fn gerar_proposta(
+    entradas: EntradasGeracao,
+    todos_utilizadores: Vec<User>,
+    periodo: Periodo,
+    dias_da_escala: HashMap<NaiveDate, TipoServico>,
+) -> AppResult<PropostaEscala> {
+    let modelo = Modelo::new(&entradas, todos_utilizadores, &dias_da_escala);
+    let mut pesquisa = Pesquisa::new(&modelo);
+    pesquisa.procurar(0);
+    let Some(escolhas) = pesquisa.melhorar() else {
+        let completa = pesquisa.nos <= LIMITE_NOS;
+        return Err(SemSolucao { conflitos: diagnosticar(&modelo, pesquisa.bloqueio.as_ref(), completa) }.into());
+    };
+    println!("🧩 Motor otimizado: escala encontrada após {} nós.", pesquisa.nos.min(LIMITE_NOS));
+
+    // Reconstrói a escala e os dados finais pela ordem dos dias
+    let mut contagem = entradas.contagem.clone();
+    let mut punicoes = entradas.punicoes.clone();
+    let mut dias: BTreeMap<NaiveDate, EscalaDiaria> = modelo
+        .dias
+        .iter()
+        .map(|(data, tipo)| (*data, EscalaDiaria { tipo_dia: tipo.clone(), escala: HashMap::new(), retem: Vec::new() }))
+        .collect();
+    for posto in &modelo.postos {
+        for (data, tipo) in &modelo.dias {
+            if *tipo != TipoServico::Retem {
+                dias.get_mut(data).unwrap().escala.entry(posto.nome.clone()).or_default();
+            }
+        }
+    }
+    let mut pagas = HashSet::new();
+    for (vaga, escolha) in modelo.vagas.iter().zip(&escolhas) {
+        let Some(u) = escolha.utilizador else { continue };
+        let user = &modelo.utilizadores[u];
+        let dia = dias.get_mut(&modelo.dias[vaga.dia].0).unwrap();
+        match &vaga.tipo {
+            TipoVaga::Posto { posto, horario, categoria, .. } => {
+                let nome = if let Some(p) = escolha.punicao {
+                    punicoes[p].cumprir(&user.name, modelo.dias[vaga.dia].0, &modelo.postos[*posto].nome, horario)
+                } else {
+                    let c = contagem.entry(user.id.clone()).or_default();
+                    if *categoria == RN {
+                        c.rn += 1;
+                    } else {
+                        c.rd += 1;
+                    }
+                    match escolha.divida {
+                        Some(i) => {
+                            pagas.insert(i);
+                            format!("{} (PG)", user.name)
+                        }
+                        None => user.name.clone(),
+                    }
+                };
+                let alocacao = Alocacao { user_id: user.id.clone(), nome, punicao: escolha.punicao.is_some() };
+                dia.escala.entry(modelo.postos[*posto].nome.clone()).or_default().insert(horario.clone(), alocacao);
+            }
+            TipoVaga::Retem => {
+                contagem.entry(user.id.clone()).or_default().retem += 1;
+                dia.retem.push(Alocacao { user_id: user.id.clone(), nome: user.name.clone(), punicao: false });
+            }
+        }
+    }
+    let mut dividas = entradas.dividas.clone();
+    for (_, d) in modelo.dividas.iter().enumerate().filter(|(i, _)| pagas.contains(i)) {
+        if let Some(lista) = dividas.get_mut(&d.chave) {
+            if let Some(pos) = lista.iter().position(|x| x == &d.divida) {
+                lista.remove(pos);
+            }
+            if lista.is_empty() {
+                dividas.remove(&d.chave);
+            }
+        }
+    }
+
+    Ok(PropostaEscala {
+        periodo,
+        motor: MotorGeracao::Otimizado,
+        tipos_dia: modelo.dias.iter().cloned().collect(),
+        postos: entradas.postos,
+        configuracao: entradas.configuracao,
+        indisponibilidades: entradas.indisponibilidades,
+        dias,
+        contagem,
+        dividas,
+        punicoes,
+        contagem_antes: entradas.contagem,
+        dividas_antes: entradas.dividas,
+        punicoes_antes: entradas.punicoes,
+    })
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::escala::{ConfiguracaoEscala, Contagem, ContagemUtilizador, DividasAtivas, Genero};
+    use crate::testes::{data, posto, utilizador};
+
+    fn entradas(postos: Vec<Posto>) -> EntradasGeracao {
+        EntradasGeracao {
+            postos,
+            contagem: Contagem::new(),
+            dividas: DividasAtivas::new(),
+            punicoes: Vec::new(),
+            configuracao: ConfiguracaoEscala { quotas_retem: Vec::new(), ..Default::default() },
+            indisponibilidades: Vec::new(),
+            de_servico_na_vespera: Vec::new(),
+        }
+    }
+
+    fn gerar(entradas: EntradasGeracao, users: Vec<User>, dias: &[(NaiveDate, TipoServico)]) -> PropostaEscala {
+        let periodo = Periodo { start_date: dias[0].0, end_date: dias[dias.len() - 1].0 };
+        gerar_proposta(entradas, users, periodo, dias.iter().cloned().collect()).unwrap()
+    }
+
+    fn quem(proposta: &PropostaEscala, dia: NaiveDate) -> &Alocacao {
+        &proposta.dias[&dia].escala["P"]["08-12"]
+    }
+
+    #[test]
+    fn distribui_por_igual_sem_dias_seguidos() {
+        let users: Vec<User> = ["1001", "1002", "1003", "1004"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect();
+        let dias: Vec<(NaiveDate, TipoServico)> = (3..=6).map(|d| (data(2030, 3, d), TipoServico::RN)).collect();
+        let proposta = gerar(entradas(vec![posto("P", &[1], &["08-12"])]), users, &dias);
+
+        let servicos = proposta.servicos_por_utilizador();
+        assert_eq!(servicos.len(), 4);
+        assert!(servicos.values().all(|s| s.normais == 1));
+        for par in dias.windows(2) {
+            assert_ne!(quem(&proposta, par[0].0).user_id, quem(&proposta, par[1].0).user_id);
+        }
+        assert!(proposta.contagem.values().all(|c| c.rn == 1));
+    }
+
+    #[test]
+    fn quem_estava_de_servico_na_vespera_nao_comeca_o_periodo() {
+        let users: Vec<User> = ["1001", "1002"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect();
+        let mut um_na_vespera = entradas(vec![posto("P", &[1], &["08-12"])]);
+        // 1001 seria o escolhido pela contagem, mas fez o último dia do período em vigor
+        um_na_vespera.contagem.insert("1002".to_string(), ContagemUtilizador { rn: 5, rd: 0, retem: 0 });
+        um_na_vespera.de_servico_na_vespera = vec!["1001".to_string()];
+        let dias = [(data(2030, 3, 4), TipoServico::RN), (data(2030, 3, 5), TipoServico::RN)];
+        let proposta = gerar(um_na_vespera, users.clone(), &dias);
+        assert_eq!(quem(&proposta, dias[0].0).user_id, "1002");
+        assert_eq!(quem(&proposta, dias[1].0).user_id, "1001");
+
+        let mut todos_na_vespera = entradas(vec![posto("P", &[1], &["08-12"])]);
+        todos_na_vespera.de_servico_na_vespera = vec!["1001".to_string(), "1002".to_string()];
+        let periodo = Periodo { start_date: dias[0].0, end_date: dias[0].0 };
+        let erro = gerar_proposta(todos_na_vespera, users, periodo, [dias[0].clone()].into_iter().collect()).unwrap_err();
+        assert!(erro.to_string().contains("no dia anterior"), "{}", erro);
+    }
+
+    #[test]
+    fn o_devedor_faz_o_servico_que_paga_a_divida() {
+        let users: Vec<User> = ["1001", "1002", "1003"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect();
+        let mut entradas = entradas(vec![posto("P", &[1], &["08-12"])]);
+        // 1002 deve um serviço RN a 1001
+        entradas.dividas.insert(
+            "1002".to_string(),
+            vec![Divida { credor: "1001".to_string(), tipo_divida: TipoServico::RN, origem: Default::default(), criada_em: None }],
+        );
+        let dia = data(2030, 3, 4);
+        let proposta = gerar(entradas, users, &[(dia, TipoServico::RN)]);
+
+        let alocacao = quem(&proposta, dia);
+        assert_eq!(alocacao.user_id, "1002");
+        assert_eq!(alocacao.nome, "Aluno 1002 (PG)");
+        assert!(proposta.dividas.is_empty());
+        assert_eq!(proposta.dividas_pagas(), 1);
+    }
+
+    #[test]
+    fn cumpre_a_punicao_no_posto_de_punicao() {
+        let users: Vec<User> = ["1001", "1002", "1003"].iter().map(|id| utilizador(id, 1, Genero::Masculino)).collect();
+        let mut entradas = entradas(vec![posto("P", &[1], &["08-12"])]);
+        entradas.configuracao.postos_punicao = vec!["P".to_string()];
+        entradas.contagem.insert("1003".to_string(), ContagemUtilizador { rn: 9, rd: 9, retem: 0 });
+        entradas.punicoes.push(Punicao { user_id: "1003".to_string(), total_a_cumprir: 1, ..Default::default() });
+        let dia = data(2030, 3, 9);
+        let proposta = gerar(entradas, users, &[(dia, TipoServico::RD)]);
+
+        let alocacao = quem(&proposta, dia);
+        assert_eq!(alocacao.user_id, "1003");
+        assert!(alocacao.punicao);
+        assert_eq!(proposta.punicoes[0].ja_cumpridos, 1);
+        assert_eq!(proposta.punicoes[0].cumpridos[0].posto, "P");
+        assert!(!proposta.punicoes[0].ativa());
+        // O serviço de punição não conta para o equilíbrio
+        assert_eq!(proposta.contagem["1003"].rd, 9);
+    }
+
+    #[test]
+    fn sem_candidatos_explica_a_vaga() {
+        let users = vec![utilizador("1001", 2, Genero::Masculino)];
+        let dia = data(2030, 3, 4);
+        let periodo = Periodo { start_date: dia, end_date: dia };
+        let erro = gerar_proposta(entradas(vec![posto("P", &[1], &["08-12"])]), users, periodo, [(dia, TipoServico::RN)].into_iter().collect())
+            .unwrap_err();
+        let sem_solucao = erro.downcast_ref::<SemSolucao>().expect("deve ser SemSolucao");
+        assert!(!sem_solucao.conflitos.is_empty());
+    }
+}
@@ -0,0 +1,365 @@
+// src/escala_validacao.rs
+
+//! # Validação da Escala
+//!
+//! Revê os dias gravados de um intervalo (`EscalaDiaria`) e lista o que viola as regras da
+//! geração. Serve para as escalas editadas depois de geradas: trocas obrigatórias, trocas
+//! aprovadas ou ficheiros mexidos à mão.
+//!
+//! Os erros impedem o lançamento do período seguinte; os avisos só se mostram.
+
+use crate::auth::User;
+use crate::escala::{EscalaDiaria, EstadoIndisponibilidade, Indisponibilidade, Posto, Punicao};
+use crate::escala_analise::genero_str;
+use crate::escala_exportar::RETEM;
+use crate::store::{AppResult, EscalaStore};
+use chrono::{Duration, NaiveDate};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Gravidade {
+    /// Impede o lançamento.
+    Erro,
+    Aviso,
+}
+
+impl Gravidade {
+    pub fn nome(&self) -> &'static str {
+        match self {
+            Gravidade::Erro => "Erro",
+            Gravidade::Aviso => "Aviso",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Regra {
+    /// Mais de um serviço no mesmo dia.
+    Duplicado,
+    /// De serviço em dias seguidos, como a geração e as trocas não deixam.
+    DiasSeguidos,
+    /// O posto já não existe, ou não aceita o género, o ano ou a função da pessoa.
+    Posto,
+    Indisponivel,
+    PunidoNoRetem,
+    /// A pessoa não existe ou foi desativada.
+    Utilizador,
+}
+
+impl Regra {
+    pub fn nome(&self) -> &'static str {
+        match self {
+            Regra::Duplicado => "Serviços no mesmo dia",
+            Regra::DiasSeguidos => "Dias seguidos",
+            Regra::Posto => "Posto",
+            Regra::Indisponivel => "Indisponibilidade",
+            Regra::PunidoNoRetem => "Punido no retém",
+            Regra::Utilizador => "Utilizador",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Problema {
+    pub gravidade: Gravidade,
+    pub regra: Regra,
+    pub data: NaiveDate,
+    pub user_id: String,
+    pub descricao: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelatorioValidacao {
+    pub inicio: NaiveDate,
+    pub fim: NaiveDate,
+    /// Dias do intervalo com escala gravada.
+    pub dias: usize,
+    /// Por data, os erros antes dos avisos.
+    pub problemas: Vec<Problema>,
+}
+
+impl RelatorioValidacao {
+    pub fn erros(&self) -> usize {
+        self.problemas.iter().filter(|p| p.gravidade == Gravidade::Erro).count()
+    }
+
+    pub fn avisos(&self) -> usize {
+        self.problemas.len() - self.erros()
+    }
+}
+
+/// O que a validação precisa além dos dias.
+pub struct EntradasValidacao<'a> {
+    /// Os dias do intervalo e, se existirem, a véspera e o dia seguinte.
+    pub escalas: &'a BTreeMap<NaiveDate, EscalaDiaria>,
+    pub users: &'a HashMap<String, User>,
+    pub postos: &'a [Posto],
+    pub punicoes: &'a [Punicao],
+    pub indisponibilidades: &'a [Indisponibilidade],
+}
+
+/// Se a punição já estava dada e ainda por cumprir nesse dia. Uma punição cumprida conta
+/// até ao último serviço.
+fn punido_em(punicao: &Punicao, data: NaiveDate) -> bool {
+    punicao.aplicada_em.is_none_or(|a| a <= data)
+        && punicao.anulada_em.is_none_or(|a| a.date_naive() > data)
+        && (punicao.ja_cumpridos < punicao.total_a_cumprir || punicao.cumpridos.last().is_some_and(|s| s.data > data))
+}
+
+/// Os serviços de cada pessoa num dia: (posto, horário), com o retém como `RETEM`.
+fn servicos_por_pessoa(dia: &EscalaDiaria) -> BTreeMap<&str, Vec<(&str, &str)>> {
+    let mut servicos: BTreeMap<&str, Vec<(&str, &str)>> = BTreeMap::new();
+    for (posto, horarios) in &dia.escala {
+        for (horario, alocacao) in horarios {
+            servicos.entry(alocacao.user_id.as_str()).or_default().push((posto.as_str(), horario.as_str()));
+        }
+    }
+    for alocacao in &dia.retem {
+        servicos.entry(alocacao.user_id.as_str()).or_default().push((RETEM, ""));
+    }
+    for lista in servicos.values_mut() {
+        lista.sort();
+    }
+    servicos
+}
+
+fn descrever_servico((posto, horario): &(&str, &str)) -> String {
+    if horario.is_empty() { posto.to_string() } else { format!("{} {}", posto, horario) }
+}
+
+/// Revê os dias entre `inicio` e `fim` (inclusive).
+pub fn validar(inicio: NaiveDate, fim: NaiveDate, entradas: &EntradasValidacao) -> RelatorioValidacao {
+    let mut problemas = Vec::new();
+    let mut problema = |gravidade: Gravidade, regra: Regra, data: NaiveDate, user_id: &str, descricao: String| {
+        problemas.push(Problema { gravidade, regra, data, user_id: user_id.to_string(), descricao });
+    };
+    let de_servico = |data: NaiveDate, user_id: &str| {
+        entradas.escalas.get(&data).is_some_and(|dia| {
+            dia.retem.iter().any(|a| a.user_id == user_id)
+                || dia.escala.values().flat_map(|h| h.values()).any(|a| a.user_id == user_id)
+        })
+    };
+
+    for (&data, dia) in entradas.escalas.range(inicio..=fim) {
+        for (&user_id, servicos) in &servicos_por_pessoa(dia) {
+            let Some(user) = entradas.users.get(user_id) else {
+                problema(Gravidade::Erro, Regra::Utilizador, data, user_id, "Utilizador desconhecido.".to_string());
+                continue;
+            };
+            if !user.ativo {
+                problema(Gravidade::Erro, Regra::Utilizador, data, user_id, "O utilizador está desativado.".to_string());
+            }
+
+            if servicos.len() > 1 {
+                let lista: Vec<String> = servicos.iter().map(descrever_servico).collect();
+                problema(Gravidade::Erro, Regra::Duplicado, data, user_id, format!("{} serviços no mesmo dia: {}.", servicos.len(), lista.join(", ")));
+            }
+            let vespera = data - Duration::days(1);
+            if de_servico(vespera, user_id) {
+                problema(Gravidade::Erro, Regra::DiasSeguidos, data, user_id, format!("Também está de serviço na véspera ({}).", vespera.format("%d/%m")));
+            }
+            let seguinte = data + Duration::days(1);
+            if data == fim && de_servico(seguinte, user_id) {
+                problema(Gravidade::Erro, Regra::DiasSeguidos, data, user_id, format!("Também está de serviço no dia seguinte ({}).", seguinte.format("%d/%m")));
+            }
+
+            for &(nome_posto, horario) in servicos.iter().filter(|(p, _)| *p != RETEM) {
+                let servico = descrever_servico(&(nome_posto, horario));
+                let Some(posto) = entradas.postos.iter().find(|p| p.nome == nome_posto) else {
+                    problema(Gravidade::Erro, Regra::Posto, data, user_id, format!("{}: o posto já não existe.", servico));
+                    continue;
+                };
+                if !posto.genero.aceita(&user.genero) {
+                    problema(Gravidade::Erro, Regra::Posto, data, user_id, format!("{}: o posto é {}, a pessoa não.", servico, genero_str(&posto.genero)));
+                }
+                if !posto.turmas_permitidas.contains(&user.ano) {
+                    problema(Gravidade::Erro, Regra::Posto, data, user_id, format!("{}: o posto não aceita o {}º ano.", servico, user.ano));
+                }
+                if let Some(funcao) = posto.funcao_exclusiva.as_ref().filter(|f| !user.roles.contains(f)) {
+                    problema(Gravidade::Erro, Regra::Posto, data, user_id, format!("{}: o posto é só para a função {}.", servico, funcao));
+                }
+            }
+
+            for indisponibilidade in entradas.indisponibilidades.iter().filter(|i| i.user_id == user_id && i.abrange(data)) {
+                match indisponibilidade.estado {
+                    EstadoIndisponibilidade::Aprovada => problema(Gravidade::Erro, Regra::Indisponivel, data, user_id, format!("Indisponível: {}.", indisponibilidade.motivo)),
+                    EstadoIndisponibilidade::Pendente => problema(Gravidade::Aviso, Regra::Indisponivel, data, user_id, format!("Pediu indisponibilidade, ainda por decidir: {}.", indisponibilidade.motivo)),
+                    EstadoIndisponibilidade::Rejeitada => {}
+                }
+            }
+
+            if servicos.iter().any(|(p, _)| *p == RETEM) {
+                if let Some(punicao) = entradas.punicoes.iter().find(|p| p.user_id == user_id && punido_em(p, data)) {
+                    let motivo = if punicao.motivo.is_empty() { String::new() } else { format!(" ({})", punicao.motivo) };
+                    problema(Gravidade::Erro, Regra::PunidoNoRetem, data, user_id, format!("Está no retém com uma punição por cumprir{}.", motivo));
+                }
+            }
+        }
+    }
+
+    problemas.sort_by(|a, b| (a.data, a.gravidade, &a.user_id).cmp(&(b.data, b.gravidade, &b.user_id)));
+    RelatorioValidacao { inicio, fim, dias: entradas.escalas.range(inicio..=fim).count(), problemas }
+}
+
+/// Lê do armazenamento o intervalo (com a véspera e o dia seguinte) e o resto, e valida.
+pub async fn validar_periodo(store: &dyn EscalaStore, users: &HashMap<String, User>, inicio: NaiveDate, fim: NaiveDate) -> AppResult<RelatorioValidacao> {
+    let escalas = store.periodo(inicio - Duration::days(1), fim + Duration::days(1)).await?;
+    let postos = store.postos().await?;
+    let punicoes = store.punicoes().await?;
+    let indisponibilidades = store.indisponibilidades().await?;
+    let entradas = EntradasValidacao { escalas: &escalas, users, postos: &postos, punicoes: &punicoes, indisponibilidades: &indisponibilidades };
+    Ok(validar(inicio, fim, &entradas))
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::escala::{Genero, TipoServico};
+    use crate::testes::{data, dia, mapa, posto, utilizador};
+
+    struct Caso {
+        escalas: BTreeMap<NaiveDate, EscalaDiaria>,
+        users: HashMap<String, User>,
+        postos: Vec<Posto>,
+        punicoes: Vec<Punicao>,
+        indisponibilidades: Vec<Indisponibilidade>,
+    }
+
+    impl Caso {
+        fn novo(dias: Vec<(NaiveDate, EscalaDiaria)>) -> Self {
+            let mut desativado = utilizador("1004", 1, Genero::Masculino);
+            desativado.ativo = false;
+            Self {
+                escalas: dias.into_iter().collect(),
+                users: mapa(&[
+                    utilizador("1001", 1, Genero::Masculino),
+                    utilizador("1002", 1, Genero::Masculino),
+                    utilizador("1003", 2, Genero::Feminino),
+                    desativado,
+                ]),
+                postos: vec![posto("P", &[1], &["08-12", "12-16"])],
+                punicoes: Vec::new(),
+                indisponibilidades: Vec::new(),
+            }
+        }
+
+        fn validar(&self, inicio: NaiveDate, fim: NaiveDate) -> RelatorioValidacao {
+            let entradas = EntradasValidacao {
+                escalas: &self.escalas,
+                users: &self.users,
+                postos: &self.postos,
+                punicoes: &self.punicoes,
+                indisponibilidades: &self.indisponibilidades,
+            };
+            validar(inicio, fim, &entradas)
+        }
+    }
+
+    fn regras(relatorio: &RelatorioValidacao) -> Vec<(Gravidade, Regra, &str)> {
+        relatorio.problemas.iter().map(|p| (p.gravidade, p.regra, p.user_id.as_str())).collect()
+    }
+
+    fn indisponibilidade(user_id: &str, data: NaiveDate, estado: EstadoIndisponibilidade) -> Indisponibilidade {
+        Indisponibilidade {
+            id: String::new(),
+            user_id: user_id.to_string(),
+            data,
+            data_fim: None,
+            dias_semana: Vec::new(),
+            categoria: Default::default(),
+            motivo: "Médico".to_string(),
+            estado,
+        }
+    }
+
+    #[test]
+    fn escala_correta_nao_tem_problemas() {
+        let d = data(2030, 3, 4);
+        let caso = Caso::novo(vec![(d, dia(TipoServico::RN, &[("P", "08-12", "1001"), ("P", "12-16", "1002")], &["1003"]))]);
+        let relatorio = caso.validar(d, d);
+        assert_eq!(relatorio.dias, 1);
+        assert!(relatorio.problemas.is_empty(), "{:?}", relatorio.problemas);
+    }
+
+    #[test]
+    fn dois_servicos_no_mesmo_dia() {
+        let d = data(2030, 3, 4);
+        let caso = Caso::novo(vec![(d, dia(TipoServico::RN, &[("P", "08-12", "1001"), ("P", "12-16", "1001")], &[]))]);
+        assert_eq!(regras(&caso.validar(d, d)), vec![(Gravidade::Erro, Regra::Duplicado, "1001")]);
+    }
+
+    #[test]
+    fn dias_seguidos_impedem_o_lancamento() {
+        let (d1, d2, d3) = (data(2030, 3, 4), data(2030, 3, 5), data(2030, 3, 6));
+        let caso = Caso::novo(vec![
+            (d1, dia(TipoServico::RN, &[("P", "08-12", "1001")], &[])),
+            (d2, dia(TipoServico::RN, &[("P", "08-12", "1001")], &[])),
+            (d3, dia(TipoServico::RN, &[("P", "08-12", "1002")], &[])),
+        ]);
+        // Só o 2.º dia é validado: a véspera vem de fora do intervalo
+        let relatorio = caso.validar(d2, d2);
+        assert_eq!(regras(&relatorio), vec![(Gravidade::Erro, Regra::DiasSeguidos, "1001")]);
+        assert_eq!(relatorio.erros(), 1);
+
+        // No último dia também conta o dia seguinte, fora do intervalo
+        let caso = Caso::novo(vec![
+            (d1, dia(TipoServico::RN, &[("P", "08-12", "1002")], &[])),
+            (d2, dia(TipoServico::RN, &[("P", "08-12", "1002")], &[])),
+        ]);
+        assert_eq!(regras(&caso.validar(d1, d1)), vec![(Gravidade::Erro, Regra::DiasSeguidos, "1002")]);
+    }
+
+    #[test]
+    fn posto_que_nao_aceita_a_pessoa_ou_ja_nao_existe() {
+        let d = data(2030, 3, 4);
+        let caso = Caso::novo(vec![(d, dia(TipoServico::RN, &[("P", "08-12", "1003"), ("VELHO", "08-12", "1001")], &[]))]);
+        let relatorio = caso.validar(d, d);
+        assert_eq!(
+            regras(&relatorio),
+            vec![(Gravidade::Erro, Regra::Posto, "1001"), (Gravidade::Erro, Regra::Posto, "1003")]
+        );
+        assert!(relatorio.problemas[0].descricao.contains("já não existe"));
+        assert!(relatorio.problemas[1].descricao.contains("2º ano"));
+    }
+
+    #[test]
+    fn utilizadores_desconhecidos_ou_desativados() {
+        let d = data(2030, 3, 4);
+        let caso = Caso::novo(vec![(d, dia(TipoServico::RN, &[("P", "08-12", "1004"), ("P", "12-16", "9999")], &[]))]);
+        assert_eq!(
+            regras(&caso.validar(d, d)),
+            vec![(Gravidade::Erro, Regra::Utilizador, "1004"), (Gravidade::Erro, Regra::Utilizador, "9999")]
+        );
+    }
+
+    #[test]
+    fn indisponibilidades_aprovadas_e_pendentes() {
+        let d = data(2030, 3, 4);
+        let mut caso = Caso::novo(vec![(d, dia(TipoServico::RN, &[("P", "08-12", "1001"), ("P", "12-16", "1002")], &[]))]);
+        caso.indisponibilidades = vec![
+            indisponibilidade("1001", d, EstadoIndisponibilidade::Aprovada),
+            indisponibilidade("1002", d, EstadoIndisponibilidade::Pendente),
+            indisponibilidade("1002", d, EstadoIndisponibilidade::Rejeitada),
+        ];
+        let relatorio = caso.validar(d, d);
+        assert_eq!(
+            regras(&relatorio),
+            vec![(Gravidade::Erro, Regra::Indisponivel, "1001"), (Gravidade::Aviso, Regra::Indisponivel, "1002")]
+        );
+        assert_eq!((relatorio.erros(), relatorio.avisos()), (1, 1));
+    }
+
+    #[test]
+    fn punido_no_retem_so_enquanto_a_punicao_vale() {
+        let d = data(2030, 3, 4);
+        let mut caso = Caso::novo(vec![(d, dia(TipoServico::RN, &[], &["1001", "1002"]))]);
+        caso.punicoes = vec![
+            Punicao { user_id: "1001".to_string(), total_a_cumprir: 2, motivo: "Atraso".to_string(), ..Default::default() },
+            // Aplicada depois do dia validado
+            Punicao { user_id: "1002".to_string(), total_a_cumprir: 2, aplicada_em: Some(data(2030, 3, 5)), ..Default::default() },
+        ];
+        let relatorio = caso.validar(d, d);
+        assert_eq!(regras(&relatorio), vec![(Gravidade::Erro, Regra::PunidoNoRetem, "1001")]);
+        assert!(relatorio.problemas[0].descricao.contains("(Atraso)"));
+    }
+}
@@ -1,23 +1,21 @@
 // src/handlers.rs
 
-use crate::auth::{self, AppState, LoginForm};
+use crate::auth::{self, AppState, AuthUser, LoginForm};
+use crate::sessions::SESSION_COOKIE;
 use crate::users;
 use axum::http::StatusCode;
 use axum::{
     debug_handler,
-    extract::{Form, State},
-    response::{IntoResponse, Redirect},
+    extract::{ConnectInfo, Form, State},
+    response::{IntoResponse, Redirect, Response},
 };
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
-use tokio::fs;
+use std::net::SocketAddr;
 use tower_cookies::{Cookie, Cookies};
-use uuid::Uuid;
 use crate::views::dashboard as view;
 
-// Estrutura para a mensagem e constante do ficheiro
-const DASHBOARD_MESSAGE_FILE: &str = "data/dashboard_message.json";
-
+// Estrutura para a mensagem do painel
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DashboardMessage {
     pub content: String,
@@ -26,58 +24,169 @@ pub struct DashboardMessage {
     pub timestamp: DateTime<Local>,
 }
 
+/// Constrói o cookie da sessão: inacessível ao JavaScript e válido em todo o site.
+/// Com TLS ativo, o cookie só é enviado por HTTPS.
+pub fn session_cookie(session_id: String, seguro: bool) -> Cookie<'static> {
+    Cookie::build((SESSION_COOKIE, session_id))
+        .path("/")
+        .http_only(true)
+        .secure(seguro)
+        .same_site(tower_cookies::cookie::SameSite::Lax)
+        .build()
+}
+
 #[debug_handler]
 pub async fn login_page() -> impl IntoResponse {
     view::login_page(None)
 }
 
+/// Resposta para um login recusado por excesso de tentativas falhadas.
+fn login_bloqueado(ate: DateTime<Local>) -> Response {
+    let mensagem = format!("Demasiadas tentativas falhadas. Tente de novo depois das {}.", ate.format("%H:%M"));
+    (StatusCode::TOO_MANY_REQUESTS, view::login_page(Some(&mensagem))).into_response()
+}
+
 #[debug_handler]
 pub async fn login_handler(
     State(state): State<AppState>,
+    ConnectInfo(origem): ConnectInfo<SocketAddr>,
     cookies: Cookies,
     Form(login): Form<LoginForm>,
 ) -> impl IntoResponse {
-    if let Ok(fresh_users) = users::load_users().await {
+    // Enquanto bloqueado, nem a senha certa é aceite
+    if let Some(ate) = state.limite_login.bloqueado(&login.username, origem.ip()) {
+        return login_bloqueado(ate);
+    }
+
+    if let Ok(fresh_users) = users::load_users(state.user_store.as_ref()).await {
         *state.users.lock().unwrap() = fresh_users;
     }
 
-    let users = state.users.lock().unwrap();
-    if let Some(user) = users.get(&login.username) {
+    // Utilizadores desativados não podem entrar
+    let user = state.users.lock().unwrap().get(&login.username).filter(|u| u.ativo).cloned();
+    if let Some(user) = user {
         if bcrypt::verify(&login.password, &user.password).unwrap_or(false) {
-            let session_id = Uuid::new_v4().to_string();
-            state.sessions.lock().unwrap().insert(session_id.clone());
-            cookies.add(Cookie::new("session_id", session_id));
-            cookies.add(Cookie::new("user_id", login.username));
-            return Redirect::to("/dashboard").into_response();
+            state.limite_login.sucesso(&user.id);
+            match state.sessions.create(&user.id, &user.roles).await {
+                Ok(session_id) => {
+                    cookies.add(session_cookie(session_id, state.config.tls_ativo()));
+                    let destino = if user.trocar_senha { "/conta/senha" } else { "/dashboard" };
+                    return Redirect::to(destino).into_response();
+                }
+                Err(e) => {
+                    eprintln!("🔥 Falha ao criar sessão para {}: {}", user.id, e);
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Falha ao iniciar sessão.").into_response();
+                }
+            }
         }
     }
-    
+
+    // Utilizadores inexistentes contam como falha, para não revelar quais números existem
+    state.limite_login.falhou(&login.username, origem.ip());
     view::login_page(Some("Usuário ou senha incorretos.")).into_response()
 }
 
+#[derive(Debug, Deserialize)]
+pub struct AlterarSenhaForm {
+    senha_atual: String,
+    nova_senha: String,
+    confirmacao: String,
+}
+
+/// Página onde o utilizador autenticado altera a própria senha.
+/// Não usa o extrator `AuthUser`, que recusa quem ainda tem de trocar a senha.
 #[debug_handler]
-pub async fn dashboard_handler(
+pub async fn alterar_senha_page(
     State(state): State<AppState>,
     cookies: Cookies,
 ) -> impl IntoResponse {
-    let session_id = cookies.get("session_id").map(|c| c.value().to_string());
-    let user_id_cookie = cookies.get("user_id");
+    let Some(sessao) = auth::current_session(&state, &cookies).await else {
+        return Redirect::to("/").into_response();
+    };
+    let obrigatoria = auth::precisa_trocar_senha(&state, &sessao.user_id);
+    view::alterar_senha_page(&state.config.senhas, obrigatoria, None).into_response()
+}
 
-    if session_id.is_none()
-        || user_id_cookie.is_none()
-        || !state.sessions.lock().unwrap().contains(&session_id.unwrap())
-    {
+#[debug_handler]
+pub async fn alterar_senha_handler(
+    State(state): State<AppState>,
+    ConnectInfo(origem): ConnectInfo<SocketAddr>,
+    cookies: Cookies,
+    Form(form): Form<AlterarSenhaForm>,
+) -> impl IntoResponse {
+    let Some(sessao) = auth::current_session(&state, &cookies).await else {
         return Redirect::to("/").into_response();
-    }
+    };
+    let obrigatoria = auth::precisa_trocar_senha(&state, &sessao.user_id);
+    let erro = |mensagem: &str, status: StatusCode| {
+        (status, view::alterar_senha_page(&state.config.senhas, obrigatoria, Some(mensagem))).into_response()
+    };
 
-    let is_admin = auth::has_role(&state, &cookies, "admin").await;
+    // A senha atual também está sujeita ao limite de tentativas
+    if let Some(ate) = state.limite_login.bloqueado(&sessao.user_id, origem.ip()) {
+        let mensagem = format!("Demasiadas tentativas falhadas. Tente de novo depois das {}.", ate.format("%H:%M"));
+        return erro(&mensagem, StatusCode::TOO_MANY_REQUESTS);
+    }
+    let Some(mut user) = state.users.lock().unwrap().get(&sessao.user_id).cloned() else {
+        return Redirect::to("/").into_response();
+    };
+    if !bcrypt::verify(&form.senha_atual, &user.password).unwrap_or(false) {
+        state.limite_login.falhou(&user.id, origem.ip());
+        return erro("A senha atual está incorreta.", StatusCode::BAD_REQUEST);
+    }
+    if form.nova_senha != form.confirmacao {
+        return erro("As duas senhas novas não coincidem.", StatusCode::BAD_REQUEST);
+    }
+    if form.nova_senha == form.senha_atual {
+        return erro("A nova senha tem de ser diferente da atual.", StatusCode::BAD_REQUEST);
+    }
+    if let Err(mensagem) = users::validar_senha(&state.config.senhas, &user.id, &form.nova_senha) {
+        return erro(&mensagem, StatusCode::BAD_REQUEST);
+    }
 
-    let message = match fs::read_to_string(DASHBOARD_MESSAGE_FILE).await {
-        Ok(content) => serde_json::from_str::<DashboardMessage>(&content).ok(),
-        Err(_) => None,
+    user.password = match bcrypt::hash(&form.nova_senha, bcrypt::DEFAULT_COST) {
+        Ok(h) => h,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Erro ao gerar hash da senha.").into_response(),
     };
+    user.trocar_senha = false;
+    if let Err(e) = users::save_user(state.user_store.as_ref(), &user).await {
+        return (StatusCode::INTERNAL_SERVER_ERROR, format!("Erro ao guardar o utilizador: {}", e)).into_response();
+    }
+    state.users.lock().unwrap().insert(user.id.clone(), user.clone());
+    state.limite_login.sucesso(&user.id);
+
+    // Termina as sessões abertas noutros dispositivos e começa uma nova neste
+    if let Err(e) = state.sessions.revoke_user(&user.id).await {
+        eprintln!("🔥 Falha ao terminar as sessões de {}: {}", user.id, e);
+    }
+    match state.sessions.create(&user.id, &user.roles).await {
+        Ok(session_id) => cookies.add(session_cookie(session_id, state.config.tls_ativo())),
+        Err(e) => {
+            eprintln!("🔥 Falha ao criar sessão para {}: {}", user.id, e);
+            return Redirect::to("/").into_response();
+        }
+    }
+    println!("✅ O utilizador '{}' alterou a própria senha.", user.id);
+    Redirect::to("/dashboard").into_response()
+}
+
+#[debug_handler]
+pub async fn dashboard_handler(
+    State(state): State<AppState>,
+    user: AuthUser,
+) -> impl IntoResponse {
+    let is_admin = user.has_role("admin");
+
+    let message = state
+        .dashboard_store
+        .mensagem()
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("🔥 Falha ao ler a mensagem do painel: {}", e);
+            None
+        });
     
-    view::render_dashboard_page(&state, &cookies, is_admin, message).await.into_response()
+    view::render_dashboard_page(&state, &user, is_admin, message).await.into_response()
 }
 
 #[debug_handler]
@@ -85,11 +194,13 @@ pub async fn logout_handler(
     State(state): State<AppState>,
     cookies: Cookies,
 ) -> impl IntoResponse {
-    if let Some(cookie) = cookies.get("session_id") {
-        state.sessions.lock().unwrap().remove(cookie.value());
-        cookies.remove(Cookie::from("session_id"));
-        cookies.remove(Cookie::from("user_id"));
+    // Termina todas as sessões do utilizador, não apenas a deste navegador
+    if let Some(sessao) = auth::current_session(&state, &cookies).await {
+        if let Err(e) = state.sessions.revoke_user(&sessao.user_id).await {
+            eprintln!("🔥 Falha ao terminar as sessões de {}: {}", sessao.user_id, e);
+        }
     }
+    cookies.remove(Cookie::build(SESSION_COOKIE).path("/").build());
     
     view::login_page(None).into_response()
 }
@@ -102,18 +213,14 @@ pub struct DashboardMessageForm {
 #[debug_handler]
 pub async fn update_dashboard_message_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
+    AuthUser { user_id, .. }: AuthUser,
     Form(form): Form<DashboardMessageForm>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await {
-        // CORREÇÃO: Adicionado .into_response()
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-
-    let user_id = cookies.get("user_id").unwrap().value().to_string();
     let (author_name, author_role) = {
         let users = state.users.lock().unwrap();
-        let user = users.get(&user_id).cloned().unwrap();
+        let Some(user) = users.get(&user_id).cloned() else {
+            return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
+        };
         let role = user
             .roles
             .get(0)
@@ -129,14 +236,12 @@ pub async fn update_dashboard_message_handler(
         timestamp: Local::now(),
     };
 
-    if let Ok(json) = serde_json::to_string_pretty(&new_message) {
-        if fs::write(DASHBOARD_MESSAGE_FILE, json).await.is_err() {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Falha ao guardar a mensagem.",
-            )
-                .into_response();
-        }
+    if state.dashboard_store.guardar_mensagem(&new_message).await.is_err() {
+        return (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Falha ao guardar a mensagem.",
+        )
+            .into_response();
     }
 
     Redirect::to("/dashboard").into_response()
@@ -0,0 +1,78 @@
+// src/limite_login.rs
+
+//! # Limite de Tentativas de Login
+//!
+//! Os números dos utilizadores são sequenciais e fáceis de adivinhar, por isso as senhas
+//! ficam expostas a tentativas repetidas. Cada número de utilizador e cada endereço IP tem
+//! um contador de falhas; ao chegar ao limite, os logins dessa origem ficam bloqueados
+//! durante algum tempo, mesmo com a senha certa. Os contadores vivem apenas em memória.
+
+use crate::config::Login;
+use chrono::{DateTime, Duration, Local};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy)]
+struct Registo {
+    falhas: u32,
+    primeira_falha: DateTime<Local>,
+    bloqueado_ate: Option<DateTime<Local>>,
+}
+
+/// Contadores de falhas de login, partilhados através do `AppState`.
+#[derive(Clone)]
+pub struct LimiteLogin {
+    config: Login,
+    registos: Arc<Mutex<HashMap<String, Registo>>>,
+}
+
+impl LimiteLogin {
+    pub fn new(config: Login) -> Self {
+        Self { config, registos: Arc::default() }
+    }
+
+    /// As origens de um pedido de login e o número de falhas que cada uma pode ter.
+    fn chaves(&self, user_id: &str, ip: IpAddr) -> [(String, u32); 2] {
+        [
+            (format!("user:{}", user_id), self.config.tentativas),
+            (format!("ip:{}", ip), self.config.tentativas_por_ip),
+        ]
+    }
+
+    /// Se o login estiver bloqueado para este utilizador ou IP, devolve até quando.
+    pub fn bloqueado(&self, user_id: &str, ip: IpAddr) -> Option<DateTime<Local>> {
+        let agora = Local::now();
+        let registos = self.registos.lock().unwrap();
+        self.chaves(user_id, ip)
+            .iter()
+            .filter_map(|(chave, _)| registos.get(chave)?.bloqueado_ate)
+            .filter(|ate| *ate > agora)
+            .max()
+    }
+
+    /// Regista uma senha errada. As falhas são contadas dentro da janela de bloqueio.
+    pub fn falhou(&self, user_id: &str, ip: IpAddr) {
+        let agora = Local::now();
+        let janela = Duration::minutes(self.config.bloqueio_minutos);
+        let mut registos = self.registos.lock().unwrap();
+        for (chave, limite) in self.chaves(user_id, ip) {
+            let registo = registos.entry(chave.clone()).or_insert(Registo { falhas: 0, primeira_falha: agora, bloqueado_ate: None });
+            if agora - registo.primeira_falha > janela {
+                *registo = Registo { falhas: 0, primeira_falha: agora, bloqueado_ate: None };
+            }
+            registo.falhas += 1;
+            if registo.falhas >= limite {
+                registo.bloqueado_ate = Some(agora + janela);
+                println!("🚫 Login bloqueado para {} durante {} minutos.", chave, self.config.bloqueio_minutos);
+            }
+        }
+        // Não deixa a tabela crescer com registos antigos
+        registos.retain(|_, r| agora - r.primeira_falha <= janela || r.bloqueado_ate.is_some_and(|ate| ate > agora));
+    }
+
+    /// Um login certo limpa as falhas do utilizador (as do IP continuam a contar).
+    pub fn sucesso(&self, user_id: &str) {
+        self.registos.lock().unwrap().remove(&format!("user:{}", user_id));
+    }
+}
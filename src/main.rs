@@ -1,10 +1,12 @@
 // src/main.rs
 
 mod auth;
+mod db;
 mod handlers;
 mod presence;
 mod presence_handlers;
 mod users;
+mod virada_ano;
 mod admin_handlers;
 mod meals;
 mod meals_handlers;
@@ -14,139 +16,255 @@ mod presence_state;
 mod escala;
 mod escala_handlers;
 mod escala_pdf;
+mod escala_exportar;
+mod escala_analise;
+mod escala_calendario;
+mod escala_dividas;
+mod escala_punicoes;
+mod escala_solver;
+mod escala_validacao;
+mod limite_login;
 mod escala_admin_handlers; 
 mod cautela;
 mod cautela_handlers;
+mod config;
+mod sessions;
+mod store;
+mod tls;
 mod views;
+#[cfg(test)]
+mod testes;
 
 use axum::{
+    middleware,
     routing::{get, post},
     Router,
 };
-use std::{collections::HashSet, net::SocketAddr, sync::{Arc, Mutex}};
-use tokio::fs; // Adicionado
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::fs;
 use tower_cookies::CookieManagerLayer;
-
-// --- NOVO: Função para garantir que o ficheiro da mensagem existe ---
-async fn ensure_dashboard_message_file() {
-    const MESSAGE_FILE: &str = "data/dashboard_message.json";
-    if fs::try_exists(MESSAGE_FILE).await.unwrap_or(false) {
-        return;
-    }
-    // Cria um ficheiro com um objeto JSON nulo se não existir
-    if let Err(e) = fs::write(MESSAGE_FILE, "null").await {
-        eprintln!("🔥 Falha ao criar {}: {}", MESSAGE_FILE, e);
-    }
-}
-
+use auth::{Admin, AuthUser, Cautela, ChefeDeDia, Conferencia, Policia, Rancheiro, RequireAnyRole, RequireRole};
 
 #[tokio::main]
 async fn main() {
+    // Lê o mercal.toml e as opções da linha de comandos
+    let config = match config::Config::carregar() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("🔥 Configuração inválida: {}", e);
+            std::process::exit(1);
+        }
+    };
     println!("🚀 A iniciar o servidor MercAl...");
 
-    // Garante a criação de todos os ficheiros e pastas necessários
-    users::ensure_users_file().await;
-    presence::ensure_presence_file().await;
-    meals::ensure_meals_structure().await;
-    escala::ensure_escala_structure().await;
-    ensure_dashboard_message_file().await;
-    cautela::ensure_paioldelivros_structure().await;
+    // Abre a base de dados (aplicando as migrações) e importa os antigos ficheiros JSON, se existirem
+    fs::create_dir_all(&config.dados.pasta).await.expect("Falha ao criar a pasta de dados");
+    let db = db::Db::open(config.caminho(db::DB_FILE))
+        .await
+        .expect("Falha ao abrir a base de dados");
+    if let Err(e) = db.importar_json_legado(&store::ficheiros::Caminhos::new(&config)).await {
+        panic!("Falha ao importar os ficheiros JSON antigos: {}", e);
+    }
+
+    // Escolhe onde os dados vivem; os handlers só falam com os stores
+    let stores = store::abrir(&db, &config).expect("Falha ao escolher o armazenamento");
 
-    let users_map = users::load_users().await.unwrap();
+    // Garante os dados iniciais e as pastas necessárias
+    users::ensure_default_users(stores.users.as_ref(), config.admin.as_ref()).await;
+    meals::ensure_meals_structure(stores.meals.as_ref()).await;
+    escala::ensure_escala_structure(stores.escala.as_ref()).await;
+    cautela::ensure_paioldelivros_structure(&config).await;
+
+    let mut users_map = users::load_users(stores.users.as_ref()).await.expect("Falha ao carregar os utilizadores");
+    if let Err(e) = cautela::migrar_responsaveis(stores.users.as_ref(), &mut users_map, &config).await {
+        eprintln!("🔥 Falha ao migrar os responsáveis da cautela: {}", e);
+    }
+    let session_store = sessions::SessionStore::open(&db, &config.sessoes)
+        .await
+        .expect("Falha ao abrir as sessões");
     
     // Inicializa o estado da aplicação
     let app_state = auth::AppState {
-        sessions: Arc::new(Mutex::new(HashSet::new())),
+        config: config.clone(),
+        user_store: stores.users,
+        presence_store: stores.presence,
+        meal_store: stores.meals,
+        escala_store: stores.escala,
+        dashboard_store: stores.dashboard,
+        sessions: session_store,
+        limite_login: limite_login::LimiteLogin::new(config.login.clone()),
         users: Arc::new(Mutex::new(users_map)),
         checkin_state: checkin::CheckinState::default(),
         presence_state: presence_state::PresenceSocketState::default(),
+        proposta_escala: Arc::default(),
     };
 
-    // Define todas as rotas da aplicação
-    let app = Router::new()
-        // Rotas Principais e de Autenticação
+    // Define todas as rotas da aplicação, agrupadas pela autorização que exigem.
+    // Cada grupo é protegido por uma `route_layer` com o extrator correspondente.
+
+    // Rotas Públicas e de Autenticação
+    let public_routes = Router::new()
         .route("/", get(handlers::login_page))
         .route("/login", post(handlers::login_handler))
-        .route("/dashboard", get(handlers::dashboard_handler))
         .route("/logout", get(handlers::logout_handler))
-        .route("/admin", get(admin_handlers::admin_page_handler))
-        .route("/admin/change-password", post(admin_handlers::change_password_handler))
-        .route("/admin/create-user", post(admin_handlers::create_user_handler))
+        // Valida a sessão no próprio handler: tem de funcionar enquanto a troca da senha é obrigatória
+        .route("/conta/senha", get(handlers::alterar_senha_page).post(handlers::alterar_senha_handler))
+        .route("/teste-json", get(cautela_handlers::teste_json_handler))
+        // O token secreto no endereço substitui a sessão, para as aplicações de calendário
+        .route("/calendario/:token", get(escala_handlers::calendario_handler));
 
-        // --- NOVO: Rota para atualizar a mensagem ---
-        .route("/dashboard/update_message", post(handlers::update_dashboard_message_handler))
-        
-        // Rotas de Presença
-        .route("/presence", get(presence_handlers::presence_page))
-        .route("/ws/presence", get(presence_handlers::presence_websocket_handler))
-        
-        // Rotas de Refeições
+    // Rotas de qualquer utilizador autenticado
+    let user_routes = Router::new()
+        .route("/dashboard", get(handlers::dashboard_handler))
         .route("/refeicoes", get(meals_handlers::user_meals_page))
         .route("/refeicoes/save_all", post(meals_handlers::save_all_meals_handler))
-        .route("/admin/refeicoes", get(meals_handlers::admin_meals_page))
-        .route("/admin/refeicoes/open", post(meals_handlers::open_meals_form))
-        .route("/admin/refeicoes/close", post(meals_handlers::close_meals_form))
-        .route("/admin/refeicoes/reopen", post(meals_handlers::reopen_active_period_form))
-        .route("/admin/refeicoes/save_edits", post(meals_handlers::save_edits_form))
-        
-        // Rotas de Check-in de Refeições
-        .route("/refeicoes/checkin", get(checkin_handlers::checkin_page))
-        .route("/ws/refeicoes/checkin", get(checkin_handlers::checkin_websocket_handler))
-        .route("/refeicoes/checkin/relatorio_ausentes", get(checkin_handlers::generate_absent_report_handler))
-        
-        // --- ROTAS DO MÓDULO DE ESCALAS (REORGANIZADAS) ---
-        // Rotas de Utilizador
         .route("/escala", get(escala_handlers::user_escala_page))
         .route("/escala/pedir_troca", post(escala_handlers::pedir_troca_handler))
         .route("/escala/responder_troca", post(escala_handlers::responder_troca_handler))
+        .route("/escala/cancelar_troca", post(escala_handlers::cancelar_troca_handler))
+        .route("/escala/trocas", get(escala_handlers::minhas_trocas_page))
+        .route("/escala/ofertas", get(escala_handlers::quadro_ofertas_page))
+        .route("/escala/ofertas/publicar", post(escala_handlers::publicar_oferta_handler))
+        .route("/escala/ofertas/retirar", post(escala_handlers::retirar_oferta_handler))
+        .route("/escala/ofertas/assumir", post(escala_handlers::assumir_oferta_handler))
+        .route("/escala/ofertas/cadeia", post(escala_handlers::propor_cadeia_handler))
+        .route("/escala/indisponibilidade/pedir", post(escala_handlers::pedir_indisponibilidade_handler))
+        .route("/escala/indisponibilidade/cancelar", post(escala_handlers::cancelar_indisponibilidade_handler))
+        .route("/escala/exportar", get(escala_handlers::exportar_meus_servicos_handler))
+        .route("/escala/calendario/gerar", post(escala_handlers::gerar_token_calendario_handler))
+        .route_layer(middleware::from_extractor_with_state::<AuthUser, _>(app_state.clone()));
 
-        // Rotas de Administração
+    // Rotas de Administração
+    let admin_routes = Router::new()
+        .route("/admin", get(admin_handlers::admin_page_handler))
+        .route("/admin/change-password", post(admin_handlers::change_password_handler))
+        .route("/admin/create-user", post(admin_handlers::create_user_handler))
+        .route("/admin/utilizadores", get(admin_handlers::utilizadores_page))
+        .route("/admin/utilizadores/editar", get(admin_handlers::editar_utilizador_page).post(admin_handlers::editar_utilizador_handler))
+        .route("/admin/utilizadores/desativar", post(admin_handlers::desativar_utilizador_handler))
+        .route("/admin/utilizadores/reativar", post(admin_handlers::reativar_utilizador_handler))
+        .route("/admin/utilizadores/apagar", post(admin_handlers::apagar_utilizador_handler))
+        .route("/admin/utilizadores/exportar", get(admin_handlers::exportar_utilizadores_handler))
+        .route("/admin/utilizadores/importar", post(admin_handlers::importar_utilizadores_handler))
+        .route("/admin/virada-ano", get(admin_handlers::virada_ano_page).post(admin_handlers::aplicar_virada_ano_handler))
+        .route("/dashboard/update_message", post(handlers::update_dashboard_message_handler))
+
+        // --- ROTAS DO MÓDULO DE ESCALAS ---
         .route("/admin/escala", get(escala_admin_handlers::admin_escala_page))
         .route("/admin/escala/gerar", post(escala_admin_handlers::gerar_escala_handler))
+        .route("/admin/escala/proposta", get(escala_admin_handlers::proposta_escala_page))
+        .route("/admin/escala/proposta/gravar", post(escala_admin_handlers::gravar_proposta_handler))
+        .route("/admin/escala/proposta/descartar", post(escala_admin_handlers::descartar_proposta_handler))
         .route("/admin/escala/lancar", post(escala_admin_handlers::lancar_escala_handler))
+        .route("/admin/escala/validar", get(escala_admin_handlers::validacao_escala_page))
+        .route("/admin/escala/analise", get(escala_admin_handlers::analise_escala_page))
+        .route("/admin/escala/dividas", get(escala_admin_handlers::dividas_page))
+        .route("/admin/escala/dividas/lancar", post(escala_admin_handlers::lancar_divida_handler))
+        .route("/admin/escala/dividas/perdoar", post(escala_admin_handlers::perdoar_divida_handler))
+        .route("/admin/escala/dividas/transferir", post(escala_admin_handlers::transferir_divida_handler))
+        .route("/admin/escala/dividas/regras", post(escala_admin_handlers::regras_dividas_handler))
+        .route("/admin/escala/calendario", get(escala_admin_handlers::calendario_page))
+        .route("/admin/escala/calendario/adicionar", post(escala_admin_handlers::adicionar_dia_calendario_handler))
+        .route("/admin/escala/calendario/remover", post(escala_admin_handlers::remover_dia_calendario_handler))
+        .route("/admin/escala/calendario/regras", post(escala_admin_handlers::regras_calendario_handler))
+        .route("/admin/escala/calendario/importar", post(escala_admin_handlers::importar_calendario_handler))
+        .route("/admin/escala/calendario/tipos", get(escala_admin_handlers::tipos_calendario_handler))
+        .route("/admin/escala/execucoes", get(escala_admin_handlers::execucoes_escala_page))
+        .route("/admin/escala/execucoes/comparar", get(escala_admin_handlers::comparar_execucoes_page))
+        .route("/admin/escala/execucoes/reverter", post(escala_admin_handlers::reverter_execucao_handler))
         .route("/admin/escala/aprovar_troca", post(escala_admin_handlers::aprovar_troca_handler))
         .route("/admin/escala/fechar_trocas", post(escala_admin_handlers::fechar_trocas_handler))
         .route("/admin/escala/reabrir_trocas", post(escala_admin_handlers::reabrir_trocas_handler))
         .route("/admin/escala/indisponibilidade/adicionar", post(escala_admin_handlers::adicionar_indisponibilidade_handler))
         .route("/admin/escala/indisponibilidade/remover", post(escala_admin_handlers::remover_indisponibilidade_handler))
-        // --- NOVAS ROTAS DE PUNIÇÃO ---
-        .route("/admin/escala/punicao/adicionar", post(escala_admin_handlers::adicionar_punicao_handler))
-        .route("/admin/escala/punicao/remover", post(escala_admin_handlers::remover_punicao_handler))
+        .route("/admin/escala/indisponibilidade/decidir", post(escala_admin_handlers::decidir_indisponibilidade_handler))
+        .route("/admin/escala/punicoes", get(escala_admin_handlers::punicoes_page))
+        .route("/admin/escala/punicoes/adicionar", post(escala_admin_handlers::adicionar_punicao_handler))
+        .route("/admin/escala/punicoes/agravar", post(escala_admin_handlers::agravar_punicao_handler))
+        .route("/admin/escala/punicoes/anular", post(escala_admin_handlers::anular_punicao_handler))
+        .route("/admin/escala/punicoes/aviso", get(escala_admin_handlers::aviso_punicao_pdf_handler))
         .route("/admin/escala/configuracao/salvar", post(escala_admin_handlers::salvar_configuracao_punicao_handler))
+        .route("/admin/escala/configuracao/retem", post(escala_admin_handlers::salvar_quotas_retem_handler))
+        .route("/admin/escala/postos", get(escala_admin_handlers::postos_page))
+        .route("/admin/escala/postos/editar", get(escala_admin_handlers::editar_posto_page).post(escala_admin_handlers::editar_posto_handler))
+        .route("/admin/escala/postos/mover", post(escala_admin_handlers::mover_posto_handler))
+        .route("/admin/escala/postos/retirar", post(escala_admin_handlers::retirar_posto_handler))
+        .route("/admin/escala/postos/reativar", post(escala_admin_handlers::reativar_posto_handler))
         .route("/admin/escala/pdf", get(escala_admin_handlers::gerar_pdf_escala_handler))
         .route("/admin/escala/troca_obrigatoria", post(escala_admin_handlers::troca_obrigatoria_handler))
+        .route_layer(middleware::from_extractor_with_state::<RequireRole<Admin>, _>(app_state.clone()));
+
+    // Exportação de um posto ao longo da escala (admin e chefe de dia)
+    let escala_posto_routes = Router::new()
+        .route("/escala/posto/exportar", get(escala_admin_handlers::exportar_posto_handler))
+        .route_layer(middleware::from_extractor_with_state::<RequireAnyRole<(Admin, ChefeDeDia)>, _>(app_state.clone()));
+
+    // Rotas de Gestão de Refeições (rancheiro)
+    let rancho_routes = Router::new()
+        .route("/admin/refeicoes", get(meals_handlers::admin_meals_page))
+        .route("/admin/refeicoes/open", post(meals_handlers::open_meals_form))
+        .route("/admin/refeicoes/close", post(meals_handlers::close_meals_form))
+        .route("/admin/refeicoes/reopen", post(meals_handlers::reopen_active_period_form))
+        .route("/admin/refeicoes/save_edits", post(meals_handlers::save_edits_form))
+        .route_layer(middleware::from_extractor_with_state::<RequireRole<Rancheiro>, _>(app_state.clone()));
+
+    // Rotas de Check-in de Refeições
+    let checkin_routes = Router::new()
+        .route("/refeicoes/checkin", get(checkin_handlers::checkin_page))
+        .route("/ws/refeicoes/checkin", get(checkin_handlers::checkin_websocket_handler))
+        .route("/refeicoes/checkin/relatorio_ausentes", get(checkin_handlers::generate_absent_report_handler))
+        .route_layer(middleware::from_extractor_with_state::<RequireAnyRole<(Rancheiro, Conferencia)>, _>(app_state.clone()));
 
+    // Rotas de Presença
+    let presence_routes = Router::new()
+        .route("/presence", get(presence_handlers::presence_page))
+        .route("/ws/presence", get(presence_handlers::presence_websocket_handler))
+        .route_layer(middleware::from_extractor_with_state::<RequireAnyRole<(Admin, Policia, ChefeDeDia)>, _>(app_state.clone()));
 
- // --- Rotas do Módulo de Cautela (do cautela_handlers.rs) ---
-        .route("/cautela", get(cautela_handlers::cautela_login_page))
-        .route("/cautela/login", post(cautela_handlers::cautela_login_handler))
-        .route("/cautela/logout", get(cautela_handlers::cautela_logout_handler))
+    // --- Rotas do Módulo de Cautela (do cautela_handlers.rs) ---
+    let cautela_routes = Router::new()
+        .route("/cautela", get(cautela_handlers::cautela_home_handler))
         .route("/cautela/dashboard", get(cautela_handlers::cautela_dashboard_handler))
-        
+
         // Suas novas rotas do catálogo e ações
         .route("/cautela/catalogo", get(cautela_handlers::cautela_catalogo_page))
         .route("/cautela/catalogo/add-item", post(cautela_handlers::cautela_add_item_handler))
         .route("/cautela/catalogo/add-exemplar", post(cautela_handlers::cautela_add_exemplar_handler))
         .route("/cautela/catalogo/delete-exemplar", post(cautela_handlers::cautela_delete_exemplar_handler))
         .route("/cautela/atrasos", get(cautela_handlers::cautela_atrasos_page))
-        
+
         // Rotas de ações de empréstimo que já existiam
         .route("/cautela/emprestar", post(cautela_handlers::cautela_emprestar_handler))
         .route("/cautela/devolver", post(cautela_handlers::cautela_devolver_handler))
         .route("/cautela/renovar", post(cautela_handlers::cautela_renovar_handler))
+        .route_layer(middleware::from_extractor_with_state::<RequireRole<Cautela>, _>(app_state.clone()));
 
-
-        .route("/teste-json", get(cautela_handlers::teste_json_handler))
-
+    let app = Router::new()
+        .merge(public_routes)
+        .merge(user_routes)
+        .merge(admin_routes)
+        .merge(escala_posto_routes)
+        .merge(rancho_routes)
+        .merge(checkin_routes)
+        .merge(presence_routes)
+        .merge(cautela_routes)
         .with_state(app_state)
         .layer(CookieManagerLayer::new());
-    
-    //172.20.10.3
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    if config.tls_ativo() {
+        if let Err(e) = tls::servir(app, &config).await {
+            eprintln!("🔥 Falha no servidor HTTPS: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let addr = config.endereco();
     println!("✅ Servidor a escutar em http://{}", addr);
 
     // MUDANÇA: Lógica de inicialização do servidor atualizada para Axum 0.8+
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app.into_make_service()).await.unwrap();
+    // O endereço de origem é usado pelo limite de tentativas de login
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await.unwrap();
 }
\ No newline at end of file
@@ -1,13 +1,10 @@
 // src/meals.rs
 
 use crate::auth::User;
+use crate::store::MealStore;
 use chrono::{DateTime, Local, NaiveDate};
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
-use tokio::fs;
-
-const MEALS_DATA_DIR: &str = "data/refeicoes";
-const STATE_FILE: &str = "data/refeicoes/estado.json";
 
 type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
@@ -90,12 +87,11 @@ pub struct MealSummary {
     pub ceia: u32,
 }
 
-pub async fn ensure_meals_structure() {
-    if let Err(e) = fs::create_dir_all(MEALS_DATA_DIR).await {
-        eprintln!("🔥 Falha crítica ao criar o diretório '{}': {}", MEALS_DATA_DIR, e);
-    }
-    if fs::try_exists(STATE_FILE).await.unwrap_or(false) {
-        return;
+pub async fn ensure_meals_structure(store: &dyn MealStore) {
+    match store.estado().await {
+        Ok(Some(_)) => return,
+        Ok(None) => {}
+        Err(e) => eprintln!("AVISO: Estado das refeições ilegível ({}). A recriar com valores padrão.", e),
     }
     let default_state = MealFormState {
         active_period: PeriodInfo {
@@ -107,102 +103,92 @@ pub async fn ensure_meals_structure() {
         closed_info: None,
         reopened_info: None,
     };
-    if let Ok(json) = serde_json::to_string_pretty(&default_state) {
-        let _ = fs::write(STATE_FILE, json).await;
+    if let Err(e) = save_form_state(store, &default_state).await {
+        eprintln!("🔥 Falha crítica ao criar o estado das refeições: {}", e);
     }
 }
 
-pub async fn load_form_state() -> AppResult<MealFormState> {
-    let content = fs::read_to_string(STATE_FILE).await?;
-    let state: MealFormState = serde_json::from_str(&content)?;
-    Ok(state)
+pub async fn load_form_state(store: &dyn MealStore) -> AppResult<MealFormState> {
+    store
+        .estado()
+        .await?
+        .ok_or_else(|| "Estado das refeições não encontrado.".into())
 }
 
-pub async fn save_form_state(state: &MealFormState) -> AppResult<()> {
-    let json_content = serde_json::to_string_pretty(state)?;
-    fs::write(STATE_FILE, json_content).await?;
-    Ok(())
+pub async fn save_form_state(store: &dyn MealStore, state: &MealFormState) -> AppResult<()> {
+    store.guardar_estado(state).await
 }
 
-/// Cria os ficheiros de refeição diários, **sem sobrescrever os que já existem**.
-pub async fn create_daily_meal_files(start: NaiveDate, end: NaiveDate, users: &HashMap<String, User>) -> AppResult<()> {
-    let mut current_date = start;
-    while current_date <= end {
-        let filename = format!("{}/{}.json", MEALS_DATA_DIR, current_date.format("%Y-%m-%d"));
-        
-        // --- CORREÇÃO ---
-        // Apenas cria o ficheiro se ele não existir, para não apagar dados ao reabrir.
-        if fs::try_exists(&filename).await? {
-            current_date = current_date.succ_opt().unwrap_or(current_date);
-            continue; // Pula para o próximo dia
-        }
-        
-        let mut daily_data: HashMap<String, MealSelection> = HashMap::new();
-        for user in users.values() {
-            daily_data.insert(
-                user.id.clone(),
-                MealSelection {
-                    nome: user.name.clone(),
-                    turma: user.turma.clone(),
-                    cafe: false,
-                    almoco: false,
-                    janta: false,
-                    ceia: false,
-                    cafe_realizado: false,
-                    almoco_realizado: false,
-                    janta_realizado: false,
-                    ceia_realizado: false,
-                    cafe_marcado_por: None,
-                    cafe_marcado_em: None,
-                    almoco_marcado_por: None,
-                    almoco_marcado_em: None,
-                    janta_marcado_por: None,
-                    janta_marcado_em: None,
-                    ceia_marcado_por: None,
-                    ceia_marcado_em: None,
-                },
-            );
-        }
-        let json_content = serde_json::to_string_pretty(&daily_data)?;
-        fs::write(filename, json_content).await?;
-        current_date = current_date.succ_opt().unwrap_or(current_date);
+/// Cria as refeições diárias de todos os utilizadores ativos, **sem sobrescrever os dias que já existem**.
+pub async fn create_daily_meals(store: &dyn MealStore, start: NaiveDate, end: NaiveDate, users: &HashMap<String, User>) -> AppResult<()> {
+    let mut selecoes = HashMap::new();
+    for user in users.values().filter(|u| u.ativo) {
+        let selection = MealSelection {
+            nome: user.name.clone(),
+            turma: user.turma.clone(),
+            cafe: false,
+            almoco: false,
+            janta: false,
+            ceia: false,
+            cafe_realizado: false,
+            almoco_realizado: false,
+            janta_realizado: false,
+            ceia_realizado: false,
+            cafe_marcado_por: None,
+            cafe_marcado_em: None,
+            almoco_marcado_por: None,
+            almoco_marcado_em: None,
+            janta_marcado_por: None,
+            janta_marcado_em: None,
+            ceia_marcado_por: None,
+            ceia_marcado_em: None,
+        };
+        selecoes.insert(user.id.clone(), selection);
     }
-    Ok(())
-}
 
-pub async fn delete_daily_meal_files(start: NaiveDate, end: NaiveDate) -> AppResult<()> {
+    let mut datas = Vec::new();
     let mut current_date = start;
     while current_date <= end {
-        let filename = format!("{}/{}.json", MEALS_DATA_DIR, current_date.format("%Y-%m-%d"));
-        if fs::try_exists(&filename).await? {
-            fs::remove_file(filename).await?;
-        }
-        current_date = current_date.succ_opt().unwrap_or(current_date);
+        datas.push(current_date);
+        current_date = match current_date.succ_opt() {
+            Some(d) => d,
+            None => break,
+        };
     }
-    Ok(())
+
+    store.criar_dias(&datas, &selecoes).await
 }
 
-pub async fn load_daily_meals(date: NaiveDate) -> AppResult<HashMap<String, MealSelection>> {
-    let filename = format!("{}/{}.json", MEALS_DATA_DIR, date.format("%Y-%m-%d"));
-    let content = fs::read_to_string(filename).await?;
-    let data = serde_json::from_str(&content)?;
-    Ok(data)
+pub async fn delete_daily_meals(store: &dyn MealStore, start: NaiveDate, end: NaiveDate) -> AppResult<()> {
+    store.apagar_dias(start, end).await
+}
+
+/// Carrega as refeições de um dia. Devolve erro se o dia não tiver sido criado.
+pub async fn load_daily_meals(store: &dyn MealStore, date: NaiveDate) -> AppResult<HashMap<String, MealSelection>> {
+    let daily_data = store.dia(date).await?;
+    if daily_data.is_empty() {
+        return Err(format!("Sem refeições registadas para {}.", date.format("%Y-%m-%d")).into());
+    }
+    Ok(daily_data)
 }
 
-pub async fn save_daily_meals(date: NaiveDate, data: &HashMap<String, MealSelection>) -> AppResult<()> {
-    let filename = format!("{}/{}.json", MEALS_DATA_DIR, date.format("%Y-%m-%d"));
-    let json_content = serde_json::to_string_pretty(data)?;
-    fs::write(filename, json_content).await?;
-    Ok(())
+/// Altera a refeição de um utilizador num dia, de forma atómica.
+/// A função devolve `true` se alterou algo; o resultado é `false` se nada foi gravado
+/// (incluindo quando o utilizador não tem refeições nesse dia).
+pub async fn update_meal_selection<F>(store: &dyn MealStore, date: NaiveDate, user_id: &str, f: F) -> AppResult<bool>
+where
+    F: FnOnce(&mut MealSelection) -> bool + Send + 'static,
+{
+    store.atualizar_selecao(date, user_id, Box::new(f)).await
 }
 
-pub async fn get_daily_summary_counts(start: NaiveDate, end: NaiveDate) -> BTreeMap<NaiveDate, MealSummary> {
+pub async fn get_daily_summary_counts(store: &dyn MealStore, start: NaiveDate, end: NaiveDate) -> BTreeMap<NaiveDate, MealSummary> {
     let mut daily_summary = BTreeMap::new();
     let mut current_date = start;
 
     while current_date <= end {
         let mut summary_for_day = MealSummary::default();
-        if let Ok(daily_data) = load_daily_meals(current_date).await {
+        if let Ok(daily_data) = load_daily_meals(store, current_date).await {
             for selection in daily_data.values() {
                 if selection.cafe { summary_for_day.cafe += 1; }
                 if selection.almoco { summary_for_day.almoco += 1; }
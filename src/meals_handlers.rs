@@ -1,6 +1,7 @@
 // src/meals_handlers.rs
 
-use crate::auth::{self, AppState};
+use crate::auth::{AppState, AuthUser};
+use crate::store::MealStore;
 use crate::meals::{self, AuditInfo, FormStatus, MealFormState, PeriodInfo};
 // ADICIONADO: Importar o novo módulo de views
 use crate::views;
@@ -13,7 +14,6 @@ use axum::{
 use chrono::{Datelike, Local, NaiveDate, Weekday};
 use serde::Deserialize;
 use std::collections::HashMap;
-use tower_cookies::Cookies;
 
 #[derive(Deserialize, Debug)]
 pub struct AdminMealsForm {
@@ -22,11 +22,11 @@ pub struct AdminMealsForm {
 }
 
 // Função auxiliar para carregar o estado de forma segura ou criar um padrão
-async fn get_or_create_form_state() -> MealFormState {
-    match meals::load_form_state().await {
+async fn get_or_create_form_state(store: &dyn MealStore) -> MealFormState {
+    match meals::load_form_state(store).await {
         Ok(state) => state,
         Err(e) => {
-            eprintln!("AVISO: Falha ao carregar 'estado.json' (pode ser formato antigo): {}. A recriar com valores padrão.", e);
+            eprintln!("AVISO: Falha ao carregar o estado das refeições (pode ser formato antigo): {}. A recriar com valores padrão.", e);
             let default_state = MealFormState {
                 active_period: PeriodInfo {
                     start_date: NaiveDate::from_ymd_opt(2025, 1, 1).unwrap(),
@@ -37,8 +37,8 @@ async fn get_or_create_form_state() -> MealFormState {
                 closed_info: None,
                 reopened_info: None,
             };
-            if let Err(save_err) = meals::save_form_state(&default_state).await {
-                eprintln!("ERRO CRÍTICO: Não foi possível recriar 'estado.json': {}", save_err);
+            if let Err(save_err) = meals::save_form_state(store, &default_state).await {
+                eprintln!("ERRO CRÍTICO: Não foi possível recriar o estado das refeições: {}", save_err);
             }
             default_state
         }
@@ -50,17 +50,8 @@ async fn get_or_create_form_state() -> MealFormState {
 #[debug_handler]
 pub async fn admin_meals_page(
     State(state): State<AppState>,
-    cookies: Cookies,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "rancheiro").await {
-        return (
-            StatusCode::FORBIDDEN,
-            "Acesso negado. Apenas para rancheiros.",
-        )
-            .into_response();
-    }
-
-    let form_state = get_or_create_form_state().await;
+    let form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
 
     // LÓGICA MOVIDA DO HTML PARA O HANDLER
     let (status_html, actions_html, new_period_disabled) = match &form_state.status {
@@ -91,7 +82,7 @@ pub async fn admin_meals_page(
         ),
     };
     
-    let daily_summary = meals::get_daily_summary_counts(form_state.active_period.start_date, form_state.active_period.end_date).await;
+    let daily_summary = meals::get_daily_summary_counts(state.meal_store.as_ref(), form_state.active_period.start_date, form_state.active_period.end_date).await;
 
     let mut audit_html = String::new();
     if let Some(info) = &form_state.opened_info {
@@ -114,10 +105,8 @@ pub async fn admin_meals_page(
     ).into_response()
 }
 
-fn get_current_user_info(state: &AppState, cookies: &Cookies) -> String {
-    let user_id = cookies
-        .get("user_id")
-        .map_or("unknown".to_string(), |c| c.value().to_string());
+fn get_current_user_info(state: &AppState, user: &AuthUser) -> String {
+    let user_id = user.user_id.clone();
     let users = state.users.lock().unwrap();
     users
         .get(&user_id)
@@ -127,14 +116,10 @@ fn get_current_user_info(state: &AppState, cookies: &Cookies) -> String {
 #[debug_handler]
 pub async fn open_meals_form(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
     Form(form): Form<AdminMealsForm>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "rancheiro").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-    
-    let mut form_state = get_or_create_form_state().await;
+    let mut form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
     if !matches!(form_state.status, FormStatus::Closed) {
         return (
             StatusCode::BAD_REQUEST,
@@ -157,21 +142,21 @@ pub async fn open_meals_form(
     let new_pending_period = PeriodInfo { start_date, end_date };
     form_state.status = FormStatus::PendingNew(new_pending_period);
     form_state.opened_info = Some(AuditInfo {
-        by: get_current_user_info(&state, &cookies),
+        by: get_current_user_info(&state, &user),
         at: Local::now(),
     });
     form_state.closed_info = None;
     form_state.reopened_info = None;
 
     let users_clone = state.users.lock().unwrap().clone();
-    if let Err(e) = meals::create_daily_meal_files(start_date, end_date, &users_clone).await {
+    if let Err(e) = meals::create_daily_meals(state.meal_store.as_ref(), start_date, end_date, &users_clone).await {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Erro ao criar ficheiros de refeição: {}", e),
+            format!("Erro ao criar as refeições diárias: {}", e),
         )
             .into_response();
     }
-    if let Err(e) = meals::save_form_state(&form_state).await {
+    if let Err(e) = meals::save_form_state(state.meal_store.as_ref(), &form_state).await {
         return (
             StatusCode::INTERNAL_SERVER_ERROR,
             format!("Erro ao guardar estado: {}", e),
@@ -184,19 +169,15 @@ pub async fn open_meals_form(
 #[debug_handler]
 pub async fn close_meals_form(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "rancheiro").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-    
-    let mut form_state = get_or_create_form_state().await;
+    let mut form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
     
     if let FormStatus::PendingNew(pending) = form_state.status {
         let old_active_period = std::mem::replace(&mut form_state.active_period, pending);
         form_state.status = FormStatus::Closed;
         form_state.closed_info = Some(AuditInfo {
-            by: get_current_user_info(&state, &cookies),
+            by: get_current_user_info(&state, &user),
             at: Local::now(),
         });
         
@@ -208,12 +189,12 @@ pub async fn close_meals_form(
         };
 
         if deletion_start <= deletion_end {
-            if let Err(e) = meals::delete_daily_meal_files(deletion_start, deletion_end).await {
-                eprintln!("AVISO: Falha ao apagar ficheiros do período antigo: {}", e);
+            if let Err(e) = meals::delete_daily_meals(state.meal_store.as_ref(), deletion_start, deletion_end).await {
+                eprintln!("AVISO: Falha ao apagar as refeições do período antigo: {}", e);
             }
         }
 
-        if let Err(e) = meals::save_form_state(&form_state).await {
+        if let Err(e) = meals::save_form_state(state.meal_store.as_ref(), &form_state).await {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Erro ao guardar estado: {}", e),
@@ -227,32 +208,28 @@ pub async fn close_meals_form(
 #[debug_handler]
 pub async fn reopen_active_period_form(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "rancheiro").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-    
-    let mut form_state = get_or_create_form_state().await;
+    let mut form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
     if matches!(form_state.status, FormStatus::Closed) {
         let users_clone = state.users.lock().unwrap().clone();
         let active_period = &form_state.active_period;
         if let Err(e) =
-            meals::create_daily_meal_files(active_period.start_date, active_period.end_date, &users_clone)
+            meals::create_daily_meals(state.meal_store.as_ref(), active_period.start_date, active_period.end_date, &users_clone)
                 .await
         {
             eprintln!(
-                "AVISO: Falha ao verificar/criar ficheiros de refeição ao reabrir: {}",
+                "AVISO: Falha ao verificar/criar as refeições diárias ao reabrir: {}",
                 e
             );
         }
 
         form_state.status = FormStatus::EditingActive;
         form_state.reopened_info = Some(AuditInfo {
-            by: get_current_user_info(&state, &cookies),
+            by: get_current_user_info(&state, &user),
             at: Local::now(),
         });
-        if let Err(e) = meals::save_form_state(&form_state).await {
+        if let Err(e) = meals::save_form_state(state.meal_store.as_ref(), &form_state).await {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Erro ao guardar estado: {}", e),
@@ -266,20 +243,16 @@ pub async fn reopen_active_period_form(
 #[debug_handler]
 pub async fn save_edits_form(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "rancheiro").await {
-        return (StatusCode::FORBIDDEN, "Acesso negado.").into_response();
-    }
-    
-    let mut form_state = get_or_create_form_state().await;
+    let mut form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
     if matches!(form_state.status, FormStatus::EditingActive) {
         form_state.status = FormStatus::Closed;
         form_state.closed_info = Some(AuditInfo {
-            by: get_current_user_info(&state, &cookies),
+            by: get_current_user_info(&state, &user),
             at: Local::now(),
         });
-        if let Err(e) = meals::save_form_state(&form_state).await {
+        if let Err(e) = meals::save_form_state(state.meal_store.as_ref(), &form_state).await {
             return (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 format!("Erro ao guardar estado: {}", e),
@@ -292,15 +265,12 @@ pub async fn save_edits_form(
 
 #[debug_handler]
 pub async fn user_meals_page(
-    State(_state): State<AppState>,
-    cookies: Cookies,
+    State(state): State<AppState>,
+    user: AuthUser,
 ) -> impl IntoResponse {
-    let user_id = cookies
-        .get("user_id")
-        .map(|c| c.value().to_string())
-        .unwrap_or_default();
+    let user_id = user.user_id;
     
-    let form_state = get_or_create_form_state().await;
+    let form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
 
     let period_to_show = match form_state.status {
         FormStatus::PendingNew(period) => Some(period),
@@ -313,7 +283,7 @@ pub async fn user_meals_page(
         let mut current_date = period.start_date;
 
         while current_date <= period.end_date {
-            let daily_data = meals::load_daily_meals(current_date).await.ok();
+            let daily_data = meals::load_daily_meals(state.meal_store.as_ref(), current_date).await.ok();
             let selection = daily_data.as_ref().and_then(|d| d.get(&user_id));
             
             let date_str = current_date.format("%Y-%m-%d");
@@ -358,24 +328,21 @@ pub async fn user_meals_page(
         }
         
         // CHAMA A FUNÇÃO DA VIEW
-        views::meals::user_meals_page(&period, day_cards_html)
+        views::meals::user_meals_page(&period, day_cards_html).into_response()
     } else {
-        views::meals::user_meals_page(&PeriodInfo::default(), String::new())
+        views::meals::user_meals_page(&PeriodInfo::default(), String::new()).into_response()
     }
 }
 
 #[debug_handler]
 pub async fn save_all_meals_handler(
-    State(_state): State<AppState>,
-    cookies: Cookies,
+    State(state): State<AppState>,
+    user: AuthUser,
     Form(form_data): Form<HashMap<String, String>>,
 ) -> impl IntoResponse {
-    let user_id = cookies
-        .get("user_id")
-        .map(|c| c.value().to_string())
-        .unwrap_or_default();
+    let user_id = user.user_id;
     
-    let form_state = get_or_create_form_state().await;
+    let form_state = get_or_create_form_state(state.meal_store.as_ref()).await;
     
     let period_to_save = match form_state.status {
         FormStatus::PendingNew(period) => Some(period),
@@ -386,17 +353,20 @@ pub async fn save_all_meals_handler(
     if let Some(period) = period_to_save {
         let mut current_date = period.start_date;
         while current_date <= period.end_date {
-            if let Ok(mut daily_data) = meals::load_daily_meals(current_date).await {
-                if let Some(selection) = daily_data.get_mut(&user_id) {
-                    let date_str = current_date.format("%Y-%m-%d");
-                    selection.cafe = form_data.contains_key(&format!("cafe-{}", date_str));
-                    selection.almoco = form_data.contains_key(&format!("almoco-{}", date_str));
-                    selection.janta = form_data.contains_key(&format!("janta-{}", date_str));
-                    selection.ceia = form_data.contains_key(&format!("ceia-{}", date_str));
-                    
-                    let _ = meals::save_daily_meals(current_date, &daily_data).await;
-                }
-            }
+            let date_str = current_date.format("%Y-%m-%d");
+            let cafe = form_data.contains_key(&format!("cafe-{}", date_str));
+            let almoco = form_data.contains_key(&format!("almoco-{}", date_str));
+            let janta = form_data.contains_key(&format!("janta-{}", date_str));
+            let ceia = form_data.contains_key(&format!("ceia-{}", date_str));
+
+            let _ = meals::update_meal_selection(state.meal_store.as_ref(), current_date, &user_id, move |selection| {
+                selection.cafe = cafe;
+                selection.almoco = almoco;
+                selection.janta = janta;
+                selection.ceia = ceia;
+                true
+            })
+            .await;
             current_date = current_date.succ_opt().unwrap_or(current_date);
         }
     }
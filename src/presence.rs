@@ -3,12 +3,8 @@
 use chrono::{DateTime, Local};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::fs;
 use crate::auth::User;
-
-// --- ALTERADO: Diretório e nome do ficheiro de dados ---
-const DATA_DIR: &str = "data/presencas";
-const PRESENCE_FILE: &str = "data/presencas/presenca.json";
+use crate::store::PresenceStore;
 
 type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
@@ -41,45 +37,16 @@ pub struct PresenceStats {
     pub total: usize,
 }
 
-/// Garante que o diretório de dados e o ficheiro presenca.json existam.
-pub async fn ensure_presence_file() {
-    if let Err(e) = fs::create_dir_all(DATA_DIR).await {
-        eprintln!("🔥 Falha crítica ao criar o diretório '{}': {}", DATA_DIR, e);
-        return;
-    }
-
-    if !fs::try_exists(PRESENCE_FILE).await.unwrap_or(false) {
-        println!("📝 A criar ficheiro de presença em {}...", PRESENCE_FILE);
-        // Cria um ficheiro com um mapa JSON vazio
-        if let Err(e) = fs::write(PRESENCE_FILE, "{}").await {
-            eprintln!("🔥 Falha crítica ao criar {}: {}", PRESENCE_FILE, e);
-        }
-    }
-}
-
-/// Carrega o mapa de presenças a partir do ficheiro JSON.
-async fn load_presence_map() -> AppResult<HashMap<String, PresenceEntry>> {
-    let content = fs::read_to_string(PRESENCE_FILE).await?;
-    let presence_map: HashMap<String, PresenceEntry> = serde_json::from_str(&content)?;
-    Ok(presence_map)
-}
-
-/// Guarda o mapa de presenças no ficheiro JSON.
-async fn save_presence_map(presence_map: &HashMap<String, PresenceEntry>) -> AppResult<()> {
-    let json_content = serde_json::to_string_pretty(presence_map)?;
-    fs::write(PRESENCE_FILE, json_content).await?;
-    Ok(())
-}
-
 /// Combina os dados de todos os utilizadores com os dados de presença para uma turma específica.
 pub async fn get_presence_list_for_turma(
+    store: &dyn PresenceStore,
     all_users: &HashMap<String, User>,
     turma_num: u8,
 ) -> AppResult<Vec<PresencePerson>> {
-    let presence_map = load_presence_map().await?;
+    let presence_map = store.listar().await?;
     let mut presence_list = Vec::new();
 
-    for user in all_users.values().filter(|u| u.ano == turma_num) {
+    for user in all_users.values().filter(|u| u.ativo && u.ano == turma_num) {
         let entry = presence_map.get(&user.id).cloned().unwrap_or_default();
         presence_list.push(PresencePerson {
             id: user.id.clone(),
@@ -99,21 +66,21 @@ pub async fn get_presence_list_for_turma(
 }
 
 /// Marca a saída de uma pessoa, atualizando o seu estado.
-pub async fn marcar_saida(user_id: String, usuario_marcou: String) -> AppResult<()> {
-    let mut presence_map = load_presence_map().await?;
-    let entry = presence_map.entry(user_id).or_default();
-    entry.ultima_saida = Some(Local::now());
-    entry.usuario_saida = Some(usuario_marcou);
-    save_presence_map(&presence_map).await
+pub async fn marcar_saida(store: &dyn PresenceStore, user_id: String, usuario_marcou: String) -> AppResult<()> {
+    store.atualizar(&user_id, Box::new(|entry| {
+        entry.ultima_saida = Some(Local::now());
+        entry.usuario_saida = Some(usuario_marcou);
+    }))
+    .await
 }
 
 /// Marca o retorno de uma pessoa, atualizando o seu estado.
-pub async fn marcar_retorno(user_id: String, usuario_marcou: String) -> AppResult<()> {
-    let mut presence_map = load_presence_map().await?;
-    let entry = presence_map.entry(user_id).or_default();
-    entry.ultimo_retorno = Some(Local::now());
-    entry.usuario_retorno = Some(usuario_marcou);
-    save_presence_map(&presence_map).await
+pub async fn marcar_retorno(store: &dyn PresenceStore, user_id: String, usuario_marcou: String) -> AppResult<()> {
+    store.atualizar(&user_id, Box::new(|entry| {
+        entry.ultimo_retorno = Some(Local::now());
+        entry.usuario_retorno = Some(usuario_marcou);
+    }))
+    .await
 }
 
 /// Calcula as estatísticas para um dado conjunto de pessoas.
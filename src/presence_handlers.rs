@@ -1,6 +1,6 @@
 // src/presence_handlers.rs
 
-use crate::auth::{self, AppState};
+use crate::auth::{AppState, AuthUser};
 use crate::presence::{self};
 use crate::presence_state::{PresenceSocketAction, PresenceSocketUpdate};
 // ADICIONADO: Importar o novo módulo de views
@@ -12,12 +12,11 @@ use axum::{
         Query, State,
     },
     http::StatusCode,
-    response::{Html, IntoResponse, Redirect},
+    response::{Html, IntoResponse},
 };
 use futures_util::{stream::StreamExt, SinkExt};
 use serde::Deserialize;
 use tokio::sync::mpsc;
-use tower_cookies::Cookies;
 use uuid::Uuid;
 
 // --- O MÓDULO 'VIEW' FOI REMOVIDO DAQUI ---
@@ -30,26 +29,12 @@ pub struct PresenceQuery {
 #[debug_handler]
 pub async fn presence_page(
     State(state): State<AppState>,
-    cookies: Cookies,
     Query(params): Query<PresenceQuery>,
 ) -> impl IntoResponse {
-    if !auth::has_role(&state, &cookies, "admin").await
-        && !auth::has_role(&state, &cookies, "polícia").await
-        && !auth::has_role(&state, &cookies, "chefe de dia").await
-    {
-        return (
-            StatusCode::FORBIDDEN,
-            Html("<h1>Acesso Negado</h1><p>Esta funcionalidade é restrita.</p><a href='/dashboard'>Voltar</a>"),
-        ).into_response();
-    }
-    if cookies.get("session_id").is_none() {
-        return Redirect::to("/").into_response();
-    }
-
     let turma_selecionada = params.turma.unwrap_or(1);
     
     let all_users = state.users.lock().unwrap().clone();
-    let pessoas = match presence::get_presence_list_for_turma(&all_users, turma_selecionada).await {
+    let pessoas = match presence::get_presence_list_for_turma(state.presence_store.as_ref(), &all_users, turma_selecionada).await {
         Ok(lista) => lista,
         Err(e) => {
             eprintln!("Erro ao carregar lista de presença: {}", e);
@@ -70,12 +55,10 @@ pub async fn presence_page(
 #[debug_handler]
 pub async fn presence_websocket_handler(
     State(state): State<AppState>,
-    cookies: Cookies,
+    user: AuthUser,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
-    let operator_id = cookies
-        .get("user_id")
-        .map_or("Desconhecido".to_string(), |c| c.value().to_string());
+    let operator_id = user.user_id;
     ws.on_upgrade(move |socket| handle_socket(socket, state, operator_id))
 }
 
@@ -128,15 +111,15 @@ async fn handle_socket(socket: WebSocket, state: AppState, operator_id: String)
                 let turma_num = user_to_update.ano;
 
                 let result = match action.action.as_str() {
-                    "saida" => presence::marcar_saida(action.user_id.clone(), operator_name).await,
+                    "saida" => presence::marcar_saida(state_clone.presence_store.as_ref(), action.user_id.clone(), operator_name).await,
                     "retorno" => {
-                        presence::marcar_retorno(action.user_id.clone(), operator_name).await
+                        presence::marcar_retorno(state_clone.presence_store.as_ref(), action.user_id.clone(), operator_name).await
                     }
                     _ => Err("Ação inválida".into()),
                 };
 
                 let all_users = state_clone.users.lock().unwrap().clone();
-                let pessoas_turma = presence::get_presence_list_for_turma(&all_users, turma_num)
+                let pessoas_turma = presence::get_presence_list_for_turma(state_clone.presence_store.as_ref(), &all_users, turma_num)
                     .await
                     .unwrap_or_default();
                 let stats = presence::calcular_stats(&pessoas_turma);
@@ -0,0 +1,138 @@
+// src/sessions.rs
+
+//! # Módulo de Sessões Persistentes
+//!
+//! Guarda as sessões de login na base de dados principal, associando cada
+//! identificador de sessão ao utilizador que a abriu. As sessões expiram por
+//! inatividade e por tempo absoluto, e sobrevivem a reinícios do servidor.
+
+use chrono::Local;
+use rusqlite::{params, OptionalExtension};
+use tokio_rusqlite::Connection;
+use uuid::Uuid;
+
+use crate::config::Sessoes;
+use crate::db::Db;
+
+pub const SESSION_COOKIE: &str = "session_id";
+
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// O dono de uma sessão válida e as funções que tinha no momento do login.
+#[derive(Debug, Clone)]
+pub struct Sessao {
+    pub user_id: String,
+    pub roles: Vec<String>,
+}
+
+/// Armazenamento das sessões, partilhado através do `AppState`.
+#[derive(Clone)]
+pub struct SessionStore {
+    conn: Connection,
+    /// Tempo máximo sem atividade antes de a sessão expirar, em segundos.
+    idle_timeout_secs: i64,
+    /// Tempo máximo de vida de uma sessão, independentemente da atividade, em segundos.
+    absolute_timeout_secs: i64,
+}
+
+impl SessionStore {
+    /// Usa a ligação da base de dados principal (a tabela é criada pelas migrações)
+    /// e apaga as sessões que expiraram enquanto o servidor esteve parado.
+    pub async fn open(db: &Db, prazos: &Sessoes) -> AppResult<Self> {
+        let store = Self {
+            conn: db.conn().clone(),
+            idle_timeout_secs: prazos.inatividade_minutos * 60,
+            absolute_timeout_secs: prazos.duracao_maxima_horas * 60 * 60,
+        };
+        store.purge_expired().await?;
+        Ok(store)
+    }
+
+    /// Cria uma nova sessão para o utilizador e devolve o seu identificador.
+    pub async fn create(&self, user_id: &str, roles: &[String]) -> AppResult<String> {
+        let session_id = Uuid::new_v4().to_string();
+        let roles_json = serde_json::to_string(roles)?;
+        let now = Local::now().timestamp();
+        let (id, user_id) = (session_id.clone(), user_id.to_string());
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO sessoes (id, user_id, roles, criada_em, ultimo_acesso) VALUES (?1, ?2, ?3, ?4, ?4)",
+                    params![id, user_id, roles_json, now],
+                )?;
+                Ok(())
+            })
+            .await?;
+        Ok(session_id)
+    }
+
+    /// Valida uma sessão: devolve-a se ainda estiver dentro dos prazos e atualiza o último acesso.
+    /// Sessões expiradas são apagadas.
+    pub async fn validate(&self, session_id: &str) -> Option<Sessao> {
+        let id = session_id.to_string();
+        let now = Local::now().timestamp();
+        let (idle, absolute) = (self.idle_timeout_secs, self.absolute_timeout_secs);
+        let res = self
+            .conn
+            .call(move |conn| {
+                let row: Option<(String, String, i64, i64)> = conn
+                    .query_row(
+                        "SELECT user_id, roles, criada_em, ultimo_acesso FROM sessoes WHERE id = ?1",
+                        [&id],
+                        |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+                    )
+                    .optional()?;
+                let Some((user_id, roles, criada_em, ultimo_acesso)) = row else {
+                    return Ok(None);
+                };
+                if now - ultimo_acesso > idle || now - criada_em > absolute {
+                    conn.execute("DELETE FROM sessoes WHERE id = ?1", [&id])?;
+                    return Ok(None);
+                }
+                conn.execute("UPDATE sessoes SET ultimo_acesso = ?1 WHERE id = ?2", params![now, id])?;
+                Ok(Some((user_id, roles)))
+            })
+            .await;
+
+        match res {
+            Ok(Some((user_id, roles))) => Some(Sessao {
+                user_id,
+                roles: serde_json::from_str(&roles).unwrap_or_default(),
+            }),
+            Ok(None) => None,
+            Err(e) => {
+                eprintln!("🔥 Erro ao validar sessão: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Termina todas as sessões de um utilizador (logout, alteração de senha, remoção).
+    pub async fn revoke_user(&self, user_id: &str) -> AppResult<()> {
+        let user_id = user_id.to_string();
+        self.conn
+            .call(move |conn| {
+                conn.execute("DELETE FROM sessoes WHERE user_id = ?1", [&user_id])?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Apaga todas as sessões que já ultrapassaram algum dos prazos.
+    pub async fn purge_expired(&self) -> AppResult<()> {
+        let now = Local::now().timestamp();
+        let (idle, absolute) = (self.idle_timeout_secs, self.absolute_timeout_secs);
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM sessoes WHERE ultimo_acesso < ?1 OR criada_em < ?2",
+                    params![now - idle, now - absolute],
+                )?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,505 @@
+// src/store/ficheiros.rs
+
+//! Implementação dos stores sobre os ficheiros JSON, com a organização usada antes da
+//! base de dados: `users.json`, `data/presencas/presenca.json`, um ficheiro por dia em
+//! `data/refeicoes/` e `data/escala/`, e os documentos de `Documento::ficheiro`.
+//!
+//! Cada operação corre numa thread de bloqueio e com um trinco partilhado, por isso as
+//! alterações nunca se sobrepõem. As escritas são feitas para um ficheiro temporário que
+//! depois substitui o original, para não deixar ficheiros meio escritos.
+
+use super::{
+    AlteracaoEscala, AlteracaoPresenca, AlteracaoRefeicao, AppResult, DashboardStore, Documento, EscalaStore, EscalaTx,
+    MealStore, PresenceStore, UserStore,
+};
+use crate::auth::User;
+use crate::config::Config;
+use crate::escala::{
+    ConfiguracaoEscala, Contagem, DividasAtivas, EscalaDiaria, EstadoEscala, ExecucaoEscala, Indisponibilidade, OfertaServico,
+    Posto, Punicao, Troca,
+};
+use crate::escala_dividas::MovimentoDivida;
+use crate::handlers::DashboardMessage;
+use crate::meals::{MealFormState, MealSelection};
+use crate::presence::PresenceEntry;
+use axum::async_trait;
+use chrono::NaiveDate;
+use serde::{de::DeserializeOwned, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+// --- CAMINHOS DOS FICHEIROS (relativos à pasta dos dados) ---
+pub const PRESENCE_FILE: &str = "presencas/presenca.json";
+pub const MEALS_DATA_DIR: &str = "refeicoes";
+pub const ESCALA_DATA_DIR: &str = "escala";
+pub const TROCAS_FILE: &str = "escala/trocas.json";
+pub const EXECUCOES_FILE: &str = "escala/execucoes.json";
+
+/// Onde vivem os ficheiros: a pasta dos dados e o `users.json`, que fica fora dela.
+#[derive(Debug, Clone)]
+pub struct Caminhos {
+    pub dados: PathBuf,
+    pub utilizadores: PathBuf,
+}
+
+impl Caminhos {
+    pub fn new(config: &Config) -> Self {
+        Self { dados: config.dados.pasta.clone(), utilizadores: config.dados.utilizadores.clone() }
+    }
+
+    pub fn presencas(&self) -> PathBuf {
+        self.dados.join(PRESENCE_FILE)
+    }
+
+    pub fn refeicoes(&self) -> PathBuf {
+        self.dados.join(MEALS_DATA_DIR)
+    }
+
+    pub fn escala(&self) -> PathBuf {
+        self.dados.join(ESCALA_DATA_DIR)
+    }
+
+    pub fn trocas(&self) -> PathBuf {
+        self.dados.join(TROCAS_FILE)
+    }
+
+    pub fn execucoes(&self) -> PathBuf {
+        self.dados.join(EXECUCOES_FILE)
+    }
+
+    pub fn documento(&self, doc: Documento) -> PathBuf {
+        self.dados.join(doc.ficheiro())
+    }
+}
+
+#[derive(Clone)]
+pub struct FicheirosStore {
+    caminhos: Caminhos,
+    trinco: Arc<Mutex<()>>,
+}
+
+impl FicheirosStore {
+    pub fn new(caminhos: Caminhos) -> Self {
+        Self { caminhos, trinco: Arc::default() }
+    }
+
+    /// Corre `f` numa thread de bloqueio, com o trinco dos ficheiros na mão.
+    async fn bloquear<R, F>(&self, f: F) -> AppResult<R>
+    where
+        F: FnOnce(&Caminhos) -> AppResult<R> + Send + 'static,
+        R: Send + 'static,
+    {
+        let (caminhos, trinco) = (self.caminhos.clone(), self.trinco.clone());
+        tokio::task::spawn_blocking(move || {
+            let _guarda = trinco.lock().unwrap_or_else(|e| e.into_inner());
+            f(&caminhos)
+        })
+        .await?
+    }
+}
+
+fn ficheiro_diario(pasta: &Path, data: NaiveDate) -> PathBuf {
+    pasta.join(format!("{}.json", data.format("%Y-%m-%d")))
+}
+
+/// Lê um ficheiro JSON. Devolve `None` se o ficheiro não existir.
+fn ler_json<T: DeserializeOwned>(caminho: &Path) -> AppResult<Option<T>> {
+    match fs::read_to_string(caminho) {
+        Ok(conteudo) => Ok(Some(serde_json::from_str(&conteudo)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Grava um ficheiro JSON, criando a pasta se for preciso.
+fn escrever_json<T: Serialize + ?Sized>(caminho: &Path, valor: &T) -> AppResult<()> {
+    escrever_texto(caminho, &serde_json::to_string_pretty(valor)?)
+}
+
+fn escrever_texto(caminho: &Path, conteudo: &str) -> AppResult<()> {
+    if let Some(pasta) = caminho.parent() {
+        fs::create_dir_all(pasta)?;
+    }
+    let mut temporario = caminho.as_os_str().to_owned();
+    temporario.push(".tmp");
+    fs::write(&temporario, conteudo)?;
+    fs::rename(&temporario, caminho)?;
+    Ok(())
+}
+
+#[async_trait]
+impl UserStore for FicheirosStore {
+    async fn listar(&self) -> AppResult<HashMap<String, User>> {
+        self.bloquear(|c| {
+            let users: Vec<User> = ler_json(&c.utilizadores)?.unwrap_or_default();
+            Ok(users.into_iter().map(|user| (user.id.clone(), user)).collect())
+        })
+        .await
+    }
+
+    async fn guardar(&self, user: &User) -> AppResult<()> {
+        let user = user.clone();
+        self.bloquear(move |c| {
+            let mut users: Vec<User> = ler_json(&c.utilizadores)?.unwrap_or_default();
+            match users.iter_mut().find(|u| u.id == user.id) {
+                Some(atual) => *atual = user,
+                None => users.push(user),
+            }
+            escrever_json(&c.utilizadores, &users)
+        })
+        .await
+    }
+
+    async fn apagar(&self, id: &str) -> AppResult<bool> {
+        let id = id.to_string();
+        self.bloquear(move |c| {
+            let mut users: Vec<User> = ler_json(&c.utilizadores)?.unwrap_or_default();
+            let antes = users.len();
+            users.retain(|u| u.id != id);
+            if users.len() == antes {
+                return Ok(false);
+            }
+            escrever_json(&c.utilizadores, &users)?;
+            Ok(true)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl PresenceStore for FicheirosStore {
+    async fn listar(&self) -> AppResult<HashMap<String, PresenceEntry>> {
+        self.bloquear(|c| Ok(ler_json(&c.presencas())?.unwrap_or_default())).await
+    }
+
+    async fn atualizar(&self, user_id: &str, f: AlteracaoPresenca) -> AppResult<()> {
+        let user_id = user_id.to_string();
+        self.bloquear(move |c| {
+            let mut presencas: HashMap<String, PresenceEntry> = ler_json(&c.presencas())?.unwrap_or_default();
+            f(presencas.entry(user_id).or_default());
+            escrever_json(&c.presencas(), &presencas)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl MealStore for FicheirosStore {
+    async fn estado(&self) -> AppResult<Option<MealFormState>> {
+        self.bloquear(|c| ler_json(&c.documento(Documento::EstadoRefeicoes))).await
+    }
+
+    async fn guardar_estado(&self, estado: &MealFormState) -> AppResult<()> {
+        let estado = estado.clone();
+        self.bloquear(move |c| escrever_json(&c.documento(Documento::EstadoRefeicoes), &estado)).await
+    }
+
+    async fn criar_dias(&self, datas: &[NaiveDate], selecoes: &HashMap<String, MealSelection>) -> AppResult<()> {
+        let (datas, conteudo) = (datas.to_vec(), serde_json::to_string_pretty(selecoes)?);
+        self.bloquear(move |c| {
+            for data in datas {
+                let caminho = ficheiro_diario(&c.refeicoes(), data);
+                // Apenas cria o dia se ele não existir, para não apagar dados ao reabrir.
+                if !caminho.exists() {
+                    escrever_texto(&caminho, &conteudo)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn apagar_dias(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<()> {
+        self.bloquear(move |c| {
+            for data in inicio.iter_days().take_while(|d| *d <= fim) {
+                match fs::remove_file(ficheiro_diario(&c.refeicoes(), data)) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    async fn dia(&self, data: NaiveDate) -> AppResult<HashMap<String, MealSelection>> {
+        self.bloquear(move |c| Ok(ler_json(&ficheiro_diario(&c.refeicoes(), data))?.unwrap_or_default())).await
+    }
+
+    async fn atualizar_selecao(&self, data: NaiveDate, user_id: &str, f: AlteracaoRefeicao) -> AppResult<bool> {
+        let user_id = user_id.to_string();
+        self.bloquear(move |c| {
+            let caminho = ficheiro_diario(&c.refeicoes(), data);
+            let Some(mut dia) = ler_json::<HashMap<String, MealSelection>>(&caminho)? else { return Ok(false) };
+            let Some(selection) = dia.get_mut(&user_id) else { return Ok(false) };
+            if !f(selection) {
+                return Ok(false);
+            }
+            escrever_json(&caminho, &dia)?;
+            Ok(true)
+        })
+        .await
+    }
+}
+
+#[async_trait]
+impl EscalaStore for FicheirosStore {
+    async fn estado(&self) -> AppResult<EstadoEscala> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::EstadoEscala))?.unwrap_or_default())).await
+    }
+
+    async fn postos(&self) -> AppResult<Vec<Posto>> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::Postos))?.unwrap_or_default())).await
+    }
+
+    async fn contagem(&self) -> AppResult<Contagem> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::Contagem))?.unwrap_or_default())).await
+    }
+
+    async fn dividas(&self) -> AppResult<DividasAtivas> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::Dividas))?.unwrap_or_default())).await
+    }
+
+    async fn movimentos_dividas(&self) -> AppResult<Vec<MovimentoDivida>> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::MovimentosDividas))?.unwrap_or_default())).await
+    }
+
+    async fn indisponibilidades(&self) -> AppResult<Vec<Indisponibilidade>> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::Indisponibilidades))?.unwrap_or_default())).await
+    }
+
+    async fn punicoes(&self) -> AppResult<Vec<Punicao>> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::Punicoes))?.unwrap_or_default())).await
+    }
+
+    async fn ofertas(&self) -> AppResult<Vec<OfertaServico>> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::OfertasServico))?.unwrap_or_default())).await
+    }
+
+    async fn configuracao(&self) -> AppResult<ConfiguracaoEscala> {
+        self.bloquear(|c| Ok(ler_json(&c.documento(Documento::ConfiguracaoEscala))?.unwrap_or_default())).await
+    }
+
+    async fn dia(&self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>> {
+        self.bloquear(move |c| ler_json(&ficheiro_diario(&c.escala(), data))).await
+    }
+
+    async fn periodo(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<BTreeMap<NaiveDate, EscalaDiaria>> {
+        self.bloquear(move |c| {
+            let mut escalas = BTreeMap::new();
+            for data in inicio.iter_days().take_while(|d| *d <= fim) {
+                if let Some(escala) = ler_json(&ficheiro_diario(&c.escala(), data))? {
+                    escalas.insert(data, escala);
+                }
+            }
+            Ok(escalas)
+        })
+        .await
+    }
+
+    async fn trocas(&self) -> AppResult<Vec<Troca>> {
+        self.bloquear(|c| Ok(ler_json(&c.trocas())?.unwrap_or_default())).await
+    }
+
+    async fn execucoes(&self) -> AppResult<Vec<ExecucaoEscala>> {
+        self.bloquear(|c| Ok(ler_json(&c.execucoes())?.unwrap_or_default())).await
+    }
+
+    async fn transacao(&self, f: AlteracaoEscala) -> AppResult<()> {
+        self.bloquear(move |c| {
+            let mut tx = TxFicheiros { caminhos: c.clone(), pendentes: BTreeMap::new() };
+            f(&mut tx)?;
+            // Só depois de a função terminar sem erros é que os ficheiros são escritos
+            for (caminho, conteudo) in &tx.pendentes {
+                match conteudo {
+                    Some(conteudo) => escrever_texto(caminho, conteudo)?,
+                    None => match fs::remove_file(caminho) {
+                        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                        _ => {}
+                    },
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+}
+
+/// Transação sobre os ficheiros: as escritas ficam pendentes até ao fim da função
+/// e as leituras já veem o que foi escrito antes dentro da mesma transação.
+/// Um ficheiro pendente sem conteúdo é apagado.
+struct TxFicheiros {
+    caminhos: Caminhos,
+    pendentes: BTreeMap<PathBuf, Option<String>>,
+}
+
+impl TxFicheiros {
+    fn ler<T: DeserializeOwned>(&self, caminho: &Path) -> AppResult<Option<T>> {
+        match self.pendentes.get(caminho) {
+            Some(Some(conteudo)) => Ok(Some(serde_json::from_str(conteudo)?)),
+            Some(None) => Ok(None),
+            None => ler_json(caminho),
+        }
+    }
+
+    fn escrever<T: Serialize + ?Sized>(&mut self, caminho: PathBuf, valor: &T) -> AppResult<()> {
+        self.pendentes.insert(caminho, Some(serde_json::to_string_pretty(valor)?));
+        Ok(())
+    }
+
+    fn documento<T: DeserializeOwned + Default>(&self, doc: Documento) -> AppResult<T> {
+        Ok(self.ler(&self.caminhos.documento(doc))?.unwrap_or_default())
+    }
+
+    fn ler_trocas(&self) -> AppResult<Vec<Troca>> {
+        Ok(self.ler(&self.caminhos.trocas())?.unwrap_or_default())
+    }
+}
+
+impl EscalaTx for TxFicheiros {
+    fn estado(&mut self) -> AppResult<EstadoEscala> {
+        self.documento(Documento::EstadoEscala)
+    }
+
+    fn guardar_estado(&mut self, estado: &EstadoEscala) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::EstadoEscala), estado)
+    }
+
+    fn postos(&mut self) -> AppResult<Vec<Posto>> {
+        self.documento(Documento::Postos)
+    }
+
+    fn guardar_postos(&mut self, postos: &[Posto]) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::Postos), postos)
+    }
+
+    fn contagem(&mut self) -> AppResult<Contagem> {
+        self.documento(Documento::Contagem)
+    }
+
+    fn guardar_contagem(&mut self, contagem: &Contagem) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::Contagem), contagem)
+    }
+
+    fn dividas(&mut self) -> AppResult<DividasAtivas> {
+        self.documento(Documento::Dividas)
+    }
+
+    fn guardar_dividas(&mut self, dividas: &DividasAtivas) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::Dividas), dividas)
+    }
+
+    fn movimentos_dividas(&mut self) -> AppResult<Vec<MovimentoDivida>> {
+        self.documento(Documento::MovimentosDividas)
+    }
+
+    fn guardar_movimentos_dividas(&mut self, movimentos: &[MovimentoDivida]) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::MovimentosDividas), movimentos)
+    }
+
+    fn indisponibilidades(&mut self) -> AppResult<Vec<Indisponibilidade>> {
+        self.documento(Documento::Indisponibilidades)
+    }
+
+    fn guardar_indisponibilidades(&mut self, indisponibilidades: &[Indisponibilidade]) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::Indisponibilidades), indisponibilidades)
+    }
+
+    fn punicoes(&mut self) -> AppResult<Vec<Punicao>> {
+        self.documento(Documento::Punicoes)
+    }
+
+    fn guardar_punicoes(&mut self, punicoes: &[Punicao]) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::Punicoes), punicoes)
+    }
+
+    fn ofertas(&mut self) -> AppResult<Vec<OfertaServico>> {
+        self.documento(Documento::OfertasServico)
+    }
+
+    fn guardar_ofertas(&mut self, ofertas: &[OfertaServico]) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::OfertasServico), ofertas)
+    }
+
+    fn configuracao(&mut self) -> AppResult<ConfiguracaoEscala> {
+        self.documento(Documento::ConfiguracaoEscala)
+    }
+
+    fn guardar_configuracao(&mut self, configuracao: &ConfiguracaoEscala) -> AppResult<()> {
+        self.escrever(self.caminhos.documento(Documento::ConfiguracaoEscala), configuracao)
+    }
+
+    fn dia(&mut self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>> {
+        self.ler(&ficheiro_diario(&self.caminhos.escala(), data))
+    }
+
+    fn guardar_dia(&mut self, data: NaiveDate, escala: &EscalaDiaria) -> AppResult<()> {
+        self.escrever(ficheiro_diario(&self.caminhos.escala(), data), escala)
+    }
+
+    fn apagar_dia(&mut self, data: NaiveDate) -> AppResult<()> {
+        self.pendentes.insert(ficheiro_diario(&self.caminhos.escala(), data), None);
+        Ok(())
+    }
+
+    fn trocas(&mut self) -> AppResult<Vec<Troca>> {
+        self.ler_trocas()
+    }
+
+    fn troca(&mut self, id: &str) -> AppResult<Option<Troca>> {
+        Ok(self.ler_trocas()?.into_iter().find(|t| t.id == id))
+    }
+
+    fn guardar_troca(&mut self, troca: &Troca) -> AppResult<()> {
+        let mut trocas = self.ler_trocas()?;
+        match trocas.iter_mut().find(|t| t.id == troca.id) {
+            Some(atual) => *atual = troca.clone(),
+            None => trocas.push(troca.clone()),
+        }
+        self.escrever(self.caminhos.trocas(), &trocas)
+    }
+
+    fn execucoes(&mut self) -> AppResult<Vec<ExecucaoEscala>> {
+        Ok(self.ler(&self.caminhos.execucoes())?.unwrap_or_default())
+    }
+
+    fn guardar_execucao(&mut self, execucao: &ExecucaoEscala) -> AppResult<()> {
+        let mut execucoes = self.execucoes()?;
+        match execucoes.iter_mut().find(|e| e.versao == execucao.versao) {
+            Some(atual) => *atual = execucao.clone(),
+            None => execucoes.push(execucao.clone()),
+        }
+        self.escrever(self.caminhos.execucoes(), &execucoes)
+    }
+
+    fn utilizadores(&mut self) -> AppResult<HashMap<String, User>> {
+        let users: Vec<User> = self.ler(&self.caminhos.utilizadores)?.unwrap_or_default();
+        Ok(users.into_iter().map(|user| (user.id.clone(), user)).collect())
+    }
+
+    fn guardar_utilizadores(&mut self, alterados: &[User]) -> AppResult<()> {
+        let mut users: Vec<User> = self.ler(&self.caminhos.utilizadores)?.unwrap_or_default();
+        for user in alterados {
+            match users.iter_mut().find(|u| u.id == user.id) {
+                Some(atual) => *atual = user.clone(),
+                None => users.push(user.clone()),
+            }
+        }
+        self.escrever(self.caminhos.utilizadores.clone(), &users)
+    }
+}
+
+#[async_trait]
+impl DashboardStore for FicheirosStore {
+    async fn mensagem(&self) -> AppResult<Option<DashboardMessage>> {
+        self.bloquear(|c| ler_json(&c.documento(Documento::MensagemDashboard))).await
+    }
+
+    async fn guardar_mensagem(&self, mensagem: &DashboardMessage) -> AppResult<()> {
+        let mensagem = mensagem.clone();
+        self.bloquear(move |c| escrever_json(&c.documento(Documento::MensagemDashboard), &mensagem)).await
+    }
+}
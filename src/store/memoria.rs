@@ -0,0 +1,366 @@
+// src/store/memoria.rs
+
+//! Implementação dos stores em memória. Nada é gravado em disco: serve para testar
+//! handlers e regras da escala sem base de dados nem ficheiros.
+
+use super::{
+    AlteracaoEscala, AlteracaoPresenca, AlteracaoRefeicao, AppResult, DashboardStore, Documento, EscalaStore, EscalaTx,
+    MealStore, PresenceStore, UserStore,
+};
+use crate::auth::User;
+use crate::escala::{
+    ConfiguracaoEscala, Contagem, DividasAtivas, EscalaDiaria, EstadoEscala, ExecucaoEscala, Indisponibilidade, OfertaServico,
+    Posto, Punicao, Troca,
+};
+use crate::escala_dividas::MovimentoDivida;
+use crate::handlers::DashboardMessage;
+use crate::meals::{MealFormState, MealSelection};
+use crate::presence::PresenceEntry;
+use axum::async_trait;
+use chrono::NaiveDate;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct MemoriaStore {
+    users: Mutex<HashMap<String, User>>,
+    presencas: Mutex<HashMap<String, PresenceEntry>>,
+    estado_refeicoes: Mutex<Option<MealFormState>>,
+    refeicoes: Mutex<BTreeMap<NaiveDate, HashMap<String, MealSelection>>>,
+    escala: Mutex<DadosEscala>,
+    mensagem: Mutex<Option<DashboardMessage>>,
+}
+
+impl MemoriaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Tudo o que pertence à escala, guardado junto para as transações serem atómicas.
+#[derive(Default, Clone)]
+struct DadosEscala {
+    documentos: HashMap<Documento, Value>,
+    dias: BTreeMap<NaiveDate, EscalaDiaria>,
+    trocas: Vec<Troca>,
+    execucoes: Vec<ExecucaoEscala>,
+    /// Cópia dos utilizadores, só preenchida durante uma transação.
+    users: HashMap<String, User>,
+}
+
+impl DadosEscala {
+    fn documento<T: DeserializeOwned + Default>(&self, doc: Documento) -> AppResult<T> {
+        match self.documentos.get(&doc) {
+            Some(valor) => Ok(serde_json::from_value(valor.clone())?),
+            None => Ok(T::default()),
+        }
+    }
+
+    fn guardar<T: Serialize + ?Sized>(&mut self, doc: Documento, valor: &T) -> AppResult<()> {
+        self.documentos.insert(doc, serde_json::to_value(valor)?);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl UserStore for MemoriaStore {
+    async fn listar(&self) -> AppResult<HashMap<String, User>> {
+        Ok(self.users.lock().unwrap().clone())
+    }
+
+    async fn guardar(&self, user: &User) -> AppResult<()> {
+        self.users.lock().unwrap().insert(user.id.clone(), user.clone());
+        Ok(())
+    }
+
+    async fn apagar(&self, id: &str) -> AppResult<bool> {
+        Ok(self.users.lock().unwrap().remove(id).is_some())
+    }
+}
+
+#[async_trait]
+impl PresenceStore for MemoriaStore {
+    async fn listar(&self) -> AppResult<HashMap<String, PresenceEntry>> {
+        Ok(self.presencas.lock().unwrap().clone())
+    }
+
+    async fn atualizar(&self, user_id: &str, f: AlteracaoPresenca) -> AppResult<()> {
+        f(self.presencas.lock().unwrap().entry(user_id.to_string()).or_default());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MealStore for MemoriaStore {
+    async fn estado(&self) -> AppResult<Option<MealFormState>> {
+        Ok(self.estado_refeicoes.lock().unwrap().clone())
+    }
+
+    async fn guardar_estado(&self, estado: &MealFormState) -> AppResult<()> {
+        *self.estado_refeicoes.lock().unwrap() = Some(estado.clone());
+        Ok(())
+    }
+
+    async fn criar_dias(&self, datas: &[NaiveDate], selecoes: &HashMap<String, MealSelection>) -> AppResult<()> {
+        let mut refeicoes = self.refeicoes.lock().unwrap();
+        for data in datas {
+            refeicoes.entry(*data).or_insert_with(|| selecoes.clone());
+        }
+        Ok(())
+    }
+
+    async fn apagar_dias(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<()> {
+        self.refeicoes.lock().unwrap().retain(|data, _| *data < inicio || *data > fim);
+        Ok(())
+    }
+
+    async fn dia(&self, data: NaiveDate) -> AppResult<HashMap<String, MealSelection>> {
+        Ok(self.refeicoes.lock().unwrap().get(&data).cloned().unwrap_or_default())
+    }
+
+    async fn atualizar_selecao(&self, data: NaiveDate, user_id: &str, f: AlteracaoRefeicao) -> AppResult<bool> {
+        let mut refeicoes = self.refeicoes.lock().unwrap();
+        let Some(atual) = refeicoes.get_mut(&data).and_then(|dia| dia.get_mut(user_id)) else { return Ok(false) };
+        let mut selection = atual.clone();
+        if !f(&mut selection) {
+            return Ok(false);
+        }
+        *atual = selection;
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl EscalaStore for MemoriaStore {
+    async fn estado(&self) -> AppResult<EstadoEscala> {
+        self.escala.lock().unwrap().documento(Documento::EstadoEscala)
+    }
+
+    async fn postos(&self) -> AppResult<Vec<Posto>> {
+        self.escala.lock().unwrap().documento(Documento::Postos)
+    }
+
+    async fn contagem(&self) -> AppResult<Contagem> {
+        self.escala.lock().unwrap().documento(Documento::Contagem)
+    }
+
+    async fn dividas(&self) -> AppResult<DividasAtivas> {
+        self.escala.lock().unwrap().documento(Documento::Dividas)
+    }
+
+    async fn movimentos_dividas(&self) -> AppResult<Vec<MovimentoDivida>> {
+        self.escala.lock().unwrap().documento(Documento::MovimentosDividas)
+    }
+
+    async fn indisponibilidades(&self) -> AppResult<Vec<Indisponibilidade>> {
+        self.escala.lock().unwrap().documento(Documento::Indisponibilidades)
+    }
+
+    async fn punicoes(&self) -> AppResult<Vec<Punicao>> {
+        self.escala.lock().unwrap().documento(Documento::Punicoes)
+    }
+
+    async fn ofertas(&self) -> AppResult<Vec<OfertaServico>> {
+        self.escala.lock().unwrap().documento(Documento::OfertasServico)
+    }
+
+    async fn configuracao(&self) -> AppResult<ConfiguracaoEscala> {
+        self.escala.lock().unwrap().documento(Documento::ConfiguracaoEscala)
+    }
+
+    async fn dia(&self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>> {
+        Ok(self.escala.lock().unwrap().dias.get(&data).cloned())
+    }
+
+    async fn periodo(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<BTreeMap<NaiveDate, EscalaDiaria>> {
+        let escala = self.escala.lock().unwrap();
+        Ok(escala.dias.range(inicio..=fim).map(|(data, dia)| (*data, dia.clone())).collect())
+    }
+
+    async fn trocas(&self) -> AppResult<Vec<Troca>> {
+        Ok(self.escala.lock().unwrap().trocas.clone())
+    }
+
+    async fn execucoes(&self) -> AppResult<Vec<ExecucaoEscala>> {
+        Ok(self.escala.lock().unwrap().execucoes.clone())
+    }
+
+    async fn transacao(&self, f: AlteracaoEscala) -> AppResult<()> {
+        let mut escala = self.escala.lock().unwrap();
+        let mut users = self.users.lock().unwrap();
+        // As alterações são feitas numa cópia, que só substitui os dados se tudo correr bem
+        let mut copia = escala.clone();
+        copia.users = users.clone();
+        f(&mut copia)?;
+        *users = std::mem::take(&mut copia.users);
+        *escala = copia;
+        Ok(())
+    }
+}
+
+impl EscalaTx for DadosEscala {
+    fn estado(&mut self) -> AppResult<EstadoEscala> {
+        self.documento(Documento::EstadoEscala)
+    }
+
+    fn guardar_estado(&mut self, estado: &EstadoEscala) -> AppResult<()> {
+        self.guardar(Documento::EstadoEscala, estado)
+    }
+
+    fn postos(&mut self) -> AppResult<Vec<Posto>> {
+        self.documento(Documento::Postos)
+    }
+
+    fn guardar_postos(&mut self, postos: &[Posto]) -> AppResult<()> {
+        self.guardar(Documento::Postos, postos)
+    }
+
+    fn contagem(&mut self) -> AppResult<Contagem> {
+        self.documento(Documento::Contagem)
+    }
+
+    fn guardar_contagem(&mut self, contagem: &Contagem) -> AppResult<()> {
+        self.guardar(Documento::Contagem, contagem)
+    }
+
+    fn dividas(&mut self) -> AppResult<DividasAtivas> {
+        self.documento(Documento::Dividas)
+    }
+
+    fn guardar_dividas(&mut self, dividas: &DividasAtivas) -> AppResult<()> {
+        self.guardar(Documento::Dividas, dividas)
+    }
+
+    fn movimentos_dividas(&mut self) -> AppResult<Vec<MovimentoDivida>> {
+        self.documento(Documento::MovimentosDividas)
+    }
+
+    fn guardar_movimentos_dividas(&mut self, movimentos: &[MovimentoDivida]) -> AppResult<()> {
+        self.guardar(Documento::MovimentosDividas, movimentos)
+    }
+
+    fn indisponibilidades(&mut self) -> AppResult<Vec<Indisponibilidade>> {
+        self.documento(Documento::Indisponibilidades)
+    }
+
+    fn guardar_indisponibilidades(&mut self, indisponibilidades: &[Indisponibilidade]) -> AppResult<()> {
+        self.guardar(Documento::Indisponibilidades, indisponibilidades)
+    }
+
+    fn punicoes(&mut self) -> AppResult<Vec<Punicao>> {
+        self.documento(Documento::Punicoes)
+    }
+
+    fn guardar_punicoes(&mut self, punicoes: &[Punicao]) -> AppResult<()> {
+        self.guardar(Documento::Punicoes, punicoes)
+    }
+
+    fn ofertas(&mut self) -> AppResult<Vec<OfertaServico>> {
+        self.documento(Documento::OfertasServico)
+    }
+
+    fn guardar_ofertas(&mut self, ofertas: &[OfertaServico]) -> AppResult<()> {
+        self.guardar(Documento::OfertasServico, ofertas)
+    }
+
+    fn configuracao(&mut self) -> AppResult<ConfiguracaoEscala> {
+        self.documento(Documento::ConfiguracaoEscala)
+    }
+
+    fn guardar_configuracao(&mut self, configuracao: &ConfiguracaoEscala) -> AppResult<()> {
+        self.guardar(Documento::ConfiguracaoEscala, configuracao)
+    }
+
+    fn dia(&mut self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>> {
+        Ok(self.dias.get(&data).cloned())
+    }
+
+    fn guardar_dia(&mut self, data: NaiveDate, escala: &EscalaDiaria) -> AppResult<()> {
+        self.dias.insert(data, escala.clone());
+        Ok(())
+    }
+
+    fn apagar_dia(&mut self, data: NaiveDate) -> AppResult<()> {
+        self.dias.remove(&data);
+        Ok(())
+    }
+
+    fn trocas(&mut self) -> AppResult<Vec<Troca>> {
+        Ok(self.trocas.clone())
+    }
+
+    fn troca(&mut self, id: &str) -> AppResult<Option<Troca>> {
+        Ok(self.trocas.iter().find(|t| t.id == id).cloned())
+    }
+
+    fn guardar_troca(&mut self, troca: &Troca) -> AppResult<()> {
+        match self.trocas.iter_mut().find(|t| t.id == troca.id) {
+            Some(atual) => *atual = troca.clone(),
+            None => self.trocas.push(troca.clone()),
+        }
+        Ok(())
+    }
+
+    fn execucoes(&mut self) -> AppResult<Vec<ExecucaoEscala>> {
+        Ok(self.execucoes.clone())
+    }
+
+    fn guardar_execucao(&mut self, execucao: &ExecucaoEscala) -> AppResult<()> {
+        match self.execucoes.iter_mut().find(|e| e.versao == execucao.versao) {
+            Some(atual) => *atual = execucao.clone(),
+            None => self.execucoes.push(execucao.clone()),
+        }
+        Ok(())
+    }
+
+    fn utilizadores(&mut self) -> AppResult<HashMap<String, User>> {
+        Ok(self.users.clone())
+    }
+
+    fn guardar_utilizadores(&mut self, users: &[User]) -> AppResult<()> {
+        for user in users {
+            self.users.insert(user.id.clone(), user.clone());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DashboardStore for MemoriaStore {
+    async fn mensagem(&self) -> AppResult<Option<DashboardMessage>> {
+        Ok(self.mensagem.lock().unwrap().clone())
+    }
+
+    async fn guardar_mensagem(&self, mensagem: &DashboardMessage) -> AppResult<()> {
+        *self.mensagem.lock().unwrap() = Some(mensagem.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod testes {
+    use crate::escala::{Genero, TipoServico};
+    use crate::store::Recusa;
+    use crate::testes::{dia, estado_app, futuro, posto, preparar, utilizador};
+
+    #[tokio::test]
+    async fn transacao_que_falha_nao_grava_nada() {
+        let state = estado_app(&[utilizador("1001", 1, Genero::Masculino)]).await;
+        preparar(&state, Box::new(|tx| tx.guardar_postos(&[posto("P", &[1], &["08-12"])]))).await;
+
+        let resultado = state
+            .escala_store
+            .transacao(Box::new(|tx| {
+                tx.guardar_dia(futuro(1), &dia(TipoServico::RN, &[("P", "08-12", "1001")], &[]))?;
+                tx.guardar_postos(&[])?;
+                Err(Recusa("Falhou a meio.").into())
+            }))
+            .await;
+
+        assert!(resultado.unwrap_err().downcast_ref::<Recusa>().is_some());
+        assert!(state.escala_store.periodo(futuro(1), futuro(1)).await.unwrap().is_empty());
+        assert_eq!(state.escala_store.postos().await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,257 @@
+// src/store/mod.rs
+
+//! # Camada de Armazenamento
+//!
+//! Os handlers não sabem onde os dados vivem: usam os traits deste módulo, um por domínio,
+//! injetados através do `AppState`. Existem três implementações:
+//! - `sqlite`: a base de dados principal, usada em produção;
+//! - `ficheiros`: os ficheiros JSON em `data/` e `users.json`, com a organização antiga;
+//! - `memoria`: tudo em memória, para testes e demonstrações.
+
+pub mod ficheiros;
+pub mod memoria;
+pub mod sqlite;
+
+use crate::auth::User;
+use crate::config::Config;
+use crate::db::Db;
+use crate::escala::{
+    ConfiguracaoEscala, Contagem, DividasAtivas, EscalaDiaria, EstadoEscala, ExecucaoEscala, Indisponibilidade, OfertaServico,
+    Posto, Punicao, Troca,
+};
+use crate::escala_dividas::MovimentoDivida;
+use crate::handlers::DashboardMessage;
+use crate::meals::{MealFormState, MealSelection};
+use crate::presence::PresenceEntry;
+use axum::async_trait;
+use chrono::NaiveDate;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+
+pub type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Regra de negócio que impediu uma transação da escala. A transação é desfeita e a
+/// mensagem pode ser mostrada a quem fez o pedido.
+#[derive(Debug)]
+pub struct Recusa(pub &'static str);
+
+impl fmt::Display for Recusa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Recusa {}
+
+/// Alteração a uma entrada de presença, aplicada de forma atómica.
+pub type AlteracaoPresenca = Box<dyn FnOnce(&mut PresenceEntry) + Send>;
+/// Alteração à refeição de um utilizador num dia. Devolve `true` se algo mudou e deve ser gravado.
+pub type AlteracaoRefeicao = Box<dyn FnOnce(&mut MealSelection) -> bool + Send>;
+/// Conjunto de alterações à escala, aplicadas todas ou nenhuma.
+pub type AlteracaoEscala = Box<dyn FnOnce(&mut dyn EscalaTx) -> AppResult<()> + Send>;
+
+#[async_trait]
+pub trait UserStore: Send + Sync {
+    async fn listar(&self) -> AppResult<HashMap<String, User>>;
+    /// Cria ou atualiza um utilizador.
+    async fn guardar(&self, user: &User) -> AppResult<()>;
+    /// Apaga o registo de um utilizador. Devolve `false` se não existia.
+    async fn apagar(&self, id: &str) -> AppResult<bool>;
+}
+
+#[async_trait]
+pub trait PresenceStore: Send + Sync {
+    async fn listar(&self) -> AppResult<HashMap<String, PresenceEntry>>;
+    /// Altera a entrada de uma pessoa (criando-a se não existir).
+    async fn atualizar(&self, user_id: &str, f: AlteracaoPresenca) -> AppResult<()>;
+}
+
+#[async_trait]
+pub trait MealStore: Send + Sync {
+    async fn estado(&self) -> AppResult<Option<MealFormState>>;
+    async fn guardar_estado(&self, estado: &MealFormState) -> AppResult<()>;
+    /// Cria os dias indicados com as seleções dadas, **sem tocar nos dias que já existem**.
+    async fn criar_dias(&self, datas: &[NaiveDate], selecoes: &HashMap<String, MealSelection>) -> AppResult<()>;
+    async fn apagar_dias(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<()>;
+    /// As refeições de um dia; vazio se o dia não existir.
+    async fn dia(&self, data: NaiveDate) -> AppResult<HashMap<String, MealSelection>>;
+    /// Altera a refeição de um utilizador num dia. Devolve `true` se foi alterada.
+    async fn atualizar_selecao(&self, data: NaiveDate, user_id: &str, f: AlteracaoRefeicao) -> AppResult<bool>;
+}
+
+#[async_trait]
+pub trait EscalaStore: Send + Sync {
+    async fn estado(&self) -> AppResult<EstadoEscala>;
+    async fn postos(&self) -> AppResult<Vec<Posto>>;
+    async fn contagem(&self) -> AppResult<Contagem>;
+    async fn dividas(&self) -> AppResult<DividasAtivas>;
+    /// O histórico das alterações feitas ao livro das dívidas, do mais antigo ao mais recente.
+    async fn movimentos_dividas(&self) -> AppResult<Vec<MovimentoDivida>>;
+    async fn indisponibilidades(&self) -> AppResult<Vec<Indisponibilidade>>;
+    async fn punicoes(&self) -> AppResult<Vec<Punicao>>;
+    /// O quadro de ofertas de serviços, pela ordem em que foram publicadas.
+    async fn ofertas(&self) -> AppResult<Vec<OfertaServico>>;
+    async fn configuracao(&self) -> AppResult<ConfiguracaoEscala>;
+    async fn dia(&self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>>;
+    /// As escalas geradas entre duas datas (inclusive), ordenadas por data.
+    async fn periodo(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<BTreeMap<NaiveDate, EscalaDiaria>>;
+    /// Todas as trocas, pela ordem em que foram pedidas.
+    async fn trocas(&self) -> AppResult<Vec<Troca>>;
+    /// O histórico das gerações gravadas, da versão mais antiga para a mais recente.
+    async fn execucoes(&self) -> AppResult<Vec<ExecucaoEscala>>;
+    /// Aplica um conjunto de alterações. Se a função devolver erro, nada é gravado.
+    async fn transacao(&self, f: AlteracaoEscala) -> AppResult<()>;
+}
+
+/// Leituras e escritas da escala dentro de uma transação (`EscalaStore::transacao`).
+pub trait EscalaTx {
+    fn estado(&mut self) -> AppResult<EstadoEscala>;
+    fn guardar_estado(&mut self, estado: &EstadoEscala) -> AppResult<()>;
+    fn postos(&mut self) -> AppResult<Vec<Posto>>;
+    fn guardar_postos(&mut self, postos: &[Posto]) -> AppResult<()>;
+    fn contagem(&mut self) -> AppResult<Contagem>;
+    fn guardar_contagem(&mut self, contagem: &Contagem) -> AppResult<()>;
+    fn dividas(&mut self) -> AppResult<DividasAtivas>;
+    fn guardar_dividas(&mut self, dividas: &DividasAtivas) -> AppResult<()>;
+    fn movimentos_dividas(&mut self) -> AppResult<Vec<MovimentoDivida>>;
+    fn guardar_movimentos_dividas(&mut self, movimentos: &[MovimentoDivida]) -> AppResult<()>;
+    fn indisponibilidades(&mut self) -> AppResult<Vec<Indisponibilidade>>;
+    fn guardar_indisponibilidades(&mut self, indisponibilidades: &[Indisponibilidade]) -> AppResult<()>;
+    fn punicoes(&mut self) -> AppResult<Vec<Punicao>>;
+    fn guardar_punicoes(&mut self, punicoes: &[Punicao]) -> AppResult<()>;
+    fn ofertas(&mut self) -> AppResult<Vec<OfertaServico>>;
+    fn guardar_ofertas(&mut self, ofertas: &[OfertaServico]) -> AppResult<()>;
+    fn configuracao(&mut self) -> AppResult<ConfiguracaoEscala>;
+    fn guardar_configuracao(&mut self, configuracao: &ConfiguracaoEscala) -> AppResult<()>;
+    fn dia(&mut self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>>;
+    fn guardar_dia(&mut self, data: NaiveDate, escala: &EscalaDiaria) -> AppResult<()>;
+    fn apagar_dia(&mut self, data: NaiveDate) -> AppResult<()>;
+    fn trocas(&mut self) -> AppResult<Vec<Troca>>;
+    fn troca(&mut self, id: &str) -> AppResult<Option<Troca>>;
+    /// Cria ou atualiza uma troca. Trocas novas ficam no fim da lista.
+    fn guardar_troca(&mut self, troca: &Troca) -> AppResult<()>;
+    fn execucoes(&mut self) -> AppResult<Vec<ExecucaoEscala>>;
+    /// Cria ou atualiza uma versão do histórico das gerações.
+    fn guardar_execucao(&mut self, execucao: &ExecucaoEscala) -> AppResult<()>;
+    /// Os utilizadores, para as alterações que mexem na escala e nos utilizadores de uma
+    /// só vez (a virada de ano).
+    fn utilizadores(&mut self) -> AppResult<HashMap<String, User>>;
+    /// Cria ou atualiza os utilizadores dados, na mesma transação.
+    fn guardar_utilizadores(&mut self, users: &[User]) -> AppResult<()>;
+}
+
+#[async_trait]
+pub trait DashboardStore: Send + Sync {
+    async fn mensagem(&self) -> AppResult<Option<DashboardMessage>>;
+    async fn guardar_mensagem(&self, mensagem: &DashboardMessage) -> AppResult<()>;
+}
+
+/// Estruturas guardadas por inteiro, cada uma num documento (SQLite) ou ficheiro próprio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Documento {
+    EstadoEscala,
+    Postos,
+    Contagem,
+    Dividas,
+    MovimentosDividas,
+    Indisponibilidades,
+    Punicoes,
+    OfertasServico,
+    ConfiguracaoEscala,
+    EstadoRefeicoes,
+    MensagemDashboard,
+}
+
+impl Documento {
+    pub const TODOS: [Documento; 11] = [
+        Documento::EstadoEscala,
+        Documento::Postos,
+        Documento::Contagem,
+        Documento::Dividas,
+        Documento::MovimentosDividas,
+        Documento::Indisponibilidades,
+        Documento::Punicoes,
+        Documento::OfertasServico,
+        Documento::ConfiguracaoEscala,
+        Documento::EstadoRefeicoes,
+        Documento::MensagemDashboard,
+    ];
+
+    /// Chave na tabela `documentos` da base de dados.
+    pub fn chave(self) -> &'static str {
+        match self {
+            Documento::EstadoEscala => "escala.estado",
+            Documento::Postos => "escala.postos",
+            Documento::Contagem => "escala.contagem",
+            Documento::Dividas => "escala.dividas",
+            Documento::MovimentosDividas => "escala.dividas.movimentos",
+            Documento::Indisponibilidades => "escala.indisponibilidades",
+            Documento::Punicoes => "escala.punicoes",
+            Documento::OfertasServico => "escala.ofertas",
+            Documento::ConfiguracaoEscala => "escala.configuracao",
+            Documento::EstadoRefeicoes => "refeicoes.estado",
+            Documento::MensagemDashboard => "dashboard.mensagem",
+        }
+    }
+
+    /// Caminho do ficheiro JSON na organização antiga, dentro da pasta dos dados.
+    pub fn ficheiro(self) -> &'static str {
+        match self {
+            Documento::EstadoEscala => "escala/estado.json",
+            Documento::Postos => "escala/postos.json",
+            Documento::Contagem => "escala/contagem.json",
+            Documento::Dividas => "escala/dividas.json",
+            Documento::MovimentosDividas => "escala/dividas_movimentos.json",
+            Documento::Indisponibilidades => "escala/indisponibilidade.json",
+            Documento::Punicoes => "escala/punidos.json",
+            Documento::OfertasServico => "escala/ofertas.json",
+            Documento::ConfiguracaoEscala => "escala/configuracao.json",
+            Documento::EstadoRefeicoes => "refeicoes/estado.json",
+            Documento::MensagemDashboard => "dashboard_message.json",
+        }
+    }
+}
+
+/// Uma implementação de armazenamento, já partilhada pelos vários domínios.
+#[derive(Clone)]
+pub struct Stores {
+    pub users: Arc<dyn UserStore>,
+    pub presence: Arc<dyn PresenceStore>,
+    pub meals: Arc<dyn MealStore>,
+    pub escala: Arc<dyn EscalaStore>,
+    pub dashboard: Arc<dyn DashboardStore>,
+}
+
+impl Stores {
+    /// Usa a mesma implementação para todos os domínios.
+    pub fn partilhada<S>(store: S) -> Self
+    where
+        S: UserStore + PresenceStore + MealStore + EscalaStore + DashboardStore + 'static,
+    {
+        let store = Arc::new(store);
+        Self {
+            users: store.clone(),
+            presence: store.clone(),
+            meals: store.clone(),
+            escala: store.clone(),
+            dashboard: store,
+        }
+    }
+}
+
+/// Abre a implementação escolhida em `dados.armazenamento`. Por omissão usa a base de dados.
+pub fn abrir(db: &Db, config: &Config) -> AppResult<Stores> {
+    match config.dados.armazenamento.as_str() {
+        "" | "sqlite" => Ok(Stores::partilhada(sqlite::SqliteStore::new(db.clone()))),
+        "ficheiros" => {
+            println!("📁 Armazenamento em ficheiros JSON (organização antiga).");
+            Ok(Stores::partilhada(ficheiros::FicheirosStore::new(ficheiros::Caminhos::new(config))))
+        }
+        "memoria" => {
+            println!("🧪 Armazenamento em memória: nada será gravado em disco.");
+            Ok(Stores::partilhada(memoria::MemoriaStore::new()))
+        }
+        outro => Err(format!("Armazenamento inválido: '{}' (use sqlite, ficheiros ou memoria)", outro).into()),
+    }
+}
@@ -0,0 +1,520 @@
+// src/store/sqlite.rs
+
+//! Implementação dos stores sobre a base de dados principal (`db::Db`).
+//! É a implementação usada em produção.
+
+use super::{
+    AlteracaoEscala, AlteracaoPresenca, AlteracaoRefeicao, AppResult, DashboardStore, Documento, EscalaStore, EscalaTx,
+    MealStore, PresenceStore, UserStore,
+};
+use crate::auth::User;
+use crate::db::{erro_json, guardar_documento_tx, ler_documento_tx, Db};
+use crate::escala::{
+    ConfiguracaoEscala, Contagem, DividasAtivas, EscalaDiaria, EstadoEscala, ExecucaoEscala, Indisponibilidade, OfertaServico,
+    Posto, Punicao, Troca,
+};
+use crate::escala_dividas::MovimentoDivida;
+use crate::handlers::DashboardMessage;
+use crate::meals::{MealFormState, MealSelection};
+use crate::presence::PresenceEntry;
+use axum::async_trait;
+use chrono::NaiveDate;
+use rusqlite::{params, OptionalExtension, Transaction};
+use std::collections::{BTreeMap, HashMap};
+
+pub struct SqliteStore {
+    db: Db,
+}
+
+impl SqliteStore {
+    pub fn new(db: Db) -> Self {
+        Self { db }
+    }
+}
+
+/// Formato das datas usado como chave nas tabelas `refeicoes` e `escalas`.
+fn chave_data(data: NaiveDate) -> String {
+    data.format("%Y-%m-%d").to_string()
+}
+
+#[async_trait]
+impl UserStore for SqliteStore {
+    async fn listar(&self) -> AppResult<HashMap<String, User>> {
+        let users_vec = self
+            .db
+            .conn()
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT dados FROM utilizadores")?;
+                let users = stmt
+                    .query_map([], |row| row.get::<_, String>(0))?
+                    .map(|json| serde_json::from_str::<User>(&json?).map_err(erro_json))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(users)
+            })
+            .await?;
+        Ok(users_vec.into_iter().map(|user| (user.id.clone(), user)).collect())
+    }
+
+    async fn guardar(&self, user: &User) -> AppResult<()> {
+        let (id, json) = (user.id.clone(), serde_json::to_string(user)?);
+        self.db
+            .conn()
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO utilizadores (id, dados) VALUES (?1, ?2)
+                     ON CONFLICT (id) DO UPDATE SET dados = excluded.dados",
+                    params![id, json],
+                )?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn apagar(&self, id: &str) -> AppResult<bool> {
+        let id = id.to_string();
+        let apagados = self
+            .db
+            .conn()
+            .call(move |conn| Ok(conn.execute("DELETE FROM utilizadores WHERE id = ?1", [&id])?))
+            .await?;
+        Ok(apagados > 0)
+    }
+}
+
+#[async_trait]
+impl PresenceStore for SqliteStore {
+    async fn listar(&self) -> AppResult<HashMap<String, PresenceEntry>> {
+        let presence_map = self
+            .db
+            .conn()
+            .call(|conn| {
+                let mut stmt = conn.prepare("SELECT user_id, dados FROM presencas")?;
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+                    .map(|row| {
+                        let (user_id, json) = row?;
+                        Ok((user_id, serde_json::from_str::<PresenceEntry>(&json).map_err(erro_json)?))
+                    })
+                    .collect::<Result<HashMap<_, _>, rusqlite::Error>>()?;
+                Ok(rows)
+            })
+            .await?;
+        Ok(presence_map)
+    }
+
+    async fn atualizar(&self, user_id: &str, f: AlteracaoPresenca) -> AppResult<()> {
+        let user_id = user_id.to_string();
+        self.db
+            .conn()
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let atual: Option<String> = tx
+                    .query_row("SELECT dados FROM presencas WHERE user_id = ?1", [&user_id], |row| row.get(0))
+                    .optional()?;
+                let mut entry: PresenceEntry = match atual {
+                    Some(json) => serde_json::from_str(&json).map_err(erro_json)?,
+                    None => PresenceEntry::default(),
+                };
+                f(&mut entry);
+                let json = serde_json::to_string(&entry).map_err(erro_json)?;
+                tx.execute(
+                    "INSERT INTO presencas (user_id, dados) VALUES (?1, ?2)
+                     ON CONFLICT (user_id) DO UPDATE SET dados = excluded.dados",
+                    params![user_id, json],
+                )?;
+                tx.commit()?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MealStore for SqliteStore {
+    async fn estado(&self) -> AppResult<Option<MealFormState>> {
+        self.db.documento(Documento::EstadoRefeicoes.chave()).await
+    }
+
+    async fn guardar_estado(&self, estado: &MealFormState) -> AppResult<()> {
+        self.db.guardar_documento(Documento::EstadoRefeicoes.chave(), estado).await
+    }
+
+    async fn criar_dias(&self, datas: &[NaiveDate], selecoes: &HashMap<String, MealSelection>) -> AppResult<()> {
+        let datas: Vec<String> = datas.iter().map(|d| chave_data(*d)).collect();
+        let linhas = selecoes
+            .iter()
+            .map(|(user_id, selection)| Ok((user_id.clone(), serde_json::to_string(selection)?)))
+            .collect::<Result<Vec<_>, serde_json::Error>>()?;
+        self.db
+            .conn()
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                for data in &datas {
+                    let existe: bool = tx.query_row(
+                        "SELECT EXISTS(SELECT 1 FROM refeicoes WHERE data = ?1)",
+                        [data],
+                        |row| row.get(0),
+                    )?;
+                    if existe {
+                        continue;
+                    }
+                    for (user_id, json) in &linhas {
+                        tx.execute(
+                            "INSERT INTO refeicoes (data, user_id, dados) VALUES (?1, ?2, ?3)",
+                            params![data, user_id, json],
+                        )?;
+                    }
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn apagar_dias(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<()> {
+        let (inicio, fim) = (chave_data(inicio), chave_data(fim));
+        self.db
+            .conn()
+            .call(move |conn| {
+                conn.execute("DELETE FROM refeicoes WHERE data BETWEEN ?1 AND ?2", params![inicio, fim])?;
+                Ok(())
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn dia(&self, data: NaiveDate) -> AppResult<HashMap<String, MealSelection>> {
+        let data = chave_data(data);
+        let daily_data = self
+            .db
+            .conn()
+            .call(move |conn| {
+                let mut stmt = conn.prepare("SELECT user_id, dados FROM refeicoes WHERE data = ?1")?;
+                let rows = stmt
+                    .query_map([&data], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+                    .map(|row| {
+                        let (user_id, json) = row?;
+                        Ok((user_id, serde_json::from_str::<MealSelection>(&json).map_err(erro_json)?))
+                    })
+                    .collect::<Result<HashMap<_, _>, rusqlite::Error>>()?;
+                Ok(rows)
+            })
+            .await?;
+        Ok(daily_data)
+    }
+
+    async fn atualizar_selecao(&self, data: NaiveDate, user_id: &str, f: AlteracaoRefeicao) -> AppResult<bool> {
+        let (data, user_id) = (chave_data(data), user_id.to_string());
+        let alterado = self
+            .db
+            .conn()
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let atual: Option<String> = tx
+                    .query_row(
+                        "SELECT dados FROM refeicoes WHERE data = ?1 AND user_id = ?2",
+                        params![data, user_id],
+                        |row| row.get(0),
+                    )
+                    .optional()?;
+                let Some(json) = atual else { return Ok(false) };
+                let mut selection: MealSelection = serde_json::from_str(&json).map_err(erro_json)?;
+                if !f(&mut selection) {
+                    return Ok(false);
+                }
+                tx.execute(
+                    "UPDATE refeicoes SET dados = ?1 WHERE data = ?2 AND user_id = ?3",
+                    params![serde_json::to_string(&selection).map_err(erro_json)?, data, user_id],
+                )?;
+                tx.commit()?;
+                Ok(true)
+            })
+            .await?;
+        Ok(alterado)
+    }
+}
+
+#[async_trait]
+impl EscalaStore for SqliteStore {
+    async fn estado(&self) -> AppResult<EstadoEscala> {
+        self.db.documento_ou_padrao(Documento::EstadoEscala.chave()).await
+    }
+
+    async fn postos(&self) -> AppResult<Vec<Posto>> {
+        self.db.documento_ou_padrao(Documento::Postos.chave()).await
+    }
+
+    async fn contagem(&self) -> AppResult<Contagem> {
+        self.db.documento_ou_padrao(Documento::Contagem.chave()).await
+    }
+
+    async fn dividas(&self) -> AppResult<DividasAtivas> {
+        self.db.documento_ou_padrao(Documento::Dividas.chave()).await
+    }
+
+    async fn movimentos_dividas(&self) -> AppResult<Vec<MovimentoDivida>> {
+        self.db.documento_ou_padrao(Documento::MovimentosDividas.chave()).await
+    }
+
+    async fn indisponibilidades(&self) -> AppResult<Vec<Indisponibilidade>> {
+        self.db.documento_ou_padrao(Documento::Indisponibilidades.chave()).await
+    }
+
+    async fn punicoes(&self) -> AppResult<Vec<Punicao>> {
+        self.db.documento_ou_padrao(Documento::Punicoes.chave()).await
+    }
+
+    async fn ofertas(&self) -> AppResult<Vec<OfertaServico>> {
+        self.db.documento_ou_padrao(Documento::OfertasServico.chave()).await
+    }
+
+    async fn configuracao(&self) -> AppResult<ConfiguracaoEscala> {
+        self.db.documento_ou_padrao(Documento::ConfiguracaoEscala.chave()).await
+    }
+
+    async fn dia(&self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>> {
+        self.db.transacao(move |tx| ler_escala_diaria_tx(tx, data)).await
+    }
+
+    async fn periodo(&self, inicio: NaiveDate, fim: NaiveDate) -> AppResult<BTreeMap<NaiveDate, EscalaDiaria>> {
+        let (de, ate) = (chave_data(inicio), chave_data(fim));
+        self.db
+            .transacao(move |tx| {
+                let mut stmt = tx.prepare("SELECT data, dados FROM escalas WHERE data BETWEEN ?1 AND ?2")?;
+                let linhas =
+                    stmt.query_map(params![de, ate], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+                let mut escalas = BTreeMap::new();
+                for linha in linhas {
+                    let (data, json) = linha?;
+                    let Ok(data) = NaiveDate::parse_from_str(&data, "%Y-%m-%d") else { continue };
+                    escalas.insert(data, serde_json::from_str(&json).map_err(erro_json)?);
+                }
+                Ok(escalas)
+            })
+            .await
+    }
+
+    async fn trocas(&self) -> AppResult<Vec<Troca>> {
+        self.db.transacao(ler_trocas_tx).await
+    }
+
+    async fn execucoes(&self) -> AppResult<Vec<ExecucaoEscala>> {
+        self.db.transacao(ler_execucoes_tx).await
+    }
+
+    async fn transacao(&self, f: AlteracaoEscala) -> AppResult<()> {
+        self.db
+            .conn()
+            .call(move |conn| {
+                let tx = conn.transaction()?;
+                let resultado = f(&mut TxSqlite { tx: &tx });
+                // Se a função falhar, a transação é desfeita ao ser largada
+                if resultado.is_ok() {
+                    tx.commit()?;
+                }
+                Ok(resultado)
+            })
+            .await?
+    }
+}
+
+fn ler_escala_diaria_tx(tx: &Transaction, data: NaiveDate) -> rusqlite::Result<Option<EscalaDiaria>> {
+    let json: Option<String> = tx
+        .query_row("SELECT dados FROM escalas WHERE data = ?1", [chave_data(data)], |row| row.get(0))
+        .optional()?;
+    json.map(|j| serde_json::from_str(&j).map_err(erro_json)).transpose()
+}
+
+fn ler_trocas_tx(tx: &Transaction) -> rusqlite::Result<Vec<Troca>> {
+    let mut stmt = tx.prepare("SELECT dados FROM trocas ORDER BY criada_em, id")?;
+    let trocas = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .map(|json| serde_json::from_str(&json?).map_err(erro_json))
+        .collect();
+    trocas
+}
+
+fn ler_execucoes_tx(tx: &Transaction) -> rusqlite::Result<Vec<ExecucaoEscala>> {
+    let mut stmt = tx.prepare("SELECT dados FROM execucoes_escala ORDER BY versao")?;
+    let execucoes = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .map(|json| serde_json::from_str(&json?).map_err(erro_json))
+        .collect();
+    execucoes
+}
+
+/// Transação aberta sobre a base de dados, entregue às funções de `EscalaStore::transacao`.
+struct TxSqlite<'a> {
+    tx: &'a Transaction<'a>,
+}
+
+impl TxSqlite<'_> {
+    fn documento<T: serde::de::DeserializeOwned + Default>(&self, doc: Documento) -> AppResult<T> {
+        Ok(ler_documento_tx(self.tx, doc.chave())?.unwrap_or_default())
+    }
+
+    fn guardar<T: serde::Serialize + ?Sized>(&self, doc: Documento, valor: &T) -> AppResult<()> {
+        Ok(guardar_documento_tx(self.tx, doc.chave(), valor)?)
+    }
+}
+
+impl EscalaTx for TxSqlite<'_> {
+    fn estado(&mut self) -> AppResult<EstadoEscala> {
+        self.documento(Documento::EstadoEscala)
+    }
+
+    fn guardar_estado(&mut self, estado: &EstadoEscala) -> AppResult<()> {
+        self.guardar(Documento::EstadoEscala, estado)
+    }
+
+    fn postos(&mut self) -> AppResult<Vec<Posto>> {
+        self.documento(Documento::Postos)
+    }
+
+    fn guardar_postos(&mut self, postos: &[Posto]) -> AppResult<()> {
+        self.guardar(Documento::Postos, postos)
+    }
+
+    fn contagem(&mut self) -> AppResult<Contagem> {
+        self.documento(Documento::Contagem)
+    }
+
+    fn guardar_contagem(&mut self, contagem: &Contagem) -> AppResult<()> {
+        self.guardar(Documento::Contagem, contagem)
+    }
+
+    fn dividas(&mut self) -> AppResult<DividasAtivas> {
+        self.documento(Documento::Dividas)
+    }
+
+    fn guardar_dividas(&mut self, dividas: &DividasAtivas) -> AppResult<()> {
+        self.guardar(Documento::Dividas, dividas)
+    }
+
+    fn movimentos_dividas(&mut self) -> AppResult<Vec<MovimentoDivida>> {
+        self.documento(Documento::MovimentosDividas)
+    }
+
+    fn guardar_movimentos_dividas(&mut self, movimentos: &[MovimentoDivida]) -> AppResult<()> {
+        self.guardar(Documento::MovimentosDividas, movimentos)
+    }
+
+    fn indisponibilidades(&mut self) -> AppResult<Vec<Indisponibilidade>> {
+        self.documento(Documento::Indisponibilidades)
+    }
+
+    fn guardar_indisponibilidades(&mut self, indisponibilidades: &[Indisponibilidade]) -> AppResult<()> {
+        self.guardar(Documento::Indisponibilidades, indisponibilidades)
+    }
+
+    fn punicoes(&mut self) -> AppResult<Vec<Punicao>> {
+        self.documento(Documento::Punicoes)
+    }
+
+    fn guardar_punicoes(&mut self, punicoes: &[Punicao]) -> AppResult<()> {
+        self.guardar(Documento::Punicoes, punicoes)
+    }
+
+    fn ofertas(&mut self) -> AppResult<Vec<OfertaServico>> {
+        self.documento(Documento::OfertasServico)
+    }
+
+    fn guardar_ofertas(&mut self, ofertas: &[OfertaServico]) -> AppResult<()> {
+        self.guardar(Documento::OfertasServico, ofertas)
+    }
+
+    fn configuracao(&mut self) -> AppResult<ConfiguracaoEscala> {
+        self.documento(Documento::ConfiguracaoEscala)
+    }
+
+    fn guardar_configuracao(&mut self, configuracao: &ConfiguracaoEscala) -> AppResult<()> {
+        self.guardar(Documento::ConfiguracaoEscala, configuracao)
+    }
+
+    fn dia(&mut self, data: NaiveDate) -> AppResult<Option<EscalaDiaria>> {
+        Ok(ler_escala_diaria_tx(self.tx, data)?)
+    }
+
+    fn guardar_dia(&mut self, data: NaiveDate, escala: &EscalaDiaria) -> AppResult<()> {
+        self.tx.execute(
+            "INSERT INTO escalas (data, dados) VALUES (?1, ?2)
+             ON CONFLICT (data) DO UPDATE SET dados = excluded.dados",
+            params![chave_data(data), serde_json::to_string(escala)?],
+        )?;
+        Ok(())
+    }
+
+    fn apagar_dia(&mut self, data: NaiveDate) -> AppResult<()> {
+        self.tx.execute("DELETE FROM escalas WHERE data = ?1", [chave_data(data)])?;
+        Ok(())
+    }
+
+    fn trocas(&mut self) -> AppResult<Vec<Troca>> {
+        Ok(ler_trocas_tx(self.tx)?)
+    }
+
+    fn troca(&mut self, id: &str) -> AppResult<Option<Troca>> {
+        let json: Option<String> = self
+            .tx
+            .query_row("SELECT dados FROM trocas WHERE id = ?1", [id], |row| row.get(0))
+            .optional()?;
+        Ok(json.map(|j| serde_json::from_str(&j)).transpose()?)
+    }
+
+    fn guardar_troca(&mut self, troca: &Troca) -> AppResult<()> {
+        self.tx.execute(
+            "INSERT INTO trocas (id, criada_em, dados)
+             VALUES (?1, (SELECT COALESCE(MAX(criada_em), 0) + 1 FROM trocas), ?2)
+             ON CONFLICT (id) DO UPDATE SET dados = excluded.dados",
+            params![troca.id, serde_json::to_string(troca)?],
+        )?;
+        Ok(())
+    }
+
+    fn execucoes(&mut self) -> AppResult<Vec<ExecucaoEscala>> {
+        Ok(ler_execucoes_tx(self.tx)?)
+    }
+
+    fn guardar_execucao(&mut self, execucao: &ExecucaoEscala) -> AppResult<()> {
+        self.tx.execute(
+            "INSERT INTO execucoes_escala (versao, gravada_em, dados) VALUES (?1, ?2, ?3)
+             ON CONFLICT (versao) DO UPDATE SET dados = excluded.dados",
+            params![execucao.versao, execucao.gravada_em.to_rfc3339(), serde_json::to_string(execucao)?],
+        )?;
+        Ok(())
+    }
+
+    fn utilizadores(&mut self) -> AppResult<HashMap<String, User>> {
+        let mut stmt = self.tx.prepare("SELECT dados FROM utilizadores")?;
+        let users = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|json| serde_json::from_str::<User>(&json?).map_err(erro_json))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users.into_iter().map(|user| (user.id.clone(), user)).collect())
+    }
+
+    fn guardar_utilizadores(&mut self, users: &[User]) -> AppResult<()> {
+        for user in users {
+            self.tx.execute(
+                "INSERT INTO utilizadores (id, dados) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET dados = excluded.dados",
+                params![user.id, serde_json::to_string(user)?],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl DashboardStore for SqliteStore {
+    async fn mensagem(&self) -> AppResult<Option<DashboardMessage>> {
+        self.db.documento(Documento::MensagemDashboard.chave()).await
+    }
+
+    async fn guardar_mensagem(&self, mensagem: &DashboardMessage) -> AppResult<()> {
+        self.db.guardar_documento(Documento::MensagemDashboard.chave(), mensagem).await
+    }
+}
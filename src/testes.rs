@@ -0,0 +1,106 @@
+// src/testes.rs
+
+//! Dados de exemplo para os testes: utilizadores, postos e dias da escala, e um
+//! `AppState` sobre o `MemoriaStore` para chamar os handlers sem servidor.
+
+use crate::auth::{AppState, User};
+use crate::checkin::CheckinState;
+use crate::config::Config;
+use crate::db::Db;
+use crate::escala::{Alocacao, EscalaDiaria, Genero, Posto, TipoServico};
+use crate::limite_login::LimiteLogin;
+use crate::presence_state::PresenceSocketState;
+use crate::sessions::SessionStore;
+use crate::store::{memoria::MemoriaStore, AlteracaoEscala, Stores};
+use chrono::{Duration, Local, NaiveDate};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Um dia a `dias` de hoje. As trocas só aceitam serviços que ainda não passaram.
+pub fn futuro(dias: i64) -> NaiveDate {
+    Local::now().date_naive() + Duration::days(dias)
+}
+
+pub fn data(ano: i32, mes: u32, dia: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(ano, mes, dia).unwrap()
+}
+
+/// Um aluno ativo, sem funções, chamado "Aluno <id>".
+pub fn utilizador(id: &str, ano: u8, genero: Genero) -> User {
+    User {
+        id: id.to_string(),
+        password: String::new(),
+        name: format!("Aluno {}", id),
+        turma: String::new(),
+        ano,
+        curso: 'A',
+        genero,
+        roles: Vec::new(),
+        ativo: true,
+        trocar_senha: false,
+        token_calendario: None,
+    }
+}
+
+pub fn mapa(users: &[User]) -> HashMap<String, User> {
+    users.iter().map(|u| (u.id.clone(), u.clone())).collect()
+}
+
+/// Um posto misto com os mesmos horários em todos os tipos de dia.
+pub fn posto(nome: &str, anos: &[u8], horarios: &[&str]) -> Posto {
+    let horarios: Vec<String> = horarios.iter().map(|h| h.to_string()).collect();
+    Posto {
+        nome: nome.to_string(),
+        turmas_permitidas: anos.to_vec(),
+        genero: Genero::Misto,
+        funcao_exclusiva: None,
+        horarios_rn: horarios.clone(),
+        horarios_rd: horarios.clone(),
+        horarios_udrd: horarios.clone(),
+        horarios_er: horarios,
+        ativo: true,
+        seccao_pdf: None,
+    }
+}
+
+pub fn alocacao(user_id: &str) -> Alocacao {
+    Alocacao { user_id: user_id.to_string(), nome: format!("Aluno {}", user_id), punicao: false }
+}
+
+/// Um dia da escala com os serviços (posto, horário, pessoa) e as pessoas do retém.
+pub fn dia(tipo: TipoServico, servicos: &[(&str, &str, &str)], retem: &[&str]) -> EscalaDiaria {
+    let mut escala: HashMap<String, HashMap<String, Alocacao>> = HashMap::new();
+    for (posto, horario, user_id) in servicos {
+        escala.entry(posto.to_string()).or_default().insert(horario.to_string(), alocacao(user_id));
+    }
+    EscalaDiaria { tipo_dia: tipo, escala, retem: retem.iter().map(|id| alocacao(id)).collect() }
+}
+
+/// Um `AppState` sobre um `MemoriaStore` vazio, com os utilizadores dados gravados.
+pub async fn estado_app(users: &[User]) -> AppState {
+    let stores = Stores::partilhada(MemoriaStore::new());
+    for user in users {
+        stores.users.guardar(user).await.unwrap();
+    }
+    let config = Config::default();
+    let db = Db::open(":memory:").await.unwrap();
+    AppState {
+        sessions: SessionStore::open(&db, &config.sessoes).await.unwrap(),
+        limite_login: LimiteLogin::new(config.login.clone()),
+        config: Arc::new(config),
+        user_store: stores.users,
+        presence_store: stores.presence,
+        meal_store: stores.meals,
+        escala_store: stores.escala,
+        dashboard_store: stores.dashboard,
+        users: Arc::new(Mutex::new(mapa(users))),
+        checkin_state: CheckinState::default(),
+        presence_state: PresenceSocketState::default(),
+        proposta_escala: Arc::default(),
+    }
+}
+
+/// Aplica uma alteração à escala do estado, a falhar o teste se der erro.
+pub async fn preparar(state: &AppState, f: AlteracaoEscala) {
+    state.escala_store.transacao(f).await.unwrap();
+}
@@ -0,0 +1,70 @@
+// src/tls.rs
+
+//! # HTTPS
+//!
+//! Quando a configuração traz certificado e chave, o MercAl termina o TLS ele próprio,
+//! sem precisar de um proxy à frente. As senhas do login e os WebSockets da presença e do
+//! check-in passam a ir cifrados pela rede Wi-Fi. Opcionalmente, uma segunda porta em HTTP
+//! simples responde apenas com um redirecionamento para o endereço HTTPS.
+
+use crate::config::Config;
+use axum::{
+    extract::Host,
+    http::{uri::Authority, Uri},
+    response::Redirect,
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use std::net::SocketAddr;
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Serve a aplicação em HTTPS e, se configurada, a porta de redirecionamento.
+pub async fn servir(app: Router, config: &Config) -> AppResult<()> {
+    let (Some(certificado), Some(chave)) = (&config.tls.certificado, &config.tls.chave) else {
+        return Err("TLS sem certificado ou chave".into());
+    };
+    // O rustls precisa de saber que implementação criptográfica usar
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let rustls_config = RustlsConfig::from_pem_file(certificado, chave)
+        .await
+        .map_err(|e| format!("Falha ao ler o certificado TLS: {}", e))?;
+
+    let addr = config.endereco();
+    if let Some(porta) = config.tls.porta_redirecionamento {
+        let addr_http = SocketAddr::new(config.servidor.endereco, porta);
+        let listener = tokio::net::TcpListener::bind(addr_http).await?;
+        println!("↪️ Redirecionamento de http://{} para HTTPS", addr_http);
+        tokio::spawn(servir_redirecionamento(listener, addr.port()));
+    }
+
+    println!("🔒 Servidor a escutar em https://{}", addr);
+    axum_server::bind_rustls(addr, rustls_config)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/// Responde a qualquer pedido HTTP com o mesmo caminho em HTTPS.
+async fn servir_redirecionamento(listener: tokio::net::TcpListener, porta_https: u16) {
+    let app = Router::new().fallback(move |Host(host): Host, uri: Uri| async move {
+        Redirect::permanent(&url_https(&host, porta_https, &uri))
+    });
+    if let Err(e) = axum::serve(listener, app).await {
+        eprintln!("🔥 O redirecionamento HTTP parou: {}", e);
+    }
+}
+
+/// Troca o esquema e a porta do pedido, mantendo o nome do servidor, o caminho e a query.
+fn url_https(host: &str, porta_https: u16, uri: &Uri) -> String {
+    let nome = host
+        .parse::<Authority>()
+        .map(|a| a.host().to_string())
+        .unwrap_or_else(|_| host.to_string());
+    let caminho = uri.path_and_query().map_or("/", |p| p.as_str());
+    if porta_https == 443 {
+        format!("https://{}{}", nome, caminho)
+    } else {
+        format!("https://{}:{}{}", nome, porta_https, caminho)
+    }
+}
@@ -1,27 +1,65 @@
 // src/users.rs
 
 use crate::auth::User;
+use crate::cautela;
+use crate::config::{AdminInicial, Config, Senhas};
+use crate::store::{EscalaStore, UserStore};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use tokio::fs;
 use crate::escala::Genero;
 
-const USERS_FILE: &str = "users.json";
 type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-pub async fn ensure_users_file() {
-    if fs::try_exists(USERS_FILE).await.unwrap_or(false) {
+/// Garante que existem utilizadores na base de dados. Se estiver vazia, cria o administrador
+/// da configuração ou, na falta dele, os utilizadores de demonstração.
+pub async fn ensure_default_users(store: &dyn UserStore, admin: Option<&AdminInicial>) {
+    match store.listar().await {
+        Ok(users) if !users.is_empty() => return,
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("🔥 Falha ao ler os utilizadores: {}", e);
+            return;
+        }
+    }
+    if let Some(admin) = admin {
+        println!("📝 Nenhum utilizador encontrado. A criar o administrador {}...", admin.id);
+        if let Err(e) = create_admin_user(store, admin).await {
+            eprintln!("🔥 Falha crítica ao criar o administrador: {}", e);
+        }
         return;
     }
-    println!("📝 Ficheiro {} não encontrado. A criar um novo...", USERS_FILE);
-    if let Err(e) = create_default_users_file().await {
-        eprintln!("🔥 Falha crítica ao criar o ficheiro de utilizadores: {}", e);
+    println!("📝 Nenhum utilizador encontrado. A criar os utilizadores padrão...");
+    println!("⚠️ Os utilizadores padrão têm a senha 1234. Configure [admin] no mercal.toml em produção.");
+    if let Err(e) = create_default_users(store).await {
+        eprintln!("🔥 Falha crítica ao criar os utilizadores padrão: {}", e);
     }
 }
 
-/// Cria o ficheiro `users.json` com utilizadores padrão, incluindo funções.
-async fn create_default_users_file() -> AppResult<()> {
+/// Cria apenas o administrador definido na configuração.
+async fn create_admin_user(store: &dyn UserStore, admin: &AdminInicial) -> AppResult<()> {
+    let user = User {
+        id: admin.id.clone(),
+        password: bcrypt::hash(&admin.senha, bcrypt::DEFAULT_COST)?,
+        name: admin.nome.clone(),
+        turma: String::new(),
+        // Sem turma, para não entrar na escala nem na presença
+        ano: 0,
+        curso: 'B',
+        genero: Genero::Masculino,
+        roles: vec!["admin".to_string()],
+        ativo: true,
+        trocar_senha: false,
+        token_calendario: None,
+    };
+    store.guardar(&user).await?;
+    println!("✅ Administrador {} criado com sucesso.", admin.id);
+    Ok(())
+}
+
+/// Cria os utilizadores padrão, incluindo funções.
+async fn create_default_users(store: &dyn UserStore) -> AppResult<()> {
     let cost = bcrypt::DEFAULT_COST;
-    let default_users = vec![
+    let default_users = [
         User {
             id: "1000".to_string(),
             password: bcrypt::hash("1234", cost)?,
@@ -32,6 +70,9 @@ async fn create_default_users_file() -> AppResult<()> {
             genero: Genero::Masculino,
             // Atribui a função 'admin'
             roles: vec!["admin".to_string()],
+            ativo: true,
+            trocar_senha: true,
+            token_calendario: None,
         },
         User {
             id: "1001".to_string(),
@@ -43,6 +84,9 @@ async fn create_default_users_file() -> AppResult<()> {
             genero: Genero::Masculino,
             // Atribui a função 'rancheiro'
             roles: vec!["rancheiro".to_string()],
+            ativo: true,
+            trocar_senha: true,
+            token_calendario: None,
         },
         User {
             id: "1002".to_string(),
@@ -54,24 +98,232 @@ async fn create_default_users_file() -> AppResult<()> {
             genero: Genero::Feminino,
             // Utilizador comum, sem funções especiais
             roles: vec![],
+            ativo: true,
+            trocar_senha: true,
+            token_calendario: None,
         },
     ];
-    let json_content = serde_json::to_string_pretty(&default_users)?;
-    fs::write(USERS_FILE, json_content).await?;
-    println!("✅ Ficheiro {} criado com sucesso.", USERS_FILE);
+    save_users(store, default_users.iter()).await?;
+    println!("✅ Utilizadores padrão criados com sucesso.");
+    Ok(())
+}
+
+pub async fn load_users(store: &dyn UserStore) -> AppResult<HashMap<String, User>> {
+    store.listar().await
+}
+
+/// Grava vários utilizadores, um a um.
+pub async fn save_users<'a>(store: &dyn UserStore, users: impl IntoIterator<Item = &'a User>) -> AppResult<()> {
+    for user in users {
+        store.guardar(user).await?;
+    }
     Ok(())
 }
 
-pub async fn load_users() -> AppResult<HashMap<String, User>> {
-    let content = fs::read_to_string(USERS_FILE).await?;
-    let users_vec: Vec<User> = serde_json::from_str(&content)?;
-    let users_map = users_vec.into_iter().map(|user| (user.id.clone(), user)).collect();
-    Ok(users_map)
+/// Cria ou atualiza um único utilizador.
+pub async fn save_user(store: &dyn UserStore, user: &User) -> AppResult<()> {
+    store.guardar(user).await
 }
 
-pub async fn save_users(users: &HashMap<String, User>) -> AppResult<()> {
-    let users_vec: Vec<User> = users.values().cloned().collect();
-    let json_content = serde_json::to_string_pretty(&users_vec)?;
-    fs::write(USERS_FILE, json_content).await?;
+/// Verifica uma senha escolhida pelo próprio utilizador contra as regras configuradas.
+/// Devolve a mensagem a mostrar quando a senha não é aceite.
+pub fn validar_senha(regras: &Senhas, user_id: &str, senha: &str) -> Result<(), String> {
+    if senha.chars().count() < regras.tamanho_minimo {
+        return Err(format!("A senha tem de ter pelo menos {} caracteres.", regras.tamanho_minimo));
+    }
+    if regras.exigir_letras_e_numeros
+        && !(senha.chars().any(|c| c.is_alphabetic()) && senha.chars().any(|c| c.is_ascii_digit()))
+    {
+        return Err("A senha tem de ter letras e números.".to_string());
+    }
+    if senha == user_id {
+        return Err("A senha não pode ser igual ao número interno.".to_string());
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+// --- GESTÃO DE UTILIZADORES ---
+
+/// Normaliza uma lista de funções: sem espaços à volta, em minúsculas, sem vazias nem repetidas.
+pub fn normalizar_funcoes<'a>(funcoes: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let mut resultado: Vec<String> = Vec::new();
+    for funcao in funcoes.into_iter().map(|f| f.trim().to_lowercase()) {
+        if !funcao.is_empty() && !resultado.contains(&funcao) {
+            resultado.push(funcao);
+        }
+    }
+    resultado
+}
+
+/// Motivos que impedem apagar um utilizador de vez (empréstimos e dívidas de serviço).
+/// Devolve uma lista vazia se o utilizador pode ser apagado.
+pub async fn impedimentos_remocao(escala: &dyn EscalaStore, config: &Config, user_id: &str) -> AppResult<Vec<String>> {
+    let mut motivos = Vec::new();
+
+    let emprestimos = cautela::emprestimos_ativos(config, user_id).await?;
+    if emprestimos > 0 {
+        motivos.push(format!("tem {} item(ns) da cautela por devolver", emprestimos));
+    }
+
+    let dividas = escala.dividas().await?;
+    let deve = dividas.get(user_id).map_or(0, |lista| lista.len());
+    if deve > 0 {
+        motivos.push(format!("deve {} serviço(s)", deve));
+    }
+    let a_receber = dividas.values().flatten().filter(|d| d.credor == user_id).count();
+    if a_receber > 0 {
+        motivos.push(format!("tem {} serviço(s) a receber", a_receber));
+    }
+    Ok(motivos)
+}
+
+/// Uma linha do CSV de utilizadores. `funcoes` vem separado por `;`.
+/// Na importação, `ativo` e `senha` podem ficar vazios para manter os valores atuais.
+#[derive(Debug, Serialize, Deserialize)]
+struct LinhaCsv {
+    id: String,
+    nome: String,
+    turma: String,
+    ano: u8,
+    curso: char,
+    genero: Genero,
+    funcoes: Option<String>,
+    ativo: Option<bool>,
+    senha: Option<String>,
+}
+
+/// Exporta os utilizadores para CSV, ordenados pelo número. As senhas nunca são exportadas.
+pub fn exportar_csv<'a>(users: impl IntoIterator<Item = &'a User>) -> AppResult<String> {
+    let mut users: Vec<&User> = users.into_iter().collect();
+    users.sort_by(|a, b| a.id.cmp(&b.id));
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for user in users {
+        writer.serialize(LinhaCsv {
+            id: user.id.clone(),
+            nome: user.name.clone(),
+            turma: user.turma.clone(),
+            ano: user.ano,
+            curso: user.curso,
+            genero: user.genero.clone(),
+            funcoes: Some(user.roles.join(";")),
+            ativo: Some(user.ativo),
+            senha: None,
+        })?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+/// Lê um CSV de utilizadores (com cabeçalho) e devolve os utilizadores a gravar.
+/// Quem já existe é atualizado; quem é novo precisa de senha. Se alguma linha tiver erros,
+/// nada é importado e o erro indica todas as linhas com problemas.
+pub fn importar_csv(texto: &str, existentes: &HashMap<String, User>) -> AppResult<Vec<User>> {
+    let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(texto.as_bytes());
+    let mut resultado: Vec<User> = Vec::new();
+    let mut erros = Vec::new();
+
+    for (i, linha) in reader.deserialize::<LinhaCsv>().enumerate() {
+        // A linha 1 é o cabeçalho
+        let numero = i + 2;
+        let linha = match linha {
+            Ok(l) => l,
+            Err(e) => {
+                erros.push(format!("linha {}: {}", numero, e));
+                continue;
+            }
+        };
+        if linha.id.is_empty() || linha.nome.is_empty() {
+            erros.push(format!("linha {}: número e nome são obrigatórios", numero));
+            continue;
+        }
+        if resultado.iter().any(|u| u.id == linha.id) {
+            erros.push(format!("linha {}: o número {} está repetido", numero, linha.id));
+            continue;
+        }
+        let senha = linha.senha.filter(|s| !s.is_empty());
+        // Uma senha vinda do CSV foi escolhida pelo admin: o utilizador terá de a trocar
+        let (password, trocar_senha) = match (senha, existentes.get(&linha.id)) {
+            (Some(senha), _) => (bcrypt::hash(senha, bcrypt::DEFAULT_COST)?, true),
+            (None, Some(atual)) => (atual.password.clone(), atual.trocar_senha),
+            (None, None) => {
+                erros.push(format!("linha {}: o utilizador {} é novo e precisa de senha", numero, linha.id));
+                continue;
+            }
+        };
+        let ativo = linha.ativo.unwrap_or_else(|| existentes.get(&linha.id).is_none_or(|u| u.ativo));
+        let token_calendario = existentes.get(&linha.id).and_then(|u| u.token_calendario.clone());
+        resultado.push(User {
+            id: linha.id,
+            password,
+            name: linha.nome,
+            turma: linha.turma,
+            ano: linha.ano,
+            curso: linha.curso,
+            genero: linha.genero,
+            roles: normalizar_funcoes(linha.funcoes.as_deref().unwrap_or_default().split(';')),
+            ativo,
+            trocar_senha,
+            token_calendario,
+        });
+    }
+
+    if !erros.is_empty() {
+        return Err(erros.join("\n").into());
+    }
+    Ok(resultado)
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::testes::{mapa, utilizador};
+
+    const CABECALHO: &str = "id,nome,turma,ano,curso,genero,funcoes,ativo,senha\n";
+
+    fn existentes() -> HashMap<String, User> {
+        let mut user = utilizador("1001", 1, Genero::Masculino);
+        user.password = "hash-antigo".to_string();
+        user.token_calendario = Some("segredo".to_string());
+        mapa(&[user])
+    }
+
+    #[test]
+    fn atualiza_quem_existe_sem_mudar_a_senha() {
+        let csv = format!("{}1001 , Novo Nome,1A,2,B,F, Admin ;escalante;admin,,\n", CABECALHO);
+        let users = importar_csv(&csv, &existentes()).unwrap();
+        assert_eq!(users.len(), 1);
+        let user = &users[0];
+        assert_eq!((user.id.as_str(), user.name.as_str(), user.turma.as_str()), ("1001", "Novo Nome", "1A"));
+        assert_eq!((user.ano, user.curso, &user.genero), (2, 'B', &Genero::Feminino));
+        assert_eq!(user.roles, vec!["admin", "escalante"]);
+        assert_eq!(user.password, "hash-antigo");
+        assert!(user.ativo);
+        assert!(!user.trocar_senha);
+        assert_eq!(user.token_calendario.as_deref(), Some("segredo"));
+    }
+
+    #[test]
+    fn exportar_e_importar_de_volta() {
+        let mut existentes = existentes();
+        existentes.get_mut("1001").unwrap().ativo = false;
+        let csv = exportar_csv(existentes.values()).unwrap();
+        assert!(!csv.contains("hash-antigo"));
+        let users = importar_csv(&csv, &existentes).unwrap();
+        let json = |users: Vec<User>| serde_json::to_value(users).unwrap();
+        assert_eq!(json(users), json(existentes.into_values().collect()));
+    }
+
+    #[test]
+    fn erros_em_todas_as_linhas_e_nada_importado() {
+        let csv = format!(
+            "{}1001,Aluno,1A,1,A,M,,,\n2002,Sem Senha,1A,1,A,M,,,\n1001,Repetido,1A,1,A,M,,,\n,Sem Numero,1A,1,A,M,,,\n3003,Ano Mau,1A,x,A,M,,,\n",
+            CABECALHO
+        );
+        let erro = importar_csv(&csv, &existentes()).unwrap_err().to_string();
+        let linhas: Vec<&str> = erro.lines().collect();
+        assert_eq!(linhas.len(), 4, "{}", erro);
+        assert!(linhas[0].starts_with("linha 3:") && linhas[0].contains("precisa de senha"));
+        assert!(linhas[1].starts_with("linha 4:") && linhas[1].contains("repetido"));
+        assert!(linhas[2].starts_with("linha 5:") && linhas[2].contains("obrigatórios"));
+        assert!(linhas[3].starts_with("linha 6:"));
+    }
+}
@@ -1,5 +1,9 @@
 // src/views/admin.rs
 
+use crate::auth::{User, FUNCOES};
+use crate::escala::Genero;
+use crate::escala_dividas::DestinoDividas;
+use crate::virada_ano::{OpcoesVirada, PlanoVirada, ULTIMO_ANO};
 use axum::response::{Html, IntoResponse};
 
 pub fn admin_page() -> impl IntoResponse {
@@ -46,9 +50,308 @@ pub fn admin_page() -> impl IntoResponse {
                     <button type="submit" class="btn-create">Criar Utilizador</button>
                 </form>
             </div>
+            <a href="/admin/utilizadores" class="nav-link">👥 Gestão de Utilizadores</a>
+            <a href="/admin/virada-ano" class="nav-link">🎓 Virada de Ano</a>
             <a href="/dashboard" class="nav-link">← Voltar ao Dashboard</a>
         </body>
         </html>
         "#,
     ).into_response()
-}
\ No newline at end of file
+}
+
+/// Filtros da lista de utilizadores, vindos da query string.
+pub struct FiltroUtilizadores<'a> {
+    pub pesquisa: &'a str,
+    pub ano: Option<u8>,
+    pub estado: &'a str,
+}
+
+const ESTILO_GESTAO: &str = r#"
+    body { font-family: Arial, sans-serif; max-width: 1100px; margin: 30px auto; padding: 20px; background: #f5f5f5; }
+    .container { background: white; padding: 25px; border-radius: 10px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); margin-bottom: 25px; }
+    h1, h2 { color: #343a40; }
+    table { width: 100%; border-collapse: collapse; }
+    th, td { padding: 8px; border-bottom: 1px solid #eee; text-align: left; font-size: 14px; }
+    tr.inativo td { color: #999; }
+    .filtros { display: flex; gap: 10px; flex-wrap: wrap; margin-bottom: 15px; }
+    input, select, textarea { padding: 8px; border: 1px solid #ddd; border-radius: 5px; box-sizing: border-box; }
+    textarea { width: 100%; min-height: 160px; font-family: monospace; }
+    button, .botao { padding: 8px 14px; color: white; border: none; border-radius: 5px; cursor: pointer; background: #007bff; text-decoration: none; font-size: 14px; }
+    .perigo { background: #dc3545; }
+    .aviso { background: #6c757d; }
+    .acoes { display: flex; gap: 6px; }
+    .acoes form { margin: 0; }
+    .campos { display: grid; grid-template-columns: 1fr 1fr; gap: 12px; }
+    .campos label { display: flex; flex-direction: column; gap: 4px; font-size: 14px; }
+    .funcoes { display: flex; gap: 15px; flex-wrap: wrap; margin: 10px 0; }
+    .nav-link { color: #007bff; }
+"#;
+
+/// Lista, pesquisa, importação e exportação de utilizadores.
+pub fn utilizadores_page(users: &[&User], filtro: &FiltroUtilizadores, total: usize) -> Html<String> {
+    let mut linhas = String::new();
+    for user in users {
+        let (acao_estado, texto_estado, classe_estado) = if user.ativo {
+            ("desativar", "Desativar", "aviso")
+        } else {
+            ("reativar", "Reativar", "")
+        };
+        linhas.push_str(&format!(
+            r#"<tr class="{classe}">
+                <td>{id}</td><td>{nome}</td><td>{turma}</td><td>{ano}º</td><td>{curso}</td><td>{genero}</td>
+                <td>{funcoes}</td><td>{estado}</td>
+                <td class="acoes">
+                    <a class="botao" href="/admin/utilizadores/editar?id={id_url}">Editar</a>
+                    <form method="POST" action="/admin/utilizadores/{acao_estado}">
+                        <input type="hidden" name="id" value="{id}"><button class="{classe_estado}">{texto_estado}</button>
+                    </form>
+                    <form method="POST" action="/admin/utilizadores/apagar" onsubmit="return confirm('Apagar {id} de vez? Esta ação não pode ser desfeita.');">
+                        <input type="hidden" name="id" value="{id}"><button class="perigo">Apagar</button>
+                    </form>
+                </td>
+            </tr>"#,
+            classe = if user.ativo { "" } else { "inativo" },
+            id = user.id,
+            id_url = urlencoding::encode(&user.id),
+            nome = user.name,
+            turma = user.turma,
+            ano = user.ano,
+            curso = user.curso,
+            genero = genero_str(&user.genero),
+            funcoes = if user.roles.is_empty() { "—".to_string() } else { user.roles.join(", ") },
+            estado = if user.ativo { "Ativo" } else { "Desativado" },
+        ));
+    }
+
+    let opcao = |valor: &str, texto: &str| {
+        format!(r#"<option value="{v}" {s}>{t}</option>"#, v = valor, t = texto, s = if filtro.estado == valor { "selected" } else { "" })
+    };
+    let opcoes_ano: String = (0..=4)
+        .map(|ano| format!(r#"<option value="{a}" {s}>{a}º ano</option>"#, a = ano, s = if filtro.ano == Some(ano) { "selected" } else { "" }))
+        .collect();
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html>
+        <head><meta charset="UTF-8"><meta name="viewport" content="width=device-width, initial-scale=1.0"><title>Utilizadores</title><style>{estilo}</style></head>
+        <body>
+            <div class="container">
+                <h1>👥 Gestão de Utilizadores</h1>
+                <form class="filtros" method="GET" action="/admin/utilizadores">
+                    <input type="text" name="q" value="{pesquisa}" placeholder="Número, nome ou turma">
+                    <select name="ano"><option value="">Todos os anos</option>{opcoes_ano}</select>
+                    <select name="estado">{ativos}{inativos}{todos}</select>
+                    <button type="submit">Pesquisar</button>
+                </form>
+                <p>{mostrados} de {total} utilizadores.</p>
+                <table>
+                    <thead><tr><th>Número</th><th>Nome</th><th>Turma</th><th>Ano</th><th>Curso</th><th>Género</th><th>Funções</th><th>Estado</th><th></th></tr></thead>
+                    <tbody>{linhas}</tbody>
+                </table>
+            </div>
+            <div class="container">
+                <h2>📥 Importar / 📤 Exportar (CSV)</h2>
+                <p>Colunas: <code>id,nome,turma,ano,curso,genero,funcoes,ativo,senha</code>. As funções são separadas por <code>;</code>.
+                Utilizadores existentes são atualizados; a senha e o estado podem ficar vazios para manter os atuais.
+                Utilizadores novos precisam de senha.</p>
+                <p><a class="botao" href="/admin/utilizadores/exportar">Exportar CSV</a></p>
+                <form method="POST" action="/admin/utilizadores/importar">
+                    <input type="file" accept=".csv,text/csv" onchange="const f = this.files[0]; if (f) f.text().then(t => document.getElementById('csv').value = t);">
+                    <textarea id="csv" name="csv" placeholder="id,nome,turma,ano,curso,genero,funcoes,ativo,senha" required></textarea>
+                    <button type="submit">Importar</button>
+                </form>
+            </div>
+            <a href="/admin" class="nav-link">← Voltar à Administração</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO_GESTAO,
+        pesquisa = filtro.pesquisa,
+        opcoes_ano = opcoes_ano,
+        ativos = opcao("ativos", "Ativos"),
+        inativos = opcao("inativos", "Desativados"),
+        todos = opcao("todos", "Todos"),
+        mostrados = users.len(),
+        total = total,
+        linhas = linhas,
+    ))
+}
+
+/// Formulário de edição de todos os campos de um utilizador.
+pub fn editar_utilizador_page(user: &User) -> Html<String> {
+    let funcoes: String = FUNCOES
+        .iter()
+        .map(|funcao| {
+            format!(
+                r#"<label><input type="checkbox" name="funcoes" value="{f}" {c}> {f}</label>"#,
+                f = funcao,
+                c = if user.roles.iter().any(|r| r.eq_ignore_ascii_case(funcao)) { "checked" } else { "" }
+            )
+        })
+        .collect();
+    // Funções que não estão na lista conhecida (p. ex. antigas) continuam editáveis como texto
+    let outras: Vec<&str> = user
+        .roles
+        .iter()
+        .filter(|r| !FUNCOES.iter().any(|f| f.eq_ignore_ascii_case(r)))
+        .map(|r| r.as_str())
+        .collect();
+    let genero_opcao = |valor: &str, texto: &str| {
+        format!(r#"<option value="{v}" {s}>{t}</option>"#, v = valor, t = texto, s = if genero_str(&user.genero) == valor { "selected" } else { "" })
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html>
+        <head><meta charset="UTF-8"><meta name="viewport" content="width=device-width, initial-scale=1.0"><title>Editar {id}</title><style>{estilo}</style></head>
+        <body>
+            <div class="container">
+                <h1>✏️ Editar Utilizador {id}</h1>
+                <form method="POST" action="/admin/utilizadores/editar">
+                    <input type="hidden" name="id" value="{id}">
+                    <div class="campos">
+                        <label>Nome<input type="text" name="name" value="{nome}" required></label>
+                        <label>Turma<input type="text" name="turma" value="{turma}"></label>
+                        <label>Ano<input type="number" name="ano" min="0" max="9" value="{ano}" required></label>
+                        <label>Curso<input type="text" name="curso" maxlength="1" value="{curso}" required></label>
+                        <label>Género<select name="genero">{masculino}{feminino}{misto}</select></label>
+                        <label>Estado<select name="ativo"><option value="true" {sel_ativo}>Ativo</option><option value="false" {sel_inativo}>Desativado</option></select></label>
+                    </div>
+                    <h2>Funções</h2>
+                    <div class="funcoes">{funcoes}</div>
+                    <label>Outras funções (separadas por vírgula)<input type="text" name="outras_funcoes" value="{outras}" style="width:100%"></label>
+                    <p><button type="submit">Guardar</button></p>
+                </form>
+            </div>
+            <a href="/admin/utilizadores" class="nav-link">← Voltar à lista</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO_GESTAO,
+        id = user.id,
+        nome = user.name,
+        turma = user.turma,
+        ano = user.ano,
+        curso = user.curso,
+        masculino = genero_opcao("M", "Masculino"),
+        feminino = genero_opcao("F", "Feminino"),
+        misto = genero_opcao("X", "Misto"),
+        sel_ativo = if user.ativo { "selected" } else { "" },
+        sel_inativo = if user.ativo { "" } else { "selected" },
+        funcoes = funcoes,
+        outras = outras.join(", "),
+    ))
+}
+
+/// Pré-visualização da virada de ano, com as opções e o botão para confirmar.
+pub fn virada_ano_page(plano: &PlanoVirada, opcoes: &OpcoesVirada) -> Html<String> {
+    let linhas: String = plano
+        .mudancas
+        .iter()
+        .map(|m| {
+            let destino = match m.para {
+                Some(ano) => format!("{}º ano", ano),
+                None => "Sai (desativado)".to_string(),
+            };
+            format!(
+                r#"<tr class="{classe}"><td>{id}</td><td>{nome}</td><td>{de}º ano</td><td>{destino}</td></tr>"#,
+                classe = if m.para.is_none() { "inativo" } else { "" },
+                id = m.id,
+                nome = m.nome,
+                de = m.de,
+                destino = destino,
+            )
+        })
+        .collect();
+    let escolha = |nome: &str, valor: bool, sim: &str, nao: &str| {
+        format!(
+            r#"<select name="{n}"><option value="true" {s1}>{sim}</option><option value="false" {s2}>{nao}</option></select>"#,
+            n = nome,
+            sim = sim,
+            nao = nao,
+            s1 = if valor { "selected" } else { "" },
+            s2 = if valor { "" } else { "selected" },
+        )
+    };
+    let escondido = |nome: &str, valor: bool| format!(r#"<input type="hidden" name="{}" value="{}">"#, nome, valor);
+    let contagem = if opcoes.zerar_contagem {
+        "A contagem de serviços de todos volta a zero.".to_string()
+    } else {
+        format!("A contagem de serviços transita; {} registo(s) de quem sai são apagados.", plano.contagens_removidas)
+    };
+    let dividas = match plano.destino_dividas {
+        DestinoDividas::Manter => format!("As dívidas de serviço transitam; {} envolvem quem sai e são apagadas.", plano.dividas_removidas),
+        DestinoDividas::Apagar => format!("Todas as {} dívidas de serviço são apagadas.", plano.dividas_removidas),
+        DestinoDividas::Punir => format!(
+            "As {} dívidas de serviço por pagar passam a {} serviço(s) de punição dos devedores que continuam.",
+            plano.dividas_removidas, plano.servicos_convertidos
+        ),
+    };
+    let dividas_opcoes: String = DestinoDividas::TODOS
+        .iter()
+        .map(|d| format!(r#"<option value="{:?}" {}>{}</option>"#, d, if *d == plano.destino_dividas { "selected" } else { "" }, d.nome()))
+        .collect();
+    let dividas_sel = format!(r#"<select name="dividas">{}</select>"#, dividas_opcoes);
+    let h_dividas = format!(r#"<input type="hidden" name="dividas" value="{:?}">"#, plano.destino_dividas);
+    let punicoes = if opcoes.manter_punicoes {
+        format!("As punições transitam; {} de quem sai são apagadas.", plano.punicoes_removidas)
+    } else {
+        format!("Todas as {} punições são apagadas.", plano.punicoes_removidas)
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html>
+        <head><meta charset="UTF-8"><meta name="viewport" content="width=device-width, initial-scale=1.0"><title>Virada de Ano</title><style>{estilo}</style></head>
+        <body>
+            <div class="container">
+                <h1>🎓 Virada de Ano</h1>
+                <p>Todos os alunos ativos sobem um ano. Quem está no {ultimo}º ano sai e é desativado (o registo é mantido).
+                Utilizadores do ano 0 e desativados não mudam.</p>
+                <form class="filtros" method="GET" action="/admin/virada-ano">
+                    <label>Contagem {contagem_sel}</label>
+                    <label>Dívidas {dividas_sel}</label>
+                    <label>Punições {punicoes_sel}</label>
+                    <button type="submit">Atualizar pré-visualização</button>
+                </form>
+            </div>
+            <div class="container">
+                <h2>Pré-visualização</h2>
+                <p>{promovidos} aluno(s) sobem de ano e {formados} saem.</p>
+                <ul><li>{contagem}</li><li>{dividas}</li><li>{punicoes}</li></ul>
+                <table>
+                    <thead><tr><th>Número</th><th>Nome</th><th>Agora</th><th>Depois</th></tr></thead>
+                    <tbody>{linhas}</tbody>
+                </table>
+                <form method="POST" action="/admin/virada-ano" onsubmit="return confirm('Aplicar a virada de ano? Esta ação não pode ser desfeita.');">
+                    {h_contagem}{h_dividas}{h_punicoes}
+                    <input type="hidden" name="assinatura" value="{assinatura}">
+                    <p><button type="submit" class="perigo">Aplicar a virada de ano</button></p>
+                </form>
+            </div>
+            <a href="/admin" class="nav-link">← Voltar à Administração</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO_GESTAO,
+        ultimo = ULTIMO_ANO,
+        contagem_sel = escolha("zerar_contagem", opcoes.zerar_contagem, "Zerar", "Manter"),
+        dividas_sel = dividas_sel,
+        punicoes_sel = escolha("manter_punicoes", opcoes.manter_punicoes, "Manter", "Apagar"),
+        promovidos = plano.promovidos().count(),
+        formados = plano.formados().count(),
+        contagem = contagem,
+        dividas = dividas,
+        punicoes = punicoes,
+        linhas = linhas,
+        h_contagem = escondido("zerar_contagem", opcoes.zerar_contagem),
+        h_dividas = h_dividas,
+        h_punicoes = escondido("manter_punicoes", opcoes.manter_punicoes),
+        assinatura = plano.assinatura(),
+    ))
+}
+
+fn genero_str(genero: &Genero) -> &'static str {
+    match genero {
+        Genero::Masculino => "M",
+        Genero::Feminino => "F",
+        Genero::Misto => "X",
+    }
+}
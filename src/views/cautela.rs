@@ -70,12 +70,6 @@ fn render_page(title: &str, content: String) -> Html<String> {
     Html(format!(r#"<!DOCTYPE html><html lang="pt-BR"><head><meta charset="UTF-8"><meta name="viewport" content="width=device-width, initial-scale=1.0"><title>{title}</title><link href="https://fonts.googleapis.com/css2?family=Roboto:wght@400;500;700&display=swap" rel="stylesheet"><style>{CSS}</style></head><body><div class="container">{content}</div></body></html>"#))
 }
 
-pub fn login_page(error: Option<&str>) -> Html<String> {
-    let error_html = error.map(|e| format!("<p style='color: #d32f2f;'>{}</p>", e)).unwrap_or_default();
-    let content = format!(r#"<div class="login-card"><div class="login-header"><h1>Acesso Restrito</h1><p>Paiol de Livros / Cautela</p></div><form method="POST" action="/cautela/login"><input type="text" name="username" placeholder="Utilizador" required /><input type="password" name="password" placeholder="Palavra-passe" required />{error_html}<button type="submit" style="width: 100%;">Entrar</button></form></div>"#);
-    render_page("Login - Cautela", content)
-}
-
 pub fn catalogo_page(
     catalogo: &[ItemCatalogo], 
     setores: &[String], 
@@ -218,7 +212,7 @@ pub fn catalogo_page(
     "#;
 
     let content = format!(r#"
-        <div class="header"><h1>Gestão de Catálogo</h1><div class="nav"><a href="/cautela/dashboard">Voltar ao Painel</a><a href="/cautela/logout">Sair</a></div></div>
+        <div class="header"><h1>Gestão de Catálogo</h1><div class="nav"><a href="/cautela/dashboard">Voltar ao Painel</a><a href="/dashboard">Painel Principal</a><a href="/logout">Sair</a></div></div>
         <div class="card"><h2>Adicionar Novo Item ao Catálogo</h2><form method="POST" action="/cautela/catalogo/add-item"><div style="display: flex; gap: 20px; align-items: center;"><input type="text" name="nome" placeholder="Nome do Item (ex: Abacate Voador)" required style="flex-grow: 2; margin: 0;"><input type="text" name="setor" placeholder="Setor (ex: Biblioteca de Exatas)" required style="flex-grow: 1; margin: 0;"><input type="text" name="numero_identificacao" placeholder="Nº do 1º Exemplar" required style="flex-grow: 1; margin: 0;"><button type="submit" style="margin: 0;">Adicionar Item</button></div></form></div>
         {filter_html}
         {items_html}
@@ -347,7 +341,7 @@ pub fn dashboard_page(
     "#);
 
     let content = format!(r#"
-        <div class="header"><h1>Painel da Cautela</h1><span>Bem-vindo, <strong>{username}</strong>!</span><div class="nav"><a href="/cautela/catalogo">Catálogo</a><a href="/cautela/atrasos">Atrasos</a><a href="/cautela/logout">Sair</a></div></div>
+        <div class="header"><h1>Painel da Cautela</h1><span>Bem-vindo, <strong>{username}</strong>!</span><div class="nav"><a href="/cautela/catalogo">Catálogo</a><a href="/cautela/atrasos">Atrasos</a><a href="/dashboard">Painel Principal</a><a href="/logout">Sair</a></div></div>
         <div class="card"><h2>Ações de Empréstimo</h2><form method="GET" action="/cautela/dashboard" class="form-inline"><input type="text" name="q" placeholder="Procurar por item, ID de exemplar ou aluno..." value="{search_query}" style="flex-grow: 1;"><button type="submit">Procurar</button></form></div>
         <div id="search-results-container">{results_html}</div>
         {script_js}
@@ -364,6 +358,6 @@ pub fn atrasos_page(atrasos: &[AtrasoInfo]) -> Html<String> {
                 a.nome_aluno, a.id_aluno, a.nome_item, a.exemplar_id, a.data_devolucao.format("%d/%m/%Y"), a.dias_atrasado)
         }).collect::<String>()
     };
-    let content = format!(r#"<div class="header"><h1>Relatório de Atrasos</h1><div class="nav"><a href="/cautela/dashboard">Voltar ao Painel</a><a href="/cautela/logout">Sair</a></div></div><div class="card"><h2>Itens com Devolução Pendente</h2><table><thead><tr><th>Aluno</th><th>Item</th><th>Data de Devolução</th><th>Dias Atrasado</th></tr></thead><tbody>{table_rows}</tbody></table></div>"#);
+    let content = format!(r#"<div class="header"><h1>Relatório de Atrasos</h1><div class="nav"><a href="/cautela/dashboard">Voltar ao Painel</a><a href="/dashboard">Painel Principal</a><a href="/logout">Sair</a></div></div><div class="card"><h2>Itens com Devolução Pendente</h2><table><thead><tr><th>Aluno</th><th>Item</th><th>Data de Devolução</th><th>Dias Atrasado</th></tr></thead><tbody>{table_rows}</tbody></table></div>"#);
     render_page("Relatório de Atrasos", content)
 }
\ No newline at end of file
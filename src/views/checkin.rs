@@ -94,7 +94,7 @@ pub fn checkin_page(
                     }});
                 }}
 
-                const ws = new WebSocket(`ws://${{window.location.host}}/ws/refeicoes/checkin`);
+                const ws = new WebSocket(`${{window.location.protocol === 'https:' ? 'wss' : 'ws'}}://${{window.location.host}}/ws/refeicoes/checkin`);
                 ws.onopen = () => console.log("WebSocket conectado.");
                 ws.onmessage = function(event) {{
                     try {{
@@ -1,14 +1,16 @@
 // src/views/dashboard.rs
 
 // ADICIONADO: Importações necessárias com caminhos absolutos
-use crate::auth::{self, AppState};
+use crate::auth::{AppState, AuthUser};
 use crate::cautela::{self};
+use crate::config::Senhas;
+use crate::store::{EscalaStore, MealStore};
 use crate::handlers::{DashboardMessage};
 use axum::response::{Html, IntoResponse};
 use chrono::{Datelike, Local, NaiveDate, Weekday};
 use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use tokio_rusqlite::Connection;
-use tower_cookies::Cookies;
 
 // O conteúdo do `mod view` antigo vem para aqui.
 // As funções que precisam ser chamadas de fora (login_page, render_dashboard_page)
@@ -80,9 +82,6 @@ const CSS: &str = r#"
     }
     .login-header h1 { margin: 0; font-size: 1.8em; color: var(--text-color); }
     .username-input { text-align: center; font-size: 1.2em; letter-spacing: 2px; }
-    .password-container { display: flex; justify-content: center; gap: 10px; margin: 10px 0; cursor: text; outline: none; margin-bottom: 20px; }
-    .password-box { width: 30px; height: 40px; border: 2px solid var(--border-color); border-radius: 8px; display: flex; align-items: center; justify-content: center; font-size: 1.5em; font-family: monospace; transition: border-color 0.2s; }
-    .password-box.active { border-color: var(--primary-color); }
     .info-box { background: #f1f1f1; padding: 10px; border-radius: 6px; margin-top: 25px; font-size: 13px; color: var(--text-light); }
     
     .header { display: flex; justify-content: space-between; align-items: center; margin-bottom: 2rem; }
@@ -154,51 +153,57 @@ pub fn login_page(error: Option<&str>) -> Html<String> {
             <div class="login-header"><h1>Área Restrita</h1></div>
             <form method="POST" action="/login">
                 <input type="text" name="username" placeholder="Número Interno" required maxlength="4" class="username-input" />
-                <input type="password" name="password" id="real-password-input" required style="display:none;">
-                <div class="password-container" id="password-boxes" tabindex="0">
-                    <div class="password-box"></div><div class="password-box"></div><div class="password-box"></div><div class="password-box"></div><div class="password-box"></div>
-                </div>
+                <input type="password" name="password" placeholder="Senha" required autocomplete="current-password" />
                 {error_html}
                 <button type="submit" class="btn btn-primary btn-full">Entrar</button>
             </form>
             <div class="info-box"><strong>Versão do Sistema:</strong> 1.0 - OUT/2025</div>
         </div></div>
-        <script>
-            const passwordContainer = document.getElementById('password-boxes');
-            const passwordBoxes = passwordContainer.querySelectorAll('.password-box');
-            const realPasswordInput = document.getElementById('real-password-input');
-            let password = '';
-            const activateInput = () => {{
-                const tempInput = document.createElement('input');
-                tempInput.type = 'number';
-                tempInput.style.position = 'absolute';
-                tempInput.style.opacity = '0';
-                document.body.appendChild(tempInput);
-                tempInput.focus();
-                tempInput.addEventListener('input', (e) => {{
-                    const value = e.target.value;
-                    if (value.length <= 5) {{ password = value; updatePasswordDisplay(); }}
-                    else {{ e.target.value = password; }}
-                }});
-                tempInput.addEventListener('blur', () => document.body.removeChild(tempInput));
-            }};
-            passwordContainer.addEventListener('click', activateInput);
-            passwordContainer.addEventListener('focus', activateInput);
-            function updatePasswordDisplay() {{
-                realPasswordInput.value = password;
-                passwordBoxes.forEach((box, index) => {{
-                    if (index < password.length) {{ box.textContent = '●'; box.classList.remove('active'); }}
-                    else {{ box.textContent = ''; box.classList.remove('active'); }}
-                }});
-                if (password.length < 5) {{ passwordBoxes[password.length].classList.add('active'); }}
-            }}
-            updatePasswordDisplay();
-        </script>
         "#
     );
     render_page("Login", content, "login-body")
 }
 
+/// Formulário para o próprio utilizador escolher uma senha nova.
+/// Com `obrigatoria`, é a página para onde o utilizador é enviado até trocar a senha.
+pub fn alterar_senha_page(senhas: &Senhas, obrigatoria: bool, error: Option<&str>) -> Html<String> {
+    let error_html = error.map(|e| format!("<p style='color: var(--danger-color); text-align: center;'>{}</p>", e)).unwrap_or_default();
+    let aviso = if obrigatoria {
+        "<p>A sua senha foi definida pela administração. Escolha uma senha nova para continuar.</p>"
+    } else {
+        ""
+    };
+    let regras = if senhas.exigir_letras_e_numeros {
+        format!("Pelo menos {} caracteres, com letras e números.", senhas.tamanho_minimo)
+    } else {
+        format!("Pelo menos {} caracteres.", senhas.tamanho_minimo)
+    };
+    let voltar = if obrigatoria {
+        r#"<a href="/logout">Sair</a>"#
+    } else {
+        r#"<a href="/dashboard">← Voltar ao Dashboard</a>"#
+    };
+    let content = format!(
+        r#"
+        <div class="login-container"><div class="login-card">
+            <div class="login-header"><h1>🔒 Alterar Senha</h1></div>
+            {aviso}
+            <form method="POST" action="/conta/senha">
+                <input type="password" name="senha_atual" placeholder="Senha atual" required autocomplete="current-password" />
+                <input type="password" name="nova_senha" placeholder="Nova senha" required minlength="{minimo}" autocomplete="new-password" />
+                <input type="password" name="confirmacao" placeholder="Repita a nova senha" required minlength="{minimo}" autocomplete="new-password" />
+                <p style="color: var(--text-light); font-size: 0.9em;">{regras}</p>
+                {error_html}
+                <button type="submit" class="btn btn-primary btn-full">Guardar</button>
+            </form>
+            <p style="text-align: center;">{voltar}</p>
+        </div></div>
+        "#,
+        minimo = senhas.tamanho_minimo,
+    );
+    render_page("Alterar Senha", content, "login-body")
+}
+
 fn weekday_to_portuguese(weekday: Weekday) -> &'static str {
     match weekday {
         Weekday::Mon => "Seg", Weekday::Tue => "Ter", Weekday::Wed => "Qua",
@@ -206,8 +211,8 @@ fn weekday_to_portuguese(weekday: Weekday) -> &'static str {
     }
 }
 
- pub async fn render_cautela_card(user_id: &str) -> String {
-     let conn = Connection::open(cautela::DB_FILE).await.unwrap();
+ pub async fn render_cautela_card(db_file: &Path, user_id: &str) -> String {
+     let conn = Connection::open(db_file).await.unwrap();
      let user_id_owned = user_id.to_string();
      
      let user_loans_info: Vec<(String, String, NaiveDate)> = conn.call(move |conn| {
@@ -246,28 +251,21 @@ fn weekday_to_portuguese(weekday: Weekday) -> &'static str {
      format!(r#"<div class="card"><h2 class="card-title"><span class="icon">📚</span> Meus Empréstimos</h2><ul class="item-list">{items_html}</ul></div>"#)
  }
 
-pub async fn render_schedule_card(user_id: &str, escala_period: Option<(chrono::NaiveDate, chrono::NaiveDate)>) -> String {
-    let Ok(estado_content) = tokio::fs::read_to_string("data/escala/estado.json").await else { return "".to_string(); };
-    let Ok(estado) = serde_json::from_str::<crate::escala::EstadoEscala>(&estado_content) else { return "".to_string(); };
+pub async fn render_schedule_card(store: &dyn EscalaStore, user_id: &str, escala_period: Option<(chrono::NaiveDate, chrono::NaiveDate)>) -> String {
+    let Ok(estado) = store.estado().await else { return "".to_string(); };
+    let Ok(escalas) = store.periodo(estado.periodo_atual.start_date, estado.periodo_atual.end_date).await else { return "".to_string(); };
     
     let mut services_by_date: BTreeMap<chrono::NaiveDate, Vec<String>> = BTreeMap::new();
-    let mut current_date = estado.periodo_atual.start_date;
 
-    while current_date <= estado.periodo_atual.end_date {
-        let filename = format!("{}/{}.json", crate::escala::ESCALA_DATA_DIR, current_date.format("%Y-%m-%d"));
-        if let Ok(content) = tokio::fs::read_to_string(filename).await {
-            if let Ok(escala_diaria) = serde_json::from_str::<crate::escala::EscalaDiaria>(&content) {
-                for (posto, horarios) in &escala_diaria.escala {
-                    for (_horario, alocacao) in horarios {
-                        if alocacao.user_id == user_id {
-                            let service_details = format!("<p><strong>{}</strong></p>", posto);
-                            services_by_date.entry(current_date).or_default().push(service_details);
-                        }
-                    }
+    for (data, escala_diaria) in &escalas {
+        for (posto, horarios) in &escala_diaria.escala {
+            for (_horario, alocacao) in horarios {
+                if alocacao.user_id == user_id {
+                    let service_details = format!("<p><strong>{}</strong></p>", posto);
+                    services_by_date.entry(*data).or_default().push(service_details);
                 }
             }
         }
-        current_date = current_date.succ_opt().unwrap_or(current_date);
     }
     
     let services_html = if services_by_date.is_empty() {
@@ -293,13 +291,52 @@ pub async fn render_schedule_card(user_id: &str, escala_period: Option<(chrono::
     format!(r#"<div class="card"><h2 class="card-title"><span class="icon">📅</span> Meus Serviços</h2>{periodo_html}<div>{services_html}</div></div>"#)
 }
 
-pub async fn render_meals_card(user_id: &str) -> String {
-    let Ok(form_state) = crate::meals::load_form_state().await else { return "".to_string() };
+/// As punições da pessoa: as ativas com o prazo e os serviços já cumpridos, e as cumpridas
+/// ou anuladas no fim. Sem punições, não há cartão.
+pub async fn render_punishments_card(store: &dyn EscalaStore, user_id: &str) -> String {
+    let Ok(punicoes) = store.punicoes().await else { return "".to_string() };
+    let mut minhas: Vec<&crate::escala::Punicao> = punicoes.iter().filter(|p| p.user_id == user_id).collect();
+    if minhas.is_empty() {
+        return "".to_string();
+    }
+    minhas.sort_by_key(|p| (!p.ativa(), p.prazo));
+    let hoje = chrono::Local::now().date_naive();
+    let data = |d: Option<chrono::NaiveDate>| d.map_or("—".to_string(), |d| d.format("%d/%m/%Y").to_string());
+    let items_html: String = minhas
+        .iter()
+        .map(|p| {
+            let (status_class, status_text) = match p.anulada_em {
+                Some(_) => ("status-rejected", "Anulada".to_string()),
+                None if p.em_atraso(hoje) => ("status-rejected", format!("Em atraso · faltam {}", p.restantes())),
+                None if p.ativa() => ("status-pending", format!("Faltam {}", p.restantes())),
+                None => ("status-approved", "Cumprida".to_string()),
+            };
+            let cumpridos = if p.cumpridos.is_empty() {
+                String::new()
+            } else {
+                format!("<br><small>Cumpridos: {}</small>", p.cumpridos.iter().map(|s| s.descrever()).collect::<Vec<_>>().join("; "))
+            };
+            format!(
+                r#"<li><strong>{motivo}</strong> <span class="status-tag {status_class}">{status_text}</span><br><small>{autoridade} · {aplicada} · Prazo: {prazo} · {feitos}/{total} serviço(s)</small>{cumpridos}</li>"#,
+                motivo = p.motivo,
+                autoridade = p.autoridade,
+                aplicada = data(p.aplicada_em),
+                prazo = data(p.prazo),
+                feitos = p.ja_cumpridos,
+                total = p.total_a_cumprir,
+            )
+        })
+        .collect();
+    format!(r#"<div class="card"><h2 class="card-title"><span class="icon">⚖️</span> Minhas Punições</h2><ul class="item-list">{items_html}</ul></div>"#)
+}
+
+pub async fn render_meals_card(store: &dyn MealStore, user_id: &str) -> String {
+    let Ok(form_state) = crate::meals::load_form_state(store).await else { return "".to_string() };
     let mut interests_html = String::new();
     let mut current_date = form_state.active_period.start_date;
 
     while current_date <= form_state.active_period.end_date {
-        if let Ok(daily_data) = crate::meals::load_daily_meals(current_date).await {
+        if let Ok(daily_data) = crate::meals::load_daily_meals(store, current_date).await {
             if let Some(selection) = daily_data.get(user_id) {
                 let daily: Vec<&str> = [
                     (selection.cafe, "Café"), (selection.almoco, "Almoço"),
@@ -317,38 +354,71 @@ pub async fn render_meals_card(user_id: &str) -> String {
     format!(r#"<div class="card"><h2 class="card-title"><span class="icon">🍳</span> Refeições</h2><ul class="item-list">{interests_html}</ul></div>"#)
 }
 
-pub async fn render_trades_content(user_id: &str, users_map: &HashMap<String, crate::auth::User>) -> String {
-    let Ok(trocas_content) = tokio::fs::read_to_string("data/escala/trocas.json").await else { return "".to_string() };
-    let Ok(todas_as_trocas) = serde_json::from_str::<Vec<crate::escala::Troca>>(&trocas_content) else { return "".to_string() };
-    
+/// Descrição curta de um serviço numa troca: "POSTO 08:00-12:00 em 12/03".
+pub(crate) fn servico_troca_str(servico: &crate::escala::DetalheServico) -> String {
+    if servico.posto == "RETEM" {
+        format!("Retém em {}", servico.data.format("%d/%m"))
+    } else {
+        format!("{} {} em {}", servico.posto, servico.horario, servico.data.format("%d/%m"))
+    }
+}
+
+/// As trocas por decidir em que a pessoa entra, as decididas nos últimos dias (como
+/// avisos) e, para quem aprova trocas, quantas aguardam o escalante.
+pub async fn render_trades_content(store: &dyn EscalaStore, user_id: &str, users_map: &HashMap<String, crate::auth::User>, aprova_trocas: bool) -> String {
+    use crate::escala::{StatusTroca, TipoTroca};
+    if let Err(e) = crate::escala::expirar_trocas(store).await {
+        eprintln!("🔥 Falha ao expirar as trocas: {}", e);
+    }
+    let Ok(todas_as_trocas) = store.trocas().await else { return "".to_string() };
+    let recentes = chrono::Local::now() - chrono::Duration::days(7);
+    let prazo = |t: &crate::escala::Troca| t.expira_em.map_or(String::new(), |p| format!(" · Prazo: {}", p.format("%d/%m %H:%M")));
+
     let mut trades_html = String::new();
-    for troca in todas_as_trocas.iter().filter(|t| t.requerente.user_id == user_id || t.alvo.user_id == user_id) {
+    if aprova_trocas {
+        let aguardam = todas_as_trocas.iter().filter(|t| t.status == StatusTroca::PendenteAdmin).count();
+        if aguardam > 0 {
+            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">🔔</span> <a href="/admin/escala">{} troca(s) aguardam a sua aprovação.</a></p></div>"#, aguardam));
+        }
+    }
+    for troca in todas_as_trocas.iter().rev().filter(|t| t.envolve(user_id)) {
         let requerente_nome = users_map.get(&troca.requerente.user_id).map_or("N/A", |u| u.name.as_str());
         let alvo_nome = users_map.get(&troca.alvo.user_id).map_or("N/A", |u| u.name.as_str());
-        if troca.alvo.user_id == user_id && troca.status == crate::escala::StatusTroca::PendenteAlvo {
-            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">📥</span> <strong>{}</strong> quer trocar um serviço consigo.</p><p><i>Motivo: {}</i></p><div class="trade-actions"><form action="/escala/responder_troca" method="post" style="display: inline-block;"><input type="hidden" name="troca_id" value="{}"><input type="hidden" name="acao" value="aprovar"><button type="submit" class="btn btn-small-success">Aprovar</button></form><form action="/escala/responder_troca" method="post" style="display: inline-block;"><input type="hidden" name="troca_id" value="{}"><input type="hidden" name="acao" value="recusar"><button type="submit" class="btn btn-small-danger">Recusar</button></form></div></div>"#, requerente_nome, troca.motivo, troca.id, troca.id));
-        } else if troca.requerente.user_id == user_id {
+        let oferta = if troca.tipo == TipoTroca::Permuta { format!(" em troca de {}", servico_troca_str(&troca.requerente)) } else { String::new() };
+        let recebe = troca.recebe(user_id).map_or(String::new(), servico_troca_str);
+        if troca.tipo == TipoTroca::Cadeia && troca.status.pendente() {
+            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">🔗</span> Cadeia de {} trocas do quadro de ofertas: fica com {}.</p><p>Status: <span class="status-tag status-pending">Aguardando Escalante</span>{}</p></div>"#, troca.cadeia.len(), recebe, prazo(troca)));
+        } else if troca.alvo.user_id == user_id && troca.status == StatusTroca::PendenteAlvo {
+            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">📥</span> <strong>{}</strong> pede o seu serviço {}{}.</p><p><i>Motivo: {}</i>{}</p><div class="trade-actions"><form action="/escala/responder_troca" method="post" style="display: inline-block;"><input type="hidden" name="troca_id" value="{}"><input type="hidden" name="acao" value="aprovar"><button type="submit" class="btn btn-small-success">Aprovar</button></form><form action="/escala/responder_troca" method="post" style="display: inline-block;"><input type="hidden" name="troca_id" value="{}"><input type="hidden" name="acao" value="recusar"><button type="submit" class="btn btn-small-danger">Recusar</button></form></div></div>"#, requerente_nome, servico_troca_str(&troca.alvo), oferta, troca.motivo, prazo(troca), troca.id, troca.id));
+        } else if troca.requerente.user_id == user_id && troca.status.pendente() {
+            let status_text = if troca.status == StatusTroca::PendenteAlvo { format!("Aguardando {}", alvo_nome) } else { "Aguardando Escalante".to_string() };
+            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">📤</span> Pedido enviado para <strong>{}</strong> ({})</p><p>Status: <span class="status-tag status-pending">{}</span>{}</p><div class="trade-actions"><form action="/escala/cancelar_troca" method="post" style="display: inline-block;" onsubmit="return confirm('Cancelar este pedido de troca?');"><input type="hidden" name="troca_id" value="{}"><button type="submit" class="btn btn-small-danger">Cancelar</button></form></div></div>"#, alvo_nome, servico_troca_str(&troca.alvo), status_text, prazo(troca), troca.id));
+        } else if troca.decidida_em.is_some_and(|d| d >= recentes) && troca.status != StatusTroca::Cancelada {
             let (status_class, status_text) = match troca.status {
-                crate::escala::StatusTroca::PendenteAlvo => ("status-pending", format!("Aguardando {}", alvo_nome)),
-                crate::escala::StatusTroca::PendenteAdmin => ("status-pending", "Aguardando Escalante".to_string()),
-                crate::escala::StatusTroca::Aprovada => ("status-approved", "Aprovada".to_string()),
-                crate::escala::StatusTroca::Recusada => ("status-rejected", "Recusada".to_string()),
+                StatusTroca::Aprovada => ("status-approved", "Aprovada"),
+                StatusTroca::Expirada => ("status-rejected", "Expirada"),
+                _ => ("status-rejected", "Recusada"),
             };
-            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">📤</span> Pedido enviado para <strong>{}</strong></p><p>Status: <span class="status-tag {}">{}</span></p></div>"#, alvo_nome, status_class, status_text));
+            let outro = match troca.tipo {
+                TipoTroca::Cadeia => "uma cadeia do quadro de ofertas",
+                _ if troca.requerente.user_id == user_id => alvo_nome,
+                _ => requerente_nome,
+            };
+            trades_html.push_str(&format!(r#"<div class="trade-item"><p class="trade-details"><span class="icon">🔔</span> Troca com <strong>{}</strong> ({})</p><p>Status: <span class="status-tag {}">{}</span></p></div>"#, outro, servico_troca_str(&troca.alvo), status_class, status_text));
         }
     }
     
     if trades_html.is_empty() { trades_html = "<p>Você não tem pedidos de troca pendentes.</p>".to_string(); }
-    format!(r#"<div>{trades_html}</div>"#)
+    format!(r#"<div>{trades_html}<p><a href="/escala/trocas">Histórico das minhas trocas</a> · <a href="/escala/ofertas">Quadro de ofertas</a></p></div>"#)
 }
 
 pub async fn render_dashboard_page(
     state: &AppState,
-    cookies: &Cookies,
+    user: &AuthUser,
     is_admin: bool,
     message: Option<DashboardMessage>,
 ) -> impl IntoResponse {
-    let user_id = cookies.get("user_id").unwrap().value().to_string();
+    let user_id = user.user_id.clone();
     let (user_name, user_roles_str, users_map) = {
         let users = state.users.lock().unwrap();
         let user = users.get(&user_id);
@@ -357,20 +427,22 @@ pub async fn render_dashboard_page(
         (name, roles, users.clone())
     };
 
-    let form_state = crate::meals::load_form_state().await.ok();
+    let form_state = crate::meals::load_form_state(state.meal_store.as_ref()).await.ok();
     let meal_status_closed = form_state.as_ref().map(|f| matches!(f.status, crate::meals::FormStatus::Closed)).unwrap_or(true);
-    let escala_estado = tokio::fs::read_to_string("data/escala/estado.json").await.ok().and_then(|c| serde_json::from_str::<crate::escala::EstadoEscala>(&c).ok());
+    let escala_estado = state.escala_store.estado().await.ok();
     let escala_period = escala_estado.as_ref().map(|e| (e.periodo_atual.start_date, e.periodo_atual.end_date));
 
-    let (schedule_card, meals_card, trades_content, cautela_card) = tokio::join!(
-        render_schedule_card(&user_id, escala_period),
-        render_meals_card(&user_id),
-        render_trades_content(&user_id, &users_map),
-        render_cautela_card(&user_id)
+    let cautela_db = cautela::caminho_db(&state.config);
+    let (schedule_card, punishments_card, meals_card, trades_content, cautela_card) = tokio::join!(
+        render_schedule_card(state.escala_store.as_ref(), &user_id, escala_period),
+        render_punishments_card(state.escala_store.as_ref(), &user_id),
+        render_meals_card(state.meal_store.as_ref(), &user_id),
+        render_trades_content(state.escala_store.as_ref(), &user_id, &users_map, is_admin || user.has_role("escalante")),
+        render_cautela_card(&cautela_db, &user_id)
     );
 
     let mut buttons_html = String::new();
-    if auth::has_role(state, cookies, "admin").await || auth::has_role(state, cookies, "polícia").await || auth::has_role(state, cookies, "chefe de dia").await {
+    if user.has_role("admin") || user.has_role("polícia") || user.has_role("chefe de dia") {
         buttons_html.push_str(r#"<a href="/presence" class="btn btn-primary">📋 Controle de Presença</a>"#);
     }
     if meal_status_closed {
@@ -378,16 +450,19 @@ pub async fn render_dashboard_page(
     } else {
         buttons_html.push_str(r#"<a href="/refeicoes" class="btn btn-primary">🍳 Municiamento</a>"#);
     }
-    if auth::has_role(state, cookies, "rancheiro").await {
+    if user.has_role("rancheiro") {
         buttons_html.push_str(r#"<a href="/admin/refeicoes" class="btn btn-primary">🔧 Admin Refeições</a>"#);
     }
-    if auth::has_role(state, cookies, "rancheiro").await || auth::has_role(state, cookies, "conferência").await {
+    if user.has_role("rancheiro") || user.has_role("conferência") {
         buttons_html.push_str(r#"<a href="/refeicoes/checkin" class="btn btn-primary">✅ Conferir Refeições</a>"#);
     }
-    if is_admin || auth::has_role(state, cookies, "escalante").await {
+    if is_admin || user.has_role("escalante") {
         buttons_html.push_str(r#"<a href="/admin" class="btn btn-accent">🔑 Admin Utilizadores</a>"#);
         buttons_html.push_str(r#"<a href="/admin/escala" class="btn btn-accent">🔧 Gerir Escalas</a>"#);
     }
+    if user.has_role("cautela") {
+        buttons_html.push_str(r#"<a href="/cautela" class="btn btn-primary">📚 Cautela</a>"#);
+    }
     buttons_html.push_str(r#"<a href="/escala" class="btn btn-primary">📅 Consultar Escala</a>"#);
 
     let (message_content_html, message_meta_html, raw_content_for_editor) = if let Some(msg) = message {
@@ -432,7 +507,7 @@ pub async fn render_dashboard_page(
     let content = format!(r#"
         <header class="header">
             <div><h2>Bem-vindo(a), {user_name}!</h2><p style="color: var(--text-light); margin: 0;">Painel do Aluno</p></div>
-            <a href="/logout" class="btn">Sair</a>
+            <div><a href="/conta/senha" class="btn">🔒 Alterar senha</a> <a href="/logout" class="btn">Sair</a></div>
         </header>
         <div class="dashboard-grid">
             <div class="main-column">
@@ -452,7 +527,7 @@ pub async fn render_dashboard_page(
                     </div>
                 </div>
             </div>
-            <div class="sidebar-column">{schedule_card}{meals_card}{cautela_card}</div>
+            <div class="sidebar-column">{schedule_card}{punishments_card}{meals_card}{cautela_card}</div>
         </div>
         <script>
             const is_admin = {is_admin};
@@ -481,7 +556,7 @@ pub async fn render_dashboard_page(
     "#, 
         user_name=user_name, user_id = user_id, user_roles_str = user_roles_str,
         is_admin = is_admin, message_html = message_html, trades_content = trades_content,
-        buttons_html = buttons_html, schedule_card = schedule_card, meals_card = meals_card,
+        buttons_html = buttons_html, schedule_card = schedule_card, punishments_card = punishments_card, meals_card = meals_card,
         cautela_card = cautela_card,
         raw_content_for_editor = raw_content_for_editor.replace('`', r#"\`"#).replace('\n', "")
     );
@@ -0,0 +1,1248 @@
+// src/views/escala.rs
+
+use crate::auth::User;
+use crate::escala_analise::{AnaliseEscala, CargaGrupo};
+use crate::escala_calendario::Calendario;
+use crate::escala_dividas::{DestinoDividas, MovimentoDivida, RegrasDividas};
+use crate::escala_punicoes;
+use crate::escala_validacao::{Gravidade, RelatorioValidacao};
+use crate::escala::{
+    CategoriaIndisponibilidade, Contagem, ContagemUtilizador, DividasAtivas, EscalaDiaria, EstadoExecucao, ExecucaoEscala, Genero, MotorGeracao, Posto, PropostaEscala,
+    PropostaPendente, Punicao, ServicosNoPeriodo, TipoServico,
+};
+use axum::response::Html;
+use chrono::{Datelike, NaiveDate};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+const ESTILO: &str = r#"
+    body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", Roboto, "Helvetica Neue", Arial, sans-serif; max-width: 1100px; margin: 40px auto; padding: 20px; background-color: #f4f7f9; color: #333; }
+    .card { background: white; border: 1px solid #e0e0e0; padding: 25px; border-radius: 8px; margin-bottom: 25px; box-shadow: 0 4px 6px rgba(0,0,0,0.05); }
+    h1, h2 { color: #0056b3; }
+    .btn { display: inline-block; padding: 10px 15px; border-radius: 6px; text-decoration: none; color: white; border: none; cursor: pointer; font-size: 14px; margin-right: 10px; }
+    .btn-primary { background-color: #007bff; } .btn-danger { background-color: #dc3545; } .btn-success { background-color: #28a745; }
+    table { width: 100%; border-collapse: collapse; margin-bottom: 10px; } th, td { border: 1px solid #ddd; padding: 6px 8px; text-align: left; font-size: 14px; } th { background-color: #f2f2f2; }
+    .dias { display: grid; grid-template-columns: repeat(auto-fill, minmax(320px, 1fr)); gap: 15px; }
+    .acoes { display: flex; gap: 10px; }
+    .acoes form { margin: 0; }
+    .mudou { font-weight: bold; }
+"#;
+
+fn dia_da_semana(data: chrono::NaiveDate) -> &'static str {
+    ["Seg", "Ter", "Qua", "Qui", "Sex", "Sáb", "Dom"][data.weekday().num_days_from_monday() as usize]
+}
+
+fn tipo_str(tipo: &TipoServico) -> &'static str {
+    match tipo {
+        TipoServico::RN => "RN",
+        TipoServico::RD => "RD",
+        TipoServico::UDRD => "UDRD",
+        TipoServico::ER => "ER",
+        TipoServico::Retem => "Retém",
+    }
+}
+
+fn motor_str(motor: MotorGeracao) -> &'static str {
+    match motor {
+        MotorGeracao::Guloso => "guloso",
+        MotorGeracao::Otimizado => "otimizado",
+    }
+}
+
+/// Campos comuns aos formulários de indisponibilidade do admin e dos utilizadores.
+/// Lidos por `escala::indisponibilidade_do_formulario`.
+pub fn campos_indisponibilidade() -> String {
+    let categorias: String = CategoriaIndisponibilidade::TODAS
+        .iter()
+        .map(|c| format!("<option value='{:?}'>{}</option>", c, c.nome()))
+        .collect();
+    let dias: String = ["Seg", "Ter", "Qua", "Qui", "Sex", "Sáb", "Dom"]
+        .iter()
+        .enumerate()
+        .map(|(n, dia)| format!("<label><input type='checkbox' name='dia_semana_{}'> {}</label> ", n, dia))
+        .collect();
+    format!(
+        r#"<p><label>Categoria:</label> <select name="categoria">{categorias}</select></p>
+        <p><label>De:</label> <input type="date" name="data" required> <label>até:</label> <input type="date" name="data_fim"></p>
+        <p><label>Só nestes dias da semana:</label> {dias}</p>
+        <p><small>Sem data de fim, é só um dia; se escolher dias da semana, repete-se sem fim.</small></p>
+        <p><label>Motivo:</label> <input type="text" name="motivo" required></p>"#
+    )
+}
+
+/// "antes → depois", destacado quando o valor muda.
+fn transicao(antes: u32, depois: u32) -> String {
+    if antes == depois {
+        antes.to_string()
+    } else {
+        format!(r#"<span class="mudou">{} → {}</span>"#, antes, depois)
+    }
+}
+
+/// Pré-visualização de uma escala gerada: os dias, a distribuição dos serviços por ano
+/// e por pessoa, e os botões para gravar ou descartar.
+pub fn proposta_page(pendente: &PropostaPendente, users: &HashMap<String, User>) -> Html<String> {
+    let proposta = &pendente.proposta;
+    let servicos = proposta.servicos_por_utilizador();
+
+    // --- Os dias ---
+    let mut dias_html = String::new();
+    for (data, escala_diaria) in &proposta.dias {
+        let mut linhas: Vec<(&str, &str, &str)> = escala_diaria
+            .escala
+            .iter()
+            .flat_map(|(posto, horarios)| horarios.iter().map(move |(h, a)| (posto.as_str(), h.as_str(), a.nome.as_str())))
+            .collect();
+        linhas.sort();
+        let linhas_html: String = linhas
+            .iter()
+            .map(|(posto, horario, nome)| format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>", posto, horario, nome))
+            .collect();
+        let retem: Vec<&str> = escala_diaria.retem.iter().map(|a| a.nome.as_str()).collect();
+        dias_html.push_str(&format!(
+            r#"<div><h3>{dia} {data} — {tipo}</h3>
+                <table><thead><tr><th>Posto</th><th>Horário</th><th>Nome</th></tr></thead><tbody>{linhas}</tbody></table>
+                <p><strong>Retém:</strong> {retem}</p></div>"#,
+            dia = dia_da_semana(*data),
+            data = data.format("%d/%m"),
+            tipo = tipo_str(&escala_diaria.tipo_dia),
+            linhas = linhas_html,
+            retem = if retem.is_empty() { "—".to_string() } else { retem.join(", ") },
+        ));
+    }
+
+    // --- Distribuição por ano: todos os alunos ativos contam, mesmo sem serviços ---
+    let mut por_ano: BTreeMap<u8, Vec<u32>> = BTreeMap::new();
+    for user in users.values().filter(|u| u.ativo && u.ano > 0) {
+        por_ano.entry(user.ano).or_default().push(servicos.get(&user.id).map_or(0, |s| s.total()));
+    }
+    let anos_html: String = por_ano
+        .iter()
+        .map(|(ano, totais)| {
+            let soma: u32 = totais.iter().sum();
+            let sem_servico = totais.iter().filter(|t| **t == 0).count();
+            format!(
+                "<tr><td>{}º ano</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{:.1}</td><td>{}</td></tr>",
+                ano,
+                totais.len(),
+                soma,
+                totais.iter().min().copied().unwrap_or(0),
+                totais.iter().max().copied().unwrap_or(0),
+                soma as f64 / totais.len() as f64,
+                sem_servico,
+            )
+        })
+        .collect();
+
+    // --- Por pessoa, de quem faz mais para quem faz menos ---
+    let mut pessoas: Vec<(&str, ServicosNoPeriodo)> = servicos.iter().map(|(id, s)| (id.as_str(), *s)).collect();
+    pessoas.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then_with(|| a.0.cmp(b.0)));
+    let vazio = ContagemUtilizador::default();
+    let pessoas_html: String = pessoas
+        .iter()
+        .map(|(id, s)| {
+            let user = users.get(*id);
+            let antes = proposta.contagem_antes.get(*id).unwrap_or(&vazio);
+            let depois = proposta.contagem.get(*id).unwrap_or(&vazio);
+            format!(
+                "<tr><td>{id}</td><td>{nome}</td><td>{ano}</td><td>{normais}</td><td>{retem}</td><td>{punicao}</td><td><strong>{total}</strong></td><td>{rn}</td><td>{rd}</td><td>{c_retem}</td></tr>",
+                id = id,
+                nome = user.map_or("Desconhecido", |u| u.name.as_str()),
+                ano = user.map_or(String::from("—"), |u| format!("{}º", u.ano)),
+                normais = s.normais,
+                retem = s.retem,
+                punicao = s.punicao,
+                total = s.total(),
+                rn = transicao(antes.rn, depois.rn),
+                rd = transicao(antes.rd, depois.rd),
+                c_retem = transicao(antes.retem, depois.retem),
+            )
+        })
+        .collect();
+
+    let cumpridos_antes: u32 = proposta.punicoes_antes.iter().map(|p| p.ja_cumpridos).sum();
+    let cumpridos_depois: u32 = proposta.punicoes.iter().map(|p| p.ja_cumpridos).sum();
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Proposta de Escala</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Proposta de Escala</h1>
+            <div class="card" style="border-left: 4px solid #ffc107;">
+                <p>Período de <strong>{inicio}</strong> a <strong>{fim}</strong>, gerado por {autor} com o motor {motor}. <strong>Ainda nada foi gravado.</strong></p>
+                <p>Ao gravar, as escalas destes dias, as contagens, as dívidas e as punições são guardadas de uma só vez,
+                e as trocas por decidir que envolvem estes dias expiram.</p>
+                <p>{dividas_pagas} dívida(s) de serviço paga(s); {punicoes_cumpridas} serviço(s) de punição cumprido(s).</p>
+                <div class="acoes">
+                    <form action="/admin/escala/proposta/gravar" method="post"><input type="hidden" name="id" value="{id}"><button type="submit" class="btn btn-success">Gravar esta escala</button></form>
+                    <form action="/admin/escala/proposta/descartar" method="post"><input type="hidden" name="id" value="{id}"><button type="submit" class="btn btn-danger">Descartar</button></form>
+                </div>
+            </div>
+            <div class="card">
+                <h2>Distribuição por Ano</h2>
+                <table><thead><tr><th>Ano</th><th>Alunos</th><th>Serviços</th><th>Mínimo</th><th>Máximo</th><th>Média</th><th>Sem serviço</th></tr></thead><tbody>{anos}</tbody></table>
+            </div>
+            <div class="card">
+                <h2>Serviços por Pessoa</h2>
+                <p>As colunas RN, RD e Retém mostram a contagem acumulada antes → depois deste período.</p>
+                <table><thead><tr><th>ID</th><th>Nome</th><th>Ano</th><th>Normais</th><th>Retém</th><th>Punição</th><th>Total</th><th>RN</th><th>RD</th><th>Retém</th></tr></thead><tbody>{pessoas}</tbody></table>
+            </div>
+            <div class="card"><h2>Dias</h2><div class="dias">{dias}</div></div>
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        inicio = proposta.periodo.start_date.format("%d/%m/%Y"),
+        fim = proposta.periodo.end_date.format("%d/%m/%Y"),
+        autor = pendente.gerada_por,
+        motor = motor_str(proposta.motor),
+        dividas_pagas = proposta.dividas_pagas(),
+        punicoes_cumpridas = cumpridos_depois.saturating_sub(cumpridos_antes),
+        id = pendente.id,
+        anos = anos_html,
+        pessoas = pessoas_html,
+        dias = dias_html,
+    ))
+}
+
+fn estado_execucao(execucao: &ExecucaoEscala) -> String {
+    match execucao.estado {
+        EstadoExecucao::Gravada => "Por lançar".to_string(),
+        EstadoExecucao::Lancada => match execucao.lancada_em {
+            Some(quando) => format!("Lançada em {}", quando.format("%d/%m/%Y %H:%M")),
+            None => "Lançada".to_string(),
+        },
+        EstadoExecucao::Revertida => match execucao.revertida_em {
+            Some(quando) => format!("Revertida em {}", quando.format("%d/%m/%Y %H:%M")),
+            None => "Revertida".to_string(),
+        },
+    }
+}
+
+fn periodo_str(proposta: &PropostaEscala) -> String {
+    format!("{} a {}", proposta.periodo.start_date.format("%d/%m/%Y"), proposta.periodo.end_date.format("%d/%m/%Y"))
+}
+
+/// Lista das gerações gravadas, da mais recente para a mais antiga.
+pub fn execucoes_page(execucoes: &[ExecucaoEscala]) -> Html<String> {
+    // Só a última geração que não foi revertida pode ser desfeita, e só se ainda não foi lançada
+    let reversivel = execucoes
+        .iter()
+        .filter(|e| e.estado != EstadoExecucao::Revertida)
+        .max_by_key(|e| e.versao)
+        .filter(|e| e.estado == EstadoExecucao::Gravada)
+        .map(|e| e.versao);
+
+    let linhas: String = execucoes
+        .iter()
+        .rev()
+        .map(|e| {
+            let acao = if Some(e.versao) == reversivel {
+                format!(
+                    r#"<form action="/admin/escala/execucoes/reverter" method="post" onsubmit="return confirm('Reverter a versão {v}? As escalas destes dias, as contagens, as dívidas e as punições voltam ao que eram antes dela.');"><input type="hidden" name="versao" value="{v}"><button type="submit" class="btn btn-danger">Reverter</button></form>"#,
+                    v = e.versao
+                )
+            } else {
+                String::new()
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                e.versao,
+                periodo_str(&e.proposta),
+                e.proposta.dias.len(),
+                e.gerada_por,
+                e.gravada_em.format("%d/%m/%Y %H:%M"),
+                estado_execucao(e),
+                acao,
+            )
+        })
+        .collect();
+
+    let opcoes = |selecionada: Option<u32>| -> String {
+        execucoes
+            .iter()
+            .rev()
+            .map(|e| {
+                format!(
+                    r#"<option value="{v}"{sel}>Versão {v} ({periodo})</option>"#,
+                    v = e.versao,
+                    sel = if Some(e.versao) == selecionada { " selected" } else { "" },
+                    periodo = periodo_str(&e.proposta),
+                )
+            })
+            .collect()
+    };
+    let ultima = execucoes.last().map(|e| e.versao);
+    let penultima = execucoes.len().checked_sub(2).map(|i| execucoes[i].versao);
+
+    let conteudo = if execucoes.is_empty() {
+        "<div class=\"card\"><p>Ainda não há gerações gravadas.</p></div>".to_string()
+    } else {
+        format!(
+            r#"<div class="card">
+                <table><thead><tr><th>Versão</th><th>Período</th><th>Dias</th><th>Gerada por</th><th>Gravada em</th><th>Estado</th><th></th></tr></thead><tbody>{linhas}</tbody></table>
+            </div>
+            <div class="card">
+                <h2>Comparar Versões</h2>
+                <form action="/admin/escala/execucoes/comparar" method="get" class="acoes">
+                    <select name="a">{opcoes_a}</select>
+                    <select name="b">{opcoes_b}</select>
+                    <button type="submit" class="btn btn-primary">Comparar</button>
+                </form>
+            </div>"#,
+            linhas = linhas,
+            opcoes_a = opcoes(penultima.or(ultima)),
+            opcoes_b = opcoes(ultima),
+        )
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Histórico das Gerações</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Histórico das Gerações</h1>
+            <p>Cada escala gravada guarda os dias e as regras usados, as contagens antes e depois e o resultado.
+            Uma geração ainda por lançar pode ser revertida, deixando as contagens, as dívidas e as punições exatamente como estavam.</p>
+            {conteudo}
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        conteudo = conteudo,
+    ))
+}
+
+/// Quem está em cada posto e horário de um dia, incluindo o retém.
+fn vagas(dia: Option<&EscalaDiaria>) -> BTreeMap<(String, String), String> {
+    let Some(dia) = dia else { return BTreeMap::new() };
+    let mut vagas: BTreeMap<(String, String), String> = dia
+        .escala
+        .iter()
+        .flat_map(|(posto, horarios)| horarios.iter().map(move |(h, a)| ((posto.clone(), h.clone()), a.nome.clone())))
+        .collect();
+    let mut retem: Vec<&str> = dia.retem.iter().map(|a| a.nome.as_str()).collect();
+    retem.sort();
+    if !retem.is_empty() {
+        vagas.insert(("Retém".to_string(), String::new()), retem.join(", "));
+    }
+    vagas
+}
+
+fn contagem_str(c: &ContagemUtilizador) -> String {
+    format!("RN {} · RD {} · Retém {}", c.rn, c.rd, c.retem)
+}
+
+/// O que mudou entre duas versões: as entradas, as alocações dia a dia e as contagens.
+pub fn comparar_execucoes_page(a: &ExecucaoEscala, b: &ExecucaoEscala, users: &HashMap<String, User>) -> Html<String> {
+    let (pa, pb) = (&a.proposta, &b.proposta);
+    let nada = "<p>Sem diferenças.</p>".to_string();
+    let talvez = |s: Option<&String>| s.map_or("—".to_string(), |s| s.to_string());
+
+    // --- Entradas ---
+    let mut entradas = Vec::new();
+    let datas: BTreeSet<_> = pa.tipos_dia.keys().chain(pb.tipos_dia.keys()).collect();
+    for data in datas {
+        let (ta, tb) = (pa.tipos_dia.get(data), pb.tipos_dia.get(data));
+        if ta != tb {
+            entradas.push((
+                format!("{} {}", dia_da_semana(*data), data.format("%d/%m/%Y")),
+                ta.map_or("—", tipo_str).to_string(),
+                tb.map_or("—", tipo_str).to_string(),
+            ));
+        }
+    }
+    let nomes_postos: BTreeSet<&String> = pa.postos.iter().chain(pb.postos.iter()).map(|p| &p.nome).collect();
+    for nome in nomes_postos {
+        let (xa, xb) = (pa.postos.iter().find(|p| &p.nome == nome), pb.postos.iter().find(|p| &p.nome == nome));
+        if xa != xb {
+            let descrever = |p: Option<&Posto>| match p {
+                Some(p) => format!("RN {} · RD {} · UDRD {} · ER {}", p.horarios_rn.join(", "), p.horarios_rd.join(", "), p.horarios_udrd.join(", "), p.horarios_er.join(", ")),
+                None => "—".to_string(),
+            };
+            entradas.push((format!("Posto {}", nome), descrever(xa), descrever(xb)));
+        }
+    }
+    if pa.configuracao.postos_punicao != pb.configuracao.postos_punicao {
+        entradas.push((
+            "Postos de punição".to_string(),
+            pa.configuracao.postos_punicao.join(", "),
+            pb.configuracao.postos_punicao.join(", "),
+        ));
+    }
+    if pa.configuracao.quotas_retem != pb.configuracao.quotas_retem {
+        let descrever = |p: &PropostaEscala| -> String {
+            p.configuracao.quotas_retem.iter().map(|q| q.descrever()).collect::<Vec<_>>().join(", ")
+        };
+        entradas.push(("Quotas do retém".to_string(), descrever(pa), descrever(pb)));
+    }
+    let indisponiveis = |p: &PropostaEscala| -> BTreeSet<String> {
+        p.indisponibilidades.iter().map(|i| format!("{} ({})", i.user_id, i.descrever_datas())).collect()
+    };
+    let (ia, ib) = (indisponiveis(pa), indisponiveis(pb));
+    if ia != ib {
+        entradas.push((
+            "Indisponibilidades".to_string(),
+            ia.difference(&ib).cloned().collect::<Vec<_>>().join(", "),
+            ib.difference(&ia).cloned().collect::<Vec<_>>().join(", "),
+        ));
+    }
+    let entradas_html = if entradas.is_empty() {
+        nada.clone()
+    } else {
+        let linhas: String =
+            entradas.iter().map(|(o, x, y)| format!("<tr><td>{}</td><td>{}</td><td>{}</td></tr>", o, x, y)).collect();
+        format!("<table><thead><tr><th></th><th>Versão {}</th><th>Versão {}</th></tr></thead><tbody>{}</tbody></table>", a.versao, b.versao, linhas)
+    };
+
+    // --- Alocações ---
+    let mut alocacoes = String::new();
+    let datas: BTreeSet<_> = pa.dias.keys().chain(pb.dias.keys()).collect();
+    for data in datas {
+        let (va, vb) = (vagas(pa.dias.get(data)), vagas(pb.dias.get(data)));
+        let chaves: BTreeSet<_> = va.keys().chain(vb.keys()).collect();
+        for chave in chaves {
+            let (na, nb) = (va.get(chave), vb.get(chave));
+            if na != nb {
+                alocacoes.push_str(&format!(
+                    "<tr><td>{} {}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    dia_da_semana(*data),
+                    data.format("%d/%m/%Y"),
+                    chave.0,
+                    chave.1,
+                    talvez(na),
+                    talvez(nb),
+                ));
+            }
+        }
+    }
+    let alocacoes_html = if alocacoes.is_empty() {
+        nada.clone()
+    } else {
+        format!(
+            "<table><thead><tr><th>Dia</th><th>Posto</th><th>Horário</th><th>Versão {}</th><th>Versão {}</th></tr></thead><tbody>{}</tbody></table>",
+            a.versao, b.versao, alocacoes
+        )
+    };
+
+    // --- Contagens ---
+    let ids: BTreeSet<&String> = [&pa.contagem_antes, &pa.contagem, &pb.contagem_antes, &pb.contagem]
+        .iter()
+        .flat_map(|c| c.keys())
+        .collect();
+    let mut contagens = String::new();
+    for id in ids {
+        let valor = |c: &Contagem| c.get(id).cloned().unwrap_or_default();
+        let (aa, ad, ba, bd) = (valor(&pa.contagem_antes), valor(&pa.contagem), valor(&pb.contagem_antes), valor(&pb.contagem));
+        if aa == ba && ad == bd {
+            continue;
+        }
+        let transicoes = |antes: &ContagemUtilizador, depois: &ContagemUtilizador| {
+            if antes == depois { contagem_str(antes) } else { format!("{} → {}", contagem_str(antes), contagem_str(depois)) }
+        };
+        contagens.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            id,
+            users.get(id).map_or("Desconhecido", |u| u.name.as_str()),
+            transicoes(&aa, &ad),
+            transicoes(&ba, &bd),
+        ));
+    }
+    let contagens_html = if contagens.is_empty() {
+        nada.clone()
+    } else {
+        format!(
+            "<table><thead><tr><th>ID</th><th>Nome</th><th>Versão {} (antes → depois)</th><th>Versão {} (antes → depois)</th></tr></thead><tbody>{}</tbody></table>",
+            a.versao, b.versao, contagens
+        )
+    };
+
+    let resumo = |p: &PropostaEscala| {
+        let por_cumprir = |ps: &[Punicao]| ps.iter().map(|p| p.total_a_cumprir.saturating_sub(p.ja_cumpridos)).sum::<u32>();
+        let dividas = |d: &DividasAtivas| d.values().map(Vec::len).sum::<usize>();
+        format!(
+            "Dívidas: {} → {} · Serviços de punição por cumprir: {} → {}",
+            dividas(&p.dividas_antes),
+            dividas(&p.dividas),
+            por_cumprir(&p.punicoes_antes),
+            por_cumprir(&p.punicoes),
+        )
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Comparar Gerações</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Versão {va} × Versão {vb}</h1>
+            <div class="card">
+                <table><thead><tr><th></th><th>Versão {va}</th><th>Versão {vb}</th></tr></thead><tbody>
+                    <tr><td>Período</td><td>{periodo_a}</td><td>{periodo_b}</td></tr>
+                    <tr><td>Gerada por</td><td>{autor_a}</td><td>{autor_b}</td></tr>
+                    <tr><td>Motor</td><td>{motor_a}</td><td>{motor_b}</td></tr>
+                    <tr><td>Gravada em</td><td>{gravada_a}</td><td>{gravada_b}</td></tr>
+                    <tr><td>Estado</td><td>{estado_a}</td><td>{estado_b}</td></tr>
+                    <tr><td>Dívidas e punições</td><td>{resumo_a}</td><td>{resumo_b}</td></tr>
+                </tbody></table>
+            </div>
+            <div class="card"><h2>Entradas</h2>{entradas}</div>
+            <div class="card"><h2>Alocações</h2>{alocacoes}</div>
+            <div class="card"><h2>Contagens</h2>{contagens}</div>
+            <a href="/admin/escala/execucoes">← Voltar ao Histórico</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        va = a.versao,
+        vb = b.versao,
+        periodo_a = periodo_str(pa),
+        periodo_b = periodo_str(pb),
+        autor_a = a.gerada_por,
+        autor_b = b.gerada_por,
+        motor_a = motor_str(pa.motor),
+        motor_b = motor_str(pb.motor),
+        gravada_a = a.gravada_em.format("%d/%m/%Y %H:%M"),
+        gravada_b = b.gravada_em.format("%d/%m/%Y %H:%M"),
+        estado_a = estado_execucao(a),
+        estado_b = estado_execucao(b),
+        resumo_a = resumo(pa),
+        resumo_b = resumo(pb),
+        entradas = entradas_html,
+        alocacoes = alocacoes_html,
+        contagens = contagens_html,
+    ))
+}
+
+fn genero_posto_str(genero: &Genero) -> &'static str {
+    match genero {
+        Genero::Masculino => "M",
+        Genero::Feminino => "F",
+        Genero::Misto => "X",
+    }
+}
+
+/// Lista dos postos, pela ordem em que são preenchidos, com os avisos dos que não se
+/// conseguem preencher e os botões para reordenar, editar e retirar.
+pub fn postos_page(postos: &[Posto], users: &[&User]) -> Html<String> {
+    let mut linhas = String::new();
+    for (i, posto) in postos.iter().enumerate() {
+        let horarios = |tipo: TipoServico| -> String {
+            let horarios = posto.horarios(&tipo);
+            if horarios.is_empty() { "—".to_string() } else { horarios.join("<br>") }
+        };
+        let avisos: String = if posto.ativo {
+            posto.avisos(users).iter().map(|a| format!("<div class=\"aviso\">⚠️ {}</div>", a)).collect()
+        } else {
+            String::new()
+        };
+        let (acao_estado, texto_estado) = if posto.ativo { ("retirar", "Retirar") } else { ("reativar", "Reativar") };
+        linhas.push_str(&format!(
+            r#"<tr class="{classe}">
+                <td>{nome}{avisos}</td><td>{seccao}</td><td>{turmas}</td><td>{genero}</td><td>{funcao}</td>
+                <td>{rn}</td><td>{rd}</td><td>{udrd}</td><td>{er}</td>
+                <td class="acoes">
+                    <form action="/admin/escala/postos/mover" method="post"><input type="hidden" name="nome" value="{nome}"><input type="hidden" name="direcao" value="cima"><button type="submit" class="btn btn-primary"{primeiro}>↑</button></form>
+                    <form action="/admin/escala/postos/mover" method="post"><input type="hidden" name="nome" value="{nome}"><input type="hidden" name="direcao" value="baixo"><button type="submit" class="btn btn-primary"{ultimo}>↓</button></form>
+                    <a class="btn btn-primary" href="/admin/escala/postos/editar?nome={nome_url}">Editar</a>
+                    <form action="/admin/escala/postos/{acao_estado}" method="post"><input type="hidden" name="nome" value="{nome}"><button type="submit" class="btn btn-danger">{texto_estado}</button></form>
+                </td>
+            </tr>"#,
+            classe = if posto.ativo { "" } else { "retirado" },
+            nome = posto.nome,
+            nome_url = urlencoding::encode(&posto.nome),
+            avisos = avisos,
+            seccao = posto.seccao(),
+            turmas = posto.turmas_permitidas.iter().map(|a| format!("{}º", a)).collect::<Vec<_>>().join(", "),
+            genero = genero_posto_str(&posto.genero),
+            funcao = posto.funcao_exclusiva.as_deref().unwrap_or("—"),
+            rn = horarios(TipoServico::RN),
+            rd = horarios(TipoServico::RD),
+            udrd = horarios(TipoServico::UDRD),
+            er = horarios(TipoServico::ER),
+            primeiro = if i == 0 { " disabled" } else { "" },
+            ultimo = if i + 1 == postos.len() { " disabled" } else { "" },
+        ));
+    }
+    let conteudo = if postos.is_empty() {
+        "<p>Ainda não há postos configurados.</p>".to_string()
+    } else {
+        format!(
+            r#"<table><thead><tr><th>Posto</th><th>Secção do PDF</th><th>Anos</th><th>Género</th><th>Função</th><th>RN</th><th>RD</th><th>UDRD</th><th>ER</th><th></th></tr></thead><tbody>{}</tbody></table>"#,
+            linhas
+        )
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Postos</title><meta charset="UTF-8"><style>{estilo}
+            .aviso {{ color: #b35900; font-size: 13px; margin-top: 4px; }}
+            .retirado {{ color: #999; }}
+            .btn[disabled] {{ opacity: 0.4; cursor: default; }}
+        </style></head>
+        <body>
+            <h1>Postos de Serviço</h1>
+            <p>Os postos são preenchidos por esta ordem em cada dia. Um posto retirado fica guardado, mas deixa de entrar nas novas gerações.</p>
+            <div class="card">{conteudo}</div>
+            <a href="/admin/escala/postos/editar" class="btn btn-success">Novo Posto</a>
+            <p><a href="/admin/escala">← Voltar à Gestão de Escalas</a></p>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        conteudo = conteudo,
+    ))
+}
+
+/// Formulário de um posto novo (`None`) ou existente.
+pub fn editar_posto_page(posto: Option<&Posto>) -> Html<String> {
+    let genero = posto.map_or("X", |p| genero_posto_str(&p.genero));
+    let genero_opcao = |valor: &str, texto: &str| {
+        format!(r#"<option value="{v}"{s}>{t}</option>"#, v = valor, t = texto, s = if genero == valor { " selected" } else { "" })
+    };
+    let horarios = |tipo: TipoServico| posto.map(|p| p.horarios(&tipo).join("\n")).unwrap_or_default();
+    let titulo = posto.map_or("Novo Posto".to_string(), |p| format!("Editar Posto {}", p.nome));
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - {titulo}</title><meta charset="UTF-8"><style>{estilo}
+            .campos {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(220px, 1fr)); gap: 15px; margin-bottom: 15px; }}
+            .campos label {{ display: flex; flex-direction: column; gap: 4px; }}
+            textarea {{ min-height: 110px; font-family: monospace; }}
+        </style></head>
+        <body>
+            <h1>{titulo}</h1>
+            <div class="card">
+                <form action="/admin/escala/postos/editar" method="post">
+                    <input type="hidden" name="nome_original" value="{nome}">
+                    <div class="campos">
+                        <label>Nome<input type="text" name="nome" value="{nome}" required></label>
+                        <label>Anos (separados por vírgula)<input type="text" name="turmas" value="{turmas}" placeholder="1, 2" required></label>
+                        <label>Género<select name="genero">{misto}{masculino}{feminino}</select></label>
+                        <label>Função exclusiva<input type="text" name="funcao_exclusiva" value="{funcao}" placeholder="nenhuma"></label>
+                        <label>Secção no PDF<input type="text" name="seccao_pdf" value="{seccao}" placeholder="{seccao_padrao}"></label>
+                    </div>
+                    <p><small>Sem secção, o posto aparece na do ano mais antigo que o pode ocupar. As secções seguem a ordem dos postos.</small></p>
+                    <p>Um horário por linha, como <code>08:00-12:00</code> ou <code>0800-1200/1400-1800</code>. Um posto que dura o dia todo tem só o horário <code>DIARIO</code>.</p>
+                    <div class="campos">
+                        <label>RN<textarea name="horarios_rn">{rn}</textarea></label>
+                        <label>RD<textarea name="horarios_rd">{rd}</textarea></label>
+                        <label>UDRD<textarea name="horarios_udrd">{udrd}</textarea></label>
+                        <label>ER<textarea name="horarios_er">{er}</textarea></label>
+                    </div>
+                    <button type="submit" class="btn btn-primary">Guardar</button>
+                </form>
+            </div>
+            <a href="/admin/escala/postos">← Voltar aos Postos</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        titulo = titulo,
+        nome = posto.map(|p| p.nome.as_str()).unwrap_or_default(),
+        turmas = posto.map(|p| p.turmas_permitidas.iter().map(|a| a.to_string()).collect::<Vec<_>>().join(", ")).unwrap_or_default(),
+        funcao = posto.and_then(|p| p.funcao_exclusiva.as_deref()).unwrap_or_default(),
+        seccao = posto.and_then(|p| p.seccao_pdf.as_deref()).unwrap_or_default(),
+        seccao_padrao = posto.map(|p| Posto { seccao_pdf: None, ..p.clone() }.seccao()).unwrap_or_else(|| "automática".to_string()),
+        misto = genero_opcao("X", "Qualquer"),
+        masculino = genero_opcao("M", "Masculino"),
+        feminino = genero_opcao("F", "Feminino"),
+        rn = horarios(TipoServico::RN),
+        rd = horarios(TipoServico::RD),
+        udrd = horarios(TipoServico::UDRD),
+        er = horarios(TipoServico::ER),
+    ))
+}
+
+/// Uma barra horizontal proporcional a `valor / maximo`, com o número à frente.
+fn barra(valor: f64, maximo: f64, texto: &str, cor: &str) -> String {
+    let largura = if maximo > 0.0 { (valor / maximo * 100.0).clamp(0.0, 100.0) } else { 0.0 };
+    format!(
+        r#"<div class="barra"><div style="width: {:.1}%; background: {};"></div><span>{}</span></div>"#,
+        largura, cor, texto
+    )
+}
+
+/// A carga de serviço num intervalo: totais por ano e género, cada pessoa com o desvio
+/// em relação à média do seu ano, e os serviços por posto.
+pub fn analise_page(analise: &AnaliseEscala) -> Html<String> {
+    let periodo = format!("{} a {}", analise.inicio.format("%d/%m/%Y"), analise.fim.format("%d/%m/%Y"));
+    let csv = format!(
+        "/admin/escala/analise?inicio={}&fim={}&formato=csv",
+        analise.inicio.format("%Y-%m-%d"),
+        analise.fim.format("%Y-%m-%d")
+    );
+
+    let grupos = |titulo: &str, grupos: &[CargaGrupo]| -> String {
+        let maior = grupos.iter().map(|g| g.media).fold(0.0, f64::max);
+        let linhas: String = grupos
+            .iter()
+            .map(|g| {
+                format!(
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{} – {}</td><td>{}</td></tr>",
+                    g.nome,
+                    g.pessoas,
+                    g.total,
+                    g.fim_de_semana,
+                    g.especiais,
+                    g.punicao,
+                    g.minimo,
+                    g.maximo,
+                    barra(g.media, maior, &format!("{:.2}", g.media), "#007bff"),
+                )
+            })
+            .collect();
+        format!(
+            r#"<div class="card"><h2>{}</h2><table><thead><tr><th></th><th>Pessoas</th><th>Serviços</th><th>Fim de semana</th><th>Dias especiais</th><th>Punição</th><th>Mín. – máx.</th><th>Média por pessoa</th></tr></thead><tbody>{}</tbody></table></div>"#,
+            titulo, linhas
+        )
+    };
+
+    let maior_total = analise.utilizadores.iter().map(|c| c.total).max().unwrap_or(0) as f64;
+    let pessoas: String = analise
+        .utilizadores
+        .iter()
+        .map(|c| {
+            // Mais de um serviço acima ou abaixo da média do ano fica a vermelho ou a verde
+            let (cor, classe) = if c.desvio > 1.0 {
+                ("#dc3545", " class=\"mudou\"")
+            } else if c.desvio < -1.0 {
+                ("#28a745", " class=\"mudou\"")
+            } else {
+                ("#6c757d", "")
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}º</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td{}>{:+.2}</td></tr>",
+                c.user_id,
+                c.nome,
+                c.ano,
+                genero_posto_str(&c.genero),
+                barra(c.total as f64, maior_total, &c.total.to_string(), cor),
+                c.rn,
+                c.especiais,
+                c.retem,
+                c.fim_de_semana,
+                c.punicao,
+                c.dividas_pagas,
+                c.dividas_devidas,
+                c.dividas_a_receber,
+                classe,
+                c.desvio,
+            )
+        })
+        .collect();
+
+    let cabecalho_postos: String = analise.postos.iter().map(|p| format!("<th>{}</th>", p)).collect();
+    let por_posto: String = analise
+        .utilizadores
+        .iter()
+        .filter(|c| c.total > 0)
+        .map(|c| {
+            let celulas: String = analise
+                .postos
+                .iter()
+                .map(|p| format!("<td>{}</td>", c.por_posto.get(p).map_or(String::new(), |n| n.to_string())))
+                .collect();
+            format!("<tr><td>{}</td><td>{}</td>{}</tr>", c.user_id, c.nome, celulas)
+        })
+        .collect();
+    let por_posto_html = if por_posto.is_empty() {
+        "<p>Não há serviços no intervalo.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>ID</th><th>Nome</th>{}</tr></thead><tbody>{}</tbody></table>",
+            cabecalho_postos, por_posto
+        )
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Análise da Escala</title><meta charset="UTF-8"><style>{estilo}
+            .barra {{ position: relative; min-width: 120px; height: 18px; background: #eef1f4; border-radius: 3px; }}
+            .barra div {{ height: 100%; border-radius: 3px; }}
+            .barra span {{ position: absolute; left: 6px; top: 0; font-size: 12px; line-height: 18px; }}
+        </style></head>
+        <body>
+            <h1>Análise da Escala</h1>
+            <div class="card">
+                <form action="/admin/escala/analise" method="get" class="acoes">
+                    <label>De <input type="date" name="inicio" value="{inicio}"></label>
+                    <label>a <input type="date" name="fim" value="{fim}"></label>
+                    <button type="submit" class="btn btn-primary">Analisar</button>
+                    <a href="{csv}" class="btn btn-success">Exportar CSV</a>
+                </form>
+                <p>{periodo}: {dias} dias com escala gravada, {pessoas_n} pessoas, {media:.2} serviços por pessoa em média.</p>
+                <p>Os serviços em dias especiais são os de RD, UDRD e ER fora do retém. As dívidas pagas são os serviços marcados (PG) no intervalo;
+                as dívidas por pagar e a receber são as de hoje, seja qual for o intervalo.</p>
+            </div>
+            {por_ano}
+            {por_genero}
+            <div class="card"><h2>Por Pessoa</h2>
+                <table><thead><tr><th>ID</th><th>Nome</th><th>Ano</th><th>Género</th><th>Serviços</th><th>RN</th><th>Especiais</th><th>Retém</th><th>Fim de semana</th><th>Punição</th>
+                <th>Dívidas pagas</th><th>Deve</th><th>A receber</th><th>Desvio da média do ano</th></tr></thead><tbody>{pessoas}</tbody></table>
+            </div>
+            <div class="card"><h2>Serviços por Posto</h2>{por_posto}</div>
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        inicio = analise.inicio.format("%Y-%m-%d"),
+        fim = analise.fim.format("%Y-%m-%d"),
+        csv = csv,
+        periodo = periodo,
+        dias = analise.dias,
+        pessoas_n = analise.utilizadores.len(),
+        media = analise.media,
+        por_ano = grupos("Por Ano", &analise.por_ano),
+        por_genero = grupos("Por Género", &analise.por_genero),
+        pessoas = pessoas,
+        por_posto = por_posto_html,
+    ))
+}
+
+fn opcoes_tipo_dia(selecionado: Option<&TipoServico>, vazio: Option<&str>) -> String {
+    let mut opcoes = vazio.map_or(String::new(), |v| format!(r#"<option value=""{}>{}</option>"#, if selecionado.is_none() { " selected" } else { "" }, v));
+    for tipo in [TipoServico::RN, TipoServico::RD, TipoServico::UDRD, TipoServico::ER] {
+        opcoes.push_str(&format!(
+            r#"<option value="{t}"{sel}>{t}</option>"#,
+            t = tipo_str(&tipo),
+            sel = if selecionado == Some(&tipo) { " selected" } else { "" },
+        ));
+    }
+    opcoes
+}
+
+/// Os dias marcados no calendário da escala, as regras e a importação de um `.ics`.
+pub fn calendario_page(calendario: &Calendario) -> Html<String> {
+    let linhas: String = calendario
+        .dias
+        .iter()
+        .map(|d| {
+            let data = if d.anual {
+                format!("{} (todos os anos)", d.data.format("%d/%m"))
+            } else {
+                format!("{} {}", dia_da_semana(d.data), d.data.format("%d/%m/%Y"))
+            };
+            format!(
+                r#"<tr><td>{data}</td><td>{descricao}</td><td>{tipo}</td><td>{feriado}</td><td><form action="/admin/escala/calendario/remover" method="post"><input type="hidden" name="data" value="{valor}"><input type="hidden" name="anual" value="{anual}"><button type="submit" class="btn btn-danger">Remover</button></form></td></tr>"#,
+                data = data,
+                descricao = d.descricao,
+                tipo = tipo_str(&d.tipo_dia),
+                feriado = if d.feriado { "Feriado" } else { "Rotina" },
+                valor = d.data.format("%Y-%m-%d"),
+                anual = d.anual,
+            )
+        })
+        .collect();
+    let dias_html = if linhas.is_empty() {
+        "<p>Ainda não há dias marcados.</p>".to_string()
+    } else {
+        format!("<table><thead><tr><th>Data</th><th>Descrição</th><th>Rotina</th><th></th><th></th></tr></thead><tbody>{}</tbody></table>", linhas)
+    };
+    let regras = &calendario.regras;
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Calendário da Escala</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Calendário da Escala</h1>
+            <p>O formulário de geração vem preenchido com o tipo de rotina de cada dia: o dos dias marcados aqui ou, nos outros, o das regras.
+            Antes de gerar, cada dia pode ainda ser mudado.</p>
+            <div class="card"><h2>Dias Marcados</h2>{dias_html}</div>
+            <div class="card"><h2>Marcar Dias</h2>
+                <form action="/admin/escala/calendario/adicionar" method="post">
+                    <p><label>De <input type="date" name="data" required></label> <label>até <input type="date" name="ate"></label> (opcional)</p>
+                    <p><label>Descrição: <input type="text" name="descricao" required placeholder="Tiradentes"></label>
+                    <label>Rotina: <select name="tipo_dia">{opcoes_tipo}</select></label></p>
+                    <p><label><input type="checkbox" name="feriado" checked> Feriado (a véspera segue a regra da véspera de feriado)</label><br>
+                    <label><input type="checkbox" name="anual"> Repete-se todos os anos (só para um dia)</label></p>
+                    <button type="submit" class="btn btn-primary">Marcar</button>
+                </form>
+            </div>
+            <div class="card"><h2>Regras</h2>
+                <p>Valem para os dias sem marcação. Sem tipo, a regra não se aplica.</p>
+                <form action="/admin/escala/calendario/regras" method="post">
+                    <table><tbody>
+                        <tr><td>Sábados e domingos</td><td><select name="fim_de_semana">{fim_de_semana}</select></td></tr>
+                        <tr><td>Véspera de feriado</td><td><select name="vespera_feriado">{vespera_feriado}</select></td></tr>
+                        <tr><td>Véspera de fim de semana</td><td><select name="vespera_fim_de_semana">{vespera_fim_de_semana}</select></td></tr>
+                        <tr><td>Feriados importados</td><td><select name="feriado">{feriado}</select></td></tr>
+                    </tbody></table>
+                    <button type="submit" class="btn btn-primary">Salvar Regras</button>
+                </form>
+            </div>
+            <div class="card"><h2>Importar Feriados (.ics)</h2>
+                <p>Cada evento do ficheiro marca os seus dias como feriado, com a rotina dos feriados importados. Os eventos anuais ficam marcados para todos os anos.
+                Os dias já marcados nas mesmas datas são substituídos.</p>
+                <form action="/admin/escala/calendario/importar" method="post">
+                    <input type="file" accept=".ics,text/calendar" onchange="const f = this.files[0]; if (f) f.text().then(t => document.getElementById('ics').value = t);">
+                    <p><textarea id="ics" name="ics" rows="6" style="width: 100%;" placeholder="BEGIN:VCALENDAR" required></textarea></p>
+                    <button type="submit" class="btn btn-primary">Importar</button>
+                </form>
+            </div>
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        dias_html = dias_html,
+        opcoes_tipo = opcoes_tipo_dia(Some(&TipoServico::RD), None),
+        fim_de_semana = opcoes_tipo_dia(regras.fim_de_semana.as_ref(), Some("—")),
+        vespera_feriado = opcoes_tipo_dia(regras.vespera_feriado.as_ref(), Some("—")),
+        vespera_fim_de_semana = opcoes_tipo_dia(regras.vespera_fim_de_semana.as_ref(), Some("—")),
+        feriado = opcoes_tipo_dia(Some(&regras.feriado), None),
+    ))
+}
+
+/// O livro das dívidas: as dívidas por pagar, com ações para perdoar e transferir, o
+/// lançamento manual, as regras da virada de ano e o histórico das alterações.
+pub fn dividas_page(
+    dividas: &DividasAtivas,
+    movimentos: &[MovimentoDivida],
+    regras: &RegrasDividas,
+    users: &HashMap<String, User>,
+) -> Html<String> {
+    let nome = |id: &str| users.get(id).map_or(id.to_string(), |u| format!("{} ({})", u.name, u.id));
+    let mut ativos: Vec<&User> = users.values().filter(|u| u.ativo).collect();
+    ativos.sort_by(|a, b| (a.ano, &a.name).cmp(&(b.ano, &b.name)));
+    let opcoes_pessoas = |vazio: &str| {
+        let mut html = format!(r#"<option value="">{}</option>"#, vazio);
+        for u in &ativos {
+            html.push_str(&format!(r#"<option value="{}">{} ({}, {}º ano)</option>"#, u.id, u.name, u.id, u.ano));
+        }
+        html
+    };
+    let opcoes_manter = opcoes_pessoas("(o mesmo)");
+
+    let mut devedores: Vec<&String> = dividas.keys().collect();
+    devedores.sort_by_key(|id| nome(id));
+    let mut linhas = String::new();
+    for devedor in devedores {
+        for (indice, divida) in dividas[devedor].iter().enumerate() {
+            let posicao = format!(
+                r#"<input type="hidden" name="devedor" value="{}"><input type="hidden" name="indice" value="{}"><input type="hidden" name="credor" value="{}">"#,
+                devedor, indice, divida.credor
+            );
+            linhas.push_str(&format!(
+                r#"<tr><td>{devedor}</td><td>{credor}</td><td>{tipo}</td><td>{origem}</td><td>{desde}</td>
+                <td><form action="/admin/escala/dividas/perdoar" method="post" onsubmit="return confirm('Perdoar esta dívida?');">{posicao}<input type="text" name="motivo" required placeholder="Motivo"> <button type="submit" class="btn btn-danger">Perdoar</button></form></td>
+                <td><form action="/admin/escala/dividas/transferir" method="post">{posicao}<select name="novo_devedor">{opcoes}</select> deve a <select name="novo_credor">{opcoes}</select><br><input type="text" name="motivo" required placeholder="Motivo"> <button type="submit" class="btn btn-primary">Transferir</button></form></td></tr>"#,
+                devedor = nome(devedor),
+                credor = nome(&divida.credor),
+                tipo = tipo_str(&divida.tipo_divida),
+                origem = divida.origem.descrever(),
+                desde = divida.criada_em.map_or("—".to_string(), |d| d.format("%d/%m/%Y").to_string()),
+                posicao = posicao,
+                opcoes = opcoes_manter,
+            ));
+        }
+    }
+    let dividas_html = if linhas.is_empty() {
+        "<p>Não há dívidas por pagar.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>Devedor</th><th>Credor</th><th>Tipo</th><th>Origem</th><th>Desde</th><th>Perdoar</th><th>Transferir</th></tr></thead><tbody>{}</tbody></table>",
+            linhas
+        )
+    };
+
+    let historico: String = movimentos
+        .iter()
+        .rev()
+        .take(200)
+        .map(|m| {
+            let depois = m.para.as_ref().map_or(String::new(), |(devedor, credor)| format!(" → {} deve a {}", nome(devedor), nome(credor)));
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{} deve a {} ({}){}</td><td>{}</td></tr>",
+                m.em.format("%d/%m/%Y %H:%M"),
+                nome(&m.por),
+                m.acao.nome(),
+                nome(&m.devedor),
+                nome(&m.divida.credor),
+                tipo_str(&m.divida.tipo_divida),
+                depois,
+                m.motivo
+            )
+        })
+        .collect();
+    let historico_html = if historico.is_empty() {
+        "<p>Ainda não há alterações registadas.</p>".to_string()
+    } else {
+        format!("<table><thead><tr><th>Quando</th><th>Por</th><th>Ação</th><th>Dívida</th><th>Motivo</th></tr></thead><tbody>{}</tbody></table>", historico)
+    };
+    let destinos: String = DestinoDividas::TODOS
+        .iter()
+        .map(|d| format!(r#"<option value="{:?}" {}>{}</option>"#, d, if *d == regras.destino_fim_de_ano { "selected" } else { "" }, d.nome()))
+        .collect();
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Dívidas de Serviço</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Dívidas de Serviço</h1>
+            <p>Quem é substituído num serviço fica a dever um serviço do mesmo tipo a quem o substituiu; a geração paga as dívidas.
+            Cada alteração feita aqui fica no histórico, com o motivo.</p>
+            <div class="card"><h2>Por Pagar</h2>{dividas_html}</div>
+            <div class="card"><h2>Lançar Dívida</h2>
+                <form action="/admin/escala/dividas/lancar" method="post">
+                    <p><label>Devedor: <select name="devedor" required>{pessoas}</select></label>
+                    <label>Credor: <select name="credor" required>{pessoas}</select></label>
+                    <label>Tipo: <select name="tipo_divida">{opcoes_tipo}</select></label></p>
+                    <p><label>Motivo: <input type="text" name="motivo" required style="width: 60%;"></label></p>
+                    <button type="submit" class="btn btn-primary">Lançar</button>
+                </form>
+            </div>
+            <div class="card"><h2>Virada de Ano</h2>
+                <p>O que acontece às dívidas por pagar na virada de ano. O admin pode ainda escolher outro destino na própria virada.</p>
+                <form action="/admin/escala/dividas/regras" method="post">
+                    <p><label>Destino: <select name="destino_fim_de_ano">{destinos}</select></label>
+                    <label>Serviços de punição por dívida convertida: <input type="number" name="servicos_por_divida" min="1" max="10" value="{servicos}"></label></p>
+                    <button type="submit" class="btn btn-primary">Salvar Regras</button>
+                </form>
+            </div>
+            <div class="card"><h2>Histórico</h2>{historico_html}</div>
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        dividas_html = dividas_html,
+        pessoas = opcoes_pessoas("—"),
+        opcoes_tipo = opcoes_tipo_dia(Some(&TipoServico::RN), None),
+        destinos = destinos,
+        servicos = regras.servicos_por_divida,
+        historico_html = historico_html,
+    ))
+}
+
+/// A gestão das punições: as em atraso (com o agravamento), as ativas, a aplicação de
+/// novas e o histórico das cumpridas e anuladas.
+pub fn punicoes_page(punicoes: &[Punicao], users: &HashMap<String, User>, hoje: NaiveDate) -> Html<String> {
+    let nome = |id: &str| users.get(id).map_or(id.to_string(), |u| format!("{} ({})", u.name, u.id));
+    let data = |d: Option<NaiveDate>| d.map_or("—".to_string(), |d| d.format("%d/%m/%Y").to_string());
+    let posicao = |indice: usize, p: &Punicao| {
+        format!(r#"<input type="hidden" name="user_id" value="{}"><input type="hidden" name="indice" value="{}">"#, p.user_id, indice)
+    };
+    let aviso = |indice: usize, p: &Punicao| format!(r#"<a href="/admin/escala/punicoes/aviso?user_id={}&indice={}">Notificação (PDF)</a>"#, p.user_id, indice);
+    let cumpridos = |p: &Punicao| {
+        if p.cumpridos.is_empty() {
+            return "—".to_string();
+        }
+        p.cumpridos.iter().map(|s| s.descrever()).collect::<Vec<_>>().join("<br>")
+    };
+    let historico = |p: &Punicao| {
+        let registos: String = p
+            .historico
+            .iter()
+            .map(|r| {
+                let por = if r.por.is_empty() { String::new() } else { format!(" — {}", nome(&r.por)) };
+                format!("<li>{}{}: {}</li>", r.em.format("%d/%m/%Y %H:%M"), por, r.texto)
+            })
+            .collect();
+        if registos.is_empty() { String::new() } else { format!("<details><summary>Histórico</summary><ul>{}</ul></details>", registos) }
+    };
+
+    let atrasadas: String = escala_punicoes::em_atraso(punicoes, hoje)
+        .into_iter()
+        .map(|(indice, p)| {
+            format!(
+                r#"<tr><td>{pessoa}</td><td>{motivo}</td><td>{prazo}</td><td>{dias}</td><td>{faltam}</td><td>{aviso}</td>
+                <td><form action="/admin/escala/punicoes/agravar" method="post">{posicao}<input type="number" name="servicos" min="1" max="{maximo}" value="1" style="width: 4em;"> serviço(s), novo prazo <input type="date" name="prazo"><br><input type="text" name="motivo" required placeholder="Motivo"> <button type="submit" class="btn btn-danger">Agravar</button></form></td></tr>"#,
+                pessoa = nome(&p.user_id),
+                motivo = p.motivo,
+                prazo = data(p.prazo),
+                dias = p.prazo.map_or(0, |prazo| (hoje - prazo).num_days()),
+                faltam = p.restantes(),
+                aviso = aviso(indice, p),
+                posicao = posicao(indice, p),
+                maximo = escala_punicoes::MAXIMO_SERVICOS,
+            )
+        })
+        .collect();
+    let atrasadas_html = if atrasadas.is_empty() {
+        "<p>Não há punições em atraso.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>Pessoa</th><th>Motivo</th><th>Prazo</th><th>Dias de atraso</th><th>Faltam</th><th>Aviso</th><th>Agravar</th></tr></thead><tbody>{}</tbody></table>",
+            atrasadas
+        )
+    };
+
+    let mut ativas: Vec<(usize, &Punicao)> = punicoes.iter().enumerate().filter(|(_, p)| p.ativa()).collect();
+    ativas.sort_by_key(|(_, p)| (p.prazo.is_none(), p.prazo, nome(&p.user_id)));
+    let ativas: String = ativas
+        .into_iter()
+        .map(|(indice, p)| {
+            format!(
+                r#"<tr><td>{pessoa}</td><td>{motivo}</td><td>{autoridade}</td><td>{aplicada}</td><td>{prazo}</td><td>{feitos}/{total}</td><td>{cumpridos}</td><td>{aviso}{historico}</td>
+                <td><form action="/admin/escala/punicoes/anular" method="post" onsubmit="return confirm('Anular esta punição?');">{posicao}<input type="text" name="motivo" required placeholder="Motivo"> <button type="submit" class="btn btn-danger">Anular</button></form></td></tr>"#,
+                pessoa = nome(&p.user_id),
+                motivo = p.motivo,
+                autoridade = p.autoridade,
+                aplicada = data(p.aplicada_em),
+                prazo = if p.em_atraso(hoje) { format!("<strong style='color:#c0392b'>{}</strong>", data(p.prazo)) } else { data(p.prazo) },
+                feitos = p.ja_cumpridos,
+                total = p.total_a_cumprir,
+                cumpridos = cumpridos(p),
+                aviso = aviso(indice, p),
+                historico = historico(p),
+                posicao = posicao(indice, p),
+            )
+        })
+        .collect();
+    let ativas_html = if ativas.is_empty() {
+        "<p>Não há punições ativas.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>Pessoa</th><th>Motivo</th><th>Autoridade</th><th>Aplicada em</th><th>Prazo</th><th>Cumpridos</th><th>Serviços</th><th></th><th>Anular</th></tr></thead><tbody>{}</tbody></table>",
+            ativas
+        )
+    };
+
+    let fechadas: String = punicoes
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, p)| !p.ativa())
+        .map(|(indice, p)| {
+            let estado = match p.anulada_em {
+                Some(em) => format!("Anulada em {}", em.format("%d/%m/%Y")),
+                None => format!("Cumprida em {}", data(p.cumpridos.last().map(|s| s.data))),
+            };
+            format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}/{}</td><td>{}</td><td>{}</td><td>{}{}</td></tr>",
+                nome(&p.user_id),
+                p.motivo,
+                p.autoridade,
+                data(p.aplicada_em),
+                p.ja_cumpridos,
+                p.total_a_cumprir,
+                cumpridos(p),
+                estado,
+                aviso(indice, p),
+                historico(p)
+            )
+        })
+        .collect();
+    let fechadas_html = if fechadas.is_empty() {
+        "<p>Ainda não há punições cumpridas ou anuladas.</p>".to_string()
+    } else {
+        format!(
+            "<table><thead><tr><th>Pessoa</th><th>Motivo</th><th>Autoridade</th><th>Aplicada em</th><th>Cumpridos</th><th>Serviços</th><th>Estado</th><th></th></tr></thead><tbody>{}</tbody></table>",
+            fechadas
+        )
+    };
+
+    let mut pessoas: Vec<&User> = users.values().filter(|u| u.ativo).collect();
+    pessoas.sort_by(|a, b| (a.ano, &a.name).cmp(&(b.ano, &b.name)));
+    let pessoas: String = pessoas
+        .iter()
+        .map(|u| format!(r#"<option value="{}">{} ({}, {}º ano)</option>"#, u.id, u.name, u.id, u.ano))
+        .collect();
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Punições</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Punições</h1>
+            <p>Os punidos fazem serviços extra nos postos de punição, em dias de RD, UDRD ou ER, e não entram no retém enquanto a punição estiver ativa.
+            Cada serviço cumprido fica ligado ao dia da escala em que foi feito.</p>
+            <div class="card"><h2>Em Atraso</h2>
+                <p>Punições com o prazo passado e serviços por cumprir. Agravar junta serviços e pode dar um novo prazo.</p>
+                {atrasadas_html}
+            </div>
+            <div class="card"><h2>Ativas</h2>{ativas_html}</div>
+            <div class="card"><h2>Aplicar Punição</h2>
+                <form action="/admin/escala/punicoes/adicionar" method="post">
+                    <p><label>Utilizador: <select name="user_id" required><option value="">—</option>{pessoas}</select></label>
+                    <label>Nº de serviços: <input type="number" name="total_a_cumprir" min="1" max="{maximo}" required></label></p>
+                    <p><label>Motivo: <input type="text" name="motivo" required style="width: 60%;"></label></p>
+                    <p><label>Autoridade: <input type="text" name="autoridade" required placeholder="Quem deu a punição"></label>
+                    <label>Data: <input type="date" name="aplicada_em" value="{hoje}"></label>
+                    <label>Prazo: <input type="date" name="prazo"></label></p>
+                    <button type="submit" class="btn btn-primary">Aplicar</button>
+                </form>
+            </div>
+            <div class="card"><h2>Cumpridas e Anuladas</h2>{fechadas_html}</div>
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        atrasadas_html = atrasadas_html,
+        ativas_html = ativas_html,
+        pessoas = pessoas,
+        maximo = escala_punicoes::MAXIMO_SERVICOS,
+        hoje = hoje.format("%Y-%m-%d"),
+        fechadas_html = fechadas_html,
+    ))
+}
+
+/// O relatório da validação de um intervalo. Com `lancamento_recusado`, explica que o
+/// período seguinte não foi lançado por causa dos erros.
+pub fn validacao_page(relatorio: &RelatorioValidacao, users: &HashMap<String, User>, lancamento_recusado: bool) -> Html<String> {
+    let nome = |id: &str| users.get(id).map_or(id.to_string(), |u| format!("{} ({})", u.name, u.id));
+    let linhas: String = relatorio
+        .problemas
+        .iter()
+        .map(|p| {
+            let cor = match p.gravidade {
+                Gravidade::Erro => "#dc3545",
+                Gravidade::Aviso => "#e0a800",
+            };
+            format!(
+                "<tr><td><strong style='color:{}'>{}</strong></td><td>{} {}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                cor,
+                p.gravidade.nome(),
+                dia_da_semana(p.data),
+                p.data.format("%d/%m/%Y"),
+                nome(&p.user_id),
+                p.regra.nome(),
+                p.descricao
+            )
+        })
+        .collect();
+    let problemas_html = if linhas.is_empty() {
+        "<p>Nenhum problema encontrado.</p>".to_string()
+    } else {
+        format!("<table><thead><tr><th>Gravidade</th><th>Dia</th><th>Pessoa</th><th>Regra</th><th>Problema</th></tr></thead><tbody>{}</tbody></table>", linhas)
+    };
+    let periodo = format!("{} a {}", relatorio.inicio.format("%d/%m/%Y"), relatorio.fim.format("%d/%m/%Y"));
+    let recusa_html = if lancamento_recusado {
+        r#"<div class="card" style="border-left: 4px solid #dc3545;"><h2>Escala Não Lançada</h2><p>A escala seguinte tem erros. Corrija-os (por exemplo, com uma troca obrigatória) e lance-a de novo.</p></div>"#
+    } else {
+        ""
+    };
+
+    Html(format!(
+        r#"<!DOCTYPE html>
+        <html lang="pt-BR">
+        <head><title>Admin - Validação da Escala</title><meta charset="UTF-8"><style>{estilo}</style></head>
+        <body>
+            <h1>Validação da Escala</h1>
+            {recusa_html}
+            <div class="card">
+                <form action="/admin/escala/validar" method="get" class="acoes">
+                    <label>De <input type="date" name="inicio" value="{inicio}"></label>
+                    <label>a <input type="date" name="fim" value="{fim}"></label>
+                    <button type="submit" class="btn btn-primary">Validar</button>
+                </form>
+                <p>{periodo}: {dias} dias com escala gravada, {erros} erro(s) e {avisos} aviso(s).</p>
+                <p>Os erros impedem o lançamento da escala seguinte: a mesma pessoa duas vezes no mesmo dia ou em dias seguidos, um posto
+                que já não existe ou que não aceita o género, o ano ou a função da pessoa, uma indisponibilidade aprovada, um punido no
+                retém ou um utilizador desconhecido ou desativado. As indisponibilidades por decidir são avisos.</p>
+            </div>
+            <div class="card"><h2>Problemas</h2>{problemas_html}</div>
+            <a href="/admin/escala">← Voltar à Gestão de Escalas</a>
+        </body>
+        </html>"#,
+        estilo = ESTILO,
+        recusa_html = recusa_html,
+        inicio = relatorio.inicio.format("%Y-%m-%d"),
+        fim = relatorio.fim.format("%Y-%m-%d"),
+        periodo = periodo,
+        dias = relatorio.dias,
+        erros = relatorio.erros(),
+        avisos = relatorio.avisos(),
+        problemas_html = problemas_html,
+    ))
+}
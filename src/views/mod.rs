@@ -1,6 +1,7 @@
 // src/views/mod.rs
 pub mod admin;
 pub mod dashboard;
+pub mod escala;
 pub mod presence;
 pub mod meals;
 pub mod cautela;
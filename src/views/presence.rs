@@ -166,7 +166,7 @@ pub fn render_presence_page(
                 setTimeout(() => {{ notification.style.display = 'none'; }}, 3000);
             }}
 
-            const ws = new WebSocket(`ws://${{window.location.host}}/ws/presence`);
+            const ws = new WebSocket(`${{window.location.protocol === 'https:' ? 'wss' : 'ws'}}://${{window.location.host}}/ws/presence`);
             
             ws.onopen = () => console.log("WebSocket de Presença Conectado.");
             ws.onerror = () => showNotification("Erro de conexão com o servidor.", "error");
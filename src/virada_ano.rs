@@ -0,0 +1,357 @@
+// src/virada_ano.rs
+
+//! # Virada de Ano
+//!
+//! No fim de cada ano letivo todos os alunos sobem um ano e os do último ano saem.
+//! O plano é calculado primeiro, sem gravar nada, para o admin ver o que vai mudar;
+//! só depois de confirmado é aplicado. Quem sai é desativado (o registo fica guardado),
+//! e a contagem de serviços, as dívidas e as punições podem transitar ou ser limpas.
+//! As dívidas por pagar podem ainda passar a punição do devedor (`RegrasDividas`).
+
+use crate::auth::User;
+use crate::escala::{Contagem, Divida, DividasAtivas, Punicao};
+use crate::escala_dividas::{self, DestinoDividas, RegrasDividas};
+use crate::store::{EscalaStore, Recusa};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+type AppResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Último ano do curso: quem está neste ano sai na virada.
+pub const ULTIMO_ANO: u8 = 3;
+
+/// Escolhas do admin para a virada, vindas do formulário.
+/// Por omissão, a contagem e as punições de quem continua transitam, e as dívidas seguem
+/// a regra configurada.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct OpcoesVirada {
+    /// Põe a contagem de serviços de todos a zero.
+    pub zerar_contagem: bool,
+    /// O destino das dívidas por pagar; `None` usa `RegrasDividas::destino_fim_de_ano`.
+    pub dividas: Option<DestinoDividas>,
+    /// Mantém as punições por cumprir de quem continua; caso contrário, são todas apagadas.
+    pub manter_punicoes: bool,
+}
+
+impl Default for OpcoesVirada {
+    fn default() -> Self {
+        Self { zerar_contagem: false, dividas: None, manter_punicoes: true }
+    }
+}
+
+/// O que acontece a um utilizador na virada. `para` é `None` para quem sai.
+#[derive(Debug, Clone)]
+pub struct Mudanca {
+    pub id: String,
+    pub nome: String,
+    pub de: u8,
+    pub para: Option<u8>,
+}
+
+/// Resultado da virada, antes de ser gravado.
+pub struct PlanoVirada {
+    pub mudancas: Vec<Mudanca>,
+    /// Utilizadores já alterados, prontos a gravar.
+    pub utilizadores: Vec<User>,
+    pub contagem: Contagem,
+    pub dividas: DividasAtivas,
+    pub punicoes: Vec<Punicao>,
+    pub contagens_removidas: usize,
+    pub dividas_removidas: usize,
+    pub punicoes_removidas: usize,
+    /// O destino escolhido para as dívidas e as que saem do livro, com o devedor.
+    pub destino_dividas: DestinoDividas,
+    pub dividas_fechadas: Vec<(String, Divida)>,
+    /// Serviços de punição que as dívidas convertidas acrescentam.
+    pub servicos_convertidos: u32,
+}
+
+impl PlanoVirada {
+    pub fn promovidos(&self) -> impl Iterator<Item = &Mudanca> {
+        self.mudancas.iter().filter(|m| m.para.is_some())
+    }
+
+    pub fn formados(&self) -> impl Iterator<Item = &Mudanca> {
+        self.mudancas.iter().filter(|m| m.para.is_none())
+    }
+
+    /// Identifica as mudanças previstas. A confirmação só é aceite se o plano calculado
+    /// nessa altura for o mesmo que foi pré-visualizado (p. ex. não aplica a virada duas vezes).
+    pub fn assinatura(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        for m in &self.mudancas {
+            (&m.id, m.de, m.para).hash(&mut hasher);
+        }
+        (self.contagens_removidas, self.dividas_removidas, self.punicoes_removidas, self.servicos_convertidos).hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// Calcula a virada sem gravar nada.
+/// Só os alunos ativos (ano 1 ou mais) mudam; o ano 0 é para quem não tem turma.
+pub fn planear(
+    users: &HashMap<String, User>,
+    contagem: Contagem,
+    dividas: DividasAtivas,
+    punicoes: Vec<Punicao>,
+    opcoes: OpcoesVirada,
+    regras: &RegrasDividas,
+) -> PlanoVirada {
+    let mut alunos: Vec<&User> = users.values().filter(|u| u.ativo && u.ano > 0).collect();
+    alunos.sort_by(|a, b| a.ano.cmp(&b.ano).then_with(|| a.id.cmp(&b.id)));
+
+    let mut mudancas = Vec::new();
+    let mut utilizadores = Vec::new();
+    for user in alunos {
+        let mut alterado = user.clone();
+        let para = if user.ano >= ULTIMO_ANO {
+            alterado.ativo = false;
+            None
+        } else {
+            alterado.ano += 1;
+            Some(alterado.ano)
+        };
+        mudancas.push(Mudanca { id: user.id.clone(), nome: user.name.clone(), de: user.ano, para });
+        utilizadores.push(alterado);
+    }
+    let saem = |id: &str| mudancas.iter().any(|m| m.id == id && m.para.is_none());
+
+    let antes = contagem.len();
+    let contagem: Contagem = if opcoes.zerar_contagem {
+        Contagem::new()
+    } else {
+        contagem.into_iter().filter(|(id, _)| !saem(id)).collect()
+    };
+    let contagens_removidas = antes - contagem.len();
+
+    // Uma dívida só pode ser paga se o devedor e o credor continuarem
+    let destino_dividas = opcoes.dividas.unwrap_or(regras.destino_fim_de_ano);
+    let mut devedores: Vec<String> = dividas.keys().cloned().collect();
+    devedores.sort();
+    let mut dividas = dividas;
+    let mut mantidas = DividasAtivas::new();
+    let mut dividas_fechadas = Vec::new();
+    for devedor in devedores {
+        for divida in dividas.remove(&devedor).unwrap_or_default() {
+            if destino_dividas == DestinoDividas::Manter && !saem(&devedor) && !saem(&divida.credor) {
+                mantidas.entry(devedor.clone()).or_default().push(divida);
+            } else {
+                dividas_fechadas.push((devedor.clone(), divida));
+            }
+        }
+    }
+    let dividas_removidas = dividas_fechadas.len();
+
+    let antes = punicoes.len();
+    let mut punicoes: Vec<Punicao> = if opcoes.manter_punicoes {
+        punicoes.into_iter().filter(|p| !saem(&p.user_id)).collect()
+    } else {
+        Vec::new()
+    };
+    let punicoes_removidas = antes - punicoes.len();
+
+    // Quem sai já não cumpre punições: as suas dívidas são só apagadas
+    let mut servicos_convertidos = 0;
+    if destino_dividas == DestinoDividas::Punir {
+        let mut por_devedor: BTreeMap<&str, u32> = BTreeMap::new();
+        for (devedor, _) in dividas_fechadas.iter().filter(|(devedor, _)| !saem(devedor)) {
+            *por_devedor.entry(devedor.as_str()).or_default() += regras.servicos_por_divida;
+        }
+        for (devedor, servicos) in por_devedor {
+            escala_dividas::acrescentar_punicao(&mut punicoes, devedor, servicos);
+            servicos_convertidos += servicos;
+        }
+    }
+
+    PlanoVirada {
+        mudancas,
+        utilizadores,
+        contagem,
+        dividas: mantidas,
+        punicoes,
+        contagens_removidas,
+        dividas_removidas,
+        punicoes_removidas,
+        destino_dividas,
+        dividas_fechadas,
+        servicos_convertidos,
+    }
+}
+
+/// Lê os dados atuais e calcula a virada.
+pub async fn carregar_plano(
+    escala: &dyn EscalaStore,
+    users: &HashMap<String, User>,
+    opcoes: OpcoesVirada,
+) -> AppResult<PlanoVirada> {
+    let regras = escala.configuracao().await?.dividas;
+    Ok(planear(users, escala.contagem().await?, escala.dividas().await?, escala.punicoes().await?, opcoes, &regras))
+}
+
+/// Grava a virada numa só transação: os utilizadores e os dados da escala. Dentro da
+/// transação confirma que os alunos ainda estão no ano de que o plano parte, para a
+/// virada nunca ser aplicada duas vezes. As dívidas que saem do livro ficam no histórico
+/// das dívidas, em nome de `por`.
+pub async fn aplicar(escala: &dyn EscalaStore, plano: &PlanoVirada, por: &str) -> AppResult<()> {
+    let mudancas = plano.mudancas.clone();
+    let utilizadores = plano.utilizadores.clone();
+    let contagem = plano.contagem.clone();
+    let dividas = plano.dividas.clone();
+    let punicoes = plano.punicoes.clone();
+    let fechadas = plano.dividas_fechadas.clone();
+    let destino = plano.destino_dividas;
+    let por = por.to_string();
+    escala
+        .transacao(Box::new(move |tx| {
+            let atuais = tx.utilizadores()?;
+            let por_aplicar = |m: &Mudanca| atuais.get(&m.id).is_some_and(|u| u.ativo && u.ano == m.de);
+            if !mudancas.iter().all(por_aplicar) {
+                return Err(Recusa("Os utilizadores mudaram desde que a virada foi calculada (ou a virada já foi aplicada).").into());
+            }
+            tx.guardar_utilizadores(&utilizadores)?;
+            tx.guardar_contagem(&contagem)?;
+            tx.guardar_dividas(&dividas)?;
+            tx.guardar_punicoes(&punicoes)?;
+            escala_dividas::registar_virada(tx, &por, &fechadas, destino)
+        }))
+        .await?;
+    println!(
+        "🎓 Virada de ano: {} aluno(s) promovido(s), {} saíram.",
+        plano.promovidos().count(),
+        plano.formados().count()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod testes {
+    use super::*;
+    use crate::escala::{ContagemUtilizador, Genero, TipoServico};
+    use crate::store::{memoria::MemoriaStore, UserStore};
+    use crate::testes::{mapa, utilizador};
+
+    fn users() -> HashMap<String, User> {
+        let mut professor = utilizador("0001", 0, Genero::Misto);
+        professor.roles = vec!["admin".to_string()];
+        mapa(&[
+            professor,
+            utilizador("1001", 1, Genero::Masculino),
+            utilizador("2001", 2, Genero::Feminino),
+            utilizador("3001", 3, Genero::Masculino),
+        ])
+    }
+
+    fn divida(credor: &str) -> Divida {
+        Divida { credor: credor.to_string(), tipo_divida: TipoServico::RN, origem: Default::default(), criada_em: None }
+    }
+
+    fn contagem() -> Contagem {
+        ["1001", "2001", "3001"].iter().map(|id| (id.to_string(), ContagemUtilizador { rn: 3, rd: 1, retem: 2 })).collect()
+    }
+
+    fn dividas() -> DividasAtivas {
+        // 1001 deve a 2001 (ambos continuam) e a 3001 (sai)
+        [("1001".to_string(), vec![divida("2001"), divida("3001")])].into_iter().collect()
+    }
+
+    #[test]
+    fn sobem_de_ano_e_os_do_ultimo_saem() {
+        let plano = planear(&users(), contagem(), DividasAtivas::new(), Vec::new(), OpcoesVirada::default(), &RegrasDividas::default());
+
+        let mudancas: Vec<(&str, u8, Option<u8>)> = plano.mudancas.iter().map(|m| (m.id.as_str(), m.de, m.para)).collect();
+        assert_eq!(mudancas, vec![("1001", 1, Some(2)), ("2001", 2, Some(3)), ("3001", 3, None)]);
+        let formado = plano.utilizadores.iter().find(|u| u.id == "3001").unwrap();
+        assert!(!formado.ativo);
+        assert_eq!(formado.ano, 3);
+        // Quem não tem ano não muda
+        assert!(plano.utilizadores.iter().all(|u| u.id != "0001"));
+        // A contagem de quem sai é removida
+        assert_eq!(plano.contagens_removidas, 1);
+        assert!(!plano.contagem.contains_key("3001"));
+        assert_eq!(plano.contagem["1001"].rn, 3);
+    }
+
+    #[test]
+    fn zerar_contagem_e_apagar_punicoes() {
+        let punicoes = vec![
+            Punicao { user_id: "1001".to_string(), total_a_cumprir: 2, ..Default::default() },
+            Punicao { user_id: "3001".to_string(), total_a_cumprir: 2, ..Default::default() },
+        ];
+        let opcoes = OpcoesVirada { zerar_contagem: false, dividas: None, manter_punicoes: true };
+        let plano = planear(&users(), contagem(), DividasAtivas::new(), punicoes.clone(), opcoes, &RegrasDividas::default());
+        assert_eq!(plano.punicoes.len(), 1);
+        assert_eq!(plano.punicoes_removidas, 1);
+
+        let opcoes = OpcoesVirada { zerar_contagem: true, dividas: None, manter_punicoes: false };
+        let plano = planear(&users(), contagem(), DividasAtivas::new(), punicoes, opcoes, &RegrasDividas::default());
+        assert!(plano.contagem.is_empty());
+        assert!(plano.punicoes.is_empty());
+        assert_eq!((plano.contagens_removidas, plano.punicoes_removidas), (3, 2));
+    }
+
+    #[test]
+    fn mantem_so_as_dividas_entre_quem_continua() {
+        let opcoes = OpcoesVirada { dividas: Some(DestinoDividas::Manter), ..Default::default() };
+        let plano = planear(&users(), contagem(), dividas(), Vec::new(), opcoes, &RegrasDividas::default());
+        assert_eq!(plano.dividas["1001"], vec![divida("2001")]);
+        assert_eq!(plano.dividas_fechadas, vec![("1001".to_string(), divida("3001"))]);
+        assert_eq!(plano.servicos_convertidos, 0);
+    }
+
+    #[test]
+    fn converte_as_dividas_em_punicao_do_devedor() {
+        let regras = RegrasDividas { destino_fim_de_ano: DestinoDividas::Punir, servicos_por_divida: 2 };
+        let plano = planear(&users(), contagem(), dividas(), Vec::new(), OpcoesVirada::default(), &regras);
+        assert!(plano.dividas.is_empty());
+        assert_eq!(plano.dividas_removidas, 2);
+        assert_eq!(plano.servicos_convertidos, 4);
+        assert_eq!(plano.punicoes.len(), 1);
+        assert_eq!(plano.punicoes[0].user_id, "1001");
+        assert_eq!(plano.punicoes[0].total_a_cumprir, 4);
+    }
+
+    #[test]
+    fn assinatura_muda_com_os_dados() {
+        let plano = planear(&users(), contagem(), dividas(), Vec::new(), OpcoesVirada::default(), &RegrasDividas::default());
+        let mesmo = planear(&users(), contagem(), dividas(), Vec::new(), OpcoesVirada::default(), &RegrasDividas::default());
+        assert_eq!(plano.assinatura(), mesmo.assinatura());
+        let mut outros = users();
+        outros.insert("1002".to_string(), utilizador("1002", 1, Genero::Masculino));
+        let outro = planear(&outros, contagem(), dividas(), Vec::new(), OpcoesVirada::default(), &RegrasDividas::default());
+        assert_ne!(plano.assinatura(), outro.assinatura());
+    }
+
+    #[tokio::test]
+    async fn aplicar_grava_tudo_e_nao_repete() {
+        let store = MemoriaStore::new();
+        for user in users().values() {
+            UserStore::guardar(&store, user).await.unwrap();
+        }
+        let (contagem, dividas) = (contagem(), dividas());
+        store
+            .transacao(Box::new(move |tx| {
+                tx.guardar_contagem(&contagem)?;
+                tx.guardar_dividas(&dividas)
+            }))
+            .await
+            .unwrap();
+
+        let plano = carregar_plano(&store, &users(), OpcoesVirada::default()).await.unwrap();
+        aplicar(&store, &plano, "0001").await.unwrap();
+
+        let gravados = UserStore::listar(&store).await.unwrap();
+        assert_eq!(gravados["1001"].ano, 2);
+        assert!(!gravados["3001"].ativo);
+        assert!(!store.contagem().await.unwrap().contains_key("3001"));
+        assert_eq!(store.dividas().await.unwrap()["1001"], vec![divida("2001")]);
+        assert_eq!(store.movimentos_dividas().await.unwrap().len(), 1);
+
+        // O mesmo plano outra vez é recusado e não muda nada
+        let erro = aplicar(&store, &plano, "0001").await.unwrap_err();
+        assert!(erro.downcast_ref::<Recusa>().is_some());
+        assert_eq!(UserStore::listar(&store).await.unwrap()["1001"].ano, 2);
+        assert_eq!(store.movimentos_dividas().await.unwrap().len(), 1);
+    }
+}